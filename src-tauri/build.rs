@@ -1,7 +1,13 @@
 use std::io;
 
 fn main() -> Result<(), io::Error> {
-  prost_build::compile_protos(&["src/client/tcpMessages.proto"], &["src/client"])?;
+  // File parts are passed around as `Bytes` so upload chunks aren't copied
+  prost_build::Config::new()
+    .bytes([
+      ".FileShare.TcpMessages.ReceiveFilePart.data",
+      ".FileShare.TcpMessages.SpeedTest.data",
+    ])
+    .compile_protos(&["src/client/tcpMessages.proto"], &["src/client"])?;
 
   tauri_build::build();
 
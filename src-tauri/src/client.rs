@@ -1,33 +1,75 @@
 use core::fmt;
-use std::{collections::HashMap, error::Error, path::PathBuf, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, Result};
+use bytes::{BufMut, Bytes, BytesMut};
 use chrono::{DateTime, Utc};
+use cryptohelpers::crc::compute_stream;
 use futures::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
 
 use tokio::{
     fs::{self, File},
-    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
     net::{tcp::WriteHalf, TcpStream},
     sync::mpsc,
+    task::JoinHandle,
 };
 use tokio_util::codec::{FramedRead, FramedWrite};
 use uuid::Uuid;
 
 mod codec;
+mod metrics;
 mod protobuf;
 
+pub use self::{
+    codec::Compression,
+    metrics::{MessageCounters, ProtocolMetrics},
+};
+
 use crate::{
     config::StoredConfig,
-    data::{ContentLocation, PeerId, ShareDirectory, ShareDirectorySignature, SharedFile},
-    server::{ClientConnectionId, MessageToServer, ServerHandle},
-    window::Download,
+    data::{
+        ContentLocation, DirectoryDigest, PeerId, ShareDirectory, ShareDirectorySignature,
+        SharedFile,
+    },
+    encryption::{open_plain, Encryptor, FileKey, PlainReader},
+    server::{ClientConnectionId, DisconnectReason, MessageToServer, ServerHandle},
+    window::{display_name, Download, SpeedTestResult},
 };
 
 use self::codec::{MessageCodec, TcpMessage};
 
 const FILE_CHUNK_SIZE: usize = 1024 * 50; // 50 KB
+const PROGRESS_UPDATE_INTERVAL_MILLIS: u64 = 100;
+const MAX_CLOCK_SKEW_SECS: i64 = 60;
+/// Extension added to files while they are being downloaded. They are only
+/// renamed to their final name once complete and verified.
+const PARTIAL_EXTENSION: &str = "part";
+const TEMPORARY_EXTENSION: &str = "tmp";
+/// Longest path the platform accepts without long path support.
+#[cfg(windows)]
+const MAX_PATH_LENGTH: usize = 259;
+#[cfg(not(windows))]
+const MAX_PATH_LENGTH: usize = 4095;
+const MAX_FILE_NAME_LENGTH: usize = 255;
+/// Directories with more files than this are announced with their signature
+/// only, and peers fetch their file lists page by page.
+const MAX_PUSHED_FILES: usize = 200;
+const FILE_LIST_PAGE_SIZE: u64 = 200;
+/// File additions and deletions not acknowledged within this time are
+/// followed up by sending the whole directory.
+const CHANGE_ACK_TIMEOUT_SECS: u64 = 10;
+/// Data sent by a speed test. The connection handles nothing else while it's
+/// sent, so it is kept small enough to finish quickly on slow networks.
+const SPEED_TEST_BYTES: usize = 1024 * 1024 * 2; // 2 MB
+const SPEED_TEST_TIMEOUT_SECS: u64 = 30;
 
 #[derive(Debug, Clone)]
 pub enum MessageToClient {
@@ -48,6 +90,13 @@ pub enum MessageToClient {
     CancelDownload {
         download_id: Uuid,
     },
+    CancelUpload {
+        download_id: Uuid,
+    },
+    CancelFileUploads {
+        directory_identifier: Uuid,
+        file_identifier: Uuid,
+    },
     UpdateOwners {
         peer_id: PeerId,
         directory_identifier: Uuid,
@@ -58,9 +107,26 @@ pub enum MessageToClient {
     LeftDirectory {
         directory_identifier: Uuid,
     },
+
+    Goodbye(String),
+    /// Stops or restarts transfers with this peer while the network is paused.
+    SetPaused(bool),
+    /// Says goodbye without removing partial downloads, so they can be
+    /// retried after the app restarts.
+    Shutdown(String),
+    /// Measures latency and throughput to this peer.
+    SpeedTest {
+        test_id: Uuid,
+    },
+    /// Asks the peer for its copy of any of these directories that differs
+    /// from ours.
+    CompareDirectories(Vec<DirectoryDigest>),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Reason a download failed. The window receives the variant itself and
+/// renders its own message; `Display` is kept for logs.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
 pub enum DownloadError {
     NoClientsConnected = 0,
     DirectoryMissing,
@@ -71,6 +137,16 @@ pub enum DownloadError {
     Canceled,
     ReadError,
     WriteError,
+    Busy,
+    HashMismatch,
+    PathTooLong,
+    EncryptionLocked,
+    /// The download directory doesn't exist, e.g. because the drive it is on
+    /// was removed. Never sent to other peers.
+    DownloadDirectoryUnavailable,
+    /// An error type sent by a newer peer that this version doesn't know.
+    /// Never sent to other peers.
+    Unknown,
 }
 
 impl fmt::Display for DownloadError {
@@ -85,6 +161,12 @@ impl fmt::Display for DownloadError {
             DownloadError::Disconnected => "Download was canceled since one of the clients disconnected".to_owned(),
             DownloadError::ReadError => "Could not read file to download.".to_owned(),
             DownloadError::WriteError => "Could not write file.".to_owned(),
+            DownloadError::Busy => "Peer is handling too many transfers. Try again later.".to_owned(),
+            DownloadError::HashMismatch => "Peer has a different version of this file. Try again once directories have synchronized.".to_owned(),
+            DownloadError::PathTooLong => "Download path is too long. Choose a shorter download directory.".to_owned(),
+            DownloadError::EncryptionLocked => "Downloads are encrypted. Enter the encryption passphrase in settings first.".to_owned(),
+            DownloadError::DownloadDirectoryUnavailable => "Download directory is unavailable. Reconnect the drive it is on or choose another download directory.".to_owned(),
+            DownloadError::Unknown => "Download failed for a reason this version does not recognize.".to_owned(),
         };
 
         write!(f, "{}", msg)
@@ -97,16 +179,123 @@ struct DownloadHandle {
     canceled: bool,
     bytes_total: u64,
     bytes_done: u64,
+    last_progress: u64,
+    last_progress_update: Instant,
     output_file: File,
     output_path: PathBuf,
+    final_path: PathBuf,
+    content_hash: u64,
     file_id: Uuid,
     dir_id: Uuid,
+    /// Set when downloads are encrypted at rest.
+    encryptor: Option<Encryptor>,
+    key: Option<FileKey>,
+}
+
+impl DownloadHandle {
+    /// Percent of the file received, or `None` if more bytes arrived than
+    /// the file has. Empty files count as done from the start.
+    fn progress(&self) -> Option<u64> {
+        if self.bytes_done > self.bytes_total {
+            return None;
+        }
+
+        if self.bytes_total == 0 {
+            return Some(100);
+        }
+
+        let percent = u128::from(self.bytes_done) * 100 / u128::from(self.bytes_total);
+
+        Some(percent as u64)
+    }
+}
+
+/// An `AddedFiles` or `DeleteFile` message waiting for the peer to
+/// acknowledge it.
+struct PendingChange {
+    directory: Uuid,
+    last_modified: DateTime<Utc>,
+    sent_at: Instant,
+}
+
+/// A speed test this side started, or one the peer is running against us.
+struct SpeedTest {
+    started: Instant,
+    stage: SpeedTestStage,
+}
+
+enum SpeedTestStage {
+    /// Waiting for the answer to an empty message, which gives the latency.
+    Pinging,
+    /// Waiting for the peer to confirm it received the data.
+    Sending {
+        latency: Duration,
+        sent_at: Instant,
+    },
+    Receiving {
+        bytes: u64,
+    },
 }
 
 struct UploadHandle {
     canceled: bool,
-    reader: BufReader<File>,
-    buffer: [u8; FILE_CHUNK_SIZE],
+    file_id: Uuid,
+    dir_id: Uuid,
+    path: PathBuf,
+    reader: PlainReader,
+    /// Chunks are split off and sent without copying. Once a sent chunk is
+    /// dropped its memory is reused for the next one.
+    buffer: BytesMut,
+    /// Sent chunks are also written here and hashed by `hash_task`, so the
+    /// contents actually sent are checked without reading the file twice.
+    hash_input: DuplexStream,
+    hash_task: JoinHandle<std::io::Result<u64>>,
+    content_hash: u64,
+}
+
+impl UploadHandle {
+    fn new(
+        file_id: Uuid,
+        dir_id: Uuid,
+        path: PathBuf,
+        reader: PlainReader,
+        content_hash: u64,
+    ) -> Self {
+        let (hash_input, mut hash_output) = tokio::io::duplex(FILE_CHUNK_SIZE * 2);
+        let hash_task = tokio::spawn(async move { compute_stream(&mut hash_output).await });
+
+        Self {
+            canceled: false,
+            file_id,
+            dir_id,
+            path,
+            reader,
+            buffer: BytesMut::with_capacity(FILE_CHUNK_SIZE),
+            hash_input,
+            hash_task,
+            content_hash,
+        }
+    }
+
+    /// Hash of everything read from the file, available once it was read to
+    /// the end.
+    async fn sent_hash(&mut self) -> Result<u64, DownloadError> {
+        self.hash_input
+            .shutdown()
+            .await
+            .map_err(|_| DownloadError::ReadError)?;
+
+        match (&mut self.hash_task).await {
+            Ok(Ok(hash)) => Ok(hash),
+            _ => Err(DownloadError::ReadError),
+        }
+    }
+}
+
+impl Drop for UploadHandle {
+    fn drop(&mut self) {
+        self.hash_task.abort();
+    }
 }
 
 pub struct ClientData {
@@ -123,6 +312,18 @@ struct ClientDataHandle<'a> {
     downloads: &'a mut HashMap<Uuid, DownloadHandle>,
     uploads: &'a mut HashMap<Uuid, UploadHandle>,
     uploading: &'a mut bool,
+    disconnect: &'a mut Option<DisconnectReason>,
+    /// Set by `disconnect_self`, so cleanup runs once even if several
+    /// branches of the client loop fail.
+    disconnecting: &'a mut bool,
+    file_lists: &'a mut HashMap<Uuid, ShareDirectory>,
+    paused: &'a mut bool,
+    peer_paused: &'a mut bool,
+    pending_changes: &'a mut HashMap<Uuid, PendingChange>,
+    speed_tests: &'a mut HashMap<Uuid, SpeedTest>,
+    /// Directory and file ids of uploads sent in full, counted once the peer
+    /// confirms the download with `DownloadedFile`.
+    served_uploads: &'a mut HashSet<(Uuid, Uuid)>,
 }
 
 pub async fn client_loop(
@@ -132,11 +333,23 @@ pub async fn client_loop(
 ) {
     let (read, write) = stream.split();
 
-    let mut framed_reader = FramedRead::new(read, MessageCodec {});
-    let mut framed_writer = FramedWrite::new(write, MessageCodec {});
+    let metrics = client_data.server.metrics.connection(client_data.addr);
+    let mut framed_reader = FramedRead::new(read, MessageCodec::new(metrics.clone()));
+    let mut framed_writer = FramedWrite::new(write, MessageCodec::new(metrics));
     let mut downloads: HashMap<Uuid, DownloadHandle> = HashMap::new();
     let mut uploads: HashMap<Uuid, UploadHandle> = HashMap::new();
     let mut uploading = false;
+    let mut disconnect = None;
+    let mut disconnecting = false;
+    let mut file_lists: HashMap<Uuid, ShareDirectory> = HashMap::new();
+    let mut paused = false;
+    let mut peer_paused = false;
+    let mut pending_changes: HashMap<Uuid, PendingChange> = HashMap::new();
+    let mut speed_tests: HashMap<Uuid, SpeedTest> = HashMap::new();
+    let mut served_uploads: HashSet<(Uuid, Uuid)> = HashSet::new();
+    let mut ack_check = tokio::time::interval(Duration::from_secs(CHANGE_ACK_TIMEOUT_SECS));
+    let idle_timeout = client_data.config.get_idle_timeout().await;
+    let mut last_activity = Instant::now();
 
     let _ = framed_writer.send(TcpMessage::RequestPeerId).await;
 
@@ -147,20 +360,35 @@ pub async fn client_loop(
         downloads: &mut downloads,
         uploads: &mut uploads,
         uploading: &mut uploading,
+        disconnect: &mut disconnect,
+        disconnecting: &mut disconnecting,
+        file_lists: &mut file_lists,
+        paused: &mut paused,
+        peer_paused: &mut peer_paused,
+        pending_changes: &mut pending_changes,
+        speed_tests: &mut speed_tests,
+        served_uploads: &mut served_uploads,
     };
 
     loop {
-        let up = *handle.uploading;
+        let up = *handle.uploading && !*handle.paused && !*handle.peer_paused;
+        let busy = !handle.downloads.is_empty()
+            || !handle.uploads.is_empty()
+            || !handle.file_lists.is_empty()
+            || !handle.pending_changes.is_empty()
+            || !handle.speed_tests.is_empty();
+        let idle_deadline = idle_timeout.map(|timeout| last_activity + timeout);
 
         tokio::select! {
 
             incoming = framed_reader.next() => {
+                last_activity = Instant::now();
                 let result = handle_response(incoming, &mut handle).await;
 
                 if let Err(e) = result {
                     error!("TCP err: {}", e);
 
-                    disconnect_self(&mut handle).await;
+                    disconnect_self(&mut handle, DisconnectReason::Lost(e.to_string())).await;
                     return;
                 }
             }
@@ -168,36 +396,188 @@ pub async fn client_loop(
             server_message = handle.client_data.receiver.recv() => {
                 match server_message {
                     Some(message_from_server) => {
+                        last_activity = Instant::now();
                         let result = handle_server_messages(message_from_server, &mut handle).await;
 
                         if let Err(e) = result {
                             error!("Server err: {}", e);
 
-                            disconnect_self(&mut handle).await;
+                            disconnect_self(&mut handle, DisconnectReason::Lost(e.to_string())).await;
                             return;
                         }
                     },
                     None => {
-                        disconnect_self(&mut handle).await;
+                        let reason = DisconnectReason::Left("Server was closed".to_owned());
+
+                        disconnect_self(&mut handle, reason).await;
                         return;
                     }
                 }
             }
 
             _ = async {}, if up => {
-                let _ = handle_uploads(&mut handle).await;
+                let result = handle_uploads(&mut handle).await;
+
+                if let Err(e) = result {
+                    error!("TCP err: {}", e);
+
+                    disconnect_self(&mut handle, DisconnectReason::Lost(e.to_string())).await;
+                    return;
+                }
             }
 
+            _ = ack_check.tick(), if !handle.pending_changes.is_empty() || !handle.speed_tests.is_empty() => {
+                expire_speed_tests(&mut handle).await;
+                let result = resend_unacknowledged_changes(&mut handle).await;
+
+                if let Err(e) = result {
+                    error!("TCP err: {}", e);
+
+                    disconnect_self(&mut handle, DisconnectReason::Lost(e.to_string())).await;
+                    return;
+                }
+            }
+
+            _ = tokio::time::sleep_until(idle_deadline.unwrap_or_else(Instant::now).into()), if idle_deadline.is_some() && !busy => {
+                let reason = "Connection was idle".to_owned();
+                info!("Closing idle connection to {:?}", handle.client_peer_id);
+
+                let _ = handle
+                    .tcp_write
+                    .send(TcpMessage::Goodbye {
+                        reason: reason.clone(),
+                        idle: true,
+                    })
+                    .await;
+
+                disconnect_self(&mut handle, DisconnectReason::Idle(reason)).await;
+                return;
+            }
+
+        }
+
+        if let Some(reason) = handle.disconnect.take() {
+            disconnect_self(&mut handle, reason).await;
+            return;
+        }
+    }
+}
+
+/// Sends the whole directory for changes the peer hasn't acknowledged in
+/// time, so a lost message doesn't leave it out of date until the next
+/// synchronization. Peers from before acknowledgements existed get the
+/// directory this way too.
+async fn resend_unacknowledged_changes(data: &mut ClientDataHandle<'_>) -> Result<()> {
+    let timeout = Duration::from_secs(CHANGE_ACK_TIMEOUT_SECS);
+    let mut directory_ids = vec![];
+
+    data.pending_changes.retain(|_, change| {
+        if change.sent_at.elapsed() < timeout {
+            return true;
+        }
+
+        if !directory_ids.contains(&change.directory) {
+            directory_ids.push(change.directory);
+        }
+
+        false
+    });
+
+    let mut directories = vec![];
+    for id in directory_ids {
+        if let Some(dir) = data.client_data.config.get_directory(id).await {
+            warn!(
+                "Change to directory {} was not acknowledged, sending full directory",
+                dir.signature.name
+            );
+
+            directories.push(dir);
+        }
+    }
+
+    if directories.is_empty() {
+        return Ok(());
+    }
+
+    send_directories(directories, data).await
+}
+
+/// Gives up on speed tests the peer hasn't answered, which is what peers
+/// from before speed tests existed do.
+async fn expire_speed_tests(data: &mut ClientDataHandle<'_>) {
+    let timeout = Duration::from_secs(SPEED_TEST_TIMEOUT_SECS);
+    let mut expired = 0;
+
+    data.speed_tests.retain(|_, test| {
+        if test.started.elapsed() < timeout {
+            return true;
+        }
+
+        if !matches!(test.stage, SpeedTestStage::Receiving { .. }) {
+            expired += 1;
         }
+
+        false
+    });
+
+    if let (true, Some(peer)) = (expired > 0, &*data.client_peer_id) {
+        let _ = data
+            .client_data
+            .server
+            .channel
+            .send(MessageToServer::SpeedTestFailed {
+                peer_id: peer.clone(),
+                reason: "Peer did not answer the speed test".to_owned(),
+            })
+            .await;
+    }
+}
+
+/// Sends the throwaway data of a speed test, marking the last part so the
+/// peer answers it.
+async fn send_speed_test_data(test_id: Uuid, data: &mut ClientDataHandle<'_>) -> Result<()> {
+    let chunk = Bytes::from(vec![0u8; FILE_CHUNK_SIZE]);
+    let chunks = SPEED_TEST_BYTES / FILE_CHUNK_SIZE;
+
+    for i in 0..chunks {
+        data.tcp_write
+            .send(TcpMessage::SpeedTest {
+                test_id,
+                data: chunk.clone(),
+                last: i + 1 == chunks,
+            })
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Returns how far ahead (positive) or behind (negative) a peer's clock is,
+/// if the difference is large enough to affect last-modified merges.
+fn clock_skew(remote_time: DateTime<Utc>, local_time: DateTime<Utc>) -> Option<chrono::Duration> {
+    let skew = remote_time - local_time;
+
+    if skew.num_seconds().abs() > MAX_CLOCK_SKEW_SECS {
+        Some(skew)
+    } else {
+        None
     }
 }
 
+/// Sends the next part of every upload. Fails if the connection was lost,
+/// since none of the uploads can reach the peer anymore.
 async fn handle_uploads<'a>(client_data: &mut ClientDataHandle<'a>) -> Result<()> {
     let mut uploads_to_remove: Vec<Uuid> = vec![];
+    let mut lost_upload = None;
     for (download_id, upload) in client_data.uploads.iter_mut() {
         let upload_result = try_upload(*download_id, client_data.tcp_write, upload).await;
 
         match upload_result {
+            // Telling the peer about it would only fail again
+            Err(DownloadError::Disconnected) => {
+                lost_upload = Some(*download_id);
+                break;
+            }
             Err(error) => {
                 uploads_to_remove.push(*download_id);
 
@@ -212,11 +592,28 @@ async fn handle_uploads<'a>(client_data: &mut ClientDataHandle<'a>) -> Result<()
             Ok(is_finished) => {
                 if is_finished {
                     uploads_to_remove.push(*download_id);
+                    client_data
+                        .served_uploads
+                        .insert((upload.dir_id, upload.file_id));
                 }
             }
         }
     }
 
+    if let Some(download_id) = lost_upload {
+        warn!(
+            "Connection to {:?} was lost while uploading {}, dropping {} upload(s)",
+            client_data.client_peer_id,
+            download_id,
+            client_data.uploads.len()
+        );
+
+        client_data.uploads.clear();
+        *client_data.uploading = false;
+
+        return Err(anyhow!("Connection was lost during upload {}", download_id));
+    }
+
     for download_id in uploads_to_remove {
         info!("Removing download {}", download_id);
         client_data.uploads.remove(&download_id);
@@ -238,19 +635,42 @@ async fn try_upload<'a>(
         return Err(DownloadError::Canceled);
     }
 
-    let read_res = upload.reader.read(&mut upload.buffer).await;
+    // An open file can still be read after it's deleted on some platforms,
+    // which would send contents the owner no longer shares
+    if fs::metadata(&upload.path).await.is_err() {
+        return Err(DownloadError::FileMissing);
+    }
+
+    upload.buffer.reserve(FILE_CHUNK_SIZE);
+    let mut chunk = (&mut upload.buffer).limit(FILE_CHUNK_SIZE);
+    let read_res = upload.reader.read_buf(&mut chunk).await;
     let n = match read_res {
         Err(_) => return Err(DownloadError::ReadError),
         Ok(n) => n,
     };
 
     let msg = if n == 0 {
+        // The file can change while it's being sent, so only the contents
+        // that were actually read count
+        if upload.sent_hash().await? != upload.content_hash {
+            warn!(
+                "Not finishing upload {}, {:?} changed since it was shared",
+                download_id, upload.path
+            );
+
+            return Err(DownloadError::HashMismatch);
+        }
+
         TcpMessage::ReceiveFileEnd { download_id }
     } else {
-        TcpMessage::ReceiveFilePart {
-            download_id,
-            data: upload.buffer[..n].to_vec(),
-        }
+        let data = upload.buffer.split().freeze();
+        upload
+            .hash_input
+            .write_all(&data)
+            .await
+            .map_err(|_| DownloadError::ReadError)?;
+
+        TcpMessage::ReceiveFilePart { download_id, data }
     };
 
     let send_result = tcp_write.send(msg).await;
@@ -281,17 +701,44 @@ async fn handle_tcp_message<'a>(
     match incoming {
         TcpMessage::RequestPeerId => {
             data.tcp_write
-                .send(TcpMessage::ReceivePeerId(
-                    data.client_data.server.peer_id.clone(),
-                ))
+                .send(TcpMessage::ReceivePeerId {
+                    peer_id: data.client_data.server.peer_id.clone(),
+                    sent_at: Some(data.client_data.config.now()),
+                    compression: Compression::SUPPORTED.to_vec(),
+                })
                 .await?;
 
             Ok(())
         }
 
-        TcpMessage::ReceivePeerId(id) => {
+        TcpMessage::ReceivePeerId {
+            peer_id: id,
+            sent_at,
+            compression,
+        } => {
             info!("Received {} peer id", &id);
 
+            // The peer answered our RequestPeerId, so it decodes frames with
+            // anything it listed from here on
+            let compression = Compression::negotiate(&Compression::SUPPORTED, &compression);
+            info!("Using {} compression with {}", compression.name(), &id);
+            data.tcp_write.encoder_mut().set_compression(compression);
+
+            if let Some(sent_at) = sent_at {
+                if let Some(skew) = clock_skew(sent_at, data.client_data.config.now()) {
+                    warn!("Clock of peer {} differs by {}s", &id, skew.num_seconds());
+
+                    data.client_data
+                        .server
+                        .channel
+                        .send(MessageToServer::ClockSkew {
+                            peer_id: id.clone(),
+                            skew_seconds: skew.num_seconds(),
+                        })
+                        .await?;
+                }
+            }
+
             let _ = data.tcp_write.send(TcpMessage::Synchronize).await;
 
             data.client_data
@@ -300,6 +747,7 @@ async fn handle_tcp_message<'a>(
                 .send(MessageToServer::SetPeerId(
                     data.client_data.addr,
                     id.clone(),
+                    compression,
                 ))
                 .await?;
 
@@ -328,10 +776,18 @@ async fn handle_tcp_message<'a>(
         TcpMessage::SharedDirectory(directory) => {
             info!("Directory was shared {:?}", &directory);
 
+            let peer = match data.client_peer_id {
+                None => {
+                    error!("Peer ID not yet set");
+                    return Ok(());
+                }
+                Some(p) => p,
+            };
+
             data.client_data
                 .server
                 .channel
-                .send(MessageToServer::SharedDirectory(directory))
+                .send(MessageToServer::SharedDirectory(directory, peer.clone()))
                 .await?;
 
             Ok(())
@@ -377,44 +833,260 @@ async fn handle_tcp_message<'a>(
             let mut directories: Vec<ShareDirectory> =
                 data.client_data.config.get_directories().await;
 
-            for dir in directories.iter_mut() {
-                for (_, mut file) in dir.shared_files.iter_mut() {
-                    file.content_location = ContentLocation::NetworkOnly;
-                }
+            directories.retain(|dir| dir.signature.shared_peers.contains(id));
+
+            if !directories.is_empty() {
+                send_directories(directories, data).await?;
             }
 
-            directories.retain(|dir| dir.signature.shared_peers.contains(id));
+            Ok(())
+        }
+
+        TcpMessage::DirectoryDigests(digests) => {
+            let id = match data.client_peer_id {
+                Some(pid) => pid,
+                None => {
+                    warn!("Client Peer Id not yet set");
+
+                    return Ok(());
+                }
+            };
+
+            // Directories the peer doesn't list are left alone, since it may
+            // have left them
+            let mut directories: Vec<ShareDirectory> =
+                data.client_data.config.get_directories().await;
+            directories.retain(|dir| {
+                dir.signature.shared_peers.contains(id)
+                    && digests.iter().any(|digest| {
+                        digest.directory_identifier == dir.signature.identifier
+                            && *digest != dir.digest()
+                    })
+            });
 
             if !directories.is_empty() {
+                info!(
+                    "{} directories differ from {}, sending them",
+                    directories.len(),
+                    id
+                );
+                send_directories(directories, data).await?;
+            }
+
+            Ok(())
+        }
+
+        TcpMessage::DirectoryAvailable {
+            signature,
+            file_count,
+        } => {
+            if let Some(peer) = data.client_peer_id {
+                data.client_data
+                    .server
+                    .channel
+                    .send(MessageToServer::DirectoryVersion {
+                        peer_id: peer.clone(),
+                        directory_identifier: signature.identifier,
+                        last_modified: signature.last_modified,
+                        fingerprint: signature.fingerprint.clone(),
+                    })
+                    .await?;
+            }
+
+            let local = data
+                .client_data
+                .config
+                .get_directory(signature.identifier)
+                .await;
+
+            if let Some(local) = local {
+                if local.signature.last_modified >= signature.last_modified {
+                    return Ok(());
+                }
+            }
+
+            info!(
+                "Requesting {} files of directory {}",
+                file_count, signature.identifier
+            );
+
+            let directory_identifier = signature.identifier;
+            data.file_lists.insert(
+                directory_identifier,
+                ShareDirectory {
+                    signature,
+                    shared_files: HashMap::new(),
+                },
+            );
+
+            data.tcp_write
+                .send(TcpMessage::RequestFileList {
+                    directory_identifier,
+                    offset: 0,
+                    limit: FILE_LIST_PAGE_SIZE,
+                })
+                .await?;
+
+            Ok(())
+        }
+
+        TcpMessage::RequestFileList {
+            directory_identifier,
+            offset,
+            limit,
+        } => {
+            let id = match data.client_peer_id {
+                Some(pid) => pid,
+                None => {
+                    warn!("Client Peer Id not yet set");
+
+                    return Ok(());
+                }
+            };
+
+            let directory = data
+                .client_data
+                .config
+                .get_directory(directory_identifier)
+                .await;
+
+            let directory = match directory {
+                Some(dir) if dir.signature.shared_peers.contains(id) => dir,
+                _ => {
+                    warn!(
+                        "Peer {} requested files of unshared directory {}",
+                        id, directory_identifier
+                    );
+
+                    return Ok(());
+                }
+            };
+
+            let mut files = file_list_page(&directory, offset, limit.min(FILE_LIST_PAGE_SIZE));
+            for file in files.iter_mut() {
+                file.content_location = ContentLocation::NetworkOnly;
+            }
+
+            data.tcp_write
+                .send(TcpMessage::FileList {
+                    total: directory.shared_files.len() as u64,
+                    signature: directory.signature,
+                    offset,
+                    files,
+                })
+                .await?;
+
+            Ok(())
+        }
+
+        TcpMessage::FileList {
+            signature,
+            offset,
+            total,
+            files,
+        } => {
+            let peer = match data.client_peer_id {
+                None => {
+                    error!("Peer ID not yet set");
+                    return Ok(());
+                }
+                Some(p) => p,
+            };
+
+            let directory_identifier = signature.identifier;
+            let list = data
+                .file_lists
+                .entry(directory_identifier)
+                .or_insert_with(|| ShareDirectory {
+                    signature: signature.clone(),
+                    shared_files: HashMap::new(),
+                });
+
+            // The directory changed while its pages were being fetched.
+            if list.signature.last_modified != signature.last_modified {
+                list.signature = signature;
+                list.shared_files.clear();
+
+                if offset != 0 {
+                    data.tcp_write
+                        .send(TcpMessage::RequestFileList {
+                            directory_identifier,
+                            offset: 0,
+                            limit: FILE_LIST_PAGE_SIZE,
+                        })
+                        .await?;
+
+                    return Ok(());
+                }
+            }
+
+            let received = files.len() as u64;
+            for file in files {
+                list.shared_files.insert(file.identifier, file);
+            }
+
+            let next_offset = offset + received;
+            if received > 0 && next_offset < total {
                 data.tcp_write
-                    .send(TcpMessage::ReceiveDirectories(directories))
+                    .send(TcpMessage::RequestFileList {
+                        directory_identifier,
+                        offset: next_offset,
+                        limit: FILE_LIST_PAGE_SIZE,
+                    })
+                    .await?;
+
+                return Ok(());
+            }
+
+            if let Some(directory) = data.file_lists.remove(&directory_identifier) {
+                data.client_data
+                    .server
+                    .channel
+                    .send(MessageToServer::SynchronizeDirectories(
+                        vec![directory],
+                        peer.clone(),
+                    ))
                     .await?;
             }
 
             Ok(())
         }
 
-        TcpMessage::AddedFiles { directory, files } => {
+        TcpMessage::AddedFiles {
+            directory,
+            files,
+            change_id,
+        } => {
             info!("Received add request for files {:?}", files);
 
-            let mut success = false;
-            data.client_data
+            let max_files = data.client_data.config.get_max_files_per_directory().await;
+            let success = data
+                .client_data
                 .config
                 .mutate_dir(directory.identifier, |dir| {
-                    let result = dir.add_files(files, directory.last_modified);
-
-                    if result.is_ok() {
-                        success = true;
+                    if let Err(e) = dir.check_file_limit(files.len(), max_files) {
+                        warn!("Rejected files from {:?}: {}", data.client_peer_id, e);
+                        return false;
                     }
+
+                    dir.add_files(files, directory.last_modified).is_ok()
                 })
                 .await;
 
+            // Without the acknowledgement the peer sends the whole directory
+            // instead, which is how rejected or unknown changes get resolved
             if success {
                 data.client_data
                     .server
                     .channel
-                    .send(MessageToServer::UpdatedDirectory(directory.identifier))
+                    .send(change_applied_message(data.client_peer_id, &directory))
                     .await?;
+
+                if let Some(change_id) = change_id {
+                    data.tcp_write
+                        .send(TcpMessage::ChangeApplied { change_id })
+                        .await?;
+                }
             }
 
             Ok(())
@@ -424,16 +1096,17 @@ async fn handle_tcp_message<'a>(
             peer_id,
             directory,
             file,
+            change_id,
         } => {
             info!("Received delete request for file {}", file);
 
-            let mut success = false;
-            data.client_data
+            let success = data
+                .client_data
                 .config
                 .mutate_dir(directory.identifier, |dir| {
                     dir.remove_files(&peer_id, directory.last_modified, vec![file]);
 
-                    success = true;
+                    true
                 })
                 .await;
 
@@ -441,21 +1114,181 @@ async fn handle_tcp_message<'a>(
                 data.client_data
                     .server
                     .channel
-                    .send(MessageToServer::UpdatedDirectory(directory.identifier))
+                    .send(change_applied_message(data.client_peer_id, &directory))
                     .await?;
-            }
 
-            Ok(())
+                if let Some(change_id) = change_id {
+                    data.tcp_write
+                        .send(TcpMessage::ChangeApplied { change_id })
+                        .await?;
+                }
+            }
+
+            Ok(())
+        }
+
+        TcpMessage::ChangeApplied { change_id } => {
+            let change = data.pending_changes.remove(&change_id);
+
+            if let (Some(change), Some(peer)) = (change, &*data.client_peer_id) {
+                data.client_data
+                    .server
+                    .channel
+                    .send(MessageToServer::DirectoryVersion {
+                        peer_id: peer.clone(),
+                        directory_identifier: change.directory,
+                        last_modified: change.last_modified,
+                        // Only known once the peer sends the directory
+                        fingerprint: None,
+                    })
+                    .await?;
+            }
+
+            Ok(())
+        }
+
+        TcpMessage::SpeedTest {
+            test_id,
+            data: test_data,
+            last,
+        } => {
+            let received = match data.speed_tests.remove(&test_id) {
+                Some(SpeedTest {
+                    stage: SpeedTestStage::Receiving { bytes },
+                    ..
+                }) => bytes,
+                _ => 0,
+            } + test_data.len() as u64;
+
+            if last {
+                data.tcp_write
+                    .send(TcpMessage::SpeedTestResult {
+                        test_id,
+                        bytes_received: received,
+                    })
+                    .await?;
+            } else {
+                data.speed_tests.insert(
+                    test_id,
+                    SpeedTest {
+                        started: Instant::now(),
+                        stage: SpeedTestStage::Receiving { bytes: received },
+                    },
+                );
+            }
+
+            Ok(())
+        }
+
+        TcpMessage::SpeedTestResult {
+            test_id,
+            bytes_received,
+        } => {
+            let test = match data.speed_tests.remove(&test_id) {
+                None => {
+                    warn!("Received result of unknown speed test {}", test_id);
+                    return Ok(());
+                }
+                Some(test) => test,
+            };
+
+            match test.stage {
+                SpeedTestStage::Pinging => {
+                    let latency = test.started.elapsed();
+                    let sent_at = Instant::now();
+                    send_speed_test_data(test_id, data).await?;
+
+                    data.speed_tests.insert(
+                        test_id,
+                        SpeedTest {
+                            started: test.started,
+                            stage: SpeedTestStage::Sending { latency, sent_at },
+                        },
+                    );
+                }
+                SpeedTestStage::Sending { latency, sent_at } => {
+                    // The answer takes about one latency to arrive
+                    let transfer = sent_at
+                        .elapsed()
+                        .saturating_sub(latency)
+                        .max(Duration::from_millis(1));
+                    let bytes_per_second =
+                        (bytes_received as u128 * 1000 / transfer.as_millis()) as u64;
+
+                    if let Some(peer) = &*data.client_peer_id {
+                        data.client_data
+                            .server
+                            .channel
+                            .send(MessageToServer::SpeedTestFinished(SpeedTestResult {
+                                peer: peer.clone(),
+                                latency_millis: latency.as_millis() as u64,
+                                bytes_sent: bytes_received,
+                                bytes_per_second,
+                            }))
+                            .await?;
+                    }
+                }
+                SpeedTestStage::Receiving { .. } => {
+                    warn!("Peer answered speed test {} it was running", test_id);
+                }
+            }
+
+            Ok(())
         }
 
         TcpMessage::StartDownload {
             download_id,
             file_id,
             dir_id,
+            content_hash,
         } => {
             info!("Started uploading");
 
-            let file_path = data.client_data.config.get_filepath(dir_id, file_id).await;
+            let max_transfers = data.client_data.config.get_max_transfers_per_peer().await;
+            if data.uploads.len() >= max_transfers {
+                warn!(
+                    "Rejecting upload {}: already serving {} uploads to {:?}",
+                    download_id,
+                    data.uploads.len(),
+                    data.client_peer_id
+                );
+
+                data.tcp_write
+                    .send(TcpMessage::DownloadError {
+                        error: DownloadError::Busy,
+                        download_id,
+                    })
+                    .await?;
+
+                return Ok(());
+            }
+
+            // Everything needed to serve the file is read here once, so the
+            // chunks sent afterwards never touch the shared directory state.
+            let shared_file = data.client_data.config.get_file(dir_id, file_id).await;
+
+            if let (Some(expected_hash), Some(file)) = (content_hash, &shared_file) {
+                if file.content_hash != expected_hash {
+                    warn!(
+                        "Rejecting upload {}: requested version {} but have {}",
+                        download_id, expected_hash, file.content_hash
+                    );
+
+                    data.tcp_write
+                        .send(TcpMessage::DownloadError {
+                            error: DownloadError::HashMismatch,
+                            download_id,
+                        })
+                        .await?;
+
+                    return Ok(());
+                }
+            }
+
+            let file_path = shared_file.and_then(|file| match file.content_location {
+                ContentLocation::NetworkOnly | ContentLocation::Partial { .. } => None,
+                ContentLocation::LocalPath(path) => Some((path, file.content_hash)),
+            });
 
             match file_path {
                 None => {
@@ -466,24 +1299,27 @@ async fn handle_tcp_message<'a>(
                         })
                         .await?
                 }
-                Some(path) => {
-                    let file = File::open(path).await;
+                Some((path, content_hash)) => {
+                    // Files we downloaded may be encrypted at rest, peers
+                    // are always sent the plain contents
+                    let key = data.client_data.config.get_file_key().await;
+                    let file = open_plain(&path, key.as_ref()).await;
 
                     match file {
-                        Err(_e) => {
+                        Err(e) => {
+                            let error = if e.kind() == std::io::ErrorKind::NotFound {
+                                DownloadError::FileMissing
+                            } else {
+                                DownloadError::ReadError
+                            };
+
                             data.tcp_write
-                                .send(TcpMessage::DownloadError {
-                                    error: DownloadError::FileMissing,
-                                    download_id,
-                                })
+                                .send(TcpMessage::DownloadError { error, download_id })
                                 .await?
                         }
-                        Ok(file) => {
-                            let upload = UploadHandle {
-                                canceled: false,
-                                reader: BufReader::new(file),
-                                buffer: [0; FILE_CHUNK_SIZE],
-                            };
+                        Ok(reader) => {
+                            let upload =
+                                UploadHandle::new(file_id, dir_id, path, reader, content_hash);
 
                             data.uploads.insert(download_id, upload);
                             *data.uploading = true;
@@ -519,7 +1355,7 @@ async fn handle_tcp_message<'a>(
                     return Ok(());
                 }
                 Some(download) => {
-                    let res = download.output_file.write_all(&raw_data).await;
+                    let res = write_output_file(download, &raw_data).await;
 
                     match res {
                         Err(_) => {
@@ -531,25 +1367,32 @@ async fn handle_tcp_message<'a>(
                                 .expect("app should be running on a 64 bit system");
                             download.bytes_done += bytes_received;
 
-                            let percent =
-                                (download.bytes_done as f64 / download.bytes_total as f64) * 100.0;
-                            let percent = percent.round() as u64;
+                            if let Some(percent) = download.progress() {
+                                let now = Instant::now();
+                                let since_last_update = now - download.last_progress_update;
+                                let update_interval =
+                                    Duration::from_millis(PROGRESS_UPDATE_INTERVAL_MILLIS);
+
+                                if percent != download.last_progress
+                                    && (percent == 100 || since_last_update >= update_interval)
+                                {
+                                    download.last_progress = percent;
+                                    download.last_progress_update = now;
+
+                                    data.client_data.server.report_progress(
+                                        MessageToServer::DownloadUpdate {
+                                            download_id,
+                                            new_progress: percent,
+                                        },
+                                    );
+                                    set_partial_location(&data.client_data.config, download).await;
+                                }
 
-                            if percent > 100 {
+                                Ok(())
+                            } else {
                                 download.canceled = true;
 
                                 Err(DownloadError::FileTooLarge)
-                            } else {
-                                data.client_data
-                                    .server
-                                    .channel
-                                    .send(MessageToServer::DownloadUpdate {
-                                        download_id,
-                                        new_progress: percent,
-                                    })
-                                    .await?;
-
-                                Ok(())
                             }
                         }
                     }
@@ -557,12 +1400,22 @@ async fn handle_tcp_message<'a>(
             };
 
             if let Err(e) = result {
-                data.downloads.remove(&download_id);
+                // The partial file is forgotten once the download is
+                // canceled, so nothing would remove it later
+                if let Some(download) = data.downloads.remove(&download_id) {
+                    discard_download(download).await;
+                }
+
+                // The peer would keep sending parts of it otherwise
+                data.tcp_write
+                    .send(TcpMessage::CancelDownload { download_id })
+                    .await?;
+
                 data.client_data
                     .server
                     .channel
                     .send(MessageToServer::CanceledDownload {
-                        cancel_reason: e.to_string(),
+                        cancel_reason: e,
                         download_id,
                     })
                     .await?;
@@ -578,13 +1431,13 @@ async fn handle_tcp_message<'a>(
             match download {
                 None => Ok(()),
                 Some(download) => {
-                    let _ = fs::remove_file(download.output_path).await;
+                    discard_download(download).await;
 
                     data.client_data
                         .server
                         .channel
                         .send(MessageToServer::CanceledDownload {
-                            cancel_reason: error.to_string(),
+                            cancel_reason: error,
                             download_id,
                         })
                         .await?;
@@ -601,19 +1454,78 @@ async fn handle_tcp_message<'a>(
                 return Ok(());
             }
 
-            let download = data.downloads.remove(&download_id).unwrap();
-            let mut success = false;
-            data.client_data
+            let mut download = data.downloads.remove(&download_id).unwrap();
+
+            // Empty files end without any parts and are done from the start,
+            // anything else that ends early changed since it was listed
+            if download.progress() != Some(100) {
+                warn!(
+                    "Download {} ended after {} of {} bytes",
+                    download_id, download.bytes_done, download.bytes_total
+                );
+
+                discard_download(download).await;
+                data.client_data
+                    .server
+                    .channel
+                    .send(MessageToServer::CanceledDownload {
+                        download_id,
+                        cancel_reason: DownloadError::FileTooLarge,
+                    })
+                    .await?;
+
+                return Ok(());
+            }
+
+            let closed = match seal_output_file(&mut download).await {
+                Ok(_) => close_output_file(&mut download.output_file).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = closed {
+                error!("Could not flush downloaded file: {}", e);
+
+                discard_download(download).await;
+                data.client_data
+                    .server
+                    .channel
+                    .send(MessageToServer::CanceledDownload {
+                        download_id,
+                        cancel_reason: DownloadError::WriteError,
+                    })
+                    .await?;
+
+                return Ok(());
+            }
+
+            if let Err(e) = finish_output_file(&download).await {
+                error!("Could not finish download {}: {}", download_id, e);
+
+                discard_download(download).await;
+                data.client_data
+                    .server
+                    .channel
+                    .send(MessageToServer::CanceledDownload {
+                        download_id,
+                        cancel_reason: e,
+                    })
+                    .await?;
+
+                return Ok(());
+            }
+
+            let now = data.client_data.config.now();
+            let success = data
+                .client_data
                 .config
                 .mutate_dir(download.dir_id, |dir| {
                     dir.add_owner(
                         &data.client_data.server.peer_id,
-                        Utc::now(),
+                        now,
                         vec![download.file_id],
-                        Some(download.output_path),
+                        Some(download.final_path),
                     );
 
-                    success = true;
+                    true
                 })
                 .await;
 
@@ -633,7 +1545,7 @@ async fn handle_tcp_message<'a>(
                     .channel
                     .send(MessageToServer::CanceledDownload {
                         download_id,
-                        cancel_reason: "Could not finish download".to_string(),
+                        cancel_reason: DownloadError::DirectoryMissing,
                     })
                     .await?;
             }
@@ -647,16 +1559,34 @@ async fn handle_tcp_message<'a>(
             file_identifier,
             date_modified,
         } => {
-            let mut success = false;
-            data.client_data
+            let success = data
+                .client_data
                 .config
                 .mutate_dir(directory_identifier, |dir| {
                     dir.add_owner(&peer_id, date_modified, vec![file_identifier], None);
 
-                    success = true;
+                    true
                 })
                 .await;
 
+            // Other owners are told about the download too, only the one
+            // that sent the file counts it
+            let served = data.client_peer_id.as_ref() == Some(&peer_id)
+                && data
+                    .served_uploads
+                    .remove(&(directory_identifier, file_identifier));
+            if served {
+                let served_at = data.client_data.config.now();
+                data.client_data
+                    .config
+                    .mutate_file(directory_identifier, file_identifier, |file| {
+                        file.record_upload(served_at);
+
+                        true
+                    })
+                    .await;
+            }
+
             if success {
                 data.client_data
                     .server
@@ -667,6 +1597,30 @@ async fn handle_tcp_message<'a>(
 
             Ok(())
         }
+
+        TcpMessage::PauseTransfers { paused } => {
+            info!(
+                "Peer {:?} {} transfers",
+                data.client_peer_id,
+                if paused { "paused" } else { "resumed" }
+            );
+
+            *data.peer_paused = paused;
+
+            Ok(())
+        }
+
+        TcpMessage::Goodbye { reason, idle } => {
+            info!("Peer {:?} said goodbye: {}", data.client_peer_id, reason);
+
+            *data.disconnect = Some(if idle {
+                DisconnectReason::Idle(reason)
+            } else {
+                DisconnectReason::Left(reason)
+            });
+
+            Ok(())
+        }
     }
 }
 
@@ -687,23 +1641,15 @@ async fn handle_server_messages(
             data.tcp_write
                 .send(TcpMessage::LeftDirectory {
                     directory_identifier,
-                    date_modified: Utc::now(),
+                    date_modified: data.client_data.config.now(),
                 })
                 .await?;
 
             Ok(())
         }
 
-        MessageToClient::SendDirectories(mut directories) => {
-            for dir in directories.iter_mut() {
-                for (_, file) in dir.shared_files.iter_mut() {
-                    file.content_location = ContentLocation::NetworkOnly;
-                }
-            }
-
-            data.tcp_write
-                .send(TcpMessage::ReceiveDirectories(directories))
-                .await?;
+        MessageToClient::SendDirectories(directories) => {
+            send_directories(directories, data).await?;
 
             Ok(())
         }
@@ -714,12 +1660,23 @@ async fn handle_server_messages(
             Ok(())
         }
 
+        MessageToClient::CompareDirectories(digests) => {
+            data.tcp_write
+                .send(TcpMessage::DirectoryDigests(digests))
+                .await?;
+
+            Ok(())
+        }
+
         MessageToClient::DeleteFile(peer_id, directory, file) => {
+            let change_id = track_change(data, &directory);
+
             data.tcp_write
                 .send(TcpMessage::DeleteFile {
                     peer_id,
                     directory,
                     file,
+                    change_id: Some(change_id),
                 })
                 .await?;
 
@@ -731,8 +1688,14 @@ async fn handle_server_messages(
                 file.content_location = ContentLocation::NetworkOnly;
             }
 
+            let change_id = track_change(data, &directory);
+
             data.tcp_write
-                .send(TcpMessage::AddedFiles { directory, files })
+                .send(TcpMessage::AddedFiles {
+                    directory,
+                    files,
+                    change_id: Some(change_id),
+                })
                 .await?;
 
             Ok(())
@@ -749,22 +1712,35 @@ async fn handle_server_messages(
                 Some(id) => id,
             };
 
-            let mut file_size = None;
-            data.client_data
+            let shared_file = data
+                .client_data
                 .config
-                .mutate_file(directory_identifier, file_identifier, |file| {
-                    file_size = Some(file.size);
-                })
+                .get_file(directory_identifier, file_identifier)
                 .await;
+            let file_size = shared_file.as_ref().map(|file| file.size);
+            let content_hash = shared_file.map(|file| file.content_hash);
+
+            let max_transfers = data.client_data.config.get_max_transfers_per_peer().await;
+            let at_capacity = data.downloads.len() >= max_transfers;
+            if at_capacity {
+                warn!(
+                    "Rejecting download {}: already downloading {} files from {}",
+                    download_id,
+                    data.downloads.len(),
+                    this_client
+                );
+            }
 
-            let result = match file_size {
-                None => Err(DownloadError::FileMissing),
-                Some(file_size) => {
-                    let file_handle = File::create(&destination).await;
+            let result = match (file_size, content_hash) {
+                (Some(_), _) if at_capacity => Err(DownloadError::Busy),
+                (Some(file_size), Some(expected_hash)) => {
+                    let output_path = partial_path(&destination);
+                    let key = data.client_data.config.get_download_key().await;
+                    let file_handle = create_output_file(&output_path, key.as_ref()).await;
 
                     match file_handle {
                         Err(_) => Err(DownloadError::WriteError),
-                        Ok(file_handle) => {
+                        Ok((file_handle, encryptor)) => {
                             let file_name = &destination
                                 .file_name()
                                 .unwrap_or_default()
@@ -777,18 +1753,30 @@ async fn handle_server_messages(
                                     canceled: false,
                                     bytes_total: file_size,
                                     bytes_done: 0,
+                                    last_progress: 0,
+                                    last_progress_update: Instant::now(),
                                     output_file: file_handle,
-                                    output_path: destination.clone(),
+                                    output_path,
+                                    final_path: destination.clone(),
+                                    content_hash: expected_hash,
                                     file_id: file_identifier,
                                     dir_id: directory_identifier,
+                                    encryptor,
+                                    key,
                                 },
                             );
+                            set_partial_location(
+                                &data.client_data.config,
+                                &data.downloads[&download_id],
+                            )
+                            .await;
 
                             data.tcp_write
                                 .send(TcpMessage::StartDownload {
                                     download_id,
                                     file_id: file_identifier,
                                     dir_id: directory_identifier,
+                                    content_hash,
                                 })
                                 .await?;
 
@@ -802,7 +1790,7 @@ async fn handle_server_messages(
                                         file_identifier,
                                         directory_identifier,
                                         progress: 0,
-                                        file_name: file_name.to_string(),
+                                        file_name: display_name(file_name),
                                         file_path: destination,
                                     },
                                 })
@@ -812,6 +1800,7 @@ async fn handle_server_messages(
                         }
                     }
                 }
+                _ => Err(DownloadError::FileMissing),
             };
 
             if let Err(e) = result {
@@ -821,7 +1810,7 @@ async fn handle_server_messages(
                     .server
                     .channel
                     .send(MessageToServer::CanceledDownload {
-                        cancel_reason: e.to_string(),
+                        cancel_reason: e,
                         download_id,
                     })
                     .await?;
@@ -858,46 +1847,1752 @@ async fn handle_server_messages(
 
             Ok(())
         }
-    }
-}
 
-async fn disconnect_self(client_data_handle: &mut ClientDataHandle<'_>) {
-    let _ = client_data_handle
-        .client_data
-        .server
-        .channel
-        .send(MessageToServer::KillClient(
-            client_data_handle.client_data.addr,
-        ))
-        .await;
+        MessageToClient::Goodbye(reason) => {
+            let _ = data
+                .tcp_write
+                .send(TcpMessage::Goodbye {
+                    reason: reason.clone(),
+                    idle: false,
+                })
+                .await;
 
-    {
-        for (id, download) in client_data_handle.downloads.iter_mut() {
-            download.canceled = true;
-            if download.output_file.shutdown().await.is_ok() {
-                let _ = fs::remove_file(download.output_path.clone()).await;
-            }
+            *data.disconnect = Some(DisconnectReason::Left(reason));
 
-            let _ = client_data_handle
-                .client_data
-                .server
-                .channel
-                .send(MessageToServer::CanceledDownload {
-                    download_id: *id,
-                    cancel_reason: "Client was disconnected".to_string(),
+            Ok(())
+        }
+
+        MessageToClient::SpeedTest { test_id } => {
+            data.speed_tests.insert(
+                test_id,
+                SpeedTest {
+                    started: Instant::now(),
+                    stage: SpeedTestStage::Pinging,
+                },
+            );
+
+            data.tcp_write
+                .send(TcpMessage::SpeedTest {
+                    test_id,
+                    data: Bytes::new(),
+                    last: true,
+                })
+                .await?;
+
+            Ok(())
+        }
+
+        MessageToClient::SetPaused(paused) => {
+            *data.paused = paused;
+
+            data.tcp_write
+                .send(TcpMessage::PauseTransfers { paused })
+                .await?;
+
+            Ok(())
+        }
+
+        MessageToClient::Shutdown(reason) => {
+            let _ = data
+                .tcp_write
+                .send(TcpMessage::Goodbye {
+                    reason: reason.clone(),
+                    idle: false,
                 })
                 .await;
+
+            for (_, mut download) in data.downloads.drain() {
+                if let Err(e) = close_output_file(&mut download.output_file).await {
+                    warn!("Could not flush partial download: {}", e);
+                }
+            }
+
+            *data.disconnect = Some(DisconnectReason::Left(reason));
+
+            Ok(())
         }
-    }
 
-    {
-        for (_, upload) in client_data_handle.uploads.iter_mut() {
-            upload.canceled = true;
+        MessageToClient::CancelUpload { download_id } => {
+            if let Some(upload) = data.uploads.get_mut(&download_id) {
+                info!("Server says to cancel upload {}", download_id);
+
+                upload.canceled = true;
+            }
+
+            Ok(())
+        }
+
+        MessageToClient::CancelFileUploads {
+            directory_identifier,
+            file_identifier,
+        } => {
+            for (download_id, upload) in data.uploads.iter_mut() {
+                if upload.dir_id == directory_identifier && upload.file_id == file_identifier {
+                    info!("Revoking upload {}", download_id);
+
+                    upload.canceled = true;
+                }
+            }
+
+            Ok(())
         }
     }
+}
+
+/// The peer that sent a change has the version of the directory it made.
+/// `DirectoryVersion` also updates the directory in the window.
+fn change_applied_message(
+    peer: &Option<PeerId>,
+    directory: &ShareDirectorySignature,
+) -> MessageToServer {
+    match peer {
+        Some(peer) => MessageToServer::DirectoryVersion {
+            peer_id: peer.clone(),
+            directory_identifier: directory.identifier,
+            last_modified: directory.last_modified,
+            fingerprint: directory.fingerprint.clone(),
+        },
+        None => MessageToServer::UpdatedDirectory(directory.identifier),
+    }
+}
 
-    warn!(
-        "Disconneting client {}.",
-        client_data_handle.client_data.addr
+/// Keeps a change sent to the peer until it acknowledges it with
+/// `ChangeApplied`. Returns the id to send along with the change.
+fn track_change(data: &mut ClientDataHandle<'_>, directory: &ShareDirectorySignature) -> Uuid {
+    let change_id = Uuid::new_v4();
+    data.pending_changes.insert(
+        change_id,
+        PendingChange {
+            directory: directory.identifier,
+            last_modified: directory.last_modified,
+            sent_at: Instant::now(),
+        },
     );
+
+    change_id
+}
+
+/// Pushes small directories in full and only announces large ones, whose
+/// files the peer requests with `RequestFileList` if it needs them.
+async fn send_directories(
+    mut directories: Vec<ShareDirectory>,
+    data: &mut ClientDataHandle<'_>,
+) -> Result<()> {
+    for dir in directories.iter_mut() {
+        for (_, file) in dir.shared_files.iter_mut() {
+            file.content_location = ContentLocation::NetworkOnly;
+        }
+    }
+
+    let (small, large): (Vec<_>, Vec<_>) = directories
+        .into_iter()
+        .partition(|dir| dir.shared_files.len() <= MAX_PUSHED_FILES);
+
+    if !small.is_empty() {
+        data.tcp_write
+            .send(TcpMessage::ReceiveDirectories(small))
+            .await?;
+    }
+
+    for dir in large {
+        data.tcp_write
+            .send(TcpMessage::DirectoryAvailable {
+                file_count: dir.shared_files.len() as u64,
+                signature: dir.signature,
+            })
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Returns up to `limit` files of `directory` starting at `offset`. Files are
+/// ordered by identifier so pages stay consistent between requests.
+fn file_list_page(directory: &ShareDirectory, offset: u64, limit: u64) -> Vec<SharedFile> {
+    let mut files: Vec<&SharedFile> = directory.shared_files.values().collect();
+    files.sort_by_key(|file| file.identifier);
+
+    files
+        .into_iter()
+        .skip(offset as usize)
+        .take(limit as usize)
+        .cloned()
+        .collect()
+}
+
+/// Records how much of a download has arrived. Files that are already stored
+/// locally keep their location, a failed download shouldn't hide them.
+async fn set_partial_location(config: &StoredConfig, download: &DownloadHandle) {
+    let path = download.output_path.clone();
+    let bytes = download.bytes_done;
+
+    config
+        .mutate_file(download.dir_id, download.file_id, |file| {
+            if matches!(file.content_location, ContentLocation::LocalPath(_)) {
+                return false;
+            }
+
+            file.content_location = ContentLocation::Partial { path, bytes };
+            true
+        })
+        .await;
+}
+
+/// Creates the partial file of a download, starting it with the encryption
+/// header if downloads are encrypted.
+async fn create_output_file(
+    path: &Path,
+    key: Option<&FileKey>,
+) -> std::io::Result<(File, Option<Encryptor>)> {
+    let mut file = File::create(path).await?;
+
+    let encryptor = match key {
+        None => None,
+        Some(key) => {
+            let (encryptor, header) = Encryptor::new(key);
+            file.write_all(&header).await?;
+            Some(encryptor)
+        }
+    };
+
+    Ok((file, encryptor))
+}
+
+async fn write_output_file(download: &mut DownloadHandle, data: &[u8]) -> std::io::Result<()> {
+    match &mut download.encryptor {
+        None => download.output_file.write_all(data).await,
+        Some(encryptor) => {
+            let sealed = encryptor.update(data)?;
+            download.output_file.write_all(&sealed).await
+        }
+    }
+}
+
+/// Writes the last chunk of an encrypted download.
+async fn seal_output_file(download: &mut DownloadHandle) -> std::io::Result<()> {
+    match download.encryptor.take() {
+        None => Ok(()),
+        Some(encryptor) => download.output_file.write_all(&encryptor.finish()?).await,
+    }
+}
+
+/// Writes out everything buffered for a finished download so the file is
+/// complete on disk before other code (or the user) opens it.
+async fn close_output_file(file: &mut File) -> std::io::Result<()> {
+    file.flush().await?;
+    file.sync_all().await?;
+    file.shutdown().await
+}
+
+pub fn partial_path(destination: &Path) -> PathBuf {
+    let mut file_name = destination.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(PARTIAL_EXTENSION);
+
+    destination.with_file_name(file_name)
+}
+
+/// Returns true if `destination` and its partial file fit within platform
+/// path limits. Lengths are counted in bytes, which is never less than what
+/// the OS counts.
+pub fn download_path_fits(destination: &Path) -> bool {
+    let partial = partial_path(destination);
+    let name_length = partial.file_name().map_or(0, |n| n.len());
+
+    partial.as_os_str().len() <= MAX_PATH_LENGTH && name_length <= MAX_FILE_NAME_LENGTH
+}
+
+/// Truncates the file stem of `destination`, keeping its extension, until the
+/// download fits within platform path limits. Returns `None` if the
+/// directory alone is already too long.
+pub fn shorten_download_path(destination: &Path) -> Option<PathBuf> {
+    if download_path_fits(destination) {
+        return Some(destination.to_path_buf());
+    }
+
+    let parent = destination.parent()?;
+    let name = destination.file_name()?.to_str()?;
+    let partial_suffix = PARTIAL_EXTENSION.len() + 1;
+    let available = MAX_PATH_LENGTH
+        .checked_sub(parent.as_os_str().len() + 1 + partial_suffix)?
+        .min(MAX_FILE_NAME_LENGTH - partial_suffix);
+
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 && name.len() - dot < available => name.split_at(dot),
+        _ => (name, ""),
+    };
+
+    let mut stem_length = available.checked_sub(extension.len())?;
+    while !stem.is_char_boundary(stem_length.min(stem.len())) {
+        stem_length -= 1;
+    }
+    if stem_length == 0 {
+        return None;
+    }
+
+    let shortened = format!("{}{}", &stem[..stem_length.min(stem.len())], extension);
+    let shortened = parent.join(shortened);
+
+    if download_path_fits(&shortened) {
+        Some(shortened)
+    } else {
+        None
+    }
+}
+
+/// Checks the downloaded contents against the expected hash and moves the
+/// partial file to its final name.
+async fn finish_output_file(download: &DownloadHandle) -> Result<(), DownloadError> {
+    let mut file = open_plain(&download.output_path, download.key.as_ref())
+        .await
+        .map_err(|_| DownloadError::ReadError)?;
+    let hash = compute_stream(&mut file)
+        .await
+        .map_err(|_| DownloadError::ReadError)?;
+
+    if hash != download.content_hash {
+        return Err(DownloadError::HashMismatch);
+    }
+
+    fs::rename(&download.output_path, &download.final_path)
+        .await
+        .map_err(|_| DownloadError::WriteError)
+}
+
+/// Removes partial files this app recorded for downloads that can't continue,
+/// keeping those of interrupted downloads the user can still retry.
+/// Interrupted downloads whose partial file is gone are forgotten. Other
+/// files in the download directory may belong to other programs and are left
+/// to `clean_download_directory`.
+pub async fn remove_partial_downloads(config: Arc<StoredConfig>) {
+    let mut partial_files = HashMap::new();
+    for download in config.get_interrupted_downloads().await {
+        let path = partial_path(&download.file_path);
+
+        if let Ok(metadata) = fs::metadata(&path).await {
+            partial_files.insert(
+                (download.directory_identifier, download.file_identifier),
+                (path, metadata.len()),
+            );
+        } else {
+            config.take_interrupted_download(download.download_id).await;
+        }
+    }
+
+    for path in config.restore_partial_locations(partial_files).await {
+        // Only files named like our partial files, in case the cache was edited
+        if path.extension().and_then(|e| e.to_str()) != Some(PARTIAL_EXTENSION) {
+            continue;
+        }
+
+        info!("Removing partial download {:?}", path);
+
+        if let Err(e) = fs::remove_file(&path).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Could not remove partial download {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+/// Files removed by the startup cleanup of the download directory.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CleanupReport {
+    pub removed_files: usize,
+    pub freed_bytes: u64,
+}
+
+/// Removes partial and temporary files anywhere in the download directory
+/// that haven't changed for the configured age, including those of
+/// interrupted downloads. Does nothing unless turned on in settings.
+pub async fn clean_download_directory(config: Arc<StoredConfig>) -> CleanupReport {
+    let mut report = CleanupReport::default();
+
+    let max_age = match config.get_cleanup_age().await {
+        None => return report,
+        Some(age) => age,
+    };
+
+    let interrupted = config.get_interrupted_downloads().await;
+    let mut directories = vec![config.get_download_directory().await];
+
+    while let Some(directory) = directories.pop() {
+        let mut entries = match fs::read_dir(&directory).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Could not read {:?} during cleanup: {}", directory, e);
+                continue;
+            }
+        };
+
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            let metadata = match entry.metadata().await {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+
+            if metadata.is_dir() {
+                directories.push(path);
+                continue;
+            }
+
+            let extension = path.extension().and_then(|e| e.to_str());
+            if extension != Some(PARTIAL_EXTENSION) && extension != Some(TEMPORARY_EXTENSION) {
+                continue;
+            }
+
+            let age = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.elapsed().ok());
+            if !matches!(age, Some(age) if age >= max_age) {
+                continue;
+            }
+
+            info!("Cleaning up {:?}", path);
+            if let Err(e) = fs::remove_file(&path).await {
+                warn!("Could not clean up {:?}: {}", path, e);
+                continue;
+            }
+
+            report.removed_files += 1;
+            report.freed_bytes += metadata.len();
+
+            if let Some(download) = interrupted
+                .iter()
+                .find(|d| partial_path(&d.file_path) == path)
+            {
+                config.take_interrupted_download(download.download_id).await;
+            }
+        }
+    }
+
+    report
+}
+
+async fn discard_download(mut download: DownloadHandle) {
+    let _ = download.output_file.shutdown().await;
+    drop(download.output_file);
+
+    let _ = fs::remove_file(download.output_path).await;
+}
+
+async fn disconnect_self(client_data_handle: &mut ClientDataHandle<'_>, reason: DisconnectReason) {
+    if *client_data_handle.disconnecting {
+        info!(
+            "Client {} is already disconnecting, ignoring {:?}",
+            client_data_handle.client_data.addr, reason
+        );
+        return;
+    }
+    *client_data_handle.disconnecting = true;
+
+    let _ = client_data_handle
+        .client_data
+        .server
+        .channel
+        .send(MessageToServer::KillClient(
+            client_data_handle.client_data.addr,
+            reason,
+        ))
+        .await;
+
+    {
+        // Drained so nothing else can touch the removed files
+        for (id, mut download) in client_data_handle.downloads.drain() {
+            download.canceled = true;
+            if download.output_file.shutdown().await.is_ok() {
+                let _ = fs::remove_file(download.output_path.clone()).await;
+            }
+
+            let _ = client_data_handle
+                .client_data
+                .server
+                .channel
+                .send(MessageToServer::CanceledDownload {
+                    download_id: id,
+                    cancel_reason: DownloadError::Disconnected,
+                })
+                .await;
+        }
+    }
+
+    {
+        for (_, upload) in client_data_handle.uploads.iter_mut() {
+            upload.canceled = true;
+        }
+    }
+
+    warn!(
+        "Disconneting client {}.",
+        client_data_handle.client_data.addr
+    );
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod close_output_file_tests {
+        use tokio::{fs::File, io::AsyncWriteExt};
+        use uuid::Uuid;
+
+        use crate::client::close_output_file;
+
+        #[tokio::test]
+        async fn close_output_file_contents_readable_immediately() {
+            let path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            let content: Vec<u8> = (0..500_000u32).map(|i| (i % 253) as u8).collect();
+
+            let mut file = File::create(&path).await.unwrap();
+            for chunk in content.chunks(1024 * 50) {
+                file.write_all(chunk).await.unwrap();
+            }
+            close_output_file(&mut file).await.unwrap();
+
+            assert_eq!(std::fs::read(&path).unwrap(), content);
+
+            drop(file);
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    mod path_length_tests {
+        use std::path::PathBuf;
+
+        use crate::client::{
+            download_path_fits, shorten_download_path, MAX_FILE_NAME_LENGTH, MAX_PATH_LENGTH,
+        };
+
+        fn nested_directory(length: usize) -> PathBuf {
+            let mut dir = PathBuf::from(std::path::MAIN_SEPARATOR.to_string());
+            while dir.as_os_str().len() < length {
+                dir.push("nested");
+            }
+            dir
+        }
+
+        #[test]
+        fn short_path_is_unchanged() {
+            let destination = nested_directory(20).join("video.mp4");
+
+            assert_eq!(shorten_download_path(&destination), Some(destination));
+        }
+
+        #[test]
+        fn deeply_nested_path_keeps_extension() {
+            let dir = nested_directory(MAX_PATH_LENGTH - 40);
+            let destination = dir.join(format!("{}.mp4", "a".repeat(100)));
+
+            let shortened = shorten_download_path(&destination).unwrap();
+
+            assert!(download_path_fits(&shortened));
+            assert_eq!(shortened.parent(), Some(dir.as_path()));
+            assert_eq!(shortened.extension().unwrap(), "mp4");
+            assert!(shortened
+                .file_name()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .starts_with("aaaa"));
+        }
+
+        #[test]
+        fn long_file_name_is_shortened() {
+            let destination = nested_directory(20).join(format!("{}.txt", "ž".repeat(300)));
+
+            let shortened = shorten_download_path(&destination).unwrap();
+
+            assert!(download_path_fits(&shortened));
+            assert!(shortened.file_name().unwrap().len() <= MAX_FILE_NAME_LENGTH);
+            assert_eq!(shortened.extension().unwrap(), "txt");
+        }
+
+        #[test]
+        fn directory_too_long_is_rejected() {
+            let destination = nested_directory(MAX_PATH_LENGTH).join("video.mp4");
+
+            assert!(!download_path_fits(&destination));
+            assert_eq!(shorten_download_path(&destination), None);
+        }
+    }
+
+    mod partial_download_tests {
+        use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+        use chrono::Utc;
+        use uuid::Uuid;
+
+        use crate::{
+            client::{
+                clean_download_directory, partial_path, remove_partial_downloads, CleanupReport,
+            },
+            config::{AppConfig, StoredConfig, TimingConfig},
+            data::{ContentLocation, PeerId, ShareDirectory, ShareDirectorySignature, SharedFile},
+            window::Download,
+        };
+
+        #[test]
+        fn partial_path_appends_extension_to_file_name() {
+            let destination = PathBuf::from("downloads").join("video.mp4");
+
+            assert_eq!(
+                partial_path(&destination),
+                PathBuf::from("downloads").join("video.mp4.part")
+            );
+        }
+
+        #[tokio::test]
+        async fn remove_partial_downloads_leaves_files_it_did_not_record() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("done.bin"), b"done").unwrap();
+            std::fs::write(dir.join("browser-download.bin.part"), b"half").unwrap();
+
+            let app_config = AppConfig {
+                download_directory: dir.clone(),
+                ..Default::default()
+            };
+            remove_partial_downloads(Arc::new(StoredConfig::new(app_config, HashMap::new()))).await;
+
+            assert!(dir.join("done.bin").exists());
+            assert!(dir.join("browser-download.bin.part").exists());
+
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn remove_partial_downloads_removes_only_recorded_partial_files() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("resumable.bin.part"), b"half").unwrap();
+            std::fs::write(dir.join("stale.bin.part"), b"half").unwrap();
+            std::fs::write(dir.join("unknown.bin.part"), b"half").unwrap();
+
+            let download = |file_name: &str| Download {
+                peer: PeerId::generate(),
+                download_id: Uuid::new_v4(),
+                file_identifier: Uuid::new_v4(),
+                directory_identifier: Uuid::new_v4(),
+                progress: 0,
+                file_name: file_name.to_owned(),
+                file_path: dir.join(file_name),
+            };
+            let resumable = download("resumable.bin");
+            let missing = download("missing.bin");
+
+            let app_config = AppConfig {
+                download_directory: dir.clone(),
+                ..Default::default()
+            };
+            let stale = SharedFile {
+                name: "stale.bin".to_owned(),
+                identifier: Uuid::new_v4(),
+                content_hash: 0,
+                last_modified: Utc::now(),
+                content_location: ContentLocation::Partial {
+                    path: dir.join("stale.bin.part"),
+                    bytes: 4,
+                },
+                owned_peers: vec![],
+                size: 8,
+                uploads: Default::default(),
+            };
+            let directory = ShareDirectory {
+                signature: ShareDirectorySignature {
+                    name: "directory".to_owned(),
+                    identifier: Uuid::new_v4(),
+                    last_modified: Utc::now(),
+                    shared_peers: vec![],
+                    fingerprint: None,
+                },
+                shared_files: HashMap::from([(stale.identifier, stale)]),
+            };
+            let cached = HashMap::from([(directory.signature.identifier, directory)]);
+            let config = StoredConfig::new(app_config, cached)
+                .with_download_log(dir.join("downloads.json"), vec![resumable.clone(), missing]);
+            let config = Arc::new(config);
+            remove_partial_downloads(config.clone()).await;
+
+            assert!(dir.join("resumable.bin.part").exists());
+            assert!(!dir.join("stale.bin.part").exists());
+            assert!(dir.join("unknown.bin.part").exists());
+
+            let interrupted = config.get_interrupted_downloads().await;
+            assert_eq!(interrupted.len(), 1);
+            assert_eq!(interrupted[0].download_id, resumable.download_id);
+
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn clean_download_directory_is_off_by_default() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("leftover.tmp"), b"junk").unwrap();
+
+            let app_config = AppConfig {
+                download_directory: dir.clone(),
+                ..Default::default()
+            };
+            let config = Arc::new(StoredConfig::new(app_config, HashMap::new()));
+
+            assert_eq!(
+                clean_download_directory(config).await,
+                CleanupReport::default()
+            );
+            assert!(dir.join("leftover.tmp").exists());
+
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn clean_download_directory_removes_nested_leftovers() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            std::fs::create_dir_all(dir.join("nested")).unwrap();
+            std::fs::write(dir.join("nested").join("video.mp4.part"), b"half").unwrap();
+            std::fs::write(dir.join("leftover.tmp"), b"junk!").unwrap();
+            std::fs::write(dir.join("done.bin"), b"done").unwrap();
+
+            let app_config = AppConfig {
+                download_directory: dir.clone(),
+                cleanup_downloads: true,
+                timing: TimingConfig {
+                    cleanup_age_days: 0,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let config = Arc::new(StoredConfig::new(app_config, HashMap::new()));
+
+            let report = clean_download_directory(config).await;
+
+            assert_eq!(
+                report,
+                CleanupReport {
+                    removed_files: 2,
+                    freed_bytes: 9,
+                }
+            );
+            assert!(dir.join("done.bin").exists());
+            assert!(!dir.join("leftover.tmp").exists());
+
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    mod progress_tests {
+        use std::{path::PathBuf, time::Instant};
+
+        use tokio::fs::File;
+        use uuid::Uuid;
+
+        use crate::client::DownloadHandle;
+
+        async fn download(bytes_total: u64, bytes_done: u64) -> DownloadHandle {
+            let path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            let output_file = File::create(&path).await.unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            DownloadHandle {
+                canceled: false,
+                bytes_total,
+                bytes_done,
+                last_progress: 0,
+                last_progress_update: Instant::now(),
+                output_file,
+                output_path: path,
+                final_path: PathBuf::new(),
+                content_hash: 0,
+                file_id: Uuid::nil(),
+                dir_id: Uuid::nil(),
+                encryptor: None,
+                key: None,
+            }
+        }
+
+        #[tokio::test]
+        async fn progress_given_empty_file_is_done() {
+            assert_eq!(download(0, 0).await.progress(), Some(100));
+            assert_eq!(download(0, 1).await.progress(), None);
+        }
+
+        #[tokio::test]
+        async fn progress_only_reaches_100_when_every_byte_arrived() {
+            assert_eq!(download(200, 0).await.progress(), Some(0));
+            assert_eq!(download(200, 199).await.progress(), Some(99));
+            assert_eq!(download(200, 200).await.progress(), Some(100));
+            assert_eq!(download(200, 201).await.progress(), None);
+        }
+    }
+
+    mod disconnect_tests {
+        use std::{
+            collections::{HashMap, HashSet},
+            path::PathBuf,
+            sync::Arc,
+            time::Instant,
+        };
+
+        use futures::StreamExt;
+        use tokio::{
+            fs::File,
+            net::{TcpListener, TcpStream},
+            sync::mpsc,
+        };
+        use tokio_util::codec::{FramedRead, FramedWrite};
+        use uuid::Uuid;
+
+        use crate::{
+            client::{
+                codec::{MessageCodec, TcpMessage},
+                disconnect_self, handle_tcp_message, ClientData, ClientDataHandle, DownloadError,
+                DownloadHandle,
+            },
+            config::{AppConfig, StoredConfig},
+            data::PeerId,
+            server::{DisconnectReason, MessageToServer, ServerHandle},
+        };
+
+        #[tokio::test]
+        async fn disconnect_self_given_two_errors_cleans_up_once() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut stream = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let _peer = listener.accept().await.unwrap();
+            let addr = stream.local_addr().unwrap();
+            let (_, write) = stream.split();
+            let mut tcp_write = FramedWrite::new(write, MessageCodec::default());
+
+            let (channel, mut server_receiver) = mpsc::channel(8);
+            let (progress, _progress_receiver) = mpsc::channel(8);
+            let (_client_sender, receiver) = mpsc::channel(8);
+            let mut client_data = ClientData {
+                server: ServerHandle {
+                    channel,
+                    progress,
+                    peer_id: PeerId::generate(),
+                    metrics: Arc::default(),
+                },
+                receiver,
+                addr,
+                config: Arc::new(StoredConfig::new(AppConfig::default(), HashMap::new())),
+            };
+
+            let output_path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            let download_id = Uuid::new_v4();
+            let mut downloads = HashMap::from([(
+                download_id,
+                DownloadHandle {
+                    canceled: false,
+                    bytes_total: 10,
+                    bytes_done: 5,
+                    last_progress: 50,
+                    last_progress_update: Instant::now(),
+                    output_file: File::create(&output_path).await.unwrap(),
+                    output_path: output_path.clone(),
+                    final_path: PathBuf::new(),
+                    content_hash: 0,
+                    file_id: Uuid::new_v4(),
+                    dir_id: Uuid::new_v4(),
+                    encryptor: None,
+                    key: None,
+                },
+            )]);
+
+            let mut handle = ClientDataHandle {
+                client_data: &mut client_data,
+                tcp_write: &mut tcp_write,
+                client_peer_id: &mut Some(PeerId::generate()),
+                downloads: &mut downloads,
+                uploads: &mut HashMap::new(),
+                uploading: &mut false,
+                disconnect: &mut None,
+                disconnecting: &mut false,
+                file_lists: &mut HashMap::new(),
+                paused: &mut false,
+                peer_paused: &mut false,
+                pending_changes: &mut HashMap::new(),
+                speed_tests: &mut HashMap::new(),
+                served_uploads: &mut HashSet::new(),
+            };
+
+            // As if reading and handling a server message both failed
+            disconnect_self(
+                &mut handle,
+                DisconnectReason::Lost("read failed".to_owned()),
+            )
+            .await;
+            disconnect_self(&mut handle, DisconnectReason::Left("closed".to_owned())).await;
+
+            let mut kills = 0;
+            let mut cancellations = vec![];
+            while let Ok(message) = server_receiver.try_recv() {
+                match message {
+                    MessageToServer::KillClient(..) => kills += 1,
+                    MessageToServer::CanceledDownload {
+                        download_id,
+                        cancel_reason,
+                    } => cancellations.push((download_id, cancel_reason)),
+                    _ => (),
+                }
+            }
+
+            assert_eq!(kills, 1);
+            assert_eq!(
+                cancellations,
+                vec![(download_id, DownloadError::Disconnected)]
+            );
+            assert!(downloads.is_empty());
+            assert!(!output_path.exists());
+        }
+
+        #[tokio::test]
+        async fn receive_file_part_given_too_many_bytes_discards_and_cancels() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut stream = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (peer, _) = listener.accept().await.unwrap();
+            let addr = stream.local_addr().unwrap();
+            let (_, write) = stream.split();
+            let mut tcp_write = FramedWrite::new(write, MessageCodec::default());
+            let mut tcp_read = FramedRead::new(peer, MessageCodec::default());
+
+            let (channel, mut server_receiver) = mpsc::channel(8);
+            let (progress, _progress_receiver) = mpsc::channel(8);
+            let (_client_sender, receiver) = mpsc::channel(8);
+            let mut client_data = ClientData {
+                server: ServerHandle {
+                    channel,
+                    progress,
+                    peer_id: PeerId::generate(),
+                    metrics: Arc::default(),
+                },
+                receiver,
+                addr,
+                config: Arc::new(StoredConfig::new(AppConfig::default(), HashMap::new())),
+            };
+
+            let output_path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            let download_id = Uuid::new_v4();
+            let mut downloads = HashMap::from([(
+                download_id,
+                DownloadHandle {
+                    canceled: false,
+                    bytes_total: 10,
+                    bytes_done: 5,
+                    last_progress: 50,
+                    last_progress_update: Instant::now(),
+                    output_file: File::create(&output_path).await.unwrap(),
+                    output_path: output_path.clone(),
+                    final_path: PathBuf::new(),
+                    content_hash: 0,
+                    file_id: Uuid::new_v4(),
+                    dir_id: Uuid::new_v4(),
+                    encryptor: None,
+                    key: None,
+                },
+            )]);
+
+            let mut handle = ClientDataHandle {
+                client_data: &mut client_data,
+                tcp_write: &mut tcp_write,
+                client_peer_id: &mut Some(PeerId::generate()),
+                downloads: &mut downloads,
+                uploads: &mut HashMap::new(),
+                uploading: &mut false,
+                disconnect: &mut None,
+                disconnecting: &mut false,
+                file_lists: &mut HashMap::new(),
+                paused: &mut false,
+                peer_paused: &mut false,
+                pending_changes: &mut HashMap::new(),
+                speed_tests: &mut HashMap::new(),
+                served_uploads: &mut HashSet::new(),
+            };
+
+            let part = TcpMessage::ReceiveFilePart {
+                download_id,
+                data: vec![1; 10].into(),
+            };
+            handle_tcp_message(part, &mut handle).await.unwrap();
+
+            assert!(matches!(
+                tcp_read.next().await.unwrap().unwrap(),
+                TcpMessage::CancelDownload { download_id: id } if id == download_id
+            ));
+            assert!(matches!(
+                server_receiver.try_recv(),
+                Ok(MessageToServer::CanceledDownload {
+                    cancel_reason: DownloadError::FileTooLarge,
+                    ..
+                })
+            ));
+            assert!(downloads.is_empty());
+            assert!(!output_path.exists());
+        }
+    }
+
+    mod upload_tests {
+        use std::{
+            collections::{HashMap, HashSet},
+            path::Path,
+            sync::Arc,
+            time::Duration,
+        };
+
+        use cryptohelpers::crc::compute_stream;
+        use futures::StreamExt;
+        use tokio::{
+            fs::File,
+            io::BufReader,
+            net::{TcpListener, TcpStream},
+            sync::mpsc,
+        };
+        use tokio_util::codec::{FramedRead, FramedWrite};
+        use uuid::Uuid;
+
+        use crate::{
+            client::{
+                codec::{MessageCodec, TcpMessage},
+                handle_uploads, try_upload, ClientData, ClientDataHandle, DownloadError,
+                UploadHandle, FILE_CHUNK_SIZE,
+            },
+            config::{AppConfig, StoredConfig},
+            data::PeerId,
+            server::ServerHandle,
+        };
+
+        async fn upload(path: &Path) -> UploadHandle {
+            let content_hash = compute_stream(&mut File::open(path).await.unwrap())
+                .await
+                .unwrap();
+
+            UploadHandle::new(
+                Uuid::new_v4(),
+                Uuid::new_v4(),
+                path.to_owned(),
+                Box::pin(BufReader::new(File::open(path).await.unwrap())),
+                content_hash,
+            )
+        }
+
+        #[tokio::test]
+        async fn handle_uploads_given_reset_connection_drops_all_uploads() {
+            let path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            std::fs::write(&path, vec![7u8; FILE_CHUNK_SIZE * 64]).unwrap();
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut stream = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (peer, _) = listener.accept().await.unwrap();
+
+            let addr = stream.local_addr().unwrap();
+            let (_, write) = stream.split();
+            let mut tcp_write = FramedWrite::new(write, MessageCodec::default());
+
+            let (channel, _server_receiver) = mpsc::channel(8);
+            let (progress, _progress_receiver) = mpsc::channel(8);
+            let (_client_sender, receiver) = mpsc::channel(8);
+            let mut client_data = ClientData {
+                server: ServerHandle {
+                    channel,
+                    progress,
+                    peer_id: PeerId::generate(),
+                    metrics: Arc::default(),
+                },
+                receiver,
+                addr,
+                config: Arc::new(StoredConfig::new(AppConfig::default(), HashMap::new())),
+            };
+            let mut uploads = HashMap::from([
+                (Uuid::new_v4(), upload(&path).await),
+                (Uuid::new_v4(), upload(&path).await),
+            ]);
+            let mut uploading = true;
+
+            let mut handle = ClientDataHandle {
+                client_data: &mut client_data,
+                tcp_write: &mut tcp_write,
+                client_peer_id: &mut Some(PeerId::generate()),
+                downloads: &mut HashMap::new(),
+                uploads: &mut uploads,
+                uploading: &mut uploading,
+                disconnect: &mut None,
+                disconnecting: &mut false,
+                file_lists: &mut HashMap::new(),
+                paused: &mut false,
+                peer_paused: &mut false,
+                pending_changes: &mut HashMap::new(),
+                speed_tests: &mut HashMap::new(),
+                served_uploads: &mut HashSet::new(),
+            };
+
+            handle_uploads(&mut handle).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            // Closing a socket with unread data resets the connection
+            drop(peer);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            let mut result = Ok(());
+            for _ in 0..64 {
+                result = handle_uploads(&mut handle).await;
+                if result.is_err() {
+                    break;
+                }
+            }
+
+            assert!(result.is_err());
+            assert!(handle.uploads.is_empty());
+            assert!(!*handle.uploading);
+
+            std::fs::remove_file(path).unwrap();
+        }
+
+        #[tokio::test]
+        async fn try_upload_sends_file_in_chunks() {
+            let path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            let content: Vec<u8> = (0..FILE_CHUNK_SIZE * 3 + 10)
+                .map(|i| (i % 251) as u8)
+                .collect();
+            std::fs::write(&path, &content).unwrap();
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut stream = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (peer, _) = listener.accept().await.unwrap();
+            let (_, write) = stream.split();
+            let mut tcp_write = FramedWrite::new(write, MessageCodec::default());
+            let mut tcp_read = FramedRead::new(peer, MessageCodec::default());
+
+            let mut upload = upload(&path).await;
+
+            let download_id = Uuid::new_v4();
+            let mut received = vec![];
+            loop {
+                let finished = try_upload(download_id, &mut tcp_write, &mut upload)
+                    .await
+                    .unwrap();
+
+                match tcp_read.next().await.unwrap().unwrap() {
+                    TcpMessage::ReceiveFilePart { data, .. } => {
+                        assert!(data.len() <= FILE_CHUNK_SIZE);
+                        received.extend_from_slice(&data);
+                    }
+                    TcpMessage::ReceiveFileEnd { .. } => {
+                        assert!(finished);
+                        break;
+                    }
+                    other => panic!("Unexpected message {:?}", other),
+                }
+            }
+
+            assert_eq!(received, content);
+
+            std::fs::remove_file(path).unwrap();
+        }
+
+        #[tokio::test]
+        async fn try_upload_given_deleted_source_reports_file_missing() {
+            let path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            std::fs::write(&path, vec![7u8; FILE_CHUNK_SIZE * 4]).unwrap();
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut stream = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (_peer, _) = listener.accept().await.unwrap();
+            let (_, write) = stream.split();
+            let mut tcp_write = FramedWrite::new(write, MessageCodec::default());
+
+            let mut upload = upload(&path).await;
+
+            let first_chunk = try_upload(Uuid::new_v4(), &mut tcp_write, &mut upload).await;
+            assert!(matches!(first_chunk, Ok(false)));
+
+            std::fs::remove_file(&path).unwrap();
+
+            let after_delete = try_upload(Uuid::new_v4(), &mut tcp_write, &mut upload).await;
+            assert!(matches!(after_delete, Err(DownloadError::FileMissing)));
+        }
+
+        #[tokio::test]
+        async fn try_upload_given_file_changed_while_sending_reports_hash_mismatch() {
+            let path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            std::fs::write(&path, vec![7u8; FILE_CHUNK_SIZE * 2]).unwrap();
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut stream = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (_peer, _) = listener.accept().await.unwrap();
+            let (_, write) = stream.split();
+            let mut tcp_write = FramedWrite::new(write, MessageCodec::default());
+
+            let mut upload = upload(&path).await;
+
+            let first_chunk = try_upload(Uuid::new_v4(), &mut tcp_write, &mut upload).await;
+            assert!(matches!(first_chunk, Ok(false)));
+
+            // Same size, so only the contents tell the versions apart
+            std::fs::write(&path, vec![8u8; FILE_CHUNK_SIZE * 2]).unwrap();
+
+            let result = loop {
+                match try_upload(Uuid::new_v4(), &mut tcp_write, &mut upload).await {
+                    Ok(false) => continue,
+                    other => break other,
+                }
+            };
+            assert!(matches!(result, Err(DownloadError::HashMismatch)));
+
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    mod clock_skew_tests {
+        use chrono::{Duration, TimeZone, Utc};
+
+        use crate::client::clock_skew;
+
+        #[test]
+        fn clock_skew_given_small_difference_returns_none() {
+            let local = Utc.with_ymd_and_hms(2023, 1, 1, 12, 0, 0).unwrap();
+
+            assert!(clock_skew(local + Duration::seconds(5), local).is_none());
+            assert!(clock_skew(local - Duration::seconds(60), local).is_none());
+        }
+
+        #[test]
+        fn clock_skew_given_fast_peer_returns_positive_skew() {
+            let local = Utc.with_ymd_and_hms(2023, 1, 1, 12, 0, 0).unwrap();
+
+            let skew = clock_skew(local + Duration::minutes(5), local);
+
+            assert_eq!(skew, Some(Duration::minutes(5)));
+        }
+
+        #[test]
+        fn clock_skew_given_slow_peer_returns_negative_skew() {
+            let local = Utc.with_ymd_and_hms(2023, 1, 1, 12, 0, 0).unwrap();
+
+            let skew = clock_skew(local - Duration::minutes(3), local);
+
+            assert_eq!(skew, Some(Duration::minutes(-3)));
+        }
+    }
+
+    mod file_list_tests {
+        use std::collections::HashMap;
+
+        use bytes::BytesMut;
+        use chrono::{TimeZone, Utc};
+        use uuid::Uuid;
+
+        use crate::{
+            client::{
+                codec::{decode_protobuf, encode_protobuf, TcpMessage},
+                file_list_page,
+            },
+            data::{ContentLocation, PeerId, ShareDirectory, ShareDirectorySignature, SharedFile},
+        };
+
+        fn directory(file_count: usize) -> ShareDirectory {
+            let last_modified = Utc.with_ymd_and_hms(2023, 1, 1, 12, 0, 0).unwrap();
+            let owner = PeerId {
+                hostname: "peer".to_owned(),
+                uuid: Uuid::new_v4(),
+            };
+
+            let shared_files: HashMap<Uuid, SharedFile> = (0..file_count)
+                .map(|i| {
+                    let identifier = Uuid::new_v4();
+                    let file = SharedFile {
+                        name: format!("file{}.txt", i),
+                        identifier,
+                        content_hash: i as u64,
+                        last_modified,
+                        content_location: ContentLocation::NetworkOnly,
+                        owned_peers: vec![owner.clone()],
+                        size: 10,
+                        uploads: Default::default(),
+                    };
+
+                    (identifier, file)
+                })
+                .collect();
+
+            ShareDirectory {
+                signature: ShareDirectorySignature {
+                    name: "Large".to_owned(),
+                    identifier: Uuid::new_v4(),
+                    last_modified,
+                    shared_peers: vec![owner],
+                    fingerprint: None,
+                },
+                shared_files,
+            }
+        }
+
+        #[test]
+        fn file_list_pages_cover_every_file_once() {
+            let dir = directory(25);
+
+            let mut seen = vec![];
+            let mut offset = 0;
+            loop {
+                let page = file_list_page(&dir, offset, 10);
+                if page.is_empty() {
+                    break;
+                }
+
+                offset += page.len() as u64;
+                seen.extend(page.into_iter().map(|file| file.identifier));
+            }
+
+            let mut expected: Vec<Uuid> = dir.shared_files.keys().cloned().collect();
+            expected.sort();
+
+            assert_eq!(seen, expected);
+        }
+
+        #[test]
+        fn file_list_survives_encoding() {
+            let dir = directory(3);
+            let files = file_list_page(&dir, 1, 5);
+
+            let mut encoded = BytesMut::new();
+            encode_protobuf(
+                TcpMessage::FileList {
+                    signature: dir.signature.clone(),
+                    offset: 1,
+                    total: 3,
+                    files,
+                },
+                &mut encoded,
+            )
+            .unwrap();
+
+            match decode_protobuf(encoded.freeze()).unwrap() {
+                Some(TcpMessage::FileList {
+                    signature,
+                    offset,
+                    total,
+                    files,
+                }) => {
+                    assert_eq!(signature.identifier, dir.signature.identifier);
+                    assert_eq!(offset, 1);
+                    assert_eq!(total, 3);
+                    assert_eq!(files.len(), 2);
+                }
+                other => panic!("Unexpected message {:?}", other),
+            }
+        }
+    }
+
+    mod change_ack_tests {
+        use std::{
+            collections::{HashMap, HashSet},
+            sync::Arc,
+        };
+
+        use chrono::Utc;
+        use futures::StreamExt;
+        use tokio::{
+            net::{TcpListener, TcpStream},
+            sync::mpsc,
+        };
+        use tokio_util::codec::{FramedRead, FramedWrite};
+        use uuid::Uuid;
+
+        use crate::{
+            client::{
+                codec::{MessageCodec, TcpMessage},
+                handle_tcp_message, ClientData, ClientDataHandle,
+            },
+            config::{AppConfig, StoredConfig},
+            data::{ContentLocation, PeerId, ShareDirectory, ShareDirectorySignature, SharedFile},
+            server::ServerHandle,
+        };
+
+        fn signature(owner: &PeerId) -> ShareDirectorySignature {
+            ShareDirectorySignature {
+                name: "Shared".to_owned(),
+                identifier: Uuid::new_v4(),
+                last_modified: Utc::now(),
+                shared_peers: vec![owner.clone()],
+                fingerprint: None,
+            }
+        }
+
+        fn added_file(owner: &PeerId) -> SharedFile {
+            SharedFile {
+                name: format!("{}.txt", Uuid::new_v4()),
+                identifier: Uuid::new_v4(),
+                content_hash: 1,
+                last_modified: Utc::now(),
+                content_location: ContentLocation::NetworkOnly,
+                owned_peers: vec![owner.clone()],
+                size: 10,
+                uploads: Default::default(),
+            }
+        }
+
+        #[tokio::test]
+        async fn added_files_are_only_acknowledged_when_applied() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut stream = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (peer, _) = listener.accept().await.unwrap();
+            let addr = stream.local_addr().unwrap();
+            let (_, write) = stream.split();
+            let mut tcp_write = FramedWrite::new(write, MessageCodec::default());
+            let mut tcp_read = FramedRead::new(peer, MessageCodec::default());
+
+            let owner = PeerId::generate();
+            let known = signature(&owner);
+            let directories = HashMap::from([(
+                known.identifier,
+                ShareDirectory {
+                    signature: known.clone(),
+                    shared_files: HashMap::new(),
+                },
+            )]);
+
+            let (channel, _server_receiver) = mpsc::channel(8);
+            let (progress, _progress_receiver) = mpsc::channel(8);
+            let (_client_sender, receiver) = mpsc::channel(8);
+            let mut client_data = ClientData {
+                server: ServerHandle {
+                    channel,
+                    progress,
+                    peer_id: PeerId::generate(),
+                    metrics: Arc::default(),
+                },
+                receiver,
+                addr,
+                config: Arc::new(StoredConfig::new(AppConfig::default(), directories)),
+            };
+            let mut handle = ClientDataHandle {
+                client_data: &mut client_data,
+                tcp_write: &mut tcp_write,
+                client_peer_id: &mut Some(owner.clone()),
+                downloads: &mut HashMap::new(),
+                uploads: &mut HashMap::new(),
+                uploading: &mut false,
+                disconnect: &mut None,
+                disconnecting: &mut false,
+                file_lists: &mut HashMap::new(),
+                paused: &mut false,
+                peer_paused: &mut false,
+                pending_changes: &mut HashMap::new(),
+                speed_tests: &mut HashMap::new(),
+                served_uploads: &mut HashSet::new(),
+            };
+
+            let unknown_change = Uuid::new_v4();
+            let known_change = Uuid::new_v4();
+            for (directory, change_id) in
+                [(signature(&owner), unknown_change), (known, known_change)]
+            {
+                let added = TcpMessage::AddedFiles {
+                    directory,
+                    files: vec![added_file(&owner)],
+                    change_id: Some(change_id),
+                };
+                handle_tcp_message(added, &mut handle).await.unwrap();
+            }
+
+            // Nothing was sent for the directory this device doesn't have
+            assert!(matches!(
+                tcp_read.next().await.unwrap().unwrap(),
+                TcpMessage::ChangeApplied { change_id } if change_id == known_change
+            ));
+        }
+    }
+
+    mod codec_tests {
+        use bytes::{BufMut, Bytes, BytesMut};
+        use chrono::{TimeZone, Utc};
+        use prost::Message;
+        use tokio_util::codec::{Decoder, Encoder};
+        use uuid::Uuid;
+
+        use crate::{
+            client::{
+                codec::{decode_protobuf, encode_protobuf, Compression, MessageCodec, TcpMessage},
+                protobuf::protobuf_types::{self, tcp_message},
+                DownloadError,
+            },
+            data::{PeerId, ShareDirectorySignature},
+        };
+
+        fn encode_raw(message: tcp_message::Message) -> Bytes {
+            protobuf_types::TcpMessage {
+                message: Some(message),
+            }
+            .encode_to_vec()
+            .into()
+        }
+
+        #[test]
+        fn decode_skips_garbage_frame_between_valid_messages() {
+            let mut codec = MessageCodec::default();
+            let mut buffer = BytesMut::new();
+
+            codec.encode(TcpMessage::Synchronize, &mut buffer).unwrap();
+            buffer.put_u32(5);
+            buffer.put_slice(&[0xff; 5]);
+            codec
+                .encode(TcpMessage::RequestPeerId, &mut buffer)
+                .unwrap();
+
+            assert!(matches!(
+                codec.decode(&mut buffer).unwrap(),
+                Some(TcpMessage::Synchronize)
+            ));
+            assert!(matches!(
+                codec.decode(&mut buffer).unwrap(),
+                Some(TcpMessage::RequestPeerId)
+            ));
+            assert!(codec.decode(&mut buffer).unwrap().is_none());
+            assert!(buffer.is_empty());
+        }
+
+        #[test]
+        fn decode_given_unknown_signal_fails_instead_of_guessing() {
+            let encoded = encode_raw(tcp_message::Message::Signal(99));
+
+            assert!(decode_protobuf(encoded).is_err());
+        }
+
+        #[test]
+        fn decode_given_unknown_download_error_keeps_it_unknown() {
+            let download_id = Uuid::new_v4();
+            let encoded = encode_raw(tcp_message::Message::DownloadError(
+                protobuf_types::DownloadError {
+                    error: 99,
+                    download_id: download_id.into(),
+                },
+            ));
+
+            match decode_protobuf(encoded).unwrap() {
+                Some(TcpMessage::DownloadError {
+                    error,
+                    download_id: id,
+                }) => {
+                    assert_eq!(error, DownloadError::Unknown);
+                    assert_eq!(id, download_id);
+                }
+                other => panic!("Unexpected message {:?}", other),
+            }
+        }
+
+        #[test]
+        fn added_files_keep_optional_change_id() {
+            let directory = ShareDirectorySignature {
+                name: "Shared".to_owned(),
+                identifier: Uuid::new_v4(),
+                last_modified: Utc.with_ymd_and_hms(2023, 1, 1, 12, 0, 0).unwrap(),
+                shared_peers: vec![],
+                fingerprint: None,
+            };
+
+            for change_id in [None, Some(Uuid::new_v4())] {
+                let mut encoded = BytesMut::new();
+                encode_protobuf(
+                    TcpMessage::AddedFiles {
+                        directory: directory.clone(),
+                        files: vec![],
+                        change_id,
+                    },
+                    &mut encoded,
+                )
+                .unwrap();
+
+                match decode_protobuf(encoded.freeze()).unwrap() {
+                    Some(TcpMessage::AddedFiles {
+                        change_id: decoded, ..
+                    }) => assert_eq!(decoded, change_id),
+                    other => panic!("Unexpected message {:?}", other),
+                }
+            }
+        }
+
+        fn file_part(data: Vec<u8>) -> TcpMessage {
+            TcpMessage::ReceiveFilePart {
+                download_id: Uuid::new_v4(),
+                data: data.into(),
+            }
+        }
+
+        #[test]
+        fn negotiate_compression_when_both_support_it() {
+            let both = Compression::negotiate(&[Compression::Deflate], &[Compression::Deflate]);
+
+            assert_eq!(both, Compression::Deflate);
+        }
+
+        #[test]
+        fn negotiate_compression_when_one_supports_it() {
+            assert_eq!(
+                Compression::negotiate(&[Compression::Deflate], &[]),
+                Compression::None
+            );
+            assert_eq!(
+                Compression::negotiate(&[], &[Compression::Deflate]),
+                Compression::None
+            );
+        }
+
+        #[test]
+        fn negotiate_compression_when_neither_supports_it() {
+            assert_eq!(Compression::negotiate(&[], &[]), Compression::None);
+        }
+
+        #[test]
+        fn compressed_frame_decodes_to_same_message() {
+            let mut sender = MessageCodec::default();
+            sender.set_compression(Compression::Deflate);
+            let mut receiver = MessageCodec::default();
+            let mut buffer = BytesMut::new();
+
+            sender
+                .encode(file_part(vec![7; 10_000]), &mut buffer)
+                .unwrap();
+            assert!(buffer.len() < 10_000);
+            assert_ne!(buffer[0] & 0x80, 0);
+
+            match receiver.decode(&mut buffer).unwrap() {
+                Some(TcpMessage::ReceiveFilePart { data, .. }) => {
+                    assert_eq!(data, vec![7; 10_000])
+                }
+                other => panic!("Unexpected message {:?}", other),
+            }
+            assert!(buffer.is_empty());
+        }
+
+        #[test]
+        fn frames_stay_plain_without_compression_or_when_it_does_not_help() {
+            let mut state = 0x2545_f491_u32;
+            let incompressible: Vec<u8> = (0..10_000)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    state as u8
+                })
+                .collect();
+            let mut compressing = MessageCodec::default();
+            compressing.set_compression(Compression::Deflate);
+
+            for (mut codec, data) in [
+                (MessageCodec::default(), vec![7; 10_000]),
+                (compressing, incompressible),
+            ] {
+                let mut buffer = BytesMut::new();
+                codec.encode(file_part(data.clone()), &mut buffer).unwrap();
+
+                // Older peers can only read frames without the flag
+                assert_eq!(buffer[0] & 0x80, 0);
+                assert!(buffer.len() > data.len());
+            }
+        }
+
+        #[test]
+        fn speed_test_frames_are_never_compressed() {
+            let mut codec = MessageCodec::default();
+            codec.set_compression(Compression::Deflate);
+            let mut buffer = BytesMut::new();
+
+            codec
+                .encode(
+                    TcpMessage::SpeedTest {
+                        test_id: Uuid::new_v4(),
+                        data: vec![0; 10_000].into(),
+                        last: false,
+                    },
+                    &mut buffer,
+                )
+                .unwrap();
+
+            // Peers measure the bytes they decode, not the ones on the wire
+            assert_eq!(buffer[0] & 0x80, 0);
+            assert!(buffer.len() > 10_000);
+        }
+
+        #[test]
+        fn receive_peer_id_drops_unknown_compression() {
+            let encoded = encode_raw(tcp_message::Message::ReceivePeerId(
+                protobuf_types::ReceivePeerId {
+                    peer_id: PeerId::generate().into(),
+                    sent_at: None,
+                    compression: vec!["zstd".to_owned(), "deflate".to_owned()],
+                },
+            ));
+
+            match decode_protobuf(encoded).unwrap() {
+                Some(TcpMessage::ReceivePeerId { compression, .. }) => {
+                    assert_eq!(compression, vec![Compression::Deflate])
+                }
+                other => panic!("Unexpected message {:?}", other),
+            }
+        }
+
+        #[test]
+        fn decode_given_invalid_length_fails() {
+            let mut codec = MessageCodec::default();
+            let mut buffer = BytesMut::new();
+            buffer.put_u32(u32::MAX);
+
+            assert!(codec.decode(&mut buffer).is_err());
+        }
+    }
+
+    mod metrics_tests {
+        use std::{net::SocketAddr, sync::Arc};
+
+        use bytes::BytesMut;
+        use tokio_util::codec::{Decoder, Encoder};
+
+        use crate::client::{
+            codec::{MessageCodec, TcpMessage},
+            metrics::{ConnectionMetrics, ProtocolMetrics},
+        };
+
+        #[test]
+        fn codec_counts_messages_and_bytes_per_connection() {
+            let metrics = ProtocolMetrics::default();
+            let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+            let mut sender = MessageCodec::new(metrics.connection(addr));
+
+            let mut buffer = BytesMut::new();
+            sender.encode(TcpMessage::Synchronize, &mut buffer).unwrap();
+            sender.encode(TcpMessage::Synchronize, &mut buffer).unwrap();
+            sender
+                .encode(TcpMessage::RequestPeerId, &mut buffer)
+                .unwrap();
+            let encoded_length = buffer.len() as u64;
+
+            let received = Arc::new(ConnectionMetrics::default());
+            let mut receiver = MessageCodec::new(received.clone());
+            while receiver.decode(&mut buffer).unwrap().is_some() {}
+
+            let sent = &metrics.snapshot()[&addr];
+            assert_eq!(sent.sent["Synchronize"], 2);
+            assert_eq!(sent.sent["RequestPeerId"], 1);
+            assert_eq!(sent.bytes_sent, encoded_length);
+
+            let received = received.snapshot();
+            assert_eq!(received.received["Synchronize"], 2);
+            assert_eq!(received.bytes_received, encoded_length);
+
+            metrics.remove(&addr);
+            assert!(metrics.snapshot().is_empty());
+        }
+    }
 }
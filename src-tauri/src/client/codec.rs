@@ -1,21 +1,137 @@
-use bytes::{Buf, BufMut, BytesMut};
+use std::{
+    io::{Read, Write},
+    sync::Arc,
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use chrono::{DateTime, Utc};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
 use prost::Message;
 use serde::{Deserialize, Serialize};
 use tokio_util::codec::{Decoder, Encoder};
 use uuid::Uuid;
 
-use crate::data::{PeerId, ShareDirectory, ShareDirectorySignature, SharedFile};
+use crate::data::{DirectoryDigest, PeerId, ShareDirectory, ShareDirectorySignature, SharedFile};
 
-use super::{protobuf::protobuf_types, DownloadError};
+use super::{metrics::ConnectionMetrics, protobuf::protobuf_types, DownloadError};
 
 const MAX_MESSAGE_SIZE: usize = 1024 * 1024 * 100; // 100 MB
 const LENGTH_MARKER_SIZE: usize = 4;
+const UNDECODABLE_KIND: &str = "Undecodable";
+/// Set in the length marker of compressed frames. Their contents start with
+/// the `Compression` id. Lengths never reach this bit, see `MAX_MESSAGE_SIZE`.
+const COMPRESSED_FLAG: u32 = 1 << 31;
+/// Smaller messages are sent as they are, compressing them saves little.
+const MIN_COMPRESSED_SIZE: usize = 256;
+
+/// How frames on a connection are compressed. Peers advertise what they can
+/// decode in `ReceivePeerId`, and frames are only compressed with something
+/// the peer listed, so older peers keep receiving plain frames.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    // Ordered from worst to best, see `negotiate`
+    None,
+    Deflate,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+impl Compression {
+    /// Algorithms this version can decode.
+    pub const SUPPORTED: [Compression; 1] = [Compression::Deflate];
+
+    /// Picks the best algorithm both peers support, or none. The ranking is
+    /// fixed, so both ends of a connection pick the same one.
+    pub fn negotiate(ours: &[Compression], theirs: &[Compression]) -> Compression {
+        ours.iter()
+            .filter(|c| theirs.contains(c))
+            .max()
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Deflate => "deflate",
+        }
+    }
+
+    /// `None` for algorithms added by newer versions.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Compression::None),
+            "deflate" => Some(Compression::Deflate),
+            _ => None,
+        }
+    }
+
+    fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Deflate => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Deflate),
+            _ => None,
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Deflate => {
+                let mut encoder = DeflateEncoder::new(vec![], flate2::Compression::fast());
+                encoder.write_all(data)?;
+
+                encoder.finish()
+            }
+        }
+    }
+
+    /// Fails on output larger than `MAX_MESSAGE_SIZE`, so a small frame can't
+    /// expand into an unbounded amount of memory.
+    fn decompress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut decompressed = vec![];
+
+        match self {
+            Compression::None => decompressed.extend_from_slice(data),
+            Compression::Deflate => {
+                DeflateDecoder::new(data)
+                    .take(MAX_MESSAGE_SIZE as u64 + 1)
+                    .read_to_end(&mut decompressed)?;
+            }
+        }
+
+        if decompressed.len() > MAX_MESSAGE_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Decompressed message is too large",
+            ));
+        }
+
+        Ok(decompressed)
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum TcpMessage {
     RequestPeerId,
-    ReceivePeerId(PeerId),
+    ReceivePeerId {
+        peer_id: PeerId,
+        sent_at: Option<DateTime<Utc>>,
+        /// What the peer can decode. Empty for older peers.
+        compression: Vec<Compression>,
+    },
 
     Synchronize,
     ReceiveDirectories(Vec<ShareDirectory>),
@@ -24,11 +140,18 @@ pub enum TcpMessage {
         peer_id: PeerId,
         directory: ShareDirectorySignature,
         file: Uuid,
+        change_id: Option<Uuid>,
     },
 
     AddedFiles {
         directory: ShareDirectorySignature,
         files: Vec<SharedFile>,
+        change_id: Option<Uuid>,
+    },
+
+    /// Acknowledges an `AddedFiles` or `DeleteFile` message.
+    ChangeApplied {
+        change_id: Uuid,
     },
 
     DownloadedFile {
@@ -42,6 +165,7 @@ pub enum TcpMessage {
         download_id: Uuid,
         file_id: Uuid,
         dir_id: Uuid,
+        content_hash: Option<u64>,
     },
 
     CancelDownload {
@@ -50,7 +174,7 @@ pub enum TcpMessage {
 
     ReceiveFilePart {
         download_id: Uuid,
-        data: Vec<u8>,
+        data: Bytes,
     },
 
     ReceiveFileEnd {
@@ -67,26 +191,148 @@ pub enum TcpMessage {
         directory_identifier: Uuid,
         date_modified: DateTime<Utc>,
     },
+
+    /// Closes the connection. Connections closed for being idle are not
+    /// reconnected until one of the peers needs the other.
+    Goodbye {
+        reason: String,
+        idle: bool,
+    },
+
+    /// Announces a directory that is too large to push during synchronization.
+    /// The peer requests its contents with `RequestFileList` if it needs them.
+    DirectoryAvailable {
+        signature: ShareDirectorySignature,
+        file_count: u64,
+    },
+    RequestFileList {
+        directory_identifier: Uuid,
+        offset: u64,
+        limit: u64,
+    },
+    FileList {
+        signature: ShareDirectorySignature,
+        offset: u64,
+        total: u64,
+        files: Vec<SharedFile>,
+    },
+
+    /// Asks the peer to stop or restart sending file parts to us.
+    PauseTransfers {
+        paused: bool,
+    },
+
+    /// Throwaway data for measuring the connection. The peer answers the
+    /// `last` message of a test with `SpeedTestResult`.
+    SpeedTest {
+        test_id: Uuid,
+        data: Bytes,
+        last: bool,
+    },
+    SpeedTestResult {
+        test_id: Uuid,
+        bytes_received: u64,
+    },
+
+    /// Digests of the directories shared with the peer. The peer answers with
+    /// its copy of every directory whose digest differs.
+    DirectoryDigests(Vec<DirectoryDigest>),
+}
+
+impl TcpMessage {
+    /// Name of the message kind, used for protocol metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TcpMessage::RequestPeerId => "RequestPeerId",
+            TcpMessage::ReceivePeerId { .. } => "ReceivePeerId",
+            TcpMessage::Synchronize => "Synchronize",
+            TcpMessage::ReceiveDirectories(_) => "ReceiveDirectories",
+            TcpMessage::DeleteFile { .. } => "DeleteFile",
+            TcpMessage::AddedFiles { .. } => "AddedFiles",
+            TcpMessage::DownloadedFile { .. } => "DownloadedFile",
+            TcpMessage::StartDownload { .. } => "StartDownload",
+            TcpMessage::CancelDownload { .. } => "CancelDownload",
+            TcpMessage::ReceiveFilePart { .. } => "ReceiveFilePart",
+            TcpMessage::ReceiveFileEnd { .. } => "ReceiveFileEnd",
+            TcpMessage::DownloadError { .. } => "DownloadError",
+            TcpMessage::SharedDirectory(_) => "SharedDirectory",
+            TcpMessage::LeftDirectory { .. } => "LeftDirectory",
+            TcpMessage::Goodbye { .. } => "Goodbye",
+            TcpMessage::DirectoryAvailable { .. } => "DirectoryAvailable",
+            TcpMessage::RequestFileList { .. } => "RequestFileList",
+            TcpMessage::FileList { .. } => "FileList",
+            TcpMessage::PauseTransfers { .. } => "PauseTransfers",
+            TcpMessage::ChangeApplied { .. } => "ChangeApplied",
+            TcpMessage::SpeedTest { .. } => "SpeedTest",
+            TcpMessage::SpeedTestResult { .. } => "SpeedTestResult",
+            TcpMessage::DirectoryDigests(_) => "DirectoryDigests",
+        }
+    }
 }
 
-pub struct MessageCodec {}
+#[derive(Default)]
+pub struct MessageCodec {
+    metrics: Arc<ConnectionMetrics>,
+    /// Used for sent frames. Received frames say how they are compressed.
+    compression: Compression,
+}
+
+impl MessageCodec {
+    pub fn new(metrics: Arc<ConnectionMetrics>) -> Self {
+        Self {
+            metrics,
+            compression: Compression::None,
+        }
+    }
+
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+}
 
 impl Encoder<TcpMessage> for MessageCodec {
     type Error = std::io::Error;
 
     fn encode(&mut self, item: TcpMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let encoded_message = match encode_protobuf(item) {
-            Ok(msg) => msg,
-            Err(e) => return Err(e),
+        let kind = item.kind();
+        // Speed tests count the bytes they receive, which have to be the
+        // bytes that crossed the network
+        let compressible = !matches!(item, TcpMessage::SpeedTest { .. });
+
+        // The message is encoded straight after its length marker, which is
+        // filled in once the length is known
+        let start = dst.len();
+        dst.put_u32(0);
+
+        let len = match encode_protobuf(item, dst) {
+            Ok(len) => len,
+            Err(e) => {
+                dst.truncate(start);
+
+                return Err(e);
+            }
         };
-
-        let len = encoded_message.len();
-        let u32_len =
+        let mut u32_len =
             u32::try_from(len).expect("large messages should have been handled by this point");
 
-        dst.reserve(len + LENGTH_MARKER_SIZE);
-        dst.put_u32(u32_len);
-        dst.put_slice(&encoded_message);
+        if compressible && self.compression != Compression::None && len >= MIN_COMPRESSED_SIZE {
+            let contents = start + LENGTH_MARKER_SIZE;
+            let compressed = self.compression.compress(&dst[contents..]);
+
+            // Already compressed files don't get any smaller
+            if let Some(compressed) = compressed.ok().filter(|c| c.len() + 1 < len) {
+                dst.truncate(contents);
+                dst.put_u8(self.compression.id());
+                dst.put_slice(&compressed);
+
+                u32_len = (compressed.len() + 1) as u32 | COMPRESSED_FLAG;
+            }
+        }
+
+        dst[start..start + LENGTH_MARKER_SIZE].copy_from_slice(&u32_len.to_be_bytes());
+        let sent = dst.len() - start;
+
+        self.metrics.record_sent(kind, sent);
 
         Ok(())
     }
@@ -96,41 +342,82 @@ impl Decoder for MessageCodec {
     type Item = TcpMessage;
     type Error = std::io::Error;
 
+    /// Decodes the next message. A frame whose contents can't be decoded is
+    /// skipped so that one malformed or newer message doesn't drop the
+    /// connection, while an invalid length marker is still treated as fatal
+    /// since the stream can no longer be split into frames.
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() < LENGTH_MARKER_SIZE {
-            return Ok(None);
-        }
+        loop {
+            if src.len() < LENGTH_MARKER_SIZE {
+                return Ok(None);
+            }
 
-        let mut length_bytes = [0u8; LENGTH_MARKER_SIZE];
-        length_bytes.copy_from_slice(&src[..LENGTH_MARKER_SIZE]);
-        let length = u32::from_be_bytes(length_bytes) as usize;
+            let mut length_bytes = [0u8; LENGTH_MARKER_SIZE];
+            length_bytes.copy_from_slice(&src[..LENGTH_MARKER_SIZE]);
+            let marker = u32::from_be_bytes(length_bytes);
+            let compressed = marker & COMPRESSED_FLAG != 0;
+            let length = (marker & !COMPRESSED_FLAG) as usize;
 
-        if length > MAX_MESSAGE_SIZE {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!(
-                    "Message length {} is too large and should have been split into parts",
-                    length
-                ),
-            ));
-        }
+            if length > MAX_MESSAGE_SIZE {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "Message length {} is too large and should have been split into parts",
+                        length
+                    ),
+                ));
+            }
+
+            let full_length = length + LENGTH_MARKER_SIZE;
+            if src.len() < full_length {
+                src.reserve(full_length - src.len());
 
-        let full_length = length + LENGTH_MARKER_SIZE;
-        if src.len() < full_length {
-            src.reserve(full_length - src.len());
+                return Ok(None);
+            }
 
-            return Ok(None);
+            // Splitting the frame off shares its memory, so file parts reach
+            // the download without being copied
+            let mut data = src.split_to(full_length).freeze();
+            data.advance(LENGTH_MARKER_SIZE);
+
+            let decoded = if compressed {
+                decompress_frame(&data).and_then(decode_protobuf)
+            } else {
+                decode_protobuf(data)
+            };
+
+            match decoded {
+                Ok(Some(message)) => {
+                    self.metrics.record_received(message.kind(), full_length);
+
+                    return Ok(Some(message));
+                }
+                Ok(None) => (),
+                Err(e) => {
+                    warn!("Skipping message that could not be decoded: {}", e);
+                    self.metrics.record_received(UNDECODABLE_KIND, full_length);
+                }
+            }
         }
+    }
+}
 
-        let data = src[LENGTH_MARKER_SIZE..full_length].to_vec();
-        src.advance(full_length);
+fn decompress_frame(data: &[u8]) -> Result<Bytes, std::io::Error> {
+    let (id, contents) = data.split_first().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, "Compressed frame is empty")
+    })?;
+    let compression = Compression::from_id(*id).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Unknown compression {}", id),
+        )
+    })?;
 
-        decode_protobuf(data)
-    }
+    Ok(compression.decompress(contents)?.into())
 }
 
-pub fn decode_protobuf(data: Vec<u8>) -> Result<Option<TcpMessage>, std::io::Error> {
-    let decoded_raw = protobuf_types::TcpMessage::decode(&data[..]);
+pub fn decode_protobuf(data: Bytes) -> Result<Option<TcpMessage>, std::io::Error> {
+    let decoded_raw = protobuf_types::TcpMessage::decode(data);
 
     let decoded_raw = match decoded_raw {
         Ok(tcp_message) => match tcp_message.message {
@@ -158,7 +445,8 @@ pub fn decode_protobuf(data: Vec<u8>) -> Result<Option<TcpMessage>, std::io::Err
     Ok(Some(msg))
 }
 
-pub fn encode_protobuf(src: TcpMessage) -> Result<Vec<u8>, std::io::Error> {
+/// Appends the encoded message to `dst` and returns its length.
+pub fn encode_protobuf(src: TcpMessage, dst: &mut BytesMut) -> Result<usize, std::io::Error> {
     match &src {
         TcpMessage::ReceiveFilePart {
             data: _,
@@ -170,11 +458,9 @@ pub fn encode_protobuf(src: TcpMessage) -> Result<Vec<u8>, std::io::Error> {
     let msg = protobuf_types::TcpMessage {
         message: Some(src.into()),
     };
-    let enc = protobuf_types::TcpMessage::encode_to_vec(&msg);
+    let len = msg.encoded_len();
 
-    let len = enc.len() + LENGTH_MARKER_SIZE;
-
-    if len > MAX_MESSAGE_SIZE {
+    if len + LENGTH_MARKER_SIZE > MAX_MESSAGE_SIZE {
         // split large messages into parts
         error!("Message too large to encode!");
 
@@ -184,5 +470,9 @@ pub fn encode_protobuf(src: TcpMessage) -> Result<Vec<u8>, std::io::Error> {
         ));
     }
 
-    Ok(enc)
+    dst.reserve(len);
+    msg.encode(dst)
+        .expect("buffer should have been reserved for the message");
+
+    Ok(len)
 }
@@ -0,0 +1,79 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use serde::Serialize;
+
+/// Number of messages of each kind and bytes sent and received over a
+/// connection.
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageCounters {
+    pub sent: HashMap<&'static str, u64>,
+    pub received: HashMap<&'static str, u64>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+#[derive(Default)]
+pub struct ConnectionMetrics {
+    counters: Mutex<MessageCounters>,
+}
+
+impl ConnectionMetrics {
+    pub fn record_sent(&self, kind: &'static str, bytes: usize) {
+        if let Ok(mut counters) = self.counters.lock() {
+            *counters.sent.entry(kind).or_default() += 1;
+            counters.bytes_sent += bytes as u64;
+        }
+    }
+
+    pub fn record_received(&self, kind: &'static str, bytes: usize) {
+        if let Ok(mut counters) = self.counters.lock() {
+            *counters.received.entry(kind).or_default() += 1;
+            counters.bytes_received += bytes as u64;
+        }
+    }
+
+    pub fn snapshot(&self) -> MessageCounters {
+        self.counters
+            .lock()
+            .map(|counters| counters.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// Message counters of every open connection, for diagnosing peers that
+/// don't synchronize.
+#[derive(Default)]
+pub struct ProtocolMetrics {
+    connections: Mutex<HashMap<SocketAddr, Arc<ConnectionMetrics>>>,
+}
+
+impl ProtocolMetrics {
+    /// Returns the counters of a connection, creating them if needed.
+    pub fn connection(&self, addr: SocketAddr) -> Arc<ConnectionMetrics> {
+        match self.connections.lock() {
+            Ok(mut connections) => connections.entry(addr).or_default().clone(),
+            Err(_) => Arc::default(),
+        }
+    }
+
+    pub fn remove(&self, addr: &SocketAddr) {
+        if let Ok(mut connections) = self.connections.lock() {
+            connections.remove(addr);
+        }
+    }
+
+    pub fn snapshot(&self) -> HashMap<SocketAddr, MessageCounters> {
+        match self.connections.lock() {
+            Ok(connections) => connections
+                .iter()
+                .map(|(addr, metrics)| (*addr, metrics.snapshot()))
+                .collect(),
+            Err(_) => HashMap::new(),
+        }
+    }
+}
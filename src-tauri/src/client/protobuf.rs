@@ -12,7 +12,7 @@ use crate::data::*;
 
 use self::protobuf_types::{AddedFiles, CancelDownload, DeleteFile, SignalType};
 
-use super::DownloadError;
+use super::{codec::Compression, DownloadError};
 
 fn map_files(files: Vec<protobuf_types::SharedFile>) -> Result<Vec<SharedFile>, std::io::Error> {
     let mut mapped = vec![];
@@ -63,12 +63,15 @@ fn map_directories_out(dirs: Vec<ShareDirectory>) -> Vec<protobuf_types::ShareDi
 impl From<super::TcpMessage> for protobuf_types::tcp_message::Message {
     fn from(value: super::TcpMessage) -> Self {
         match value {
-            super::TcpMessage::AddedFiles { directory, files } => {
-                tcp_message::Message::AddedFiles(AddedFiles {
-                    directory: directory.into(),
-                    files: map_files_out(files),
-                })
-            }
+            super::TcpMessage::AddedFiles {
+                directory,
+                files,
+                change_id,
+            } => tcp_message::Message::AddedFiles(AddedFiles {
+                directory: directory.into(),
+                files: map_files_out(files),
+                change_id: change_id.map(|id| id.into()),
+            }),
 
             super::TcpMessage::CancelDownload { download_id } => {
                 tcp_message::Message::CancelDownload(CancelDownload {
@@ -80,10 +83,12 @@ impl From<super::TcpMessage> for protobuf_types::tcp_message::Message {
                 peer_id,
                 directory,
                 file,
+                change_id,
             } => tcp_message::Message::DeleteFile(DeleteFile {
                 peer_id: peer_id.into(),
                 directory: directory.into(),
                 file_identifier: file.into(),
+                change_id: change_id.map(|id| id.into()),
             }),
 
             super::TcpMessage::DownloadError { error, download_id } => {
@@ -129,11 +134,15 @@ impl From<super::TcpMessage> for protobuf_types::tcp_message::Message {
                     data,
                 })
             }
-            super::TcpMessage::ReceivePeerId(id) => {
-                tcp_message::Message::ReceivePeerId(protobuf_types::ReceivePeerId {
-                    peer_id: id.into(),
-                })
-            }
+            super::TcpMessage::ReceivePeerId {
+                peer_id,
+                sent_at,
+                compression,
+            } => tcp_message::Message::ReceivePeerId(protobuf_types::ReceivePeerId {
+                peer_id: peer_id.into(),
+                sent_at: sent_at.map(|date| date.into()),
+                compression: compression.iter().map(|c| c.name().to_owned()).collect(),
+            }),
             super::TcpMessage::RequestPeerId => {
                 tcp_message::Message::Signal(SignalType::RequestPeerId.into())
             }
@@ -146,14 +155,78 @@ impl From<super::TcpMessage> for protobuf_types::tcp_message::Message {
                 download_id,
                 file_id,
                 dir_id,
+                content_hash,
             } => tcp_message::Message::StartDownload(protobuf_types::StartDownload {
                 download_id: download_id.into(),
                 file_id: file_id.into(),
                 dir_id: dir_id.into(),
+                content_hash,
             }),
             super::TcpMessage::Synchronize => {
                 tcp_message::Message::Signal(SignalType::Synchronize.into())
             }
+            super::TcpMessage::Goodbye { reason, idle } => {
+                tcp_message::Message::Goodbye(protobuf_types::Goodbye {
+                    reason,
+                    idle: Some(idle),
+                })
+            }
+            super::TcpMessage::DirectoryAvailable {
+                signature,
+                file_count,
+            } => tcp_message::Message::DirectoryAvailable(protobuf_types::DirectoryAvailable {
+                signature: signature.into(),
+                file_count,
+            }),
+            super::TcpMessage::RequestFileList {
+                directory_identifier,
+                offset,
+                limit,
+            } => tcp_message::Message::RequestFileList(protobuf_types::RequestFileList {
+                directory_identifier: directory_identifier.into(),
+                offset,
+                limit,
+            }),
+            super::TcpMessage::FileList {
+                signature,
+                offset,
+                total,
+                files,
+            } => tcp_message::Message::FileList(protobuf_types::FileList {
+                signature: signature.into(),
+                offset,
+                total,
+                files: map_files_out(files),
+            }),
+            super::TcpMessage::PauseTransfers { paused } => {
+                tcp_message::Message::PauseTransfers(protobuf_types::PauseTransfers { paused })
+            }
+            super::TcpMessage::ChangeApplied { change_id } => {
+                tcp_message::Message::ChangeApplied(protobuf_types::ChangeApplied {
+                    change_id: change_id.into(),
+                })
+            }
+            super::TcpMessage::SpeedTest {
+                test_id,
+                data,
+                last,
+            } => tcp_message::Message::SpeedTest(protobuf_types::SpeedTest {
+                test_id: test_id.into(),
+                data,
+                last,
+            }),
+            super::TcpMessage::SpeedTestResult {
+                test_id,
+                bytes_received,
+            } => tcp_message::Message::SpeedTestResult(protobuf_types::SpeedTestResult {
+                test_id: test_id.into(),
+                bytes_received,
+            }),
+            super::TcpMessage::DirectoryDigests(digests) => {
+                tcp_message::Message::DirectoryDigests(protobuf_types::DirectoryDigests {
+                    digests: digests.into_iter().map(|d| d.into()).collect(),
+                })
+            }
         }
     }
 }
@@ -165,11 +238,13 @@ impl TryFrom<protobuf_types::tcp_message::Message> for super::TcpMessage {
         match value {
             tcp_message::Message::AddedFiles(added_files) => added_files.try_into(),
             tcp_message::Message::DeleteFile(delete_file) => delete_file.try_into(),
-            tcp_message::Message::Signal(signal) => {
-                Ok(protobuf_types::SignalType::from_i32(signal)
-                    .unwrap_or_default()
-                    .into())
-            }
+            tcp_message::Message::Signal(signal) => match SignalType::from_i32(signal) {
+                Some(signal) => Ok(signal.into()),
+                None => Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Unknown signal type {}", signal),
+                )),
+            },
             tcp_message::Message::CancelDownload(cancel_download) => cancel_download.try_into(),
             tcp_message::Message::DownloadError(err) => err.try_into(),
             tcp_message::Message::DownloadedFile(d) => d.try_into(),
@@ -180,6 +255,15 @@ impl TryFrom<protobuf_types::tcp_message::Message> for super::TcpMessage {
             tcp_message::Message::ReceivePeerId(p) => p.try_into(),
             tcp_message::Message::SharedDirectory(d) => d.try_into(),
             tcp_message::Message::StartDownload(d) => d.try_into(),
+            tcp_message::Message::Goodbye(g) => Ok(g.into()),
+            tcp_message::Message::DirectoryAvailable(d) => d.try_into(),
+            tcp_message::Message::RequestFileList(r) => r.try_into(),
+            tcp_message::Message::FileList(f) => f.try_into(),
+            tcp_message::Message::PauseTransfers(p) => Ok(p.into()),
+            tcp_message::Message::ChangeApplied(c) => c.try_into(),
+            tcp_message::Message::SpeedTest(s) => s.try_into(),
+            tcp_message::Message::SpeedTestResult(s) => s.try_into(),
+            tcp_message::Message::DirectoryDigests(d) => d.try_into(),
         }
     }
 }
@@ -207,6 +291,13 @@ impl From<protobuf_types::DownloadErrorType> for DownloadError {
             protobuf_types::DownloadErrorType::FileTooLarge => DownloadError::FileTooLarge,
             protobuf_types::DownloadErrorType::ReadError => DownloadError::ReadError,
             protobuf_types::DownloadErrorType::WriteError => DownloadError::WriteError,
+            protobuf_types::DownloadErrorType::Busy => DownloadError::Busy,
+            protobuf_types::DownloadErrorType::HashMismatch => DownloadError::HashMismatch,
+            protobuf_types::DownloadErrorType::PathTooLong => DownloadError::PathTooLong,
+            protobuf_types::DownloadErrorType::EncryptionLocked => DownloadError::EncryptionLocked,
+            protobuf_types::DownloadErrorType::DownloadDirectoryUnavailable => {
+                DownloadError::DownloadDirectoryUnavailable
+            }
         }
     }
 }
@@ -287,6 +378,7 @@ impl TryFrom<protobuf_types::SharedFile> for SharedFile {
             content_location,
             owned_peers,
             size: value.size,
+            uploads: Default::default(),
         })
     }
 }
@@ -307,6 +399,7 @@ impl TryFrom<protobuf_types::ShareDirectorySignature> for ShareDirectorySignatur
             identifier: value.identifier.try_into()?,
             last_modified: value.last_modified.try_into()?,
             shared_peers,
+            fingerprint: value.fingerprint,
         })
     }
 }
@@ -343,9 +436,15 @@ impl TryFrom<protobuf_types::AddedFiles> for super::TcpMessage {
     type Error = std::io::Error;
 
     fn try_from(value: protobuf_types::AddedFiles) -> Result<Self, Self::Error> {
+        let change_id = match value.change_id {
+            Some(id) => Some(id.try_into()?),
+            None => None,
+        };
+
         Ok(super::TcpMessage::AddedFiles {
             directory: value.directory.try_into()?,
             files: map_files(value.files)?,
+            change_id,
         })
     }
 }
@@ -354,10 +453,16 @@ impl TryFrom<protobuf_types::DeleteFile> for super::TcpMessage {
     type Error = std::io::Error;
 
     fn try_from(value: protobuf_types::DeleteFile) -> Result<Self, Self::Error> {
+        let change_id = match value.change_id {
+            Some(id) => Some(id.try_into()?),
+            None => None,
+        };
+
         Ok(super::TcpMessage::DeleteFile {
             peer_id: value.peer_id.try_into()?,
             directory: value.directory.try_into()?,
             file: value.file_identifier.try_into()?,
+            change_id,
         })
     }
 }
@@ -377,9 +482,14 @@ impl TryFrom<protobuf_types::DownloadError> for super::TcpMessage {
 
     fn try_from(value: protobuf_types::DownloadError) -> Result<Self, Self::Error> {
         Ok(super::TcpMessage::DownloadError {
-            error: protobuf_types::DownloadErrorType::from_i32(value.error)
-                .unwrap_or_default()
-                .into(),
+            error: match protobuf_types::DownloadErrorType::from_i32(value.error) {
+                Some(error) => error.into(),
+                None => {
+                    warn!("Received unknown download error type {}", value.error);
+
+                    DownloadError::Unknown
+                }
+            },
             download_id: value.download_id.try_into()?,
         })
     }
@@ -444,7 +554,21 @@ impl TryFrom<protobuf_types::ReceivePeerId> for super::TcpMessage {
     type Error = std::io::Error;
 
     fn try_from(value: protobuf_types::ReceivePeerId) -> Result<Self, Self::Error> {
-        Ok(super::TcpMessage::ReceivePeerId(value.peer_id.try_into()?))
+        let sent_at = match value.sent_at {
+            Some(date) => Some(date.try_into()?),
+            None => None,
+        };
+
+        Ok(super::TcpMessage::ReceivePeerId {
+            peer_id: value.peer_id.try_into()?,
+            sent_at,
+            // Algorithms of newer versions are left out
+            compression: value
+                .compression
+                .iter()
+                .filter_map(|name| Compression::from_name(name))
+                .collect(),
+        })
     }
 }
 
@@ -456,6 +580,7 @@ impl TryFrom<protobuf_types::StartDownload> for super::TcpMessage {
             download_id: value.download_id.try_into()?,
             file_id: value.file_id.try_into()?,
             dir_id: value.dir_id.try_into()?,
+            content_hash: value.content_hash,
         })
     }
 }
@@ -470,6 +595,126 @@ impl TryFrom<protobuf_types::SharedDirectory> for super::TcpMessage {
     }
 }
 
+impl From<protobuf_types::Goodbye> for super::TcpMessage {
+    fn from(value: protobuf_types::Goodbye) -> Self {
+        super::TcpMessage::Goodbye {
+            reason: value.reason,
+            idle: value.idle.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<protobuf_types::PauseTransfers> for super::TcpMessage {
+    fn from(value: protobuf_types::PauseTransfers) -> Self {
+        super::TcpMessage::PauseTransfers {
+            paused: value.paused,
+        }
+    }
+}
+
+impl TryFrom<protobuf_types::ChangeApplied> for super::TcpMessage {
+    type Error = std::io::Error;
+
+    fn try_from(value: protobuf_types::ChangeApplied) -> Result<Self, Self::Error> {
+        Ok(super::TcpMessage::ChangeApplied {
+            change_id: value.change_id.try_into()?,
+        })
+    }
+}
+
+impl TryFrom<protobuf_types::SpeedTest> for super::TcpMessage {
+    type Error = std::io::Error;
+
+    fn try_from(value: protobuf_types::SpeedTest) -> Result<Self, Self::Error> {
+        Ok(super::TcpMessage::SpeedTest {
+            test_id: value.test_id.try_into()?,
+            data: value.data,
+            last: value.last,
+        })
+    }
+}
+
+impl TryFrom<protobuf_types::SpeedTestResult> for super::TcpMessage {
+    type Error = std::io::Error;
+
+    fn try_from(value: protobuf_types::SpeedTestResult) -> Result<Self, Self::Error> {
+        Ok(super::TcpMessage::SpeedTestResult {
+            test_id: value.test_id.try_into()?,
+            bytes_received: value.bytes_received,
+        })
+    }
+}
+
+impl TryFrom<protobuf_types::DirectoryDigests> for super::TcpMessage {
+    type Error = std::io::Error;
+
+    fn try_from(value: protobuf_types::DirectoryDigests) -> Result<Self, Self::Error> {
+        let digests = value
+            .digests
+            .into_iter()
+            .map(|d| d.try_into())
+            .collect::<Result<Vec<DirectoryDigest>, Self::Error>>()?;
+
+        Ok(super::TcpMessage::DirectoryDigests(digests))
+    }
+}
+
+impl TryFrom<protobuf_types::DirectoryDigest> for DirectoryDigest {
+    type Error = std::io::Error;
+
+    fn try_from(value: protobuf_types::DirectoryDigest) -> Result<Self, Self::Error> {
+        Ok(DirectoryDigest {
+            directory_identifier: value.directory_identifier.try_into()?,
+            digest: value.digest,
+        })
+    }
+}
+
+impl From<DirectoryDigest> for protobuf_types::DirectoryDigest {
+    fn from(value: DirectoryDigest) -> Self {
+        Self {
+            directory_identifier: value.directory_identifier.into(),
+            digest: value.digest,
+        }
+    }
+}
+
+impl TryFrom<protobuf_types::DirectoryAvailable> for super::TcpMessage {
+    type Error = std::io::Error;
+
+    fn try_from(value: protobuf_types::DirectoryAvailable) -> Result<Self, Self::Error> {
+        Ok(super::TcpMessage::DirectoryAvailable {
+            signature: value.signature.try_into()?,
+            file_count: value.file_count,
+        })
+    }
+}
+
+impl TryFrom<protobuf_types::RequestFileList> for super::TcpMessage {
+    type Error = std::io::Error;
+
+    fn try_from(value: protobuf_types::RequestFileList) -> Result<Self, Self::Error> {
+        Ok(super::TcpMessage::RequestFileList {
+            directory_identifier: value.directory_identifier.try_into()?,
+            offset: value.offset,
+            limit: value.limit,
+        })
+    }
+}
+
+impl TryFrom<protobuf_types::FileList> for super::TcpMessage {
+    type Error = std::io::Error;
+
+    fn try_from(value: protobuf_types::FileList) -> Result<Self, Self::Error> {
+        Ok(super::TcpMessage::FileList {
+            signature: value.signature.try_into()?,
+            offset: value.offset,
+            total: value.total,
+            files: map_files(value.files)?,
+        })
+    }
+}
+
 impl From<Uuid> for protobuf_types::Uuid {
     fn from(value: Uuid) -> Self {
         Self {
@@ -495,6 +740,8 @@ impl From<PeerId> for protobuf_types::PeerId {
     }
 }
 
+/// Local paths and partial downloads only mean something on this device, so
+/// peers are always sent `NetworkOnly`.
 impl From<ContentLocation> for protobuf_types::ContentLocation {
     fn from(_: ContentLocation) -> Self {
         Self {
@@ -536,6 +783,7 @@ impl From<ShareDirectorySignature> for protobuf_types::ShareDirectorySignature {
             identifier: value.identifier.into(),
             last_modified: value.last_modified.into(),
             shared_peers,
+            fingerprint: value.fingerprint,
         }
     }
 }
@@ -1,30 +1,64 @@
 use anyhow::{bail, Result};
 
+use chrono::{DateTime, Utc};
 use platform_dirs::AppDirs;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    collections::{hash_map::Entry, HashMap, HashSet},
     fs::{self, File, OpenOptions},
-    io::Write,
+    io::{self, Write},
+    ops::RangeInclusive,
     path::{Path, PathBuf},
     str::FromStr,
     sync::Arc,
     time::Duration,
 };
 use tauri::async_runtime::Mutex;
+use tokio::{io::AsyncWriteExt, sync::Notify};
 use uuid::Uuid;
 
-use crate::data::{ContentLocation, PeerId, ShareDirectory, SharedFile};
+use crate::{
+    data::{ContentLocation, PeerId, ShareDirectory, SharedFile},
+    encryption::{generate_salt, FileKey},
+    listen::AUTO_INTERFACE,
+    logging::DEFAULT_LOG_FILTER,
+    window::Download,
+};
 
 const APP_FILES_LOCATION: &str = "fileshare";
 const APP_CONFIG_LOCATION: &str = "config.json";
 const APP_CACHE_LOCATION: &str = "cached_files.json";
+const APP_DOWNLOADS_LOCATION: &str = "downloads.json";
+const CHECKSUM_EXTENSION: &str = "sha256";
+const BACKUP_EXTENSION: &str = "bak";
+const JOURNAL_EXTENSION: &str = "journal";
 const DEFAULT_DOWNLOAD_LOCATION: &str = "downloads";
-const SAVE_INTERVAL_SECS: u64 = 300;
+const DEFAULT_SAVE_INTERVAL_SECS: u64 = 300;
+const SAVE_INTERVAL_SECS: RangeInclusive<u64> = 30..=86_400;
+const DEFAULT_SAVE_DELAY_SECS: u64 = 2;
+const SAVE_DELAY_SECS: RangeInclusive<u64> = 0..=60;
+const DATA_DIR_ENV: &str = "FILESHARE_DATA_DIR";
+const DATA_DIR_FLAG: &str = "--data-dir";
+const DEFAULT_MAX_TRANSFERS_PER_PEER: usize = 16;
+const DEFAULT_MAX_CONCURRENT_DOWNLOADS: usize = 8;
+const DEFAULT_MAX_DOWNLOADS_PER_PEER: usize = 4;
+const DEFAULT_MDNS_UPDATE_SECS: u64 = 15;
+const MDNS_UPDATE_SECS: RangeInclusive<u64> = 5..=3600;
+const DEFAULT_RECONNECT_AFTER_SECS: u64 = 15;
+const RECONNECT_AFTER_SECS: RangeInclusive<u64> = 0..=3600;
+const DEFAULT_ANTI_ENTROPY_SECS: u64 = 300;
+const ANTI_ENTROPY_SECS: RangeInclusive<u64> = 5..=86_400;
+const DEFAULT_CONNECT_TIMEOUT_MILLIS: u64 = 2000;
+const CONNECT_TIMEOUT_MILLIS: RangeInclusive<u64> = 100..=60_000;
+const IDLE_TIMEOUT_SECS: RangeInclusive<u64> = 1..=604_800;
+const DEFAULT_CLEANUP_AGE_DAYS: u64 = 7;
+const CLEANUP_AGE_DAYS: RangeInclusive<u64> = 0..=3650;
+const DEFAULT_MAX_DIRECTORIES: usize = 1000;
+const DEFAULT_MAX_FILES_PER_DIRECTORY: usize = 100_000;
 
 pub fn load_stored_data() -> (StoredConfig, PeerId) {
-    let app_dir =
-        AppDirs::new(Some(APP_FILES_LOCATION), false).expect("to be able to create config files");
+    let app_dir = app_dirs();
 
     let config_path = ensure_path(app_dir.config_dir, APP_CONFIG_LOCATION);
     let cache_path = ensure_path(app_dir.data_dir.clone(), APP_CACHE_LOCATION);
@@ -32,10 +66,14 @@ pub fn load_stored_data() -> (StoredConfig, PeerId) {
     let config_str = fs::read_to_string(config_path).expect("to be able to read the config file");
     let mut config: AppConfig = serde_json::from_str(&config_str).unwrap_or_default();
 
-    if config.peer_id.is_none() {
-        config.peer_id = Some(PeerId::generate());
-    }
-    let peer_id = config.peer_id.clone().unwrap();
+    let peer_id = match config.peer_id.take() {
+        None => PeerId::generate(),
+        Some(peer_id) => PeerId {
+            hostname: PeerId::local_hostname(),
+            ..peer_id
+        },
+    };
+    config.peer_id = Some(peer_id.clone());
 
     if !config.download_directory.exists() {
         let default_download_path = app_dir.data_dir.join(DEFAULT_DOWNLOAD_LOCATION);
@@ -48,21 +86,38 @@ pub fn load_stored_data() -> (StoredConfig, PeerId) {
         config.download_directory = default_download_path;
     }
 
-    let cache_str = fs::read_to_string(cache_path).expect("to be able to read cache file");
-    let cache: HashMap<Uuid, ShareDirectory> = serde_json::from_str(&cache_str).unwrap_or_default();
+    let mut cache = load_cache(&cache_path);
+    replay_journal(&cache_path, &mut cache);
+
+    // The device might have been renamed since the cache was written.
+    for dir in cache.values_mut() {
+        dir.rename_peer(&peer_id);
+    }
 
-    (StoredConfig::new(config, cache), peer_id)
+    let downloads_path = ensure_path(app_dir.data_dir, APP_DOWNLOADS_LOCATION);
+    let downloads_str =
+        fs::read_to_string(&downloads_path).expect("to be able to read downloads file");
+    let interrupted: Vec<Download> = serde_json::from_str(&downloads_str).unwrap_or_default();
+
+    let stored_config =
+        StoredConfig::new(config, cache).with_download_log(downloads_path, interrupted);
+
+    (stored_config, peer_id)
 }
 
 pub fn write_stored_data(stored_config: &StoredConfig) {
-    let app_dir =
-        AppDirs::new(Some(APP_FILES_LOCATION), false).expect("to be able to create config files");
+    let app_dir = app_dirs();
 
     let config_path = app_dir.config_dir.join(APP_CONFIG_LOCATION);
     let cache_path = app_dir.data_dir.join(APP_CACHE_LOCATION);
 
     let config_bytes = serde_json::to_vec_pretty(&*stored_config.app_config.blocking_lock());
-    let cache_bytes = serde_json::to_vec_pretty(&*stored_config.cached_data.blocking_lock());
+    let cache_bytes = {
+        let cache = stored_config.cached_data.blocking_lock();
+        stored_config.take_changed_directories();
+
+        serde_json::to_vec_pretty(&*cache)
+    };
 
     let mut open_settings = OpenOptions::new();
     let open_settings = open_settings.write(true).truncate(true);
@@ -80,142 +135,1323 @@ pub fn write_stored_data(stored_config: &StoredConfig) {
     }
 
     if let Ok(cache) = cache_bytes {
-        let file = open_settings.open(cache_path);
+        if let Err(e) = write_snapshot(&cache_path, &cache) {
+            error!("could not write cache to file: {}", e);
+        } else {
+            info!("Successfully wrote cache to file");
+            remove_journal(&cache_path);
+        }
+    }
+}
 
-        if let Ok(mut file) = file {
-            if let Err(e) = file.write_all(&cache) {
+pub async fn write_stored_data_async(stored_config: &StoredConfig) {
+    let app_dir = app_dirs();
+
+    let config_path = app_dir.config_dir.join(APP_CONFIG_LOCATION);
+    let cache_path = app_dir.data_dir.join(APP_CACHE_LOCATION);
+
+    write_config_async(stored_config, &config_path).await;
+
+    let (cache_bytes, changed) = {
+        let cache = stored_config.cached_data.lock().await;
+
+        (
+            serde_json::to_vec_pretty(&*cache),
+            stored_config.take_changed_directories(),
+        )
+    };
+
+    if let Ok(cache) = cache_bytes {
+        let result = tokio::task::spawn_blocking(move || {
+            write_snapshot(&cache_path, &cache)?;
+            remove_journal(&cache_path);
+
+            Ok(())
+        })
+        .await
+        .unwrap_or_else(|e| Err(io::Error::new(io::ErrorKind::Other, e)));
+
+        match result {
+            Ok(()) => info!("Successfully wrote cache to file"),
+            Err(e) => {
                 error!("could not write cache to file: {}", e);
-            } else {
-                info!("Successfully wrote cache to file");
+                stored_config.restore_changed_directories(changed);
             }
         }
     }
 }
 
-pub async fn write_stored_data_async(stored_config: &StoredConfig) {
-    let app_dir =
-        AppDirs::new(Some(APP_FILES_LOCATION), false).expect("to be able to create config files");
+async fn write_config_async(stored_config: &StoredConfig, config_path: &Path) {
+    let config_bytes = serde_json::to_vec_pretty(&*stored_config.app_config.lock().await);
+
+    if let Ok(config) = config_bytes {
+        if let Err(e) = write_file_atomically(config_path, &config).await {
+            error!("could not write config to file: {}", e);
+        } else {
+            info!("Successfully wrote config to file");
+        }
+    }
+}
+
+/// A line of the cache journal. The journal holds the directories changed
+/// since the snapshot was written, so a save only writes those instead of
+/// every directory. Its first line is the checksum of the snapshot it
+/// follows.
+#[derive(Serialize, Deserialize)]
+enum JournalEntry<D> {
+    Saved(D),
+    Removed(Uuid),
+}
+
+/// Appends the directories changed since the last save to the cache
+/// journal. Writes a full snapshot instead if there is none for the journal
+/// to follow yet.
+pub async fn write_changes_async(stored_config: &StoredConfig) {
+    let app_dir = app_dirs();
 
     let config_path = app_dir.config_dir.join(APP_CONFIG_LOCATION);
     let cache_path = app_dir.data_dir.join(APP_CACHE_LOCATION);
 
-    let config_bytes = serde_json::to_vec_pretty(&*stored_config.app_config.lock().await);
-    let cache_bytes = serde_json::to_vec_pretty(&*stored_config.cached_data.lock().await);
+    let checksum_path = with_added_extension(&cache_path, CHECKSUM_EXTENSION);
+    if !checksum_path.exists() {
+        write_stored_data_async(stored_config).await;
+        return;
+    }
 
-    let mut open_settings = tokio::fs::OpenOptions::new();
-    let open_settings = open_settings.write(true).truncate(true);
+    write_config_async(stored_config, &config_path).await;
+
+    let (lines, changed) = {
+        let cache = stored_config.cached_data.lock().await;
+        let changed = stored_config.take_changed_directories();
+
+        (journal_lines(&cache, &changed), changed)
+    };
+
+    if lines.is_empty() {
+        return;
+    }
+
+    match append_journal(&cache_path, &lines).await {
+        Ok(()) => info!("Saved {} changed directories to journal", changed.len()),
+        Err(e) => {
+            error!("could not write cache journal: {}", e);
+            stored_config.restore_changed_directories(changed);
+        }
+    }
+}
+
+fn journal_lines(cache: &HashMap<Uuid, ShareDirectory>, changed: &HashSet<Uuid>) -> Vec<u8> {
+    let mut lines = vec![];
+
+    for dir_id in changed {
+        let entry = match cache.get(dir_id) {
+            Some(dir) => JournalEntry::Saved(dir),
+            None => JournalEntry::Removed(*dir_id),
+        };
+
+        match serde_json::to_vec(&entry) {
+            Ok(line) => {
+                lines.extend(line);
+                lines.push(b'\n');
+            }
+            Err(e) => error!("Could not serialize directory {}: {}", dir_id, e),
+        }
+    }
+
+    lines
+}
+
+async fn append_journal(cache_path: &Path, lines: &[u8]) -> io::Result<()> {
+    let journal_path = with_added_extension(cache_path, JOURNAL_EXTENSION);
+    let mut journal = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&journal_path)
+        .await?;
+
+    if journal.metadata().await?.len() == 0 {
+        let checksum =
+            tokio::fs::read_to_string(with_added_extension(cache_path, CHECKSUM_EXTENSION)).await?;
+        journal
+            .write_all(format!("{}\n", checksum.trim()).as_bytes())
+            .await?;
+    }
+
+    journal.write_all(lines).await?;
+    journal.sync_data().await
+}
+
+fn remove_journal(cache_path: &Path) {
+    let journal_path = with_added_extension(cache_path, JOURNAL_EXTENSION);
+
+    if let Err(e) = fs::remove_file(&journal_path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            warn!("Could not remove cache journal {:?}: {}", journal_path, e);
+        }
+    }
+}
+
+/// Applies the directories saved to the journal after the snapshot at
+/// `path` was written. A journal that follows another snapshot is ignored,
+/// the snapshot already has its changes.
+fn replay_journal(path: &Path, cache: &mut HashMap<Uuid, ShareDirectory>) {
+    let journal = match fs::read_to_string(with_added_extension(path, JOURNAL_EXTENSION)) {
+        Ok(journal) => journal,
+        Err(_) => return,
+    };
+    let checksum = match fs::read_to_string(with_added_extension(path, CHECKSUM_EXTENSION)) {
+        Ok(checksum) => checksum,
+        Err(_) => return,
+    };
+
+    let mut lines = journal.lines();
+    if lines.next().map(str::trim) != Some(checksum.trim()) {
+        info!("Ignoring cache journal written for an older snapshot");
+        return;
+    }
+
+    for line in lines {
+        match serde_json::from_str::<JournalEntry<ShareDirectory>>(line) {
+            Ok(JournalEntry::Saved(dir)) => {
+                cache.insert(dir.signature.identifier, dir);
+            }
+            Ok(JournalEntry::Removed(dir_id)) => {
+                cache.remove(&dir_id);
+            }
+            // A crash while appending leaves the last line cut off
+            Err(e) => {
+                warn!("Stopped reading cache journal at a broken line: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+/// Length and SHA-256 of a snapshot, which catches truncated and corrupted
+/// files without parsing them.
+fn snapshot_checksum(bytes: &[u8]) -> String {
+    format!("{} {:x}", bytes.len(), Sha256::digest(bytes))
+}
+
+fn with_added_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+
+    PathBuf::from(path)
+}
+
+/// Writes `bytes` and their checksum to `path`, keeping the previous
+/// snapshot as a backup. Everything is written to temporary files first and
+/// renamed into place, so a crash at any point leaves either the new or the
+/// previous snapshot loadable.
+fn write_snapshot(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let checksum_path = with_added_extension(path, CHECKSUM_EXTENSION);
+    let backup_path = with_added_extension(path, BACKUP_EXTENSION);
+    let temp_path = with_added_extension(path, "tmp");
+    let temp_checksum_path = with_added_extension(&checksum_path, "tmp");
+
+    fs::write(&temp_path, bytes)?;
+    fs::write(&temp_checksum_path, snapshot_checksum(bytes))?;
+
+    // The checksum goes first, so a crash in between leaves the current
+    // snapshot without a checksum rather than with the wrong one
+    if checksum_path.exists() && path.exists() {
+        fs::rename(
+            &checksum_path,
+            with_added_extension(&backup_path, CHECKSUM_EXTENSION),
+        )?;
+        fs::rename(path, &backup_path)?;
+    }
+
+    fs::rename(&temp_path, path)?;
+    fs::rename(&temp_checksum_path, &checksum_path)
+}
+
+/// Reads a snapshot written by `write_snapshot`. Snapshots without a
+/// checksum, written by older versions, are trusted.
+fn read_snapshot<T>(path: &Path) -> Result<T>
+where
+    T: serde::de::DeserializeOwned + Default,
+{
+    let bytes = fs::read(path)?;
+
+    match fs::read_to_string(with_added_extension(path, CHECKSUM_EXTENSION)) {
+        Ok(checksum) if checksum.trim() == snapshot_checksum(&bytes) => (),
+        Ok(_) => bail!("checksum does not match"),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+
+    if bytes.is_empty() {
+        return Ok(T::default());
+    }
+
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+/// Loads the cache, falling back to the previous snapshot if the latest one
+/// is corrupted. Directories are only lost if both are.
+fn load_cache(path: &Path) -> HashMap<Uuid, ShareDirectory> {
+    let error = match read_snapshot(path) {
+        Ok(cache) => return cache,
+        Err(e) => e,
+    };
+    error!("Could not load cache from {:?}: {}", path, error);
+
+    let backup_path = with_added_extension(path, BACKUP_EXTENSION);
+    match read_snapshot(&backup_path) {
+        Ok(cache) => {
+            warn!("Restored cache from backup {:?}", backup_path);
+            cache
+        }
+        Err(e) => {
+            error!(
+                "Could not load cache backup from {:?}, starting without directories: {}",
+                backup_path, e
+            );
+            HashMap::new()
+        }
+    }
+}
+
+/// Writes `bytes` next to `path` and renames them over it, so a crash in the
+/// middle of a save leaves the previous file intact.
+async fn write_file_atomically(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let temp_path = path.with_extension("json.tmp");
+
+    tokio::fs::write(&temp_path, bytes).await?;
+    tokio::fs::rename(&temp_path, path).await
+}
+
+/// Saves the config and changed directories shortly after they change,
+/// waiting `timing.save_delay_secs` so a burst of changes is written once.
+/// Every `timing.save_interval_secs` the whole cache is written instead,
+/// which also empties the journal of changed directories.
+pub async fn save_config_loop(configs: Arc<StoredConfig>) {
+    let timing = configs.get_timing().await;
+    let mut job_interval = tokio::time::interval(timing.save_interval());
+
+    loop {
+        tokio::select! {
+            _ = job_interval.tick() => {
+                write_stored_data_async(&configs).await;
+            }
+            _ = configs.changed.notified() => {
+                tokio::time::sleep(timing.save_delay()).await;
+                write_changes_async(&configs).await;
+            }
+        }
+    }
+}
+
+/// Directories used for stored config and cache. They can be relocated with
+/// the `--data-dir <path>` flag or the `FILESHARE_DATA_DIR` environment
+/// variable, e.g. to run several instances on the same machine.
+pub fn app_dirs() -> AppDirs {
+    match data_dir_override() {
+        Some(base_dir) => AppDirs {
+            cache_dir: base_dir.join("cache"),
+            config_dir: base_dir.clone(),
+            data_dir: base_dir.clone(),
+            state_dir: base_dir.join("state"),
+        },
+        None => AppDirs::new(Some(APP_FILES_LOCATION), false)
+            .expect("to be able to create config files"),
+    }
+}
+
+fn data_dir_override() -> Option<PathBuf> {
+    let mut args = std::env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        if arg == DATA_DIR_FLAG {
+            return args.next().map(PathBuf::from);
+        }
+
+        if let Some(dir) = arg
+            .strip_prefix(DATA_DIR_FLAG)
+            .and_then(|a| a.strip_prefix('='))
+        {
+            return Some(PathBuf::from(dir));
+        }
+    }
+
+    std::env::var_os(DATA_DIR_ENV).map(PathBuf::from)
+}
+
+fn ensure_path<P>(path: PathBuf, subpath: P) -> PathBuf
+where
+    P: AsRef<Path>,
+{
+    fs::create_dir_all(&path).expect("should be able to create directory for stored data");
+
+    let resulting_path = path.join(&subpath);
+    if !resulting_path.exists() {
+        File::create(&resulting_path).expect("should be able to create file for stored data");
+    }
+
+    resulting_path
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct AppConfig {
+    pub peer_id: Option<PeerId>,
+    pub hide_on_close: bool,
+    pub download_directory: PathBuf,
+    #[serde(deserialize_with = "deserialize_theme_or_default")]
+    pub theme: Theme,
+    pub listen_port: Option<u16>,
+    pub download_notifications: DownloadNotifications,
+    pub max_transfers_per_peer: usize,
+    /// Downloads we request at once, from all peers and from each one.
+    /// Further downloads wait in the queue for a free slot.
+    pub max_concurrent_downloads: usize,
+    pub max_downloads_per_peer: usize,
+    pub auto_accept_shares: bool,
+    pub timing: TimingConfig,
+    /// Written by versions from before `timing` existed. Moved into it when
+    /// the config is loaded.
+    #[serde(skip_serializing)]
+    pub mdns_update_secs: Option<u64>,
+    #[serde(skip_serializing)]
+    pub connect_timeout_millis: Option<u64>,
+    #[serde(skip_serializing)]
+    pub cleanup_age_days: Option<u64>,
+    #[serde(skip_serializing)]
+    pub idle_timeout_secs: Option<u64>,
+    pub stream_media: bool,
+    /// Remove leftover partial and temporary files from the download
+    /// directory at startup once they are older than
+    /// `timing.cleanup_age_days`.
+    pub cleanup_downloads: bool,
+    /// Limits on shared directories and the files in each, so a huge share
+    /// or a misbehaving peer can't grow the cache without bound.
+    pub max_directories: usize,
+    pub max_files_per_directory: usize,
+    /// Write downloads encrypted with a key derived from a passphrase that is
+    /// entered after every start. See the `encryption` module for what this
+    /// does and doesn't protect against.
+    pub encrypt_downloads: bool,
+    /// Set when the first passphrase is entered. The verifier rejects later
+    /// passphrases that don't derive the same key.
+    pub encryption_salt: Option<Vec<u8>>,
+    pub encryption_verifier: Option<Vec<u8>>,
+    /// Directories shared with every peer that connects, see
+    /// `WindowResponse::ShareDirectoryToAllPeers`.
+    pub shared_with_all: HashSet<Uuid>,
+    /// Which log messages are written, in the `RUST_LOG` syntax, so verbose
+    /// logs can be turned on for one module, e.g. `warn,app::mdns=debug`.
+    /// `RUST_LOG` takes precedence when set.
+    pub log_filter: String,
+    /// Patterns of files left out whenever a folder is added, on top of the
+    /// ones given with `WindowResponse::AddFolder`. See `ExcludePatterns`.
+    pub exclude_patterns: Vec<String>,
+    /// The interface to listen on, by name or as an IPv4 subnet in CIDR
+    /// notation. See `InterfaceSelection`.
+    pub network_interface: String,
+    /// Writes every event the window receives to connections on this
+    /// localhost port, as JSON lines, so scripts can react to them. Off when
+    /// unset. See the `events` module.
+    pub event_stream_port: Option<u16>,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            peer_id: None,
+            hide_on_close: false,
+            download_directory: PathBuf::new(),
+            theme: Theme::default(),
+            listen_port: None,
+            download_notifications: DownloadNotifications::default(),
+            max_transfers_per_peer: DEFAULT_MAX_TRANSFERS_PER_PEER,
+            max_concurrent_downloads: DEFAULT_MAX_CONCURRENT_DOWNLOADS,
+            max_downloads_per_peer: DEFAULT_MAX_DOWNLOADS_PER_PEER,
+            auto_accept_shares: true,
+            timing: TimingConfig::default(),
+            mdns_update_secs: None,
+            connect_timeout_millis: None,
+            cleanup_age_days: None,
+            idle_timeout_secs: None,
+            stream_media: false,
+            cleanup_downloads: false,
+            max_directories: DEFAULT_MAX_DIRECTORIES,
+            max_files_per_directory: DEFAULT_MAX_FILES_PER_DIRECTORY,
+            encrypt_downloads: false,
+            encryption_salt: None,
+            encryption_verifier: None,
+            shared_with_all: HashSet::new(),
+            log_filter: DEFAULT_LOG_FILTER.to_owned(),
+            exclude_patterns: vec![],
+            network_interface: AUTO_INTERFACE.to_owned(),
+            event_stream_port: None,
+        }
+    }
+}
+
+/// How often the app saves its state and talks to the network, for tuning
+/// headless nodes. Values outside the allowed ranges are clamped when the
+/// config is loaded.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct TimingConfig {
+    /// How often, in seconds, the config and cache are saved even if no
+    /// change was reported. Between 30 seconds and a day.
+    pub save_interval_secs: u64,
+    /// How long, in seconds, to wait after a change before saving, so a burst
+    /// of changes is written once. Longer delays mean fewer disk writes but
+    /// more lost changes on a crash. At most a minute.
+    pub save_delay_secs: u64,
+    /// How often, in seconds, lost services are retried. Shorter intervals
+    /// reconnect sooner on networks that age out mDNS records quickly, longer
+    /// ones wake the network less often, which helps battery life. Between
+    /// 5 seconds and an hour.
+    pub mdns_update_secs: u64,
+    /// How long, in seconds, a peer has to be disconnected before it is
+    /// reconnected to, so a peer that keeps dropping isn't retried on every
+    /// mDNS update. At most an hour.
+    pub reconnect_after_secs: u64,
+    /// How often, in seconds, directories are compared with every connected
+    /// peer to catch changes that were missed. Only directories that differ
+    /// are sent, so this is cheap, but each pass wakes every connection.
+    /// Between 5 seconds and a day.
+    pub anti_entropy_secs: u64,
+    /// How long, in milliseconds, to wait for a discovered peer to accept a
+    /// connection before trying its next address. Between 100 milliseconds
+    /// and a minute.
+    pub connect_timeout_millis: u64,
+    /// Connections that exchange no messages for this many seconds are
+    /// closed, and reopened once either peer needs the other. Connections
+    /// are kept open indefinitely when unset. At most a week.
+    pub idle_timeout_secs: Option<u64>,
+    /// How old, in days, leftover download files must be before the startup
+    /// cleanup removes them. At most ten years.
+    pub cleanup_age_days: u64,
+}
+
+impl Default for TimingConfig {
+    fn default() -> Self {
+        Self {
+            save_interval_secs: DEFAULT_SAVE_INTERVAL_SECS,
+            save_delay_secs: DEFAULT_SAVE_DELAY_SECS,
+            mdns_update_secs: DEFAULT_MDNS_UPDATE_SECS,
+            reconnect_after_secs: DEFAULT_RECONNECT_AFTER_SECS,
+            anti_entropy_secs: DEFAULT_ANTI_ENTROPY_SECS,
+            connect_timeout_millis: DEFAULT_CONNECT_TIMEOUT_MILLIS,
+            idle_timeout_secs: None,
+            cleanup_age_days: DEFAULT_CLEANUP_AGE_DAYS,
+        }
+    }
+}
+
+impl TimingConfig {
+    /// Clamps every value into its allowed range, so a typo can't make the
+    /// app save constantly or flood the network.
+    pub fn validated(self) -> Self {
+        Self {
+            save_interval_secs: clamp_setting(
+                "save_interval_secs",
+                self.save_interval_secs,
+                SAVE_INTERVAL_SECS,
+            ),
+            save_delay_secs: clamp_setting(
+                "save_delay_secs",
+                self.save_delay_secs,
+                SAVE_DELAY_SECS,
+            ),
+            mdns_update_secs: clamp_setting(
+                "mdns_update_secs",
+                self.mdns_update_secs,
+                MDNS_UPDATE_SECS,
+            ),
+            reconnect_after_secs: clamp_setting(
+                "reconnect_after_secs",
+                self.reconnect_after_secs,
+                RECONNECT_AFTER_SECS,
+            ),
+            anti_entropy_secs: clamp_setting(
+                "anti_entropy_secs",
+                self.anti_entropy_secs,
+                ANTI_ENTROPY_SECS,
+            ),
+            connect_timeout_millis: clamp_setting(
+                "connect_timeout_millis",
+                self.connect_timeout_millis,
+                CONNECT_TIMEOUT_MILLIS,
+            ),
+            idle_timeout_secs: self
+                .idle_timeout_secs
+                .map(|secs| clamp_setting("idle_timeout_secs", secs, IDLE_TIMEOUT_SECS)),
+            cleanup_age_days: clamp_setting(
+                "cleanup_age_days",
+                self.cleanup_age_days,
+                CLEANUP_AGE_DAYS,
+            ),
+        }
+    }
+
+    pub fn save_interval(&self) -> Duration {
+        Duration::from_secs(self.save_interval_secs)
+    }
+
+    pub fn save_delay(&self) -> Duration {
+        Duration::from_secs(self.save_delay_secs)
+    }
+
+    pub fn mdns_update_interval(&self) -> Duration {
+        Duration::from_secs(self.mdns_update_secs)
+    }
+
+    pub fn reconnect_after(&self) -> Duration {
+        Duration::from_secs(self.reconnect_after_secs)
+    }
+
+    pub fn anti_entropy_interval(&self) -> Duration {
+        Duration::from_secs(self.anti_entropy_secs)
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_millis(self.connect_timeout_millis)
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout_secs.map(Duration::from_secs)
+    }
+
+    pub fn cleanup_age(&self) -> Duration {
+        Duration::from_secs(self.cleanup_age_days * 24 * 60 * 60)
+    }
+}
+
+/// Everything inside the download directory can be opened from the window,
+/// see `StoredConfig::is_known_path`, so it has to be a directory of its own
+/// rather than a whole drive or the home directory.
+fn check_download_directory(path: &Path, home: Option<&Path>) -> Result<()> {
+    let path = match path.canonicalize() {
+        Ok(path) if path.is_dir() => path,
+        _ => bail!("Download directory {} does not exist", path.display()),
+    };
+
+    if path.parent().is_none() {
+        bail!("Download directory can't be the root of a drive");
+    }
+
+    if let Some(home) = home.and_then(|home| home.canonicalize().ok()) {
+        if home.starts_with(&path) {
+            bail!("Download directory can't be the home directory or contain it");
+        }
+    }
+
+    Ok(())
+}
+
+fn home_directory() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+}
+
+fn clamp_setting(name: &str, value: u64, range: RangeInclusive<u64>) -> u64 {
+    let clamped = value.clamp(*range.start(), *range.end());
+    if clamped != value {
+        warn!(
+            "timing.{} = {} is outside {}..={}, using {}",
+            name,
+            value,
+            range.start(),
+            range.end(),
+            clamped
+        );
+    }
+
+    clamped
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    Dark,
+    Light,
+}
+
+impl Theme {
+    pub const ALL: [Theme; 2] = [Theme::Dark, Theme::Light];
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::Dark
+    }
+}
+
+/// Themes saved by older versions were free-form strings, so an unknown one
+/// falls back to the default instead of failing to load the whole config.
+fn deserialize_theme_or_default<'de, D>(deserializer: D) -> Result<Theme, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = serde_json::Value::deserialize(deserializer)?;
+
+    Ok(serde_json::from_value(value).unwrap_or_default())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum DownloadNotifications {
+    Disabled,
+    EveryDownload,
+    LastDownload,
+}
+
+impl Default for DownloadNotifications {
+    fn default() -> Self {
+        Self::Disabled
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Settings {
+    pub minimize_on_close: bool,
+    pub theme: Theme,
+    pub download_directory: String,
+    #[serde(default)]
+    pub listen_port: Option<u16>,
+    #[serde(default)]
+    pub download_notifications: DownloadNotifications,
+    #[serde(default = "default_auto_accept_shares")]
+    pub auto_accept_shares: bool,
+    #[serde(default)]
+    pub stream_media: bool,
+    #[serde(default)]
+    pub cleanup_downloads: bool,
+    #[serde(default = "default_cleanup_age_days")]
+    pub cleanup_age_days: u64,
+    #[serde(default)]
+    pub encrypt_downloads: bool,
+}
+
+fn default_auto_accept_shares() -> bool {
+    true
+}
+
+fn default_cleanup_age_days() -> u64 {
+    DEFAULT_CLEANUP_AGE_DAYS
+}
+
+/// How many downloads may run at once, see `AppConfig::max_concurrent_downloads`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DownloadLimits {
+    pub total: usize,
+    pub per_peer: usize,
+}
+
+/// A directory a peer shared with us that waits for the user to accept it.
+pub struct PendingShare {
+    pub directory: ShareDirectory,
+    pub peer: PeerId,
+}
+
+/// Source of timestamps for directory changes, so tests can control time.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+pub struct StoredConfig {
+    app_config: Mutex<AppConfig>,
+    cached_data: Mutex<HashMap<Uuid, ShareDirectory>>,
+    pending_shares: Mutex<HashMap<Uuid, PendingShare>>,
+    clock: Arc<dyn Clock>,
+    download_log: Option<PathBuf>,
+    running_downloads: Mutex<HashMap<Uuid, Download>>,
+    interrupted_downloads: Mutex<HashMap<Uuid, Download>>,
+    /// Only kept in memory, so encrypted downloads can't be read until the
+    /// passphrase is entered again after a restart.
+    file_key: Mutex<Option<FileKey>>,
+    /// Wakes `save_config_loop` when directories or settings change.
+    changed: Notify,
+    /// Directories changed since they were last saved, appended to the cache
+    /// journal by the next save.
+    changed_directories: std::sync::Mutex<HashSet<Uuid>>,
+    /// Wakes the listener when the port or interface to listen on changes,
+    /// so it is bound and advertised again right away.
+    listener_changed: Notify,
+}
+
+impl StoredConfig {
+    pub fn new(app_config: AppConfig, cached_data: HashMap<Uuid, ShareDirectory>) -> Self {
+        Self::with_clock(app_config, cached_data, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(
+        app_config: AppConfig,
+        cached_data: HashMap<Uuid, ShareDirectory>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let mut app_config = app_config;
+        if let Some(secs) = app_config.mdns_update_secs.take() {
+            app_config.timing.mdns_update_secs = secs;
+        }
+        if let Some(millis) = app_config.connect_timeout_millis.take() {
+            app_config.timing.connect_timeout_millis = millis;
+        }
+        if let Some(days) = app_config.cleanup_age_days.take() {
+            app_config.timing.cleanup_age_days = days;
+        }
+        if let Some(secs) = app_config.idle_timeout_secs.take() {
+            app_config.timing.idle_timeout_secs = Some(secs);
+        }
+        app_config.timing = app_config.timing.validated();
+
+        // Caches written by older versions have no fingerprints
+        let mut cached_data = cached_data;
+        for dir in cached_data.values_mut() {
+            dir.refresh_fingerprint();
+        }
+
+        Self {
+            app_config: Mutex::new(app_config),
+            cached_data: Mutex::new(cached_data),
+            pending_shares: Mutex::new(HashMap::new()),
+            clock,
+            download_log: None,
+            running_downloads: Mutex::new(HashMap::new()),
+            interrupted_downloads: Mutex::new(HashMap::new()),
+            file_key: Mutex::new(None),
+            changed: Notify::new(),
+            changed_directories: std::sync::Mutex::new(HashSet::new()),
+            listener_changed: Notify::new(),
+        }
+    }
+
+    /// Keeps running downloads listed in `path`, so downloads cut off by a
+    /// crash or quit can be offered again on the next start. `interrupted`
+    /// are the downloads that were listed when the app last stopped.
+    pub fn with_download_log(mut self, path: PathBuf, interrupted: Vec<Download>) -> Self {
+        self.download_log = Some(path);
+        self.interrupted_downloads = Mutex::new(
+            interrupted
+                .into_iter()
+                .map(|download| (download.download_id, download))
+                .collect(),
+        );
+
+        self
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+
+    /// Fixes cached directories before any of them are sent to peers. See
+    /// `ShareDirectory::repair`.
+    pub async fn repair_directories(&self, myself: &PeerId) {
+        let mut directories = self.cached_data.lock().await;
+
+        for dir in directories.values_mut() {
+            let repair = dir.repair(myself);
+
+            if !repair.is_empty() {
+                warn!(
+                    "Repaired cached directory {} ({}): {:?}",
+                    dir.signature.name, dir.signature.identifier, repair
+                );
+                self.mark_directory_changed(dir.signature.identifier);
+            }
+        }
+    }
+
+    /// Asks for the config to be saved soon. Changes made before the save
+    /// starts are written together.
+    fn mark_changed(&self) {
+        self.changed.notify_one();
+    }
+
+    /// Asks for a directory that was changed, added or removed to be saved
+    /// soon, along with the config.
+    fn mark_directory_changed(&self, dir_id: Uuid) {
+        self.changed_directories
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(dir_id);
+        self.mark_changed();
+    }
+
+    fn take_changed_directories(&self) -> HashSet<Uuid> {
+        std::mem::take(
+            &mut *self
+                .changed_directories
+                .lock()
+                .unwrap_or_else(|e| e.into_inner()),
+        )
+    }
+
+    /// Keeps directories whose save failed for the next one.
+    fn restore_changed_directories(&self, changed: HashSet<Uuid>) {
+        self.changed_directories
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .extend(changed);
+    }
+
+    pub async fn get_settings(&self) -> Settings {
+        let app_conf = self.app_config.lock().await;
+
+        Settings {
+            minimize_on_close: app_conf.hide_on_close,
+            theme: app_conf.theme,
+            download_directory: app_conf
+                .download_directory
+                .to_str()
+                .unwrap_or_default()
+                .to_string(),
+            listen_port: app_conf.listen_port,
+            download_notifications: app_conf.download_notifications,
+            auto_accept_shares: app_conf.auto_accept_shares,
+            stream_media: app_conf.stream_media,
+            cleanup_downloads: app_conf.cleanup_downloads,
+            cleanup_age_days: app_conf.timing.cleanup_age_days,
+            encrypt_downloads: app_conf.encrypt_downloads,
+        }
+    }
+
+    pub async fn set_settings(&self, new_settings: Settings) -> Result<()> {
+        let mut app_conf = self.app_config.lock().await;
+
+        let download_directory = PathBuf::from_str(&new_settings.download_directory)?;
+        // Left as it is while unavailable, e.g. on a removed drive
+        if download_directory != app_conf.download_directory {
+            check_download_directory(&download_directory, home_directory().as_deref())?;
+        }
+        app_conf.download_directory = download_directory;
+        app_conf.hide_on_close = new_settings.minimize_on_close;
+        app_conf.theme = new_settings.theme;
+        if app_conf.listen_port != new_settings.listen_port {
+            app_conf.listen_port = new_settings.listen_port;
+            self.listener_changed.notify_one();
+        }
+        app_conf.download_notifications = new_settings.download_notifications;
+        app_conf.auto_accept_shares = new_settings.auto_accept_shares;
+        app_conf.stream_media = new_settings.stream_media;
+        app_conf.cleanup_downloads = new_settings.cleanup_downloads;
+        app_conf.timing.cleanup_age_days = clamp_setting(
+            "cleanup_age_days",
+            new_settings.cleanup_age_days,
+            CLEANUP_AGE_DAYS,
+        );
+        app_conf.encrypt_downloads = new_settings.encrypt_downloads;
+        self.mark_changed();
+
+        Ok(())
+    }
+
+    pub async fn get_listen_port(&self) -> Option<u16> {
+        let app_conf = self.app_config.lock().await;
+
+        app_conf.listen_port
+    }
+
+    pub async fn get_peer_id(&self) -> Option<PeerId> {
+        let app_conf = self.app_config.lock().await;
+
+        app_conf.peer_id.clone()
+    }
+
+    pub async fn get_download_directory(&self) -> PathBuf {
+        let app_conf = self.app_config.lock().await;
+
+        app_conf.download_directory.clone()
+    }
+
+    pub async fn get_download_notifications(&self) -> DownloadNotifications {
+        let app_conf = self.app_config.lock().await;
+
+        app_conf.download_notifications
+    }
+
+    pub async fn get_max_transfers_per_peer(&self) -> usize {
+        let app_conf = self.app_config.lock().await;
+
+        app_conf.max_transfers_per_peer
+    }
+
+    pub async fn get_download_limits(&self) -> DownloadLimits {
+        let app_conf = self.app_config.lock().await;
+
+        // Our side rejects more downloads on a connection than this
+        let per_peer = app_conf
+            .max_downloads_per_peer
+            .min(app_conf.max_transfers_per_peer);
+
+        DownloadLimits {
+            total: app_conf.max_concurrent_downloads.max(1),
+            per_peer: per_peer.max(1),
+        }
+    }
+
+    pub async fn get_timing(&self) -> TimingConfig {
+        let app_conf = self.app_config.lock().await;
+
+        app_conf.timing.clone()
+    }
+
+    pub async fn get_shared_with_all(&self) -> Vec<Uuid> {
+        let app_conf = self.app_config.lock().await;
+
+        app_conf.shared_with_all.iter().copied().collect()
+    }
+
+    pub async fn set_shared_with_all(&self, dir_id: Uuid, shared_with_all: bool) {
+        let mut app_conf = self.app_config.lock().await;
+
+        let changed = if shared_with_all {
+            app_conf.shared_with_all.insert(dir_id)
+        } else {
+            app_conf.shared_with_all.remove(&dir_id)
+        };
+
+        if changed {
+            self.mark_changed();
+        }
+    }
+
+    pub async fn get_log_filter(&self) -> String {
+        let app_conf = self.app_config.lock().await;
+
+        app_conf.log_filter.clone()
+    }
+
+    pub async fn set_log_filter(&self, filter: String) {
+        let mut app_conf = self.app_config.lock().await;
+
+        app_conf.log_filter = filter;
+        self.mark_changed();
+    }
+
+    pub async fn get_exclude_patterns(&self) -> Vec<String> {
+        let app_conf = self.app_config.lock().await;
+
+        app_conf.exclude_patterns.clone()
+    }
+
+    pub async fn set_exclude_patterns(&self, patterns: Vec<String>) {
+        let mut app_conf = self.app_config.lock().await;
+
+        app_conf.exclude_patterns = patterns;
+        self.mark_changed();
+    }
+
+    pub async fn get_event_stream_port(&self) -> Option<u16> {
+        let app_conf = self.app_config.lock().await;
+
+        app_conf.event_stream_port
+    }
+
+    pub async fn get_network_interface(&self) -> String {
+        let app_conf = self.app_config.lock().await;
+
+        app_conf.network_interface.clone()
+    }
+
+    pub async fn set_network_interface(&self, selection: String) {
+        let mut app_conf = self.app_config.lock().await;
+
+        if app_conf.network_interface != selection {
+            app_conf.network_interface = selection;
+            self.listener_changed.notify_one();
+        }
+        self.mark_changed();
+    }
+
+    /// Waits until the listen port or network interface is changed.
+    pub async fn listener_changed(&self) {
+        self.listener_changed.notified().await;
+    }
+
+    pub async fn get_connect_timeout(&self) -> Duration {
+        let app_conf = self.app_config.lock().await;
+
+        app_conf.timing.connect_timeout()
+    }
+
+    pub async fn get_stream_media(&self) -> bool {
+        let app_conf = self.app_config.lock().await;
+
+        app_conf.stream_media
+    }
+
+    /// How old leftover download files must be before the startup cleanup
+    /// removes them, or `None` if the cleanup is turned off.
+    pub async fn get_cleanup_age(&self) -> Option<Duration> {
+        let app_conf = self.app_config.lock().await;
+
+        if app_conf.cleanup_downloads {
+            Some(app_conf.timing.cleanup_age())
+        } else {
+            None
+        }
+    }
+
+    /// Derives the key encrypted downloads are read and written with. The
+    /// first passphrase entered sets it, later ones have to match it.
+    pub async fn unlock_encryption(&self, passphrase: &str) -> Result<()> {
+        if passphrase.is_empty() {
+            bail!("Passphrase can't be empty");
+        }
+
+        let salt = self.app_config.lock().await.encryption_salt.clone();
+        let salt = salt.unwrap_or_else(generate_salt);
+
+        // Derivation is deliberately slow, so keep it off the async workers
+        let passphrase = passphrase.to_owned();
+        let derive_salt = salt.clone();
+        let key =
+            tokio::task::spawn_blocking(move || FileKey::derive(&passphrase, &derive_salt)).await?;
+
+        let mut app_conf = self.app_config.lock().await;
+        match &app_conf.encryption_verifier {
+            Some(verifier) if *verifier != key.verifier() => bail!("Wrong passphrase"),
+            Some(_) => {}
+            None => {
+                app_conf.encryption_salt = Some(salt);
+                app_conf.encryption_verifier = Some(key.verifier());
+                self.mark_changed();
+            }
+        }
+        drop(app_conf);
+
+        *self.file_key.lock().await = Some(key);
+
+        Ok(())
+    }
+
+    /// The key to read encrypted files with, whether or not new downloads
+    /// are still encrypted.
+    pub async fn get_file_key(&self) -> Option<FileKey> {
+        self.file_key.lock().await.clone()
+    }
+
+    /// The key new downloads are encrypted with, or `None` if they're written
+    /// as they are.
+    pub async fn get_download_key(&self) -> Option<FileKey> {
+        if !self.app_config.lock().await.encrypt_downloads {
+            return None;
+        }
+
+        self.get_file_key().await
+    }
+
+    /// Whether downloads have to wait for the passphrase to be entered.
+    pub async fn is_encryption_locked(&self) -> bool {
+        self.app_config.lock().await.encrypt_downloads && self.file_key.lock().await.is_none()
+    }
+
+    pub async fn get_idle_timeout(&self) -> Option<Duration> {
+        let app_conf = self.app_config.lock().await;
+
+        app_conf.timing.idle_timeout()
+    }
+
+    pub async fn get_max_files_per_directory(&self) -> usize {
+        let app_conf = self.app_config.lock().await;
+
+        app_conf.max_files_per_directory
+    }
+
+    /// Fails if `dir` would be one directory too many, or has more files
+    /// than a directory may have.
+    pub async fn check_share_limits(&self, dir: &ShareDirectory) -> Result<()> {
+        let (max_directories, max_files) = self.share_limits().await;
+        let directories = self.cached_data.lock().await;
+
+        check_share_limits(&directories, dir, max_directories, max_files)
+    }
+
+    async fn share_limits(&self) -> (usize, usize) {
+        let app_conf = self.app_config.lock().await;
+
+        (app_conf.max_directories, app_conf.max_files_per_directory)
+    }
+
+    pub async fn get_auto_accept_shares(&self) -> bool {
+        let app_conf = self.app_config.lock().await;
+
+        app_conf.auto_accept_shares
+    }
+
+    /// Splits directories received from `peer` into ones already known here
+    /// and new ones, which are held back as pending shares. Returns the known
+    /// directories and the new pending shares that had not been offered yet.
+    pub async fn hold_new_shares(
+        &self,
+        dirs: Vec<ShareDirectory>,
+        peer: &PeerId,
+    ) -> (Vec<ShareDirectory>, Vec<ShareDirectory>) {
+        let directories = self.cached_data.lock().await;
+        let mut pending = self.pending_shares.lock().await;
+
+        let mut known = vec![];
+        let mut offered = vec![];
+        for dir in dirs {
+            let id = dir.signature.identifier;
 
-    if let Ok(config) = config_bytes {
-        let file = open_settings.open(config_path).await;
+            if directories.contains_key(&id) {
+                known.push(dir);
+                continue;
+            }
 
-        if let Ok(mut file) = file {
-            if let Err(e) = tokio::io::AsyncWriteExt::write_all(&mut file, &config).await {
-                error!("could not write config to file: {}", e);
-            } else {
-                info!("Successfully wrote config to file");
+            if !pending.contains_key(&id) {
+                offered.push(dir.clone());
             }
+
+            pending.insert(
+                id,
+                PendingShare {
+                    directory: dir,
+                    peer: peer.clone(),
+                },
+            );
         }
+
+        (known, offered)
     }
 
-    if let Ok(cache) = cache_bytes {
-        let file = open_settings.open(cache_path).await;
+    pub async fn take_pending_share(&self, dir_id: Uuid) -> Option<PendingShare> {
+        let mut pending = self.pending_shares.lock().await;
 
-        if let Ok(mut file) = file {
-            if let Err(e) = tokio::io::AsyncWriteExt::write_all(&mut file, &cache).await {
-                error!("could not write cache to file: {}", e);
-            } else {
-                info!("Successfully wrote cache to file");
-            }
-        }
+        pending.remove(&dir_id)
     }
-}
 
-pub async fn save_config_loop(configs: Arc<StoredConfig>) {
-    let mut job_interval = tokio::time::interval(Duration::from_secs(SAVE_INTERVAL_SECS));
-
-    loop {
-        let _ = job_interval.tick().await;
+    pub async fn record_download(&self, download: Download) {
+        self.running_downloads
+            .lock()
+            .await
+            .insert(download.download_id, download);
 
-        write_stored_data_async(&configs).await;
+        self.write_download_log().await;
     }
-}
 
-fn ensure_path<P>(path: PathBuf, subpath: P) -> PathBuf
-where
-    P: AsRef<Path>,
-{
-    fs::create_dir_all(&path).expect("should be able to create directory for stored data");
+    pub async fn forget_download(&self, download_id: Uuid) {
+        let removed = self.running_downloads.lock().await.remove(&download_id);
 
-    let resulting_path = path.join(&subpath);
-    if !resulting_path.exists() {
-        File::create(&resulting_path).expect("should be able to create file for stored data");
+        if removed.is_some() {
+            self.write_download_log().await;
+        }
     }
 
-    resulting_path
-}
+    pub async fn get_interrupted_downloads(&self) -> Vec<Download> {
+        self.interrupted_downloads
+            .lock()
+            .await
+            .values()
+            .cloned()
+            .collect()
+    }
 
-#[derive(Serialize, Deserialize)]
-pub struct AppConfig {
-    pub peer_id: Option<PeerId>,
-    pub hide_on_close: bool,
-    pub download_directory: PathBuf,
-    pub theme: String,
-}
+    /// Forgets that a file is being downloaded into the partial file at
+    /// `path`, once that download failed or was discarded.
+    pub async fn clear_partial_location(&self, dir_id: Uuid, file_id: Uuid, path: &Path) {
+        self.mutate_file(dir_id, file_id, |file| {
+            if let ContentLocation::Partial { path: partial, .. } = &file.content_location {
+                if partial == path {
+                    file.content_location = ContentLocation::NetworkOnly;
+                    return true;
+                }
+            }
 
-impl Default for AppConfig {
-    fn default() -> Self {
-        Self {
-            peer_id: None,
-            hide_on_close: false,
-            download_directory: PathBuf::new(),
-            theme: "dark".to_string(),
-        }
+            false
+        })
+        .await;
     }
-}
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-pub struct Settings {
-    pub minimize_on_close: bool,
-    pub theme: String,
-    pub download_directory: String,
-}
+    /// Makes partial locations match the partial files kept for interrupted
+    /// downloads, keyed by directory and file. Any other partial location is
+    /// left over from a download that can't continue and is cleared. Caches
+    /// written before partial locations existed gain them this way too.
+    ///
+    /// Returns the paths of the cleared partial locations.
+    pub async fn restore_partial_locations(
+        &self,
+        mut partial_files: HashMap<(Uuid, Uuid), (PathBuf, u64)>,
+    ) -> Vec<PathBuf> {
+        let mut directories = self.cached_data.lock().await;
+        let mut cleared = vec![];
 
-pub struct StoredConfig {
-    app_config: Mutex<AppConfig>,
-    cached_data: Mutex<HashMap<Uuid, ShareDirectory>>,
-}
+        for dir in directories.values_mut() {
+            let dir_id = dir.signature.identifier;
+            let mut changed = false;
 
-impl StoredConfig {
-    pub fn new(app_config: AppConfig, cached_data: HashMap<Uuid, ShareDirectory>) -> Self {
-        Self {
-            app_config: Mutex::new(app_config),
-            cached_data: Mutex::new(cached_data),
-        }
-    }
+            for file in dir.shared_files.values_mut() {
+                if let ContentLocation::LocalPath(_) = file.content_location {
+                    continue;
+                }
 
-    pub async fn get_settings(&self) -> Settings {
-        let app_conf = self.app_config.lock().await;
+                match partial_files.remove(&(dir_id, file.identifier)) {
+                    Some((path, bytes)) => {
+                        file.content_location = ContentLocation::Partial { path, bytes };
+                        changed = true;
+                    }
+                    None => {
+                        if let ContentLocation::Partial { path, .. } = &file.content_location {
+                            cleared.push(path.clone());
+                            file.content_location = ContentLocation::NetworkOnly;
+                            changed = true;
+                        }
+                    }
+                }
+            }
 
-        Settings {
-            minimize_on_close: app_conf.hide_on_close,
-            theme: app_conf.theme.clone(),
-            download_directory: app_conf
-                .download_directory
-                .to_str()
-                .unwrap_or_default()
-                .to_string(),
+            if changed {
+                self.mark_directory_changed(dir_id);
+            }
         }
+
+        cleared
     }
 
-    pub async fn set_settings(&self, new_settings: Settings) -> Result<()> {
-        let mut app_conf = self.app_config.lock().await;
+    pub async fn take_interrupted_download(&self, download_id: Uuid) -> Option<Download> {
+        let removed = self.interrupted_downloads.lock().await.remove(&download_id);
 
-        app_conf.download_directory = PathBuf::from_str(&new_settings.download_directory)?;
-        app_conf.hide_on_close = new_settings.minimize_on_close;
-        app_conf.theme = new_settings.theme;
+        if removed.is_some() {
+            self.write_download_log().await;
+        }
 
-        Ok(())
+        removed
+    }
+
+    async fn write_download_log(&self) {
+        let path = match &self.download_log {
+            None => return,
+            Some(path) => path,
+        };
+
+        let mut downloads: Vec<Download> = self
+            .running_downloads
+            .lock()
+            .await
+            .values()
+            .cloned()
+            .collect();
+        downloads.extend(self.interrupted_downloads.lock().await.values().cloned());
+
+        match serde_json::to_vec_pretty(&downloads) {
+            Err(e) => error!("could not serialize downloads: {}", e),
+            Ok(bytes) => {
+                if let Err(e) = tokio::fs::write(path, bytes).await {
+                    error!("could not write downloads to file: {}", e);
+                }
+            }
+        }
     }
 
     pub async fn get_directories(&self) -> Vec<ShareDirectory> {
@@ -230,6 +1466,15 @@ impl StoredConfig {
         directories.get(&dir_id).cloned()
     }
 
+    pub async fn get_file(&self, dir_id: Uuid, file_id: Uuid) -> Option<SharedFile> {
+        let directories = self.cached_data.lock().await;
+
+        directories
+            .get(&dir_id)
+            .and_then(|dir| dir.shared_files.get(&file_id))
+            .cloned()
+    }
+
     pub async fn get_filepath(&self, dir_id: Uuid, file_id: Uuid) -> Option<PathBuf> {
         let directories = self.cached_data.lock().await;
 
@@ -241,7 +1486,7 @@ impl StoredConfig {
                 match file {
                     None => None,
                     Some(file) => match &file.content_location {
-                        ContentLocation::NetworkOnly => None,
+                        ContentLocation::NetworkOnly | ContentLocation::Partial { .. } => None,
                         ContentLocation::LocalPath(path) => Some(path.clone()),
                     },
                 }
@@ -249,41 +1494,138 @@ impl StoredConfig {
         }
     }
 
-    pub async fn mutate_dir<F>(&self, dir_id: Uuid, f: F)
+    /// Whether `path` is a locally stored shared file or inside the download
+    /// directory. Only such paths may be opened from the window.
+    pub async fn is_known_path(&self, path: &Path) -> bool {
+        let canonical = match path.canonicalize() {
+            Ok(canonical) => canonical,
+            Err(_) => return false,
+        };
+
+        let download_directory = self.get_download_directory().await;
+        if let Ok(download_directory) = download_directory.canonicalize() {
+            if canonical.starts_with(download_directory) {
+                return true;
+            }
+        }
+
+        // Only files with the same name can be the same file, so just those
+        // are resolved, after letting go of the cache
+        let candidates: Vec<PathBuf> = {
+            let directories = self.cached_data.lock().await;
+            let mut candidates = vec![];
+
+            for file in directories
+                .values()
+                .flat_map(|dir| dir.shared_files.values())
+            {
+                if let ContentLocation::LocalPath(local_path) = &file.content_location {
+                    if local_path == path {
+                        return true;
+                    }
+
+                    if local_path.file_name() == canonical.file_name() {
+                        candidates.push(local_path.clone());
+                    }
+                }
+            }
+
+            candidates
+        };
+
+        let known: HashSet<PathBuf> = candidates
+            .iter()
+            .filter_map(|local_path| local_path.canonicalize().ok())
+            .collect();
+
+        known.contains(&canonical)
+    }
+
+    /// Changes a directory with `f`, which returns whether it changed
+    /// anything, so only changes are saved. Returns the same.
+    pub async fn mutate_dir<F>(&self, dir_id: Uuid, f: F) -> bool
     where
-        F: FnOnce(&mut ShareDirectory),
+        F: FnOnce(&mut ShareDirectory) -> bool,
     {
         let mut directories = self.cached_data.lock().await;
-        let dir = directories.get_mut(&dir_id);
+        let changed = match directories.get_mut(&dir_id) {
+            Some(dir) => f(dir),
+            None => false,
+        };
 
-        if let Some(dir) = dir {
-            f(dir);
+        if changed {
+            self.mark_directory_changed(dir_id);
         }
+
+        changed
     }
 
-    pub async fn mutate_file<F>(&self, dir_id: Uuid, file_id: Uuid, f: F)
+    /// Like `mutate_dir`, for a single file.
+    pub async fn mutate_file<F>(&self, dir_id: Uuid, file_id: Uuid, f: F) -> bool
     where
-        F: FnOnce(&mut SharedFile),
+        F: FnOnce(&mut SharedFile) -> bool,
     {
         let mut directories = self.cached_data.lock().await;
+        let changed = match directories
+            .get_mut(&dir_id)
+            .and_then(|dir| dir.shared_files.get_mut(&file_id))
+        {
+            Some(file) => f(file),
+            None => false,
+        };
+
+        if changed {
+            self.mark_directory_changed(dir_id);
+        }
 
-        if let Some(dir) = directories.get_mut(&dir_id) {
-            if let Some(file) = dir.shared_files.get_mut(&file_id) {
-                f(file);
-            }
+        changed
+    }
+
+    /// Updates the hostname of `peer` in every directory and returns the
+    /// directories that changed.
+    pub async fn rename_peer(&self, peer: &PeerId) -> Vec<ShareDirectory> {
+        let renamed: Vec<ShareDirectory> = self
+            .cached_data
+            .lock()
+            .await
+            .values_mut()
+            .filter_map(|dir| {
+                if dir.rename_peer(peer) {
+                    Some(dir.clone())
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        for dir in renamed.iter() {
+            self.mark_directory_changed(dir.signature.identifier);
         }
+
+        renamed
     }
 
-    pub async fn add_directory(&self, dir: ShareDirectory) {
+    pub async fn add_directory(&self, mut dir: ShareDirectory) -> Result<()> {
+        let (max_directories, max_files) = self.share_limits().await;
         let mut directories = self.cached_data.lock().await;
 
+        check_share_limits(&directories, &dir, max_directories, max_files)?;
+        dir.refresh_fingerprint();
+        self.mark_directory_changed(dir.signature.identifier);
         directories.insert(dir.signature.identifier, dir);
+
+        Ok(())
     }
 
     pub async fn remove_directory(&self, dir_id: Uuid) -> Option<ShareDirectory> {
-        let mut directories = self.cached_data.lock().await;
+        let removed = self.cached_data.lock().await.remove(&dir_id);
+        self.app_config.lock().await.shared_with_all.remove(&dir_id);
+
+        if removed.is_some() {
+            self.mark_directory_changed(dir_id);
+        }
 
-        directories.remove(&dir_id)
+        removed
     }
 
     pub async fn generate_filepath(
@@ -332,10 +1674,14 @@ impl StoredConfig {
         }
     }
 
-    pub async fn shared_directory(&self, dir: ShareDirectory) -> Result<()> {
+    pub async fn shared_directory(&self, mut dir: ShareDirectory) -> Result<()> {
+        let (max_directories, max_files) = self.share_limits().await;
         let mut directories = self.cached_data.lock().await;
 
+        check_share_limits(&directories, &dir, max_directories, max_files)?;
         if let Entry::Vacant(e) = directories.entry(dir.signature.identifier) {
+            dir.refresh_fingerprint();
+            self.mark_directory_changed(dir.signature.identifier);
             e.insert(dir);
 
             return Ok(());
@@ -349,9 +1695,15 @@ impl StoredConfig {
         dirs: Vec<ShareDirectory>,
         host: &PeerId,
     ) -> Vec<ShareDirectory> {
+        let (max_directories, max_files) = self.share_limits().await;
         let mut owned_dirs = self.cached_data.lock().await;
 
         for dir in dirs {
+            if let Err(e) = check_share_limits(&owned_dirs, &dir, max_directories, max_files) {
+                warn!("Rejected directory {}: {}", dir.signature.identifier, e);
+                continue;
+            }
+
             let od = owned_dirs.get_mut(&dir.signature.identifier);
 
             match od {
@@ -377,7 +1729,11 @@ impl StoredConfig {
                             match matched_dir.shared_files.get_mut(file_id) {
                                 None => files_to_add.push(file.clone()),
                                 Some(matched_file) => {
-                                    matched_file.owned_peers = file.owned_peers.clone();
+                                    matched_file
+                                        .owned_peers
+                                        .extend(file.owned_peers.iter().cloned());
+                                    matched_file.dedup_owners();
+                                    matched_file.keep_local_owner(host);
                                 }
                             }
                         }
@@ -386,12 +1742,21 @@ impl StoredConfig {
                             .shared_files
                             .retain(|file_id, _| !files_to_delete.contains(file_id));
 
-                        for file in files_to_add {
+                        for mut file in files_to_add {
+                            file.dedup_owners();
                             matched_dir.shared_files.insert(file.identifier, file);
                         }
+
+                        matched_dir.rename_peer(host);
+                        matched_dir.signature.last_modified = dir.signature.last_modified;
+                        matched_dir.refresh_fingerprint();
+                        self.mark_directory_changed(matched_dir.signature.identifier);
                     }
                 }
                 None => {
+                    let mut dir = dir;
+                    dir.refresh_fingerprint();
+                    self.mark_directory_changed(dir.signature.identifier);
                     owned_dirs.insert(dir.signature.identifier, dir);
                 }
             }
@@ -400,3 +1765,851 @@ impl StoredConfig {
         owned_dirs.values().cloned().collect()
     }
 }
+
+fn check_share_limits(
+    directories: &HashMap<Uuid, ShareDirectory>,
+    dir: &ShareDirectory,
+    max_directories: usize,
+    max_files: usize,
+) -> Result<()> {
+    let is_new = !directories.contains_key(&dir.signature.identifier);
+    if is_new && directories.len() >= max_directories {
+        bail!("Can't share more than {} directories", max_directories);
+    }
+
+    if dir.shared_files.len() > max_files {
+        bail!(
+            "Directory {} has {} files, more than the limit of {}",
+            dir.signature.name,
+            dir.shared_files.len(),
+            max_files
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod timing_tests {
+        use std::{collections::HashMap, time::Duration};
+
+        use crate::config::{
+            AppConfig, StoredConfig, TimingConfig, ANTI_ENTROPY_SECS, CONNECT_TIMEOUT_MILLIS,
+            IDLE_TIMEOUT_SECS, MDNS_UPDATE_SECS, SAVE_INTERVAL_SECS,
+        };
+
+        #[test]
+        fn app_config_without_timing_uses_defaults() {
+            let config: AppConfig = serde_json::from_str(r#"{"theme": "dark"}"#).unwrap();
+            let partial: AppConfig =
+                serde_json::from_str(r#"{"timing": {"save_delay_secs": 10}}"#).unwrap();
+
+            assert_eq!(config.timing, TimingConfig::default());
+            assert_eq!(partial.timing.save_delay_secs, 10);
+            assert_eq!(
+                partial.timing.save_interval_secs,
+                TimingConfig::default().save_interval_secs
+            );
+        }
+
+        #[tokio::test]
+        async fn stored_config_given_out_of_range_timing_clamps_it() {
+            let app_config = AppConfig {
+                timing: TimingConfig {
+                    save_interval_secs: 0,
+                    save_delay_secs: 2,
+                    mdns_update_secs: 0,
+                    reconnect_after_secs: u64::MAX,
+                    anti_entropy_secs: 1,
+                    connect_timeout_millis: 0,
+                    idle_timeout_secs: Some(u64::MAX),
+                    cleanup_age_days: 3,
+                },
+                ..Default::default()
+            };
+            let config = StoredConfig::new(app_config, HashMap::new());
+            let timing = config.get_timing().await;
+
+            assert_eq!(timing.save_interval_secs, *SAVE_INTERVAL_SECS.start());
+            assert_eq!(timing.save_delay_secs, 2);
+            assert_eq!(
+                timing.mdns_update_interval(),
+                Duration::from_secs(*MDNS_UPDATE_SECS.start())
+            );
+            assert_eq!(timing.reconnect_after(), Duration::from_secs(3600));
+            assert_eq!(timing.anti_entropy_secs, *ANTI_ENTROPY_SECS.start());
+            assert_eq!(
+                timing.connect_timeout_millis,
+                *CONNECT_TIMEOUT_MILLIS.start()
+            );
+            assert_eq!(timing.idle_timeout_secs, Some(*IDLE_TIMEOUT_SECS.end()));
+            assert_eq!(timing.cleanup_age_days, 3);
+        }
+
+        #[tokio::test]
+        async fn stored_config_moves_old_mdns_interval_into_timing() {
+            let app_config: AppConfig =
+                serde_json::from_str(r#"{"mdns_update_secs": 40}"#).unwrap();
+            let config = StoredConfig::new(app_config, HashMap::new());
+
+            assert_eq!(config.get_timing().await.mdns_update_secs, 40);
+            let saved = serde_json::to_value(&*config.app_config.lock().await).unwrap();
+            assert!(saved.get("mdns_update_secs").is_none());
+            assert_eq!(saved["timing"]["mdns_update_secs"], 40);
+        }
+
+        #[tokio::test]
+        async fn stored_config_moves_old_timeouts_into_timing() {
+            let app_config: AppConfig = serde_json::from_str(
+                r#"{"connect_timeout_millis": 500, "idle_timeout_secs": 90, "cleanup_age_days": 0}"#,
+            )
+            .unwrap();
+            let config = StoredConfig::new(app_config, HashMap::new());
+
+            let timing = config.get_timing().await;
+            assert_eq!(timing.connect_timeout_millis, 500);
+            assert_eq!(timing.idle_timeout_secs, Some(90));
+            assert_eq!(timing.cleanup_age_days, 0);
+            let saved = serde_json::to_value(&*config.app_config.lock().await).unwrap();
+            for name in [
+                "connect_timeout_millis",
+                "idle_timeout_secs",
+                "cleanup_age_days",
+            ] {
+                assert!(saved.get(name).is_none());
+                assert!(saved["timing"].get(name).is_some());
+            }
+        }
+    }
+
+    mod theme_tests {
+        use crate::config::{AppConfig, Settings, Theme};
+
+        #[test]
+        fn app_config_given_unknown_theme_uses_default() {
+            let config: AppConfig = serde_json::from_str(r#"{"theme": "solarized"}"#).unwrap();
+
+            assert_eq!(config.theme, Theme::Dark);
+        }
+
+        #[test]
+        fn app_config_given_known_theme_keeps_it() {
+            let config: AppConfig = serde_json::from_str(r#"{"theme": "light"}"#).unwrap();
+
+            assert_eq!(config.theme, Theme::Light);
+        }
+
+        #[test]
+        fn settings_given_unknown_theme_is_rejected() {
+            let settings = serde_json::from_str::<Settings>(
+                r#"{"minimizeOnClose": false, "theme": "solarized", "downloadDirectory": ""}"#,
+            );
+
+            assert!(settings.is_err());
+        }
+    }
+
+    mod settings_tests {
+        use std::{collections::HashMap, time::Duration};
+
+        use tokio::time::timeout;
+
+        use uuid::Uuid;
+
+        use crate::config::{
+            check_download_directory, AppConfig, DownloadNotifications, Settings, StoredConfig,
+            Theme,
+        };
+
+        #[tokio::test]
+        async fn set_settings_then_get_settings_round_trips_all_fields() {
+            let download_directory = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            std::fs::create_dir_all(&download_directory).unwrap();
+
+            let config = StoredConfig::new(AppConfig::default(), HashMap::new());
+            let settings = Settings {
+                minimize_on_close: true,
+                theme: Theme::Light,
+                download_directory: download_directory.to_str().unwrap().to_string(),
+                listen_port: Some(50_000),
+                download_notifications: DownloadNotifications::LastDownload,
+                auto_accept_shares: false,
+                stream_media: true,
+                cleanup_downloads: true,
+                cleanup_age_days: 30,
+                encrypt_downloads: true,
+            };
+
+            config.set_settings(settings.clone()).await.unwrap();
+
+            assert_eq!(config.get_settings().await, settings);
+
+            std::fs::remove_dir_all(download_directory).unwrap();
+        }
+
+        #[test]
+        fn settings_without_download_notifications_leave_them_disabled() {
+            let settings: Settings = serde_json::from_value(serde_json::json!({
+                "minimizeOnClose": false,
+                "theme": "dark",
+                "downloadDirectory": "",
+            }))
+            .unwrap();
+
+            assert_eq!(
+                settings.download_notifications,
+                DownloadNotifications::Disabled
+            );
+        }
+
+        #[tokio::test]
+        async fn set_settings_rejects_download_directory_that_is_not_its_own() {
+            let config = StoredConfig::new(AppConfig::default(), HashMap::new());
+            let mut settings = config.get_settings().await;
+            let before = settings.clone();
+
+            let missing = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            settings.download_directory = missing.to_str().unwrap().to_string();
+            assert!(config.set_settings(settings).await.is_err());
+
+            assert_eq!(config.get_settings().await, before);
+        }
+
+        #[test]
+        fn check_download_directory_rejects_roots_and_home() {
+            let home = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            let downloads = home.join("Downloads");
+            std::fs::create_dir_all(&downloads).unwrap();
+
+            assert!(check_download_directory(&downloads, Some(&home)).is_ok());
+            assert!(check_download_directory(&home, Some(&home)).is_err());
+            assert!(check_download_directory(&std::env::temp_dir(), Some(&home)).is_err());
+            let root = home.ancestors().last().unwrap();
+            assert!(check_download_directory(root, None).is_err());
+
+            std::fs::remove_dir_all(home).unwrap();
+        }
+
+        #[tokio::test]
+        async fn set_settings_given_new_listen_port_wakes_listener() {
+            let config = StoredConfig::new(AppConfig::default(), HashMap::new());
+            let mut settings = config.get_settings().await;
+            let wait = Duration::from_millis(100);
+
+            config.set_settings(settings.clone()).await.unwrap();
+            assert!(timeout(wait, config.listener_changed()).await.is_err());
+
+            settings.listen_port = Some(50_000);
+            config.set_settings(settings).await.unwrap();
+            assert!(timeout(wait, config.listener_changed()).await.is_ok());
+        }
+    }
+
+    mod known_path_tests {
+        use std::{collections::HashMap, fs};
+
+        use chrono::Utc;
+        use uuid::Uuid;
+
+        use crate::{
+            config::{AppConfig, StoredConfig},
+            data::{ContentLocation, PeerId, ShareDirectory, ShareDirectorySignature, SharedFile},
+        };
+
+        #[tokio::test]
+        async fn is_known_path_allows_only_shared_files_and_downloads() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            let downloads = dir.join("downloads");
+            fs::create_dir_all(&downloads).unwrap();
+
+            let downloaded = downloads.join("downloaded.txt");
+            let shared = dir.join("shared.txt");
+            let unrelated = dir.join("unrelated.txt");
+            for path in [&downloaded, &shared, &unrelated] {
+                fs::write(path, b"content").unwrap();
+            }
+
+            let owner = PeerId::generate();
+            let file = SharedFile {
+                name: "shared.txt".to_owned(),
+                identifier: Uuid::new_v4(),
+                content_hash: 0,
+                last_modified: Utc::now(),
+                content_location: ContentLocation::LocalPath(shared.clone()),
+                owned_peers: vec![owner.clone()],
+                size: 7,
+                uploads: Default::default(),
+            };
+            let directory = ShareDirectory {
+                signature: ShareDirectorySignature {
+                    name: "directory".to_owned(),
+                    identifier: Uuid::new_v4(),
+                    last_modified: Utc::now(),
+                    shared_peers: vec![owner],
+                    fingerprint: None,
+                },
+                shared_files: HashMap::from([(file.identifier, file)]),
+            };
+
+            let app_config = AppConfig {
+                download_directory: downloads.clone(),
+                ..Default::default()
+            };
+            let config = StoredConfig::new(
+                app_config,
+                HashMap::from([(directory.signature.identifier, directory)]),
+            );
+
+            assert!(config.is_known_path(&downloaded).await);
+            assert!(config.is_known_path(&shared).await);
+            assert!(!config.is_known_path(&unrelated).await);
+            assert!(
+                !config
+                    .is_known_path(&downloads.join("..").join("unrelated.txt"))
+                    .await
+            );
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    mod partial_location_tests {
+        use std::{collections::HashMap, path::PathBuf};
+
+        use chrono::Utc;
+        use uuid::Uuid;
+
+        use crate::{
+            config::{AppConfig, StoredConfig},
+            data::{ContentLocation, PeerId, ShareDirectory, ShareDirectorySignature, SharedFile},
+        };
+
+        fn file(content_location: ContentLocation) -> SharedFile {
+            SharedFile {
+                name: "file.txt".to_owned(),
+                identifier: Uuid::new_v4(),
+                content_hash: 0,
+                last_modified: Utc::now(),
+                content_location,
+                owned_peers: vec![],
+                size: 100,
+                uploads: Default::default(),
+            }
+        }
+
+        #[tokio::test]
+        async fn restore_partial_locations_matches_kept_partial_files() {
+            let local = file(ContentLocation::LocalPath(PathBuf::from("local.txt")));
+            let resumed = file(ContentLocation::NetworkOnly);
+            let stale = file(ContentLocation::Partial {
+                path: PathBuf::from("stale.txt.part"),
+                bytes: 10,
+            });
+            let ids = [local.identifier, resumed.identifier, stale.identifier];
+
+            let directory = ShareDirectory {
+                signature: ShareDirectorySignature {
+                    name: "directory".to_owned(),
+                    identifier: Uuid::new_v4(),
+                    last_modified: Utc::now(),
+                    shared_peers: vec![PeerId::generate()],
+                    fingerprint: None,
+                },
+                shared_files: HashMap::from([
+                    (local.identifier, local),
+                    (resumed.identifier, resumed),
+                    (stale.identifier, stale),
+                ]),
+            };
+            let dir_id = directory.signature.identifier;
+            let config =
+                StoredConfig::new(AppConfig::default(), HashMap::from([(dir_id, directory)]));
+
+            let resumed_path = PathBuf::from("resumed.txt.part");
+            let cleared = config
+                .restore_partial_locations(HashMap::from([
+                    ((dir_id, ids[0]), (PathBuf::from("local.txt.part"), 5)),
+                    ((dir_id, ids[1]), (resumed_path.clone(), 40)),
+                ]))
+                .await;
+            assert_eq!(cleared, vec![PathBuf::from("stale.txt.part")]);
+
+            let mut locations = vec![];
+            for id in ids {
+                locations.push(config.get_file(dir_id, id).await.unwrap().content_location);
+            }
+            assert!(
+                matches!(&locations[0], ContentLocation::LocalPath(path) if path == &PathBuf::from("local.txt"))
+            );
+            assert!(matches!(
+                &locations[1],
+                ContentLocation::Partial { path, bytes: 40 } if path == &resumed_path
+            ));
+            assert!(matches!(locations[2], ContentLocation::NetworkOnly));
+        }
+    }
+
+    mod share_limit_tests {
+        use std::collections::HashMap;
+
+        use chrono::Utc;
+        use uuid::Uuid;
+
+        use crate::{
+            config::{AppConfig, StoredConfig},
+            data::{ContentLocation, PeerId, ShareDirectory, ShareDirectorySignature, SharedFile},
+        };
+
+        fn directory(file_count: usize) -> ShareDirectory {
+            let owner = PeerId::generate();
+            let shared_files = (0..file_count)
+                .map(|_| SharedFile {
+                    name: "file".to_owned(),
+                    identifier: Uuid::new_v4(),
+                    content_hash: 0,
+                    last_modified: Utc::now(),
+                    content_location: ContentLocation::NetworkOnly,
+                    owned_peers: vec![owner.clone()],
+                    size: 0,
+                    uploads: Default::default(),
+                })
+                .map(|file| (file.identifier, file))
+                .collect();
+
+            ShareDirectory {
+                signature: ShareDirectorySignature {
+                    name: "directory".to_owned(),
+                    identifier: Uuid::new_v4(),
+                    last_modified: Utc::now(),
+                    shared_peers: vec![owner],
+                    fingerprint: None,
+                },
+                shared_files,
+            }
+        }
+
+        #[tokio::test]
+        async fn add_directory_given_too_many_directories_fails() {
+            let app_config = AppConfig {
+                max_directories: 1,
+                ..Default::default()
+            };
+            let config = StoredConfig::new(app_config, HashMap::new());
+
+            config.add_directory(directory(0)).await.unwrap();
+
+            assert!(config.add_directory(directory(0)).await.is_err());
+            assert_eq!(config.get_directories().await.len(), 1);
+        }
+
+        #[tokio::test]
+        async fn synchronize_given_directory_with_too_many_files_rejects_it() {
+            let app_config = AppConfig {
+                max_files_per_directory: 2,
+                ..Default::default()
+            };
+            let config = StoredConfig::new(app_config, HashMap::new());
+            let peer = PeerId::generate();
+
+            let directories = config
+                .synchronize(vec![directory(2), directory(3)], &peer)
+                .await;
+
+            assert_eq!(directories.len(), 1);
+            assert_eq!(directories[0].shared_files.len(), 2);
+        }
+    }
+
+    mod synchronize_tests {
+        use std::{collections::HashMap, sync::Arc, sync::Mutex};
+
+        use chrono::{DateTime, Duration, TimeZone, Utc};
+        use uuid::Uuid;
+
+        use crate::{
+            config::{AppConfig, Clock, StoredConfig},
+            data::{ContentLocation, PeerId, ShareDirectory, ShareDirectorySignature, SharedFile},
+        };
+
+        struct ManualClock {
+            time: Mutex<DateTime<Utc>>,
+        }
+
+        impl ManualClock {
+            fn advance(&self, duration: Duration) {
+                let mut time = self.time.lock().unwrap();
+                *time += duration;
+            }
+        }
+
+        impl Clock for ManualClock {
+            fn now(&self) -> DateTime<Utc> {
+                *self.time.lock().unwrap()
+            }
+        }
+
+        fn peer(uuid: u128) -> PeerId {
+            PeerId {
+                hostname: "test".to_string(),
+                uuid: Uuid::from_u128(uuid),
+            }
+        }
+
+        fn file(owner: &PeerId, date_modified: DateTime<Utc>) -> SharedFile {
+            SharedFile {
+                name: "file".to_string(),
+                identifier: Uuid::new_v4(),
+                content_hash: 0,
+                last_modified: date_modified,
+                content_location: ContentLocation::NetworkOnly,
+                owned_peers: vec![owner.clone()],
+                size: 0,
+                uploads: Default::default(),
+            }
+        }
+
+        fn setup() -> (Arc<ManualClock>, StoredConfig, ShareDirectory) {
+            let clock = Arc::new(ManualClock {
+                time: Mutex::new(Utc.with_ymd_and_hms(2023, 1, 1, 12, 0, 0).unwrap()),
+            });
+            let config =
+                StoredConfig::with_clock(AppConfig::default(), HashMap::new(), clock.clone());
+
+            let directory = ShareDirectory {
+                signature: ShareDirectorySignature {
+                    name: "test".to_string(),
+                    identifier: Uuid::new_v4(),
+                    last_modified: config.now(),
+                    shared_peers: vec![peer(1), peer(2)],
+                    fingerprint: None,
+                },
+                shared_files: HashMap::new(),
+            };
+
+            (clock, config, directory)
+        }
+
+        #[tokio::test]
+        async fn synchronize_given_newer_remote_takes_remote_files() {
+            let (clock, config, directory) = setup();
+            let dir_id = directory.signature.identifier;
+            config.add_directory(directory.clone()).await.unwrap();
+
+            clock.advance(Duration::seconds(1));
+            let mut remote = directory;
+            let remote_file = file(&peer(2), config.now());
+            remote
+                .add_files(vec![remote_file.clone()], config.now())
+                .unwrap();
+
+            config.synchronize(vec![remote], &peer(2)).await;
+
+            let merged = config.get_directory(dir_id).await.unwrap();
+            assert!(merged.shared_files.contains_key(&remote_file.identifier));
+            assert_eq!(merged.signature.last_modified, config.now());
+        }
+
+        #[tokio::test]
+        async fn synchronize_given_equal_timestamps_keeps_local() {
+            let (_, config, directory) = setup();
+            let dir_id = directory.signature.identifier;
+            config.add_directory(directory.clone()).await.unwrap();
+
+            let mut remote = directory;
+            let remote_file = file(&peer(2), config.now());
+            remote
+                .add_files(vec![remote_file.clone()], config.now())
+                .unwrap();
+
+            config.synchronize(vec![remote], &peer(2)).await;
+
+            let merged = config.get_directory(dir_id).await.unwrap();
+            assert!(merged.shared_files.is_empty());
+        }
+
+        #[tokio::test]
+        async fn synchronize_given_older_remote_keeps_local() {
+            let (clock, config, directory) = setup();
+            let dir_id = directory.signature.identifier;
+
+            let mut remote = directory.clone();
+            let remote_file = file(&peer(2), config.now());
+            remote
+                .add_files(vec![remote_file.clone()], config.now())
+                .unwrap();
+
+            clock.advance(Duration::seconds(1));
+            let mut local = directory;
+            let local_file = file(&peer(1), config.now());
+            local
+                .add_files(vec![local_file.clone()], config.now())
+                .unwrap();
+            config.add_directory(local).await.unwrap();
+
+            config.synchronize(vec![remote], &peer(2)).await;
+
+            let merged = config.get_directory(dir_id).await.unwrap();
+            assert!(merged.shared_files.contains_key(&local_file.identifier));
+            assert!(!merged.shared_files.contains_key(&remote_file.identifier));
+        }
+
+        #[tokio::test]
+        async fn synchronize_keeps_files_added_with_remote_timestamp() {
+            let (clock, config, directory) = setup();
+            let dir_id = directory.signature.identifier;
+            config.add_directory(directory.clone()).await.unwrap();
+
+            clock.advance(Duration::seconds(1));
+            let mut stale = directory.clone();
+            stale.signature.last_modified = config.now();
+
+            clock.advance(Duration::seconds(1));
+            let mut added_signature = directory.signature;
+            added_signature.last_modified = config.now();
+            let added_file = file(&peer(2), config.now());
+            config
+                .mutate_dir(dir_id, |dir| {
+                    dir.add_files(vec![added_file.clone()], added_signature.last_modified)
+                        .unwrap();
+
+                    true
+                })
+                .await;
+
+            config.synchronize(vec![stale], &peer(1)).await;
+
+            let merged = config.get_directory(dir_id).await.unwrap();
+            assert!(merged.shared_files.contains_key(&added_file.identifier));
+        }
+
+        #[tokio::test]
+        async fn synchronize_given_remote_omitting_local_owner_keeps_it() {
+            let (clock, config, directory) = setup();
+            let dir_id = directory.signature.identifier;
+            let myself = peer(1);
+
+            let mut remote = directory.clone();
+            let remote_file = file(&peer(2), config.now());
+            remote
+                .add_files(vec![remote_file.clone()], config.now())
+                .unwrap();
+
+            let mut local = remote.clone();
+            local.add_owner(
+                &myself,
+                config.now(),
+                vec![remote_file.identifier],
+                Some("downloaded.bin".into()),
+            );
+            config.add_directory(local).await.unwrap();
+
+            clock.advance(Duration::seconds(1));
+            remote
+                .shared_files
+                .get_mut(&remote_file.identifier)
+                .unwrap()
+                .owned_peers = vec![peer(2), peer(2)];
+            remote.signature.last_modified = config.now();
+
+            config.synchronize(vec![remote], &myself).await;
+
+            let merged = config
+                .get_file(dir_id, remote_file.identifier)
+                .await
+                .unwrap();
+            assert!(merged.owned_peers.contains(&myself));
+            assert_eq!(
+                merged.owned_peers.iter().filter(|p| **p == peer(2)).count(),
+                1
+            );
+            assert!(matches!(
+                merged.content_location,
+                ContentLocation::LocalPath(_)
+            ));
+        }
+
+        #[tokio::test]
+        async fn synchronize_given_newer_remote_merges_owners() {
+            let (clock, config, directory) = setup();
+            let dir_id = directory.signature.identifier;
+            let myself = peer(1);
+
+            let mut local = directory.clone();
+            let mut shared_file = file(&myself, config.now());
+            shared_file.content_location = ContentLocation::LocalPath("shared.bin".into());
+            shared_file.owned_peers.push(peer(3));
+            local
+                .add_files(vec![shared_file.clone()], config.now())
+                .unwrap();
+            config.add_directory(local).await.unwrap();
+
+            clock.advance(Duration::seconds(1));
+            let mut remote = directory;
+            let mut remote_file = shared_file.clone();
+            remote_file.content_location = ContentLocation::NetworkOnly;
+            remote_file.owned_peers = vec![peer(2)];
+            remote.add_files(vec![remote_file], config.now()).unwrap();
+
+            config.synchronize(vec![remote], &myself).await;
+
+            let merged = config
+                .get_file(dir_id, shared_file.identifier)
+                .await
+                .unwrap();
+            assert_eq!(merged.owned_peers, vec![myself, peer(3), peer(2)]);
+            assert!(matches!(
+                merged.content_location,
+                ContentLocation::LocalPath(_)
+            ));
+        }
+    }
+
+    mod encryption_tests {
+        use std::collections::HashMap;
+
+        use crate::config::{AppConfig, StoredConfig};
+
+        #[tokio::test]
+        async fn unlock_encryption_rejects_a_different_passphrase() {
+            let config = StoredConfig::new(
+                AppConfig {
+                    encrypt_downloads: true,
+                    ..AppConfig::default()
+                },
+                HashMap::new(),
+            );
+            assert!(config.is_encryption_locked().await);
+
+            config.unlock_encryption("correct horse").await.unwrap();
+            assert!(!config.is_encryption_locked().await);
+            let key = config.get_download_key().await.unwrap();
+
+            assert!(config.unlock_encryption("battery staple").await.is_err());
+            config.unlock_encryption("correct horse").await.unwrap();
+            assert_eq!(
+                config.get_download_key().await.unwrap().verifier(),
+                key.verifier()
+            );
+        }
+    }
+
+    mod snapshot_tests {
+        use std::{
+            collections::{HashMap, HashSet},
+            fs,
+        };
+
+        use chrono::Utc;
+        use uuid::Uuid;
+
+        use crate::{
+            config::{
+                append_journal, journal_lines, load_cache, replay_journal, write_snapshot,
+                BACKUP_EXTENSION, CHECKSUM_EXTENSION,
+            },
+            data::{PeerId, ShareDirectory, ShareDirectorySignature},
+        };
+
+        #[test]
+        fn load_cache_falls_back_to_backup_when_checksum_does_not_match() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("cached_files.json");
+
+            let directory = ShareDirectory {
+                signature: ShareDirectorySignature {
+                    name: "directory".to_owned(),
+                    identifier: Uuid::new_v4(),
+                    last_modified: Utc::now(),
+                    shared_peers: vec![PeerId::generate()],
+                    fingerprint: None,
+                },
+                shared_files: HashMap::new(),
+            };
+            let identifier = directory.signature.identifier;
+            let cache = HashMap::from([(identifier, directory)]);
+            let cache_bytes = serde_json::to_vec(&cache).unwrap();
+
+            write_snapshot(&path, &cache_bytes).unwrap();
+            assert_eq!(load_cache(&path).len(), 1);
+
+            // Cache written by an older version, without a checksum
+            fs::remove_file(dir.join(format!("cached_files.json.{}", CHECKSUM_EXTENSION))).unwrap();
+            assert_eq!(load_cache(&path).len(), 1);
+
+            write_snapshot(&path, &cache_bytes).unwrap();
+            write_snapshot(&path, b"{}").unwrap();
+            assert!(load_cache(&path).is_empty());
+            assert!(dir
+                .join(format!("cached_files.json.{}", BACKUP_EXTENSION))
+                .exists());
+
+            // Truncated while writing, so the previous snapshot is loaded
+            fs::write(&path, b"{").unwrap();
+            assert!(load_cache(&path).contains_key(&identifier));
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        fn directory(name: &str) -> ShareDirectory {
+            ShareDirectory {
+                signature: ShareDirectorySignature {
+                    name: name.to_owned(),
+                    identifier: Uuid::new_v4(),
+                    last_modified: Utc::now(),
+                    shared_peers: vec![PeerId::generate()],
+                    fingerprint: None,
+                },
+                shared_files: HashMap::new(),
+            }
+        }
+
+        #[tokio::test]
+        async fn replay_journal_applies_changes_made_after_the_snapshot() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("cached_files.json");
+
+            let kept = directory("kept");
+            let removed = directory("removed");
+            let (kept_id, removed_id) = (kept.signature.identifier, removed.signature.identifier);
+            let mut cache = HashMap::from([(kept_id, kept), (removed_id, removed)]);
+            write_snapshot(&path, &serde_json::to_vec(&cache).unwrap()).unwrap();
+
+            let added = directory("added");
+            let added_id = added.signature.identifier;
+            cache.insert(added_id, added);
+            cache.remove(&removed_id);
+            cache.get_mut(&kept_id).unwrap().signature.name = "renamed".to_owned();
+
+            let changed = HashSet::from([added_id, removed_id]);
+            append_journal(&path, &journal_lines(&cache, &changed))
+                .await
+                .unwrap();
+            append_journal(&path, &journal_lines(&cache, &HashSet::from([kept_id])))
+                .await
+                .unwrap();
+
+            let mut loaded = load_cache(&path);
+            replay_journal(&path, &mut loaded);
+            assert!(loaded.contains_key(&added_id));
+            assert!(!loaded.contains_key(&removed_id));
+            assert_eq!(loaded[&kept_id].signature.name, "renamed");
+
+            // A newer snapshot already has the journal's changes
+            write_snapshot(
+                &path,
+                &serde_json::to_vec(&HashMap::from([(kept_id, directory("kept"))])).unwrap(),
+            )
+            .unwrap();
+            let mut loaded = load_cache(&path);
+            replay_journal(&path, &mut loaded);
+            assert_eq!(loaded.len(), 1);
+            assert_eq!(loaded[&kept_id].signature.name, "kept");
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
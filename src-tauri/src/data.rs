@@ -1,6 +1,7 @@
 use anyhow::{anyhow, Result};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{collections::HashMap, path::PathBuf};
 use uuid::Uuid;
 
@@ -10,7 +11,175 @@ pub struct ShareDirectory {
     pub shared_files: HashMap<Uuid, SharedFile>,
 }
 
+/// What `ShareDirectory::repair` changed.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DirectoryRepair {
+    pub added_self: bool,
+    /// Files whose local copy is gone, now only on the network.
+    pub missing_files: usize,
+    /// Files stored locally that didn't list us as an owner.
+    pub listed_files: usize,
+    /// Files without any owner, which nobody can download.
+    pub removed_files: usize,
+}
+
+impl DirectoryRepair {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Summary of a directory that peers compare to find out whether they
+/// disagree about it, without sending the whole directory.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirectoryDigest {
+    pub directory_identifier: Uuid,
+    pub digest: u64,
+}
+
 impl ShareDirectory {
+    /// Merkle root of the directory's files, over their identifiers and
+    /// content hashes in identifier order. Two peers with the same
+    /// fingerprint list the same contents, whoever owns them.
+    pub fn compute_fingerprint(&self) -> String {
+        let mut files: Vec<&SharedFile> = self.shared_files.values().collect();
+        files.sort_by_key(|file| file.identifier);
+
+        // Leaves and inner nodes are hashed with different prefixes, so a
+        // node can't pass as a leaf
+        let mut level: Vec<[u8; 32]> = files
+            .iter()
+            .map(|file| {
+                let mut hasher = Sha256::new();
+                hasher.update([0]);
+                hasher.update(file.identifier.as_bytes());
+                hasher.update(file.content_hash.to_le_bytes());
+                hasher.finalize().into()
+            })
+            .collect();
+
+        if level.is_empty() {
+            return format!("{:x}", Sha256::digest([]));
+        }
+
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => {
+                        let mut hasher = Sha256::new();
+                        hasher.update([1]);
+                        hasher.update(left);
+                        hasher.update(right);
+                        hasher.finalize().into()
+                    }
+                    // An odd node is carried up as is
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+        }
+
+        level[0]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Updates the fingerprint kept in the signature. Called whenever files
+    /// are added or removed.
+    pub fn refresh_fingerprint(&mut self) {
+        self.signature.fingerprint = Some(self.compute_fingerprint());
+    }
+
+    /// Hashes what peers synchronize: the version, members, files and their
+    /// owners. Where files are stored locally differs between peers, so it
+    /// is left out. Peers are identified by uuid only, since hostnames can
+    /// change without a new version of the directory.
+    pub fn digest(&self) -> DirectoryDigest {
+        let mut hasher = Sha256::new();
+        hasher.update(self.signature.identifier.as_bytes());
+        hasher.update(self.signature.last_modified.timestamp().to_le_bytes());
+        hasher.update(
+            self.signature
+                .last_modified
+                .timestamp_subsec_nanos()
+                .to_le_bytes(),
+        );
+
+        let mut peers: Vec<&Uuid> = self
+            .signature
+            .shared_peers
+            .iter()
+            .map(|p| &p.uuid)
+            .collect();
+        peers.sort();
+        peers.dedup();
+        hasher.update((peers.len() as u64).to_le_bytes());
+        for peer in peers {
+            hasher.update(peer.as_bytes());
+        }
+
+        let mut files: Vec<&SharedFile> = self.shared_files.values().collect();
+        files.sort_by_key(|file| file.identifier);
+        for file in files {
+            hasher.update(file.identifier.as_bytes());
+            hasher.update(file.content_hash.to_le_bytes());
+            hasher.update((file.name.len() as u64).to_le_bytes());
+            hasher.update(file.name.as_bytes());
+
+            let mut owners: Vec<&Uuid> = file.owned_peers.iter().map(|p| &p.uuid).collect();
+            owners.sort();
+            owners.dedup();
+            hasher.update((owners.len() as u64).to_le_bytes());
+            for owner in owners {
+                hasher.update(owner.as_bytes());
+            }
+        }
+
+        let mut digest = [0; 8];
+        digest.copy_from_slice(&hasher.finalize()[..8]);
+
+        DirectoryDigest {
+            directory_identifier: self.signature.identifier,
+            digest: u64::from_le_bytes(digest),
+        }
+    }
+
+    /// Fixes state that a hand-edited cache or a bug could have left behind,
+    /// so it isn't sent to peers on the next sync.
+    pub fn repair(&mut self, myself: &PeerId) -> DirectoryRepair {
+        let mut repair = DirectoryRepair::default();
+
+        if !self.signature.shared_peers.contains(myself) {
+            self.signature.shared_peers.push(myself.clone());
+            repair.added_self = true;
+        }
+
+        for file in self.shared_files.values_mut() {
+            if let ContentLocation::LocalPath(path) = &file.content_location {
+                if !path.exists() {
+                    file.content_location = ContentLocation::NetworkOnly;
+                    file.owned_peers.retain(|peer| peer != myself);
+                    repair.missing_files += 1;
+                }
+            }
+
+            if file.local_ownership(myself) == LocalOwnership::StoredWithoutListing {
+                file.keep_local_owner(myself);
+                repair.listed_files += 1;
+            }
+        }
+
+        let file_count = self.shared_files.len();
+        self.shared_files
+            .retain(|_, file| !file.owned_peers.is_empty());
+        repair.removed_files = file_count - self.shared_files.len();
+        self.refresh_fingerprint();
+
+        repair
+    }
+
     pub fn remove_peer(&mut self, peer: &PeerId, date_modified: DateTime<Utc>) {
         self.signature.last_modified = date_modified;
 
@@ -22,30 +191,53 @@ impl ShareDirectory {
 
         self.shared_files
             .retain(|_, file| !file.owned_peers.is_empty());
+        self.refresh_fingerprint();
     }
 
+    /// Adds files to the directory and returns the ones that were skipped
+    /// because a file with the same name and content is already shared.
+    /// Copies with different names are kept, so the same content can be
+    /// shared under several names.
     pub fn add_files(
         &mut self,
         files: Vec<SharedFile>,
         date_modified: DateTime<Utc>,
-    ) -> Result<()> {
-        for file in files {
-            if self.shared_files.contains_key(&file.identifier) {
-                return Err(anyhow!("File has already been added"));
-            }
+    ) -> Result<Vec<SharedFile>> {
+        if files
+            .iter()
+            .any(|file| self.shared_files.contains_key(&file.identifier))
+        {
+            return Err(anyhow!("File has already been added"));
+        }
 
-            if self
-                .shared_files
-                .values()
-                .any(|f| f.content_hash == file.content_hash)
-            {
-                return Err(anyhow!("File with same content has already been added"));
+        let mut skipped = vec![];
+        for mut file in files {
+            file.dedup_owners();
+
+            if self.shared_files.values().any(|f| f.is_copy_of(&file)) {
+                skipped.push(file);
+                continue;
             }
 
             self.shared_files.insert(file.identifier, file);
         }
 
         self.signature.last_modified = date_modified;
+        self.refresh_fingerprint();
+
+        Ok(skipped)
+    }
+
+    /// Fails if adding `count` files would take the directory over
+    /// `max_files`.
+    pub fn check_file_limit(&self, count: usize, max_files: usize) -> Result<()> {
+        if self.shared_files.len() + count > max_files {
+            return Err(anyhow!(
+                "Directory {} can't have more than {} files",
+                self.signature.name,
+                max_files
+            ));
+        }
 
         Ok(())
     }
@@ -74,11 +266,46 @@ impl ShareDirectory {
                 self.shared_files.remove(&file_id);
             }
         }
+
+        self.refresh_fingerprint();
     }
 
-    pub fn add_peers(&mut self, new_peers: Vec<PeerId>, date_modified: DateTime<Utc>) {
-        self.signature.last_modified = date_modified;
-        self.signature.shared_peers.extend(new_peers);
+    /// Shares the directory with peers that don't have it yet. Returns
+    /// whether any peer was added.
+    pub fn add_peers(&mut self, new_peers: Vec<PeerId>, date_modified: DateTime<Utc>) -> bool {
+        let mut added = false;
+        for peer in new_peers {
+            if !self.signature.shared_peers.contains(&peer) {
+                self.signature.shared_peers.push(peer);
+                added = true;
+            }
+        }
+
+        if added {
+            self.signature.last_modified = date_modified;
+        }
+
+        added
+    }
+
+    /// Updates the hostname stored for `peer` wherever it appears. Returns
+    /// whether anything changed.
+    pub fn rename_peer(&mut self, peer: &PeerId) -> bool {
+        let peers = self.signature.shared_peers.iter_mut().chain(
+            self.shared_files
+                .values_mut()
+                .flat_map(|file| file.owned_peers.iter_mut()),
+        );
+
+        let mut renamed = false;
+        for p in peers {
+            if p == peer && p.hostname != peer.hostname {
+                p.hostname = peer.hostname.clone();
+                renamed = true;
+            }
+        }
+
+        renamed
     }
 
     pub fn add_owner(
@@ -94,9 +321,14 @@ impl ShareDirectory {
             let some_file = self.shared_files.get_mut(&file_id);
 
             if let Some(file) = some_file {
-                if !file.owned_peers.contains(new_owner) {
+                let was_owner = file.owned_peers.contains(new_owner);
+                if !was_owner {
                     file.owned_peers.push(new_owner.clone());
+                }
 
+                // A finished download replaces the partial file it was in
+                let partial = matches!(file.content_location, ContentLocation::Partial { .. });
+                if !was_owner || partial {
                     if let Some(new_location) = location.take() {
                         file.content_location = ContentLocation::LocalPath(new_location);
                     }
@@ -125,6 +357,10 @@ pub struct ShareDirectorySignature {
     pub identifier: Uuid,
     pub last_modified: DateTime<Utc>,
     pub shared_peers: Vec<PeerId>,
+    /// See `ShareDirectory::compute_fingerprint`. Missing in signatures sent
+    /// by older versions.
+    #[serde(default)]
+    pub fingerprint: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -137,12 +373,93 @@ pub struct SharedFile {
     pub content_location: ContentLocation,
     pub owned_peers: Vec<PeerId>,
     pub size: u64,
+    /// Only kept on this device, peers aren't sent it.
+    #[serde(default)]
+    pub uploads: UploadStats,
+}
+
+/// How many times peers finished downloading a file from this device.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadStats {
+    pub count: u64,
+    pub last_served: Option<DateTime<Utc>>,
+}
+
+impl SharedFile {
+    pub fn record_upload(&mut self, served_at: DateTime<Utc>) {
+        self.uploads.count += 1;
+        self.uploads.last_served = Some(served_at);
+    }
+
+    /// Same name and content, so sharing `other` as well would only list the
+    /// file twice.
+    pub fn is_copy_of(&self, other: &SharedFile) -> bool {
+        self.content_hash == other.content_hash && self.name == other.name
+    }
+
+    /// Removes repeated owners, keeping the first occurrence of each.
+    pub fn dedup_owners(&mut self) {
+        let mut seen = Vec::with_capacity(self.owned_peers.len());
+        self.owned_peers.retain(|peer| {
+            if seen.contains(peer) {
+                false
+            } else {
+                seen.push(peer.clone());
+                true
+            }
+        });
+    }
+
+    /// Compares whether `myself` is listed as an owner with whether the file
+    /// is actually stored locally. The two should always agree.
+    pub fn local_ownership(&self, myself: &PeerId) -> LocalOwnership {
+        let listed = self.owned_peers.contains(myself);
+        let stored = matches!(self.content_location, ContentLocation::LocalPath(_));
+
+        match (listed, stored) {
+            (true, true) => LocalOwnership::Owned,
+            (false, false) => LocalOwnership::NotOwned,
+            (true, false) => LocalOwnership::ListedWithoutContent,
+            (false, true) => LocalOwnership::StoredWithoutListing,
+        }
+    }
+
+    /// Lists `myself` as an owner if the file is stored locally, since other
+    /// peers might not know about it yet.
+    pub fn keep_local_owner(&mut self, myself: &PeerId) {
+        if let ContentLocation::LocalPath(_) = self.content_location {
+            if !self.owned_peers.contains(myself) {
+                self.owned_peers.push(myself.clone());
+            }
+        }
+    }
+}
+
+/// Whether this device owns a file, according to both its owner list and
+/// its content location. The last two mean the two have drifted apart.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum LocalOwnership {
+    Owned,
+    NotOwned,
+    /// Peers are told to download the file from us, but we don't have it.
+    ListedWithoutContent,
+    /// We have the file, but peers don't know they can download it from us.
+    StoredWithoutListing,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub enum ContentLocation {
     LocalPath(PathBuf),
+    /// Being downloaded into the partial file at `path`, which holds `bytes`
+    /// so far. Becomes `LocalPath` once the download is complete and
+    /// verified.
+    Partial {
+        path: PathBuf,
+        bytes: u64,
+    },
     NetworkOnly,
 }
 
@@ -150,12 +467,28 @@ use std::fmt::Display;
 
 const INSTANCE_SEPARATOR: &str = ";";
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+/// Identifies a device. Only the uuid is compared, since the hostname is just
+/// a display name and changes when the device is renamed.
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PeerId {
     pub hostname: String,
     pub uuid: Uuid,
 }
 
+impl PartialEq for PeerId {
+    fn eq(&self, other: &Self) -> bool {
+        self.uuid == other.uuid
+    }
+}
+
+impl Eq for PeerId {}
+
+impl std::hash::Hash for PeerId {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.uuid.hash(state);
+    }
+}
+
 impl PeerId {
     pub fn parse(instance: &str) -> Option<Self> {
         let (hostname, uuid_str) = instance.split_once(INSTANCE_SEPARATOR)?;
@@ -168,16 +501,20 @@ impl PeerId {
     }
 
     pub fn generate() -> Self {
-        let os_hostname = hostname::get().unwrap().into_string();
-        let hostname = match os_hostname {
-            Ok(h) => h,
-            Err(_) => "generic_hostname".to_owned(),
-        };
-
+        let hostname = Self::local_hostname();
         let uuid = Uuid::new_v4();
 
         Self { hostname, uuid }
     }
+
+    pub fn local_hostname() -> String {
+        let os_hostname = hostname::get().unwrap().into_string();
+
+        match os_hostname {
+            Ok(h) => h,
+            Err(_) => "generic_hostname".to_owned(),
+        }
+    }
 }
 
 impl Display for PeerId {
@@ -220,6 +557,36 @@ mod tests {
             assert!(parsed.is_none());
         }
 
+        #[test]
+        fn peer_with_renamed_host_is_same_peer() {
+            let before = PeerId {
+                uuid: Uuid::from_u128(1),
+                hostname: "old-name".to_string(),
+            };
+            let after = PeerId {
+                hostname: "new-name".to_string(),
+                ..before.clone()
+            };
+
+            assert_eq!(before, after);
+            assert!([before.clone()].contains(&after));
+            assert!(std::collections::HashSet::from([before]).contains(&after));
+        }
+
+        #[test]
+        fn peers_with_same_host_and_different_uuid_differ() {
+            let first = PeerId {
+                uuid: Uuid::from_u128(1),
+                hostname: "test".to_string(),
+            };
+            let second = PeerId {
+                uuid: Uuid::from_u128(2),
+                hostname: "test".to_string(),
+            };
+
+            assert_ne!(first, second);
+        }
+
         #[test]
         fn to_string_returns_correct_format() {
             let peer_id = PeerId {
@@ -260,6 +627,7 @@ mod tests {
                 identifier: Uuid::new_v4(),
                 last_modified: now,
                 shared_peers: vec![peer.clone()],
+                fingerprint: None,
             };
 
             let shared_file = SharedFile {
@@ -270,6 +638,7 @@ mod tests {
                 content_location: ContentLocation::NetworkOnly,
                 owned_peers: vec![peer],
                 size: 0,
+                uploads: Default::default(),
             };
 
             let shared_files = HashMap::from([(Uuid::nil(), shared_file)]);
@@ -280,6 +649,86 @@ mod tests {
             }
         }
 
+        #[test]
+        fn repair_fixes_missing_and_orphaned_files() {
+            use crate::data::DirectoryRepair;
+
+            let mut directory = setup();
+            let myself = PeerId {
+                hostname: HOSTNAME.to_string(),
+                uuid: Uuid::from_u128(1),
+            };
+            let stored_path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            std::fs::write(&stored_path, b"content").unwrap();
+
+            let template = directory.shared_files[&Uuid::nil()].clone();
+            let mut files = vec![];
+            for (location, owners) in [
+                (ContentLocation::LocalPath(stored_path.clone()), vec![]),
+                (
+                    ContentLocation::LocalPath(stored_path.with_extension("gone")),
+                    vec![myself.clone()],
+                ),
+                (ContentLocation::NetworkOnly, vec![]),
+            ] {
+                let mut file = template.clone();
+                file.identifier = Uuid::new_v4();
+                file.content_location = location;
+                file.owned_peers = owners;
+                files.push(file.identifier);
+                directory.shared_files.insert(file.identifier, file);
+            }
+
+            let repair = directory.repair(&myself);
+
+            assert_eq!(
+                repair,
+                DirectoryRepair {
+                    added_self: true,
+                    missing_files: 1,
+                    listed_files: 1,
+                    removed_files: 2,
+                }
+            );
+            assert!(directory.signature.shared_peers.contains(&myself));
+            assert_eq!(
+                directory.shared_files[&files[0]].owned_peers,
+                vec![myself.clone()]
+            );
+            assert!(!directory.shared_files.contains_key(&files[1]));
+            assert!(!directory.shared_files.contains_key(&files[2]));
+            assert!(directory.shared_files.contains_key(&Uuid::nil()));
+            assert!(directory.repair(&myself).is_empty());
+
+            std::fs::remove_file(stored_path).unwrap();
+        }
+
+        #[test]
+        fn local_ownership_compares_owner_list_with_location() {
+            use crate::data::LocalOwnership;
+
+            let mut directory = setup();
+            let file = directory.shared_files.get_mut(&Uuid::nil()).unwrap();
+            let myself = file.owned_peers[0].clone();
+            let other = PeerId {
+                hostname: HOSTNAME.to_string(),
+                uuid: Uuid::from_u128(1),
+            };
+
+            assert_eq!(
+                file.local_ownership(&myself),
+                LocalOwnership::ListedWithoutContent
+            );
+            assert_eq!(file.local_ownership(&other), LocalOwnership::NotOwned);
+
+            file.content_location = ContentLocation::LocalPath(PathBuf::from("test file"));
+            assert_eq!(file.local_ownership(&myself), LocalOwnership::Owned);
+            assert_eq!(
+                file.local_ownership(&other),
+                LocalOwnership::StoredWithoutListing
+            );
+        }
+
         #[test]
         fn add_owner_should_contain_new_peer_id() {
             let mut directory = setup();
@@ -302,6 +751,24 @@ mod tests {
                 .contains(&new_peer));
         }
 
+        #[test]
+        fn rename_peer_updates_every_occurrence() {
+            let mut directory = setup();
+            let renamed = PeerId {
+                hostname: "renamed".to_string(),
+                uuid: PEER_UUID,
+            };
+
+            assert!(directory.rename_peer(&renamed));
+            assert!(!directory.rename_peer(&renamed));
+
+            assert_eq!(directory.signature.shared_peers[0].hostname, "renamed");
+            assert_eq!(
+                directory.shared_files[&Uuid::nil()].owned_peers[0].hostname,
+                "renamed"
+            );
+        }
+
         #[test]
         fn add_owner_should_not_add_twice() {
             let mut directory = setup();
@@ -356,7 +823,7 @@ mod tests {
             let file = directory.shared_files.get(&Uuid::nil()).unwrap();
 
             match &file.content_location {
-                ContentLocation::NetworkOnly => assert!(false),
+                ContentLocation::NetworkOnly | ContentLocation::Partial { .. } => assert!(false),
                 ContentLocation::LocalPath(path) => assert_eq!(path, &expected_path_buf),
             }
         }
@@ -378,6 +845,7 @@ mod tests {
                 content_location: crate::data::ContentLocation::NetworkOnly,
                 owned_peers: vec![myself],
                 size: 1,
+                uploads: Default::default(),
             }];
 
             let result = directory.add_files(files, mod_date);
@@ -405,6 +873,7 @@ mod tests {
                 content_location: crate::data::ContentLocation::NetworkOnly,
                 owned_peers: vec![myself],
                 size: 1,
+                uploads: Default::default(),
             }];
 
             let result = directory.add_files(files, mod_date);
@@ -415,7 +884,7 @@ mod tests {
         }
 
         #[test]
-        fn add_files_should_return_error_when_file_with_same_content_hash_exists() {
+        fn add_files_should_skip_file_with_same_name_and_content() {
             let mut directory = setup();
             let mod_date = Utc::now();
             let myself = PeerId {
@@ -424,20 +893,55 @@ mod tests {
             };
             let file_id = Uuid::from_bytes([1; 16]);
             let files = vec![SharedFile {
-                name: "file 2".to_string(),
+                name: "test file".to_string(),
                 identifier: file_id,
                 content_hash: 0,
                 last_modified: mod_date,
                 content_location: crate::data::ContentLocation::NetworkOnly,
                 owned_peers: vec![myself],
                 size: 1,
+                uploads: Default::default(),
             }];
 
-            let result = directory.add_files(files, mod_date);
+            let skipped = directory.add_files(files, mod_date).unwrap();
 
-            assert!(directory.signature.last_modified != mod_date);
             assert_eq!(directory.shared_files.len(), 1);
-            assert!(result.is_err());
+            assert_eq!(skipped.len(), 1);
+            assert_eq!(skipped[0].identifier, file_id);
+        }
+
+        #[test]
+        fn add_files_should_keep_files_with_same_content_and_different_names() {
+            let mut directory = setup();
+            let mod_date = Utc::now();
+            let myself = PeerId {
+                hostname: HOSTNAME.to_string(),
+                uuid: PEER_UUID,
+            };
+            let copy = |name: &str, id: u8| SharedFile {
+                name: name.to_string(),
+                identifier: Uuid::from_bytes([id; 16]),
+                content_hash: 0,
+                last_modified: mod_date,
+                content_location: crate::data::ContentLocation::NetworkOnly,
+                owned_peers: vec![myself.clone()],
+                size: 1,
+                uploads: Default::default(),
+            };
+            let files = vec![copy("copy 1", 1), copy("copy 2", 2), copy("copy 2", 3)];
+
+            let skipped = directory.add_files(files, mod_date).unwrap();
+
+            assert!(directory.signature.last_modified == mod_date);
+            assert_eq!(directory.shared_files.len(), 3);
+            assert!(directory
+                .shared_files
+                .contains_key(&Uuid::from_bytes([1; 16])));
+            assert!(directory
+                .shared_files
+                .contains_key(&Uuid::from_bytes([2; 16])));
+            assert_eq!(skipped.len(), 1);
+            assert_eq!(skipped[0].identifier, Uuid::from_bytes([3; 16]));
         }
 
         #[test]
@@ -591,5 +1095,109 @@ mod tests {
             assert!(directory.signature.shared_peers.contains(&new_peer));
             assert!(directory.signature.shared_peers.contains(&myself));
         }
+
+        #[test]
+        fn add_peers_repeated_does_not_duplicate_peers() {
+            let mut directory = setup();
+            let first_date = Utc::now();
+            let new_peer = PeerId {
+                hostname: "test 2".to_owned(),
+                uuid: Uuid::from_bytes([1; 16]),
+            };
+
+            assert!(directory.add_peers(vec![new_peer.clone(), new_peer.clone()], first_date));
+            assert!(!directory.add_peers(
+                vec![new_peer.clone()],
+                first_date + chrono::Duration::hours(1)
+            ));
+
+            let count = directory
+                .signature
+                .shared_peers
+                .iter()
+                .filter(|p| **p == new_peer)
+                .count();
+            assert_eq!(count, 1);
+            assert_eq!(directory.signature.last_modified, first_date);
+        }
+
+        #[test]
+        fn digest_should_ignore_local_details_and_notice_changes() {
+            let directory = setup();
+            let digest = directory.digest();
+
+            let mut copy = setup();
+            copy.signature = directory.signature.clone();
+            let file = copy.shared_files.get_mut(&Uuid::nil()).unwrap();
+            file.content_location = ContentLocation::LocalPath(PathBuf::from("file"));
+            file.owned_peers[0].hostname = "renamed".to_string();
+            assert_eq!(copy.digest(), digest);
+
+            copy.shared_files.get_mut(&Uuid::nil()).unwrap().name = "other".to_string();
+            assert_ne!(copy.digest(), digest);
+
+            let mut newer = setup();
+            newer.signature = directory.signature.clone();
+            newer.signature.last_modified += chrono::Duration::nanoseconds(1);
+            assert_ne!(newer.digest(), digest);
+        }
+
+        #[test]
+        fn fingerprint_should_only_depend_on_file_ids_and_contents() {
+            let mut directory = setup();
+            let fingerprint = directory.compute_fingerprint();
+
+            let mut copy = setup();
+            let file = copy.shared_files.get_mut(&Uuid::nil()).unwrap();
+            file.content_location = ContentLocation::LocalPath(PathBuf::from("file"));
+            file.owned_peers.clear();
+            copy.signature.last_modified += chrono::Duration::seconds(1);
+            assert_eq!(copy.compute_fingerprint(), fingerprint);
+
+            let mod_date = Utc::now();
+            let added = SharedFile {
+                name: "file 1".to_string(),
+                identifier: Uuid::from_bytes([1; 16]),
+                content_hash: 1,
+                last_modified: mod_date,
+                content_location: ContentLocation::NetworkOnly,
+                owned_peers: vec![],
+                size: 1,
+                uploads: Default::default(),
+            };
+            directory.add_files(vec![added], mod_date).unwrap();
+
+            let refreshed = directory.signature.fingerprint.clone().unwrap();
+            assert_ne!(refreshed, fingerprint);
+            assert_eq!(refreshed, directory.compute_fingerprint());
+        }
+
+        #[test]
+        fn add_files_should_not_keep_duplicate_owners() {
+            let mut directory = setup();
+            let mod_date = Utc::now();
+            let myself = PeerId {
+                hostname: HOSTNAME.to_string(),
+                uuid: PEER_UUID,
+            };
+            let file_id = Uuid::from_bytes([1; 16]);
+            let files = vec![SharedFile {
+                name: "file 1".to_string(),
+                identifier: file_id,
+                content_hash: 1,
+                last_modified: mod_date,
+                content_location: ContentLocation::NetworkOnly,
+                owned_peers: vec![myself.clone(), myself.clone()],
+                size: 1,
+                uploads: Default::default(),
+            }];
+
+            directory.add_files(files, mod_date).unwrap();
+
+            assert_eq!(
+                directory.shared_files.get(&file_id).unwrap().owned_peers,
+                vec![myself]
+            );
+        }
     }
 }
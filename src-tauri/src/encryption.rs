@@ -0,0 +1,419 @@
+//! Optional encryption of downloaded files at rest.
+//!
+//! When enabled, downloads are written encrypted with a key derived from a
+//! passphrase the user enters each time the app starts. The passphrase and key
+//! are only kept in memory; the config stores the salt and a verifier.
+//!
+//! This protects downloads from someone who reads the disk, a backup or a
+//! synced copy of the download directory without the passphrase. It does not
+//! protect against software running as the same user while the app is
+//! unlocked, against copies the user opens (they are decrypted into a
+//! temporary directory so other programs can read them), or against anyone
+//! learning file names and sizes. Files shared from their original location
+//! are never encrypted.
+//!
+//! An encrypted file starts with `MAGIC` and a random nonce prefix, followed
+//! by its contents in `CHUNK_SIZE` chunks, each sealed with ChaCha20-Poly1305.
+//! A chunk's nonce holds its index and its associated data marks the last
+//! chunk, so chunks can't be reordered, dropped or cut off unnoticed.
+
+use std::{
+    fmt,
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    pin::Pin,
+};
+
+use bytes::Bytes;
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hmac::Hmac;
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, BufReader},
+};
+use tokio_util::io::StreamReader;
+use uuid::Uuid;
+
+const MAGIC: &[u8; 8] = b"FSAENC01";
+const NONCE_PREFIX_SIZE: usize = 8;
+const HEADER_SIZE: usize = MAGIC.len() + NONCE_PREFIX_SIZE;
+const CHUNK_SIZE: usize = 1024 * 64; // 64 KB
+const TAG_SIZE: usize = 16;
+const SEALED_CHUNK_SIZE: usize = CHUNK_SIZE + TAG_SIZE;
+const KEY_ITERATIONS: u32 = 200_000;
+const SALT_SIZE: usize = 16;
+const VERIFIER_CONTEXT: &[u8] = b"file-sharing-app download key";
+const DECRYPTED_DIRECTORY: &str = "file-sharing-app-decrypted";
+
+/// Contents of a file, decrypted if needed.
+pub type PlainReader = Pin<Box<dyn AsyncRead + Send>>;
+
+#[derive(Clone)]
+pub struct FileKey(Key);
+
+impl fmt::Debug for FileKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FileKey(..)")
+    }
+}
+
+impl FileKey {
+    pub fn derive(passphrase: &str, salt: &[u8]) -> Self {
+        let mut key = Key::default();
+        pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), salt, KEY_ITERATIONS, &mut key);
+
+        Self(key)
+    }
+
+    /// A value stored with the salt, so a mistyped passphrase is rejected
+    /// instead of producing files nobody can decrypt.
+    pub fn verifier(&self) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(VERIFIER_CONTEXT);
+        hasher.update(self.0);
+
+        hasher.finalize().to_vec()
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(&self.0)
+    }
+}
+
+pub fn generate_salt() -> Vec<u8> {
+    let mut salt = vec![0u8; SALT_SIZE];
+    OsRng.fill_bytes(&mut salt);
+
+    salt
+}
+
+/// Encrypts a file as its contents arrive. Write the header returned by `new`
+/// first, then everything returned by `update` and `finish`.
+pub struct Encryptor {
+    cipher: ChaCha20Poly1305,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+    chunk_index: u32,
+    pending: Vec<u8>,
+}
+
+impl Encryptor {
+    pub fn new(key: &FileKey) -> (Self, Vec<u8>) {
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+        OsRng.fill_bytes(&mut nonce_prefix);
+
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&nonce_prefix);
+
+        let encryptor = Self {
+            cipher: key.cipher(),
+            nonce_prefix,
+            chunk_index: 0,
+            pending: Vec::with_capacity(CHUNK_SIZE),
+        };
+
+        (encryptor, header)
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut sealed = vec![];
+        let mut data = data;
+
+        while !data.is_empty() {
+            // A full chunk is only sealed once more data follows, because
+            // the last chunk is sealed differently
+            if self.pending.len() == CHUNK_SIZE {
+                sealed.extend(self.seal(false)?);
+            }
+
+            let take = (CHUNK_SIZE - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+        }
+
+        Ok(sealed)
+    }
+
+    pub fn finish(mut self) -> io::Result<Vec<u8>> {
+        self.seal(true)
+    }
+
+    fn seal(&mut self, last: bool) -> io::Result<Vec<u8>> {
+        let nonce = chunk_nonce(&self.nonce_prefix, self.chunk_index);
+        let sealed = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &self.pending,
+                    aad: &[last as u8],
+                },
+            )
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Could not encrypt file"))?;
+
+        self.pending.clear();
+        self.chunk_index = self
+            .chunk_index
+            .checked_add(1)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "File is too large to encrypt"))?;
+
+        Ok(sealed)
+    }
+}
+
+struct Decryptor {
+    cipher: ChaCha20Poly1305,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+    chunk_index: u32,
+    remaining: u64,
+    done: bool,
+}
+
+impl Decryptor {
+    async fn next_chunk(&mut self, file: &mut File) -> io::Result<Option<Bytes>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let result = self.open_chunk(file).await;
+        if result.is_err() {
+            self.done = true;
+        }
+
+        result.map(Some)
+    }
+
+    async fn open_chunk(&mut self, file: &mut File) -> io::Result<Bytes> {
+        let size = self.remaining.min(SEALED_CHUNK_SIZE as u64) as usize;
+        if size < TAG_SIZE {
+            return Err(invalid_data());
+        }
+
+        let last = self.remaining == size as u64;
+        let mut sealed = vec![0u8; size];
+        file.read_exact(&mut sealed).await?;
+
+        let nonce = chunk_nonce(&self.nonce_prefix, self.chunk_index);
+        let plain = self
+            .cipher
+            .decrypt(
+                &nonce,
+                Payload {
+                    msg: &sealed,
+                    aad: &[last as u8],
+                },
+            )
+            .map_err(|_| invalid_data())?;
+
+        self.remaining -= size as u64;
+        self.chunk_index = self.chunk_index.wrapping_add(1);
+        self.done = last;
+
+        Ok(Bytes::from(plain))
+    }
+}
+
+fn chunk_nonce(prefix: &[u8; NONCE_PREFIX_SIZE], index: u32) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[..NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_SIZE..].copy_from_slice(&index.to_be_bytes());
+
+    nonce
+}
+
+fn invalid_data() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Encrypted file is damaged or was encrypted with a different passphrase",
+    )
+}
+
+/// Reads the header of `file`, returning the nonce prefix if it's encrypted.
+/// Leaves `file` positioned at the start of its contents.
+async fn read_header(file: &mut File) -> io::Result<Option<[u8; NONCE_PREFIX_SIZE]>> {
+    let mut header = [0u8; HEADER_SIZE];
+
+    match file.read_exact(&mut header).await {
+        Ok(_) if header.starts_with(MAGIC) => {
+            let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+            nonce_prefix.copy_from_slice(&header[MAGIC.len()..]);
+
+            Ok(Some(nonce_prefix))
+        }
+        Ok(_) => {
+            file.seek(SeekFrom::Start(0)).await?;
+            Ok(None)
+        }
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            file.seek(SeekFrom::Start(0)).await?;
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+pub async fn is_encrypted(path: &Path) -> io::Result<bool> {
+    let mut file = File::open(path).await?;
+
+    Ok(read_header(&mut file).await?.is_some())
+}
+
+/// Opens a file for reading its plain contents. Encrypted files are decrypted
+/// with `key` as they're read, other files are read as they are.
+pub async fn open_plain(path: &Path, key: Option<&FileKey>) -> io::Result<PlainReader> {
+    let mut file = File::open(path).await?;
+    let length = file.metadata().await?.len();
+
+    let nonce_prefix = match read_header(&mut file).await? {
+        None => return Ok(Box::pin(BufReader::new(file))),
+        Some(nonce_prefix) => nonce_prefix,
+    };
+
+    let key = key.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "File is encrypted and the passphrase hasn't been entered",
+        )
+    })?;
+
+    let decryptor = Decryptor {
+        cipher: key.cipher(),
+        nonce_prefix,
+        chunk_index: 0,
+        remaining: length - HEADER_SIZE as u64,
+        done: false,
+    };
+
+    let chunks = futures::stream::unfold((file, decryptor), |(mut file, mut decryptor)| async {
+        match decryptor.next_chunk(&mut file).await {
+            Ok(None) => None,
+            result => Some((result.map(Option::unwrap_or_default), (file, decryptor))),
+        }
+    });
+
+    Ok(Box::pin(StreamReader::new(Box::pin(chunks))))
+}
+
+/// Where opened encrypted files are decrypted to.
+pub fn decrypted_directory() -> PathBuf {
+    std::env::temp_dir().join(DECRYPTED_DIRECTORY)
+}
+
+/// Returns a path other programs can open `path` from. Encrypted files are
+/// decrypted into their own directory under `decrypted_directory`, keeping
+/// the file name so they open with the right program.
+pub async fn path_for_opening(path: &Path, key: Option<&FileKey>) -> io::Result<PathBuf> {
+    if !is_encrypted(path).await? {
+        return Ok(path.to_path_buf());
+    }
+
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Path has no file name"))?;
+
+    let directory = decrypted_directory().join(Uuid::new_v4().to_string());
+    fs::create_dir_all(&directory).await?;
+
+    let destination = directory.join(file_name);
+    let mut reader = open_plain(path, key).await?;
+    let mut output = File::create(&destination).await?;
+    tokio::io::copy(&mut reader, &mut output).await?;
+
+    Ok(destination)
+}
+
+/// Removes files decrypted for opening during earlier runs.
+pub async fn remove_decrypted_files() {
+    match fs::remove_dir_all(decrypted_directory()).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            warn!("Could not remove decrypted files: {}", e);
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod encryption_tests {
+        use std::fs;
+
+        use tokio::io::AsyncReadExt;
+        use uuid::Uuid;
+
+        use crate::encryption::{
+            generate_salt, is_encrypted, open_plain, Encryptor, FileKey, CHUNK_SIZE,
+        };
+
+        async fn read_plain(
+            path: &std::path::Path,
+            key: Option<&FileKey>,
+        ) -> std::io::Result<Vec<u8>> {
+            let mut reader = open_plain(path, key).await?;
+            let mut content = vec![];
+            reader.read_to_end(&mut content).await?;
+
+            Ok(content)
+        }
+
+        fn encrypt(key: &FileKey, content: &[u8], part_size: usize) -> Vec<u8> {
+            let (mut encryptor, mut output) = Encryptor::new(key);
+            for part in content.chunks(part_size.max(1)) {
+                output.extend(encryptor.update(part).unwrap());
+            }
+            output.extend(encryptor.finish().unwrap());
+
+            output
+        }
+
+        #[tokio::test]
+        async fn encrypted_file_decrypts_to_original_contents() {
+            let key = FileKey::derive("correct horse", &generate_salt());
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+
+            for size in [0, 10, CHUNK_SIZE, CHUNK_SIZE * 2 + 7] {
+                let content: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+                let path = dir.join(format!("{}.bin", size));
+                fs::write(&path, encrypt(&key, &content, 50_000)).unwrap();
+
+                assert!(is_encrypted(&path).await.unwrap());
+                assert_eq!(read_plain(&path, Some(&key)).await.unwrap(), content);
+            }
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn encrypted_file_rejects_wrong_key_and_truncation() {
+            let salt = generate_salt();
+            let key = FileKey::derive("correct horse", &salt);
+            let wrong_key = FileKey::derive("battery staple", &salt);
+            assert_ne!(key.verifier(), wrong_key.verifier());
+
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+            let content = vec![3u8; CHUNK_SIZE * 2];
+            let encrypted = encrypt(&key, &content, CHUNK_SIZE);
+
+            let path = dir.join("file.bin");
+            fs::write(&path, &encrypted).unwrap();
+            assert!(read_plain(&path, Some(&wrong_key)).await.is_err());
+            assert!(read_plain(&path, None).await.is_err());
+
+            let truncated = dir.join("truncated.bin");
+            fs::write(&truncated, &encrypted[..encrypted.len() / 2]).unwrap();
+            assert!(read_plain(&truncated, Some(&key)).await.is_err());
+
+            let plain = dir.join("plain.bin");
+            fs::write(&plain, &content).unwrap();
+            assert!(!is_encrypted(&plain).await.unwrap());
+            assert_eq!(read_plain(&plain, None).await.unwrap(), content);
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::{net::Ipv4Addr, sync::Arc};
+
+use anyhow::Result;
+use serde::Serialize;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    sync::broadcast::{self, error::RecvError},
+};
+
+use crate::window::{WindowManager, WindowRequest};
+
+/// Events kept for a slow reader before it starts missing them.
+const EVENT_BUFFER_SIZE: usize = 256;
+
+/// One line written to the event stream, e.g.
+/// `{"event":"DownloadUpdate","payload":{"downloadId":"…","progress":40}}`.
+/// `event` and `payload` are the name and contents of the event the window
+/// receives.
+#[derive(Serialize)]
+struct EventLine<'a> {
+    event: &'a str,
+    payload: &'a WindowRequest,
+}
+
+/// Sends every event to the scripts connected to the event stream, as one
+/// JSON object per line.
+#[derive(Clone)]
+pub struct EventStream {
+    sender: broadcast::Sender<Arc<str>>,
+}
+
+impl EventStream {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER_SIZE);
+
+        Self { sender }
+    }
+
+    pub fn publish(&self, action: &WindowRequest) {
+        // Not worth serializing when nobody listens
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+
+        let line = EventLine {
+            event: action.to_string(),
+            payload: action,
+        };
+
+        match serde_json::to_string(&line) {
+            Ok(mut line) => {
+                line.push('\n');
+                let _ = self.sender.send(line.into());
+            }
+            Err(e) => error!("Could not serialize {} event: {}", action.to_string(), e),
+        }
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.sender.receiver_count()
+    }
+}
+
+impl Default for EventStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Listens for scripts on `port`, only reachable from this machine.
+pub async fn bind_event_stream(port: u16) -> Result<TcpListener> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await?;
+
+    Ok(listener)
+}
+
+pub async fn serve_events(listener: TcpListener, events: EventStream) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                info!("Event stream reader connected from {}", addr);
+                let receiver = events.sender.subscribe();
+
+                tokio::spawn(async move {
+                    if let Err(e) = write_events(stream, receiver).await {
+                        info!("Event stream reader {} left: {}", addr, e);
+                    }
+                });
+            }
+            Err(e) => error!("Could not accept event stream connection: {}", e),
+        }
+    }
+}
+
+async fn write_events(
+    mut stream: TcpStream,
+    mut receiver: broadcast::Receiver<Arc<str>>,
+) -> Result<()> {
+    loop {
+        match receiver.recv().await {
+            Ok(line) => stream.write_all(line.as_bytes()).await?,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!(
+                    "Event stream reader fell behind, skipped {} events",
+                    skipped
+                )
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+/// Sends events to the window and copies them to the event stream, if it is
+/// turned on.
+pub struct SocketWindowManager<M> {
+    pub window_manager: M,
+    pub events: Option<EventStream>,
+}
+
+impl<M> WindowManager for SocketWindowManager<M>
+where
+    M: WindowManager,
+{
+    fn send(&self, action: WindowRequest) -> Result<(), tauri::Error> {
+        if let Some(events) = &self.events {
+            events.publish(&action);
+        }
+
+        self.window_manager.send(action)
+    }
+
+    fn notify(&self, title: &str, body: &str) -> Result<(), tauri::api::Error> {
+        self.window_manager.notify(title, body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod event_stream_tests {
+        use std::time::Duration;
+
+        use tokio::{
+            io::{AsyncBufReadExt, BufReader},
+            net::TcpStream,
+        };
+
+        use crate::{
+            events::{bind_event_stream, serve_events, EventStream, SocketWindowManager},
+            window::{BackendError, ErrorCode, WindowManager, WindowRequest},
+        };
+
+        struct NullWindowManager;
+
+        impl WindowManager for NullWindowManager {
+            fn send(&self, _action: WindowRequest) -> Result<(), tauri::Error> {
+                Ok(())
+            }
+
+            fn notify(&self, _title: &str, _body: &str) -> Result<(), tauri::api::Error> {
+                Ok(())
+            }
+        }
+
+        #[tokio::test]
+        async fn socket_window_manager_writes_events_as_json_lines() {
+            let listener = bind_event_stream(0).await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            assert!(addr.ip().is_loopback());
+
+            let events = EventStream::new();
+            tokio::spawn(serve_events(listener, events.clone()));
+            let window_manager = SocketWindowManager {
+                window_manager: NullWindowManager,
+                events: Some(events.clone()),
+            };
+
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let wait = async {
+                while events.subscriber_count() == 0 {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(5), wait)
+                .await
+                .expect("reader should subscribe");
+
+            window_manager
+                .send(WindowRequest::Error(BackendError {
+                    code: ErrorCode::PeerUnavailable,
+                    title: "Title".to_owned(),
+                    error: "first".to_owned(),
+                }))
+                .unwrap();
+            window_manager
+                .send(WindowRequest::Error(BackendError {
+                    code: ErrorCode::PeerUnavailable,
+                    title: "Title".to_owned(),
+                    error: "second".to_owned(),
+                }))
+                .unwrap();
+
+            let mut lines = BufReader::new(stream).lines();
+            for expected in ["first", "second"] {
+                let line = tokio::time::timeout(Duration::from_secs(5), lines.next_line())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .unwrap();
+                let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+
+                assert_eq!(event["event"], "Error");
+                assert_eq!(event["payload"]["error"], expected);
+            }
+        }
+    }
+}
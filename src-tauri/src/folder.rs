@@ -0,0 +1,223 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+
+/// Files and directories to leave out when adding a folder, written in a
+/// subset of the `.gitignore` syntax:
+///
+/// - `*` matches anything but `/`, `**` also matches `/` and `?` matches a
+///   single character, e.g. `*.tmp`.
+/// - A trailing `/` only matches directories, e.g. `node_modules/`.
+/// - Patterns containing any other `/` match the path relative to the added
+///   folder, e.g. `build/**/*.o`. The rest match entry names at any depth.
+#[derive(Debug, Default, Clone)]
+pub struct ExcludePatterns {
+    patterns: Vec<ExcludePattern>,
+}
+
+#[derive(Debug, Clone)]
+struct ExcludePattern {
+    glob: Vec<char>,
+    directory_only: bool,
+    match_path: bool,
+}
+
+impl ExcludePatterns {
+    pub fn new<S>(patterns: &[S]) -> Result<Self>
+    where
+        S: AsRef<str>,
+    {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| parse_pattern(pattern.as_ref()))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { patterns })
+    }
+
+    /// `relative_path` uses `/` as separator, whatever the platform.
+    pub fn is_excluded(&self, relative_path: &str, is_directory: bool) -> bool {
+        let name = relative_path.rsplit('/').next().unwrap_or(relative_path);
+        let path: Vec<char> = relative_path.chars().collect();
+        let name: Vec<char> = name.chars().collect();
+
+        self.patterns.iter().any(|pattern| {
+            if pattern.directory_only && !is_directory {
+                return false;
+            }
+
+            let text = if pattern.match_path { &path } else { &name };
+            glob_match(&pattern.glob, text)
+        })
+    }
+}
+
+pub fn validate_exclude_pattern(pattern: &str) -> Result<()> {
+    parse_pattern(pattern).map(|_| ())
+}
+
+fn parse_pattern(pattern: &str) -> Result<ExcludePattern> {
+    // Paths copied from Windows use the other separator
+    let pattern = pattern.trim().replace('\\', "/");
+    let directory_only = pattern.ends_with('/');
+    let pattern = pattern.trim_end_matches('/');
+    let match_path = pattern.contains('/');
+    let pattern = pattern.trim_start_matches('/');
+
+    if pattern.is_empty() {
+        bail!("Exclude pattern is empty");
+    }
+
+    if pattern
+        .split('/')
+        .any(|part| part.is_empty() || part == "..")
+    {
+        bail!("{:?} is not a valid exclude pattern", pattern);
+    }
+
+    Ok(ExcludePattern {
+        glob: pattern.chars().collect(),
+        directory_only,
+        match_path,
+    })
+}
+
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('*', ['*', rest @ ..])) => {
+            // `**/` also matches no directories at all
+            (rest.first() == Some(&'/') && glob_match(&rest[1..], text))
+                || (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+        }
+        Some(('*', rest)) => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != '/')
+            .any(|i| glob_match(rest, &text[i..])),
+        Some(('?', rest)) => {
+            matches!(text.split_first(), Some((c, text)) if *c != '/' && glob_match(rest, text))
+        }
+        Some((p, rest)) => {
+            matches!(text.split_first(), Some((c, text)) if c == p && glob_match(rest, text))
+        }
+    }
+}
+
+/// Files found in a folder and its subdirectories.
+#[derive(Debug, Default)]
+pub struct FolderScan {
+    pub files: Vec<PathBuf>,
+    /// Files and directories matching an exclude pattern. Excluded
+    /// directories aren't walked, so each counts once.
+    pub excluded: usize,
+}
+
+/// Walks `root` and its subdirectories. Symlinked directories aren't
+/// followed, so a link to a parent can't make the walk loop.
+pub fn scan_folder(root: &Path, excludes: &ExcludePatterns) -> io::Result<FolderScan> {
+    let mut scan = FolderScan::default();
+    let mut pending = vec![(root.to_path_buf(), String::new())];
+
+    while let Some((dir, prefix)) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(name) => {
+                    warn!("Skipping {:?}, its name is not valid unicode", name);
+                    continue;
+                }
+            };
+            let relative_path = format!("{}{}", prefix, name);
+            let file_type = entry.file_type()?;
+
+            if excludes.is_excluded(&relative_path, file_type.is_dir()) {
+                scan.excluded += 1;
+                continue;
+            }
+
+            if file_type.is_dir() {
+                pending.push((entry.path(), format!("{}/", relative_path)));
+            } else if file_type.is_file() || entry.path().is_file() {
+                scan.files.push(entry.path());
+            }
+        }
+    }
+
+    scan.files.sort();
+
+    Ok(scan)
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod exclude_tests {
+        use std::fs;
+
+        use uuid::Uuid;
+
+        use crate::folder::{scan_folder, validate_exclude_pattern, ExcludePatterns};
+
+        #[test]
+        fn is_excluded_matches_names_paths_and_directories() {
+            let excludes =
+                ExcludePatterns::new(&["*.tmp", "node_modules/", ".*", "build/**/*.o", "?.log"])
+                    .unwrap();
+
+            assert!(excludes.is_excluded("notes.tmp", false));
+            assert!(excludes.is_excluded("deep/inside/notes.tmp", false));
+            assert!(excludes.is_excluded("app/node_modules", true));
+            assert!(!excludes.is_excluded("node_modules", false));
+            assert!(excludes.is_excluded(".git", true));
+            assert!(excludes.is_excluded("build/main.o", false));
+            assert!(excludes.is_excluded("build/a/b/main.o", false));
+            assert!(!excludes.is_excluded("src/build/main.o", false));
+            assert!(excludes.is_excluded("a.log", false));
+            assert!(!excludes.is_excluded("ab.log", false));
+            assert!(!excludes.is_excluded("notes.tmp.txt", false));
+        }
+
+        #[test]
+        fn validate_exclude_pattern_rejects_empty_patterns() {
+            for pattern in ["", "  ", "/", "a//b", "../secret"] {
+                assert!(
+                    validate_exclude_pattern(pattern).is_err(),
+                    "accepted {:?}",
+                    pattern
+                );
+            }
+
+            assert!(validate_exclude_pattern("\\build\\").is_ok());
+        }
+
+        #[test]
+        fn scan_folder_skips_excluded_entries() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(dir.join("src")).unwrap();
+            fs::create_dir_all(dir.join("node_modules/package")).unwrap();
+            for path in [
+                "readme.md",
+                "scratch.tmp",
+                "src/main.rs",
+                "src/backup.tmp",
+                "node_modules/package/index.js",
+            ] {
+                fs::write(dir.join(path), b"content").unwrap();
+            }
+
+            let excludes = ExcludePatterns::new(&["*.tmp", "node_modules/"]).unwrap();
+            let scan = scan_folder(&dir, &excludes).unwrap();
+
+            assert_eq!(
+                scan.files,
+                vec![dir.join("readme.md"), dir.join("src/main.rs")]
+            );
+            assert_eq!(scan.excluded, 3);
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
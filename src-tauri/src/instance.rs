@@ -0,0 +1,191 @@
+use std::{
+    fs::{self, File},
+    net::Ipv4Addr,
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use fs2::FileExt;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+use crate::{link::find_share_link, window::WindowResponse};
+
+/// Held by the running instance for as long as it runs.
+const LOCK_FILE: &str = "instance.lock";
+/// Port the running instance takes forwarded links on.
+const PORT_FILE: &str = "instance.port";
+/// A link is one line, anything longer isn't one.
+const MAX_FORWARDED_LENGTH: u64 = 512;
+/// The running instance may still be starting and not have written its port.
+const FORWARD_ATTEMPTS: u32 = 10;
+const FORWARD_RETRY_MILLIS: u64 = 200;
+
+/// Whether this process is the one running the app for its data directory.
+pub enum Instance {
+    Running {
+        listener: TcpListener,
+        _lock: File,
+    },
+    /// Another instance was running and has been passed the arguments.
+    Forwarded,
+}
+
+/// Makes this process the running instance for `dir`, unless one already
+/// runs. In that case the share link among `args`, if any, is passed to it
+/// and it brings its window up, since a link opened by the system would
+/// otherwise start a second copy of the app on the same data.
+pub async fn claim_instance<I>(dir: &Path, args: I) -> Result<Instance>
+where
+    I: IntoIterator<Item = String>,
+{
+    fs::create_dir_all(dir)?;
+    let lock = File::create(dir.join(LOCK_FILE))?;
+
+    if lock.try_lock_exclusive().is_err() {
+        let link = find_share_link(args);
+        forward(dir, link.map(|l| l.to_string()).unwrap_or_default()).await?;
+
+        return Ok(Instance::Forwarded);
+    }
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    fs::write(
+        dir.join(PORT_FILE),
+        listener.local_addr()?.port().to_string(),
+    )?;
+
+    Ok(Instance::Running {
+        listener,
+        _lock: lock,
+    })
+}
+
+async fn forward(dir: &Path, line: String) -> Result<()> {
+    for _ in 0..FORWARD_ATTEMPTS {
+        let port = fs::read_to_string(dir.join(PORT_FILE))
+            .ok()
+            .and_then(|port| port.trim().parse::<u16>().ok());
+
+        if let Some(port) = port {
+            if let Ok(mut stream) = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).await {
+                stream.write_all(format!("{}\n", line).as_bytes()).await?;
+
+                return Ok(());
+            }
+        }
+
+        tokio::time::sleep(Duration::from_millis(FORWARD_RETRY_MILLIS)).await;
+    }
+
+    Err(anyhow!("Could not reach the running instance"))
+}
+
+/// Takes links forwarded by later starts of the app. `show_window` is called
+/// for each of them, then the link is opened like one pasted into the window.
+pub async fn serve_forwarded<F>(
+    listener: TcpListener,
+    server: mpsc::Sender<WindowResponse>,
+    show_window: F,
+) where
+    F: Fn(),
+{
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("Could not accept forwarded link: {}", e);
+                continue;
+            }
+        };
+
+        let mut line = String::new();
+        let read = BufReader::new(stream.take(MAX_FORWARDED_LENGTH))
+            .read_line(&mut line)
+            .await;
+
+        if let Err(e) = read {
+            warn!("Could not read forwarded link: {}", e);
+            continue;
+        }
+
+        show_window();
+
+        let link = line.trim();
+        if link.is_empty() {
+            continue;
+        }
+
+        info!("Opening link {} from another start of the app", link);
+        let open = WindowResponse::OpenShareLink {
+            link: link.to_owned(),
+        };
+
+        if server.send(open).await.is_err() {
+            error!("Could not open link {}", link);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod instance_tests {
+        use std::time::Duration;
+
+        use tokio::sync::mpsc;
+        use uuid::Uuid;
+
+        use crate::{
+            instance::{claim_instance, serve_forwarded, Instance},
+            link::ShareLink,
+            window::WindowResponse,
+        };
+
+        #[tokio::test]
+        async fn second_instance_forwards_link_to_running_one() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            let link = ShareLink {
+                peer: Uuid::new_v4(),
+                directory_identifier: Uuid::new_v4(),
+                file_identifier: Uuid::new_v4(),
+            };
+
+            let (listener, lock) = match claim_instance(&dir, Vec::new()).await.unwrap() {
+                Instance::Running { listener, _lock } => (listener, _lock),
+                Instance::Forwarded => panic!("No instance should be running yet"),
+            };
+            let (sender, mut receiver) = mpsc::channel(4);
+            let (shown_sender, mut shown) = mpsc::unbounded_channel();
+            tokio::spawn(serve_forwarded(listener, sender, move || {
+                let _ = shown_sender.send(());
+            }));
+
+            let args = vec!["app".to_owned(), link.to_string()];
+            let second = claim_instance(&dir, args).await.unwrap();
+            assert!(matches!(second, Instance::Forwarded));
+
+            let forwarded = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+                .await
+                .unwrap();
+            assert!(matches!(
+                forwarded,
+                Some(WindowResponse::OpenShareLink { link: l }) if l == link.to_string()
+            ));
+            assert!(shown.recv().await.is_some());
+
+            // The next start runs the app again once the lock is released
+            drop(lock);
+            assert!(matches!(
+                claim_instance(&dir, Vec::new()).await.unwrap(),
+                Instance::Running { .. }
+            ));
+
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
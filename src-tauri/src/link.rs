@@ -0,0 +1,239 @@
+#[cfg(any(windows, target_os = "linux"))]
+use std::process::Command;
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, bail, Error, Result};
+use uuid::Uuid;
+
+/// URL scheme of links to shared files, as in
+/// `fileshare://<peer>/<directory>/<file>`.
+pub const LINK_SCHEME: &str = "fileshare";
+
+/// Links are pasted or passed in by other programs, so anything much longer
+/// than three ids is rejected before parsing.
+const MAX_LINK_LENGTH: usize = 256;
+
+/// Points to a file in a shared directory and the peer that has it, so
+/// another device in that directory can download the file right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShareLink {
+    pub peer: Uuid,
+    pub directory_identifier: Uuid,
+    pub file_identifier: Uuid,
+}
+
+impl fmt::Display for ShareLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}://{}/{}/{}",
+            LINK_SCHEME, self.peer, self.directory_identifier, self.file_identifier
+        )
+    }
+}
+
+impl FromStr for ShareLink {
+    type Err = Error;
+
+    fn from_str(link: &str) -> Result<Self, Self::Err> {
+        let link = link.trim();
+        if link.len() > MAX_LINK_LENGTH {
+            bail!("Link is too long");
+        }
+
+        let (scheme, rest) = link
+            .split_once("://")
+            .ok_or_else(|| anyhow!("Not a {} link", LINK_SCHEME))?;
+        if !scheme.eq_ignore_ascii_case(LINK_SCHEME) {
+            bail!("Not a {} link", LINK_SCHEME);
+        }
+
+        let ids = rest
+            .trim_end_matches('/')
+            .split('/')
+            .map(|part| Uuid::parse_str(part).ok().filter(|id| !id.is_nil()))
+            .collect::<Option<Vec<Uuid>>>()
+            .ok_or_else(|| anyhow!("Link contains an invalid identifier"))?;
+
+        match ids[..] {
+            [peer, directory_identifier, file_identifier] => Ok(Self {
+                peer,
+                directory_identifier,
+                file_identifier,
+            }),
+            _ => bail!("Link should name a peer, a directory and a file"),
+        }
+    }
+}
+
+/// Finds a link among command line arguments, which is how the system passes
+/// one in when a `fileshare://` link is opened.
+pub fn find_share_link<I>(args: I) -> Option<ShareLink>
+where
+    I: IntoIterator<Item = String>,
+{
+    args.into_iter().find_map(|arg| arg.parse().ok())
+}
+
+/// Makes the system open `fileshare://` links with this executable, passing
+/// the link as an argument. Done on every start so the entry follows the app
+/// when it's moved or updated.
+#[cfg(windows)]
+pub fn register_link_scheme() -> Result<()> {
+    let exe = std::env::current_exe()?;
+    let key = format!(r"HKCU\Software\Classes\{}", LINK_SCHEME);
+    let open_key = format!(r"{}\shell\open\command", key);
+    let open_command = format!("\"{}\" \"%1\"", exe.display());
+
+    run(Command::new("reg").args(["add", &key, "/ve", "/d", "URL:File share link", "/f"]))?;
+    run(Command::new("reg").args(["add", &key, "/v", "URL Protocol", "/d", "", "/f"]))?;
+    run(Command::new("reg").args(["add", &open_key, "/ve", "/d", &open_command, "/f"]))
+}
+
+/// Makes the system open `fileshare://` links with this executable, passing
+/// the link as an argument. Done on every start so the entry follows the app
+/// when it's moved or updated.
+#[cfg(target_os = "linux")]
+pub fn register_link_scheme() -> Result<()> {
+    let exe = std::env::current_exe()?;
+    let applications = std::env::var_os("XDG_DATA_HOME")
+        .map(std::path::PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".local/share"))
+        })
+        .ok_or_else(|| anyhow!("No home directory to register links in"))?
+        .join("applications");
+
+    std::fs::create_dir_all(&applications)?;
+    std::fs::write(applications.join(DESKTOP_FILE), desktop_entry(&exe))?;
+
+    run(Command::new("xdg-mime").args([
+        "default",
+        DESKTOP_FILE,
+        &format!("x-scheme-handler/{}", LINK_SCHEME),
+    ]))
+}
+
+/// macOS passes opened links as events rather than arguments, which this
+/// version of Tauri doesn't expose, so links stay paste-only there.
+#[cfg(not(any(windows, target_os = "linux")))]
+pub fn register_link_scheme() -> Result<()> {
+    bail!(
+        "Opening {} links isn't supported on this platform",
+        LINK_SCHEME
+    )
+}
+
+#[cfg(target_os = "linux")]
+const DESKTOP_FILE: &str = "fileshare-links.desktop";
+
+/// Hidden from menus, it only tells the system which program handles links.
+#[cfg(target_os = "linux")]
+fn desktop_entry(exe: &std::path::Path) -> String {
+    let exe = exe
+        .to_string_lossy()
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"");
+
+    format!(
+        "[Desktop Entry]\n\
+         Type=Application\n\
+         Name=File Share\n\
+         Exec=\"{}\" %u\n\
+         MimeType=x-scheme-handler/{};\n\
+         NoDisplay=true\n",
+        exe, LINK_SCHEME
+    )
+}
+
+#[cfg(any(windows, target_os = "linux"))]
+fn run(command: &mut Command) -> Result<()> {
+    let status = command.status()?;
+
+    if !status.success() {
+        bail!("{:?} exited with {}", command, status);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod link_tests {
+        use uuid::Uuid;
+
+        #[cfg(target_os = "linux")]
+        use crate::link::desktop_entry;
+        use crate::link::{find_share_link, ShareLink};
+
+        fn link() -> ShareLink {
+            ShareLink {
+                peer: Uuid::new_v4(),
+                directory_identifier: Uuid::new_v4(),
+                file_identifier: Uuid::new_v4(),
+            }
+        }
+
+        #[test]
+        fn share_link_round_trips_through_string() {
+            let link = link();
+            let text = link.to_string();
+
+            assert!(text.starts_with("fileshare://"));
+            assert_eq!(text.parse::<ShareLink>().unwrap(), link);
+            assert_eq!(
+                format!(" {}/\n", text.to_uppercase())
+                    .parse::<ShareLink>()
+                    .unwrap(),
+                link
+            );
+        }
+
+        #[test]
+        fn share_link_rejects_malformed_links() {
+            let link = link();
+            let ids = format!(
+                "{}/{}/{}",
+                link.peer, link.directory_identifier, link.file_identifier
+            );
+
+            for bad in [
+                ids.clone(),
+                format!("https://{}", ids),
+                format!("fileshare://{}/{}", link.peer, link.directory_identifier),
+                format!("fileshare://{}/extra", ids),
+                format!("fileshare://{}?download=1", ids),
+                format!("fileshare://../{}", ids),
+                format!(
+                    "fileshare://{}/{}/{}",
+                    link.peer,
+                    link.directory_identifier,
+                    Uuid::nil()
+                ),
+                format!("fileshare://{}{}", ids, "/".repeat(300)),
+            ] {
+                assert!(bad.parse::<ShareLink>().is_err(), "accepted {}", bad);
+            }
+        }
+
+        #[test]
+        fn find_share_link_skips_other_arguments() {
+            let link = link();
+            let args = vec!["app".to_owned(), "--flag".to_owned(), link.to_string()];
+
+            assert_eq!(find_share_link(args), Some(link));
+            assert_eq!(find_share_link(vec!["app".to_owned()]), None);
+        }
+
+        #[cfg(target_os = "linux")]
+        #[test]
+        fn desktop_entry_quotes_executable_and_handles_scheme() {
+            let entry = desktop_entry(std::path::Path::new("/opt/file share/app"));
+
+            assert!(entry.starts_with("[Desktop Entry]\n"));
+            assert!(entry.contains("\nExec=\"/opt/file share/app\" %u\n"));
+            assert!(entry.contains("\nMimeType=x-scheme-handler/fileshare;\n"));
+        }
+    }
+}
@@ -1,10 +1,11 @@
-use std::{net::Ipv4Addr, time::Duration};
+use std::{net::Ipv4Addr, sync::Arc, time::Duration};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use if_addrs::IfAddr;
 use tokio::{net::TcpListener, sync::mpsc};
 
 use crate::{
+    config::StoredConfig,
     mdns::MessageToMdns,
     server::{MessageToServer, ServerHandle},
 };
@@ -12,13 +13,53 @@ use crate::{
 pub async fn start_accept(
     send_addr: mpsc::Sender<MessageToMdns>,
     server_handle: ServerHandle,
+    config: Arc<StoredConfig>,
 ) -> Result<()> {
+    let mut reported_port = None;
+    let mut reported_interface = None;
+
     loop {
-        let intf = get_ipv4_intf();
-        if let Some(addr) = intf {
-            let bind_res = TcpListener::bind((addr, 0)).await;
+        let selection = config.get_network_interface().await;
+        let intf = get_ipv4_intf(&selection);
+
+        if let Some(missing) = intf.missing {
+            if reported_interface.as_deref() != Some(missing) {
+                reported_interface = Some(missing.to_owned());
+                warn!(
+                    "Network interface {:?} not found, using {:?} instead",
+                    missing, intf.address
+                );
+
+                let msg = MessageToServer::InterfaceNotFound {
+                    selection: missing.to_owned(),
+                    fallback: intf.address,
+                };
+                let _ = server_handle.channel.send(msg).await;
+            }
+        } else {
+            reported_interface = None;
+        }
+
+        if let Some(addr) = intf.address {
+            let listen_port = config.get_listen_port().await;
+            let bind_res = TcpListener::bind((addr, listen_port.unwrap_or(0))).await;
+
+            if let (Err(e), Some(port)) = (&bind_res, listen_port) {
+                if reported_port != Some(port) {
+                    error!("Could not listen on configured port {}: {}", port, e);
+                    reported_port = Some(port);
+
+                    let msg = MessageToServer::ListenFailed {
+                        port,
+                        reason: e.to_string(),
+                    };
+                    let _ = server_handle.channel.send(msg).await;
+                }
+            }
 
             if let Ok(tcp_listener) = bind_res {
+                reported_port = None;
+
                 let socket_addr = tcp_listener.local_addr();
 
                 if let Ok(socket_addr) = socket_addr {
@@ -32,23 +73,163 @@ pub async fn start_accept(
                         .await;
 
                     if let Ok(()) = send_res {
-                        while let Ok((tcp, ip)) = tcp_listener.accept().await {
-                            info!("Accepted connection from {}", ip);
+                        let changed = accept_until_listener_changes(
+                            &tcp_listener,
+                            &server_handle,
+                            &config,
+                            addr,
+                            listen_port,
+                        )
+                        .await;
 
-                            let msg = MessageToServer::ConnectionAccepted(tcp, ip);
-                            let _ = server_handle.channel.send(msg).await;
+                        if changed {
+                            continue;
                         }
                     }
                 }
             }
         }
 
-        let _ = tokio::time::interval(Duration::from_secs(5)).tick().await;
+        // Retried in a while, or right away once another port is chosen
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(LISTEN_RETRY_SECS)) => {}
+            _ = config.listener_changed() => {}
+        }
+    }
+}
+
+/// Accepts connections until accepting fails, the configured interface
+/// resolves to another address than `addr` or another port than
+/// `listen_port` is configured, so the listener can be bound again. Returns
+/// whether it stopped because of such a change, which is bound right away.
+async fn accept_until_listener_changes(
+    tcp_listener: &TcpListener,
+    server_handle: &ServerHandle,
+    config: &StoredConfig,
+    addr: Ipv4Addr,
+    listen_port: Option<u16>,
+) -> bool {
+    let mut check_interface = tokio::time::interval(Duration::from_secs(INTERFACE_CHECK_SECS));
+    check_interface.tick().await;
+
+    loop {
+        tokio::select! {
+            accepted = tcp_listener.accept() => match accepted {
+                Ok((tcp, ip)) => {
+                    info!("Accepted connection from {}", ip);
+
+                    let msg = MessageToServer::ConnectionAccepted(tcp, ip);
+                    let _ = server_handle.channel.send(msg).await;
+                }
+                Err(e) => {
+                    error!("Could not accept connection: {}", e);
+                    return false;
+                }
+            },
+            _ = check_interface.tick() => {
+                let selection = config.get_network_interface().await;
+                let current = get_ipv4_intf(&selection).address;
+
+                if current != Some(addr) {
+                    info!("Network interface changed from {} to {:?}", addr, current);
+                    return true;
+                }
+            }
+            _ = config.listener_changed() => {
+                let selection = config.get_network_interface().await;
+                let current = get_ipv4_intf(&selection).address;
+                let current_port = config.get_listen_port().await;
+
+                if current != Some(addr) || current_port != listen_port {
+                    info!(
+                        "Listener settings changed, listening on {:?} port {:?}",
+                        current, current_port
+                    );
+                    return true;
+                }
+            }
+        }
+    }
+}
+
+/// How often the listener checks whether the interface it is bound to is
+/// still the one to use.
+const INTERFACE_CHECK_SECS: u64 = 5;
+/// How long to wait before binding again after the listener failed or the
+/// configured port was taken.
+const LISTEN_RETRY_SECS: u64 = 5;
+
+/// Lets the first IPv4 interface that isn't a loopback be used.
+pub const AUTO_INTERFACE: &str = "auto";
+
+/// Which interface to listen on, and so which address is advertised over
+/// mDNS. Picking the first one often finds a VPN tunnel or a virtual bridge
+/// on machines with several networks, which peers on the LAN can't reach.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InterfaceSelection {
+    Auto,
+    /// An interface name, e.g. `eth0` or `Wi-Fi`.
+    Name(String),
+    /// The first interface with an address in a subnet, e.g.
+    /// `192.168.1.0/24`.
+    Subnet {
+        network: Ipv4Addr,
+        prefix: u8,
+    },
+}
+
+impl InterfaceSelection {
+    pub fn parse(selection: &str) -> Result<Self> {
+        let selection = selection.trim();
+
+        if selection.is_empty() || selection.eq_ignore_ascii_case(AUTO_INTERFACE) {
+            return Ok(Self::Auto);
+        }
+
+        match selection.split_once('/') {
+            None => Ok(Self::Name(selection.to_owned())),
+            Some((network, prefix)) => {
+                let network: Ipv4Addr = network
+                    .trim()
+                    .parse()
+                    .map_err(|_| anyhow!("{:?} is not an IPv4 address", network))?;
+                let prefix: u8 = prefix
+                    .trim()
+                    .parse()
+                    .map_err(|_| anyhow!("{:?} is not a prefix length", prefix))?;
+
+                if prefix > 32 {
+                    bail!("Prefix length {} is longer than 32", prefix);
+                }
+
+                Ok(Self::Subnet { network, prefix })
+            }
+        }
+    }
+
+    fn matches(&self, name: &str, ip: Ipv4Addr) -> bool {
+        match self {
+            Self::Auto => true,
+            Self::Name(selected) => selected == name,
+            Self::Subnet { network, prefix } => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(*prefix)).unwrap_or(0);
+
+                u32::from(ip) & mask == u32::from(*network) & mask
+            }
+        }
     }
 }
 
-fn get_ipv4_intf() -> Option<Ipv4Addr> {
-    if_addrs::get_if_addrs()
+/// The address to listen on, and the selection it replaces if the
+/// configured interface wasn't found.
+#[derive(Debug, PartialEq, Eq)]
+struct SelectedInterface<'a> {
+    address: Option<Ipv4Addr>,
+    missing: Option<&'a str>,
+}
+
+fn get_ipv4_intf(selection: &str) -> SelectedInterface<'_> {
+    let interfaces: Vec<(String, Ipv4Addr)> = if_addrs::get_if_addrs()
         .expect("should be able to get IP interfaces")
         .into_iter()
         .filter_map(|intf| {
@@ -56,11 +237,135 @@ fn get_ipv4_intf() -> Option<Ipv4Addr> {
                 None
             } else {
                 match intf.addr {
-                    IfAddr::V4(ifv4) => Some(ifv4),
+                    IfAddr::V4(ifv4) => Some((intf.name, ifv4.ip)),
                     _ => None,
                 }
             }
         })
-        .map(|intf| intf.ip)
-        .next()
+        .collect();
+
+    select_interface(&interfaces, selection)
+}
+
+/// Falls back to the first interface if `selection` is invalid or matches
+/// none of `interfaces`.
+fn select_interface<'a>(
+    interfaces: &[(String, Ipv4Addr)],
+    selection: &'a str,
+) -> SelectedInterface<'a> {
+    let first = interfaces.first().map(|(_, ip)| *ip);
+    let parsed = match InterfaceSelection::parse(selection) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            warn!("Ignoring network interface {:?}: {}", selection, e);
+            InterfaceSelection::Auto
+        }
+    };
+
+    if parsed == InterfaceSelection::Auto {
+        return SelectedInterface {
+            address: first,
+            missing: None,
+        };
+    }
+
+    let selected = interfaces
+        .iter()
+        .find(|(name, ip)| parsed.matches(name, *ip))
+        .map(|(_, ip)| *ip);
+
+    match selected {
+        Some(address) => SelectedInterface {
+            address: Some(address),
+            missing: None,
+        },
+        None => SelectedInterface {
+            address: first,
+            missing: Some(selection),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod interface_tests {
+        use std::net::Ipv4Addr;
+
+        use crate::listen::{select_interface, InterfaceSelection};
+
+        fn interfaces() -> Vec<(String, Ipv4Addr)> {
+            vec![
+                ("docker0".to_owned(), Ipv4Addr::new(172, 17, 0, 1)),
+                ("tun0".to_owned(), Ipv4Addr::new(10, 8, 0, 2)),
+                ("eth0".to_owned(), Ipv4Addr::new(192, 168, 1, 20)),
+            ]
+        }
+
+        #[test]
+        fn parse_accepts_auto_names_and_subnets() {
+            assert_eq!(
+                InterfaceSelection::parse(" Auto ").unwrap(),
+                InterfaceSelection::Auto
+            );
+            assert_eq!(
+                InterfaceSelection::parse("").unwrap(),
+                InterfaceSelection::Auto
+            );
+            assert_eq!(
+                InterfaceSelection::parse("eth0").unwrap(),
+                InterfaceSelection::Name("eth0".to_owned())
+            );
+            assert_eq!(
+                InterfaceSelection::parse("192.168.1.0/24").unwrap(),
+                InterfaceSelection::Subnet {
+                    network: Ipv4Addr::new(192, 168, 1, 0),
+                    prefix: 24
+                }
+            );
+
+            for invalid in [
+                "192.168.1.0/33",
+                "192.168.1/24",
+                "192.168.1.0/",
+                "fe80::/64",
+            ] {
+                assert!(
+                    InterfaceSelection::parse(invalid).is_err(),
+                    "accepted {}",
+                    invalid
+                );
+            }
+        }
+
+        #[test]
+        fn select_interface_prefers_configured_interface() {
+            let interfaces = interfaces();
+
+            let auto = select_interface(&interfaces, "auto");
+            assert_eq!(auto.address, Some(Ipv4Addr::new(172, 17, 0, 1)));
+
+            let by_name = select_interface(&interfaces, "eth0");
+            assert_eq!(by_name.address, Some(Ipv4Addr::new(192, 168, 1, 20)));
+            assert_eq!(by_name.missing, None);
+
+            let by_subnet = select_interface(&interfaces, "10.0.0.0/8");
+            assert_eq!(by_subnet.address, Some(Ipv4Addr::new(10, 8, 0, 2)));
+
+            let everything = select_interface(&interfaces, "0.0.0.0/0");
+            assert_eq!(everything.address, Some(Ipv4Addr::new(172, 17, 0, 1)));
+        }
+
+        #[test]
+        fn select_interface_falls_back_when_interface_is_missing() {
+            let interfaces = interfaces();
+
+            let missing = select_interface(&interfaces, "wlan0");
+            assert_eq!(missing.address, Some(Ipv4Addr::new(172, 17, 0, 1)));
+            assert_eq!(missing.missing, Some("wlan0"));
+
+            let no_interfaces = select_interface(&[], "eth0");
+            assert_eq!(no_interfaces.address, None);
+        }
+    }
 }
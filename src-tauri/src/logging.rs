@@ -0,0 +1,152 @@
+use std::sync::{Arc, RwLock};
+
+use anyhow::{bail, Result};
+use log::{LevelFilter, Log, Metadata, Record};
+use pretty_env_logger::env_logger::Logger;
+
+/// Overrides the filter saved in the config, for running from a terminal.
+const LOG_ENV: &str = "RUST_LOG";
+
+/// Filters use the `RUST_LOG` syntax: an optional default level followed by
+/// levels for single modules, e.g. `warn,app::mdns=debug,app::client=info`.
+pub const DEFAULT_LOG_FILTER: &str = "error";
+
+/// Changes the log filter while the app runs.
+#[derive(Clone)]
+pub struct LogHandle {
+    logger: Arc<RwLock<Logger>>,
+}
+
+impl LogHandle {
+    pub fn set_filter(&self, filter: &str) -> Result<()> {
+        validate_log_filter(filter)?;
+
+        let logger = build_logger(filter);
+        log::set_max_level(logger.filter());
+        if let Ok(mut current) = self.logger.write() {
+            *current = logger;
+        }
+
+        Ok(())
+    }
+
+    /// Applies the filter saved in the config, unless `RUST_LOG` is set.
+    pub fn use_configured_filter(&self, filter: &str) {
+        if std::env::var_os(LOG_ENV).is_some() {
+            return;
+        }
+
+        if let Err(e) = self.set_filter(filter) {
+            warn!("Ignoring saved log filter {:?}: {}", filter, e);
+        }
+    }
+}
+
+/// Forwards records to a logger that `LogHandle` can replace.
+struct ReloadableLogger {
+    logger: Arc<RwLock<Logger>>,
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match self.logger.read() {
+            Ok(logger) => logger.enabled(metadata),
+            Err(_) => false,
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if let Ok(logger) = self.logger.read() {
+            logger.log(record);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the logger, filtered by `RUST_LOG` or the default filter until
+/// the config is loaded.
+pub fn init_logging() -> LogHandle {
+    let filter = std::env::var(LOG_ENV).unwrap_or_else(|_| DEFAULT_LOG_FILTER.to_owned());
+    let logger = build_logger(&filter);
+    let max_level = logger.filter();
+    let logger = Arc::new(RwLock::new(logger));
+
+    log::set_boxed_logger(Box::new(ReloadableLogger {
+        logger: logger.clone(),
+    }))
+    .expect("logger should only be installed once");
+    log::set_max_level(max_level);
+
+    LogHandle { logger }
+}
+
+fn build_logger(filter: &str) -> Logger {
+    pretty_env_logger::formatted_builder()
+        .parse_filters(filter)
+        .build()
+}
+
+/// `env_logger` skips directives it can't parse, and treats a misspelled
+/// level as a module name, so filters are checked before they are applied.
+pub fn validate_log_filter(filter: &str) -> Result<()> {
+    for directive in filter.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        match directive.split_once('=') {
+            None if directive.parse::<LevelFilter>().is_ok() => (),
+            None => bail!("{:?} is not a log level", directive),
+            Some((module, level)) => {
+                if !is_module_path(module.trim()) {
+                    bail!("{:?} is not a module name", module);
+                }
+
+                if level.trim().parse::<LevelFilter>().is_err() {
+                    bail!("{:?} is not a log level", level);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn is_module_path(module: &str) -> bool {
+    !module.is_empty()
+        && module
+            .split("::")
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_alphanumeric() || c == '_'))
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod filter_tests {
+        use crate::logging::{validate_log_filter, DEFAULT_LOG_FILTER};
+
+        #[test]
+        fn validate_log_filter_accepts_levels_per_module() {
+            for filter in [
+                DEFAULT_LOG_FILTER,
+                "",
+                "WARN",
+                "warn,app::mdns=debug,app::client=info",
+                " info , mdns_sd=off ",
+            ] {
+                assert!(validate_log_filter(filter).is_ok(), "rejected {}", filter);
+            }
+        }
+
+        #[test]
+        fn validate_log_filter_rejects_typos() {
+            for filter in [
+                "debgu",
+                "app::mdns=loud",
+                "=debug",
+                "app::=debug",
+                "app mdns=debug",
+                "app::mdns=debug=info",
+            ] {
+                assert!(validate_log_filter(filter).is_err(), "accepted {}", filter);
+            }
+        }
+    }
+}
@@ -10,47 +10,134 @@ extern crate log;
 pub mod client;
 pub mod config;
 pub mod data;
+pub mod encryption;
+pub mod events;
+pub mod folder;
+pub mod instance;
+pub mod link;
 pub mod listen;
+pub mod logging;
 pub mod mdns;
 pub mod server;
+pub mod stream;
 pub mod window;
 
 use std::sync::Arc;
 
-use config::{load_stored_data, save_config_loop, write_stored_data};
+use client::{clean_download_directory, remove_partial_downloads};
+use config::{app_dirs, load_stored_data, save_config_loop, write_stored_data};
+use encryption::remove_decrypted_files;
+use events::{bind_event_stream, serve_events, EventStream, SocketWindowManager};
+use instance::{claim_instance, serve_forwarded, Instance};
+use link::{find_share_link, register_link_scheme};
 use listen::start_accept;
+use logging::init_logging;
 use mdns::{start_mdns, MessageToMdns};
 use server::{server_loop, MessageToServer, ServerHandle};
+use stream::{serve_streams, MediaStreams};
 use tauri::{async_runtime::Mutex, CustomMenuItem, Manager, SystemTray, SystemTrayMenu};
-use tokio::sync::mpsc;
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, oneshot},
+};
 use window::{
-    commands::{get_settings, network_command, open_file, save_settings, Window},
-    MainWindowManager, WindowResponse,
+    commands::{
+        get_app_directories, get_directory, get_directory_fingerprint, get_exclude_patterns,
+        get_log_filter, get_network_interface, get_settings, get_share_link, get_themes,
+        network_command, open_file, save_settings, set_exclude_patterns, set_log_filter,
+        set_network_interface, unlock_encryption, Window,
+    },
+    format_size, MainWindowManager, WindowManager, WindowResponse,
 };
 use window_shadows::set_shadow;
 
+/// Queue size for control messages between the window, server, mDNS and
+/// client tasks. These are low volume, so the queue only needs to absorb
+/// bursts such as many peers connecting at once.
 const THREAD_CHANNEL_SIZE: usize = 64;
+/// Queue size for download and verification progress. Updates beyond this
+/// are dropped rather than blocking transfers, see `ServerHandle::report_progress`.
+const PROGRESS_CHANNEL_SIZE: usize = 256;
 const MAIN_WINDOW_LABEL: &str = "main";
 
 fn main() {
-    pretty_env_logger::init();
+    let log_handle = init_logging();
+
+    // Links opened while the app runs start it again, which only passes the
+    // link on to this instance
+    let instance = tauri::async_runtime::block_on(claim_instance(
+        &app_dirs().config_dir,
+        std::env::args().skip(1),
+    ));
+    // The lock is held until the process exits
+    let (instance_listener, _instance_lock) = match instance {
+        Ok(Instance::Running { listener, _lock }) => (Some(listener), Some(_lock)),
+        Ok(Instance::Forwarded) => {
+            info!("App is already running, passed the arguments on to it");
+            return;
+        }
+        Err(e) => {
+            error!("Could not check for a running instance: {}", e);
+            (None, None)
+        }
+    };
+
+    tauri::async_runtime::spawn_blocking(|| {
+        if let Err(e) = register_link_scheme() {
+            warn!("Could not register {} links: {}", link::LINK_SCHEME, e);
+        }
+    });
 
     let (conf, id) = load_stored_data();
     let stored_data = Arc::new(conf);
+    log_handle.use_configured_filter(&tauri::async_runtime::block_on(
+        stored_data.get_log_filter(),
+    ));
+    tauri::async_runtime::block_on(stored_data.repair_directories(&id));
+    tauri::async_runtime::block_on(remove_partial_downloads(stored_data.clone()));
+    tauri::async_runtime::block_on(remove_decrypted_files());
+    let cleanup = tauri::async_runtime::block_on(clean_download_directory(stored_data.clone()));
+
+    let event_listener = tauri::async_runtime::block_on(async {
+        let port = stored_data.get_event_stream_port().await?;
+
+        match bind_event_stream(port).await {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                error!("Could not start event stream on port {}: {}", port, e);
+                None
+            }
+        }
+    });
+
+    let stream_listener = tauri::async_runtime::block_on(TcpListener::bind("127.0.0.1:0"))
+        .expect("should be able to listen for media streams");
+    let streams = Arc::new(MediaStreams::new(
+        stream_listener
+            .local_addr()
+            .expect("media stream listener should have an address")
+            .port(),
+    ));
 
     let (network_sender, network_receiver) = mpsc::channel::<WindowResponse>(THREAD_CHANNEL_SIZE);
+    let link_sender = network_sender.clone();
     let (mdns_sender, mdns_receiver) = mpsc::channel::<MessageToMdns>(THREAD_CHANNEL_SIZE);
     let (server_sender, server_receiver) = mpsc::channel::<MessageToServer>(THREAD_CHANNEL_SIZE);
+    let (progress_sender, progress_receiver) =
+        mpsc::channel::<MessageToServer>(PROGRESS_CHANNEL_SIZE);
 
     let server_handle = ServerHandle {
         channel: server_sender,
+        progress: progress_sender,
         peer_id: id.clone(),
+        metrics: Arc::default(),
     };
 
     let exit = CustomMenuItem::new("exit".to_string(), "Exit");
     let system_tray_menu = SystemTrayMenu::new().add_item(exit);
     let system_tray = SystemTray::new().with_menu(system_tray_menu);
 
+    let exit_server = server_handle.clone();
     let exit_config = stored_data.clone();
     let window_config = stored_data.clone();
     let loop_config = stored_data.clone();
@@ -88,6 +175,7 @@ fn main() {
             server: Mutex::new(network_sender),
         })
         .manage(settings_config)
+        .manage(log_handle)
         .on_window_event(move |event| match event.event() {
             tauri::WindowEvent::CloseRequested { api, .. } => {
                 let settings = tauri::async_runtime::block_on(window_config.get_settings());
@@ -103,6 +191,13 @@ fn main() {
                 }
             }
             tauri::WindowEvent::Destroyed => {
+                let (done_sender, done_receiver) = oneshot::channel();
+                let shutdown = MessageToServer::Shutdown(done_sender);
+
+                if exit_server.channel.blocking_send(shutdown).is_ok() {
+                    let _ = done_receiver.blocking_recv();
+                }
+
                 write_stored_data(&exit_config);
             }
             _ => {}
@@ -111,7 +206,19 @@ fn main() {
             network_command,
             open_file,
             save_settings,
-            get_settings
+            get_settings,
+            get_themes,
+            get_app_directories,
+            unlock_encryption,
+            get_share_link,
+            get_directory,
+            get_directory_fingerprint,
+            get_log_filter,
+            set_log_filter,
+            get_exclude_patterns,
+            set_exclude_patterns,
+            get_network_interface,
+            set_network_interface
         ])
         .setup(move |app| {
             let window = app
@@ -122,26 +229,85 @@ fn main() {
                 warn!("Could not set shadows: {}", e)
             }
 
-            tauri::async_runtime::spawn(start_accept(mdns_sender.clone(), server_handle.clone()));
+            tauri::async_runtime::spawn(start_accept(
+                mdns_sender.clone(),
+                server_handle.clone(),
+                stored_data.clone(),
+            ));
             tauri::async_runtime::spawn(start_mdns(
                 mdns_receiver,
                 server_handle.clone(),
                 id.clone(),
+                stored_data.clone(),
             ));
 
             let app_handle = app.handle();
-            let window_manager = MainWindowManager {
-                app_handle,
-                window_label: MAIN_WINDOW_LABEL,
+            let events = event_listener.map(|listener| {
+                let events = EventStream::new();
+                tauri::async_runtime::spawn(serve_events(listener, events.clone()));
+
+                events
+            });
+            let window_manager = SocketWindowManager {
+                window_manager: MainWindowManager {
+                    app_handle,
+                    window_label: MAIN_WINDOW_LABEL,
+                },
+                events,
             };
+
+            if cleanup.removed_files > 0 {
+                let body = format!(
+                    "Removed {} leftover file(s) from the download folder, freeing {}.",
+                    cleanup.removed_files,
+                    format_size(cleanup.freed_bytes)
+                );
+
+                if let Err(e) = window_manager.notify("Download folder cleaned up", &body) {
+                    warn!("Could not show cleanup notification: {}", e);
+                }
+            }
             tauri::async_runtime::spawn(server_loop(
                 window_manager,
                 server_receiver,
+                progress_receiver,
                 network_receiver,
                 mdns_sender,
                 server_handle.clone(),
                 stored_data.clone(),
+                streams.clone(),
             ));
+            tauri::async_runtime::spawn(serve_streams(stream_listener, streams));
+
+            if let Some(listener) = instance_listener {
+                let app_handle = app.handle();
+                let show_window = move || {
+                    if let Some(window) = app_handle.get_window(MAIN_WINDOW_LABEL) {
+                        if let Err(e) = window.show().and_then(|_| window.set_focus()) {
+                            error!("Could not show window: {}", e);
+                        }
+                    }
+                };
+
+                tauri::async_runtime::spawn(serve_forwarded(
+                    listener,
+                    link_sender.clone(),
+                    show_window,
+                ));
+            }
+
+            // Started by opening a link to a shared file
+            if let Some(link) = find_share_link(std::env::args().skip(1)) {
+                tauri::async_runtime::spawn(async move {
+                    let open = WindowResponse::OpenShareLink {
+                        link: link.to_string(),
+                    };
+
+                    if link_sender.send(open).await.is_err() {
+                        error!("Could not open link {}", link);
+                    }
+                });
+            }
 
             tauri::async_runtime::spawn(save_config_loop(loop_config));
 
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, net::SocketAddrV4, time::Duration};
+use std::{collections::HashMap, net::SocketAddrV4, sync::Arc};
 
 use anyhow::Result;
 use chrono::{DateTime, Utc};
@@ -6,19 +6,33 @@ use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
 use tokio::sync::mpsc;
 
 use crate::{
+    config::StoredConfig,
     data::PeerId,
     server::{MessageToServer, ServerHandle},
 };
 
 pub const SERVICE_TYPE: &str = "_ktu_fileshare._tcp.local.";
-pub const MDNS_UPDATE_TIME: u64 = 15;
-pub const RECONNECT_TIME: i64 = 15;
+
+/// Longest DNS label. Both our instance name and host name must fit in one.
+const MAX_LABEL_LEN: usize = 63;
 
 #[derive(Debug)]
 pub enum MessageToMdns {
     RemoveService(ServiceInfo),
     ConnectedService(ServiceInfo),
     SwitchedNetwork(SocketAddrV4),
+    Rescan,
+    /// Stops advertising and browsing while the network is paused.
+    SetPaused(bool),
+    /// Stops advertising and browsing so new peers can't find us, while
+    /// known peers are still reconnected to.
+    SetDiscoverable(bool),
+    /// Stops reconnecting to a peer whose connection was closed for being
+    /// idle.
+    IdlePeer(PeerId),
+    /// Reconnects right away to idle or disconnected peers that are needed
+    /// again.
+    Reconnect(Vec<PeerId>),
 }
 
 pub struct ResolvedServiceInfo {
@@ -29,28 +43,107 @@ pub struct ResolvedServiceInfo {
 pub enum ServiceStatus {
     Disconnected(DateTime<Utc>),
     Connected,
+    Idle,
+}
+
+/// Extracts the peer id from a full service name such as
+/// `host;uuid._ktu_fileshare._tcp.local.`.
+pub fn parse_service_peer_id(fullname: &str) -> Option<PeerId> {
+    let instance = fullname.strip_suffix(SERVICE_TYPE)?.strip_suffix('.')?;
+
+    PeerId::parse(instance)
+}
+
+/// Our peer id with a hostname that is safe to put in service and host names:
+/// only ASCII letters, digits and `-`, short enough for the uuid to fit in a
+/// label too. Peers are compared by uuid, so the hostname can change freely.
+fn advertised_peer_id(peer_id: &PeerId) -> PeerId {
+    // Room left next to the uuid and its separator
+    let max_hostname_len = MAX_LABEL_LEN - peer_id.uuid.to_string().len() - 1;
+
+    let hostname: String = peer_id
+        .hostname
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .take(max_hostname_len)
+        .collect();
+    let hostname = hostname.trim_matches('-');
+
+    PeerId {
+        hostname: if hostname.is_empty() {
+            "peer"
+        } else {
+            hostname
+        }
+        .to_owned(),
+        uuid: peer_id.uuid,
+    }
+}
+
+/// Creates the service advertising us at `addr`, logging why it couldn't be.
+fn create_service(peer_id: &PeerId, addr: SocketAddrV4) -> Option<ServiceInfo> {
+    let advertised = advertised_peer_id(peer_id);
+    let my_name = advertised.to_string();
+    let host_name = format!("{}-{}.local.", advertised.hostname, advertised.uuid);
+
+    match ServiceInfo::new(
+        SERVICE_TYPE,
+        &my_name,
+        &host_name,
+        *addr.ip(),
+        addr.port(),
+        None,
+    ) {
+        Ok(service) => Some(service),
+        Err(e) => {
+            error!("Could not create mDNS service {}: {}", my_name, e);
+            None
+        }
+    }
+}
+
+/// Checks whether a service was advertised by us. Our hostname is only known
+/// once our own service is registered, so the peer id in the service name is
+/// compared too.
+fn is_own_service(
+    fullname: &str,
+    hostname: &str,
+    peer_id: &PeerId,
+    my_hostname: &Option<String>,
+) -> bool {
+    let same_peer = parse_service_peer_id(fullname).map_or(false, |p| p.uuid == peer_id.uuid);
+    let same_host = my_hostname.as_deref() == Some(hostname);
+
+    same_peer || same_host
 }
 
 pub async fn start_mdns(
     mut recv: mpsc::Receiver<MessageToMdns>,
     server_handle: ServerHandle,
     peer_id: PeerId,
+    config: Arc<StoredConfig>,
 ) -> Result<()> {
-    let mut fullname: Option<String> = None;
+    let mut registered: Option<ServiceInfo> = None;
+    // Address to advertise once creating the service stops failing
+    let mut unregistered_addr: Option<SocketAddrV4> = None;
     let mut my_hostname: Option<String> = None;
+    let mut paused = false;
+    let mut discoverable = true;
     let mdns = ServiceDaemon::new().expect("should be able to create mDNS daemon");
 
-    let service_receiver = mdns.browse(SERVICE_TYPE).expect("should start mDNS browse");
+    let mut service_receiver = mdns.browse(SERVICE_TYPE).expect("should start mDNS browse");
 
-    let reconnect_time = chrono::Duration::seconds(RECONNECT_TIME);
-    let mut reconnect_interval = tokio::time::interval(Duration::from_secs(MDNS_UPDATE_TIME));
+    let timing = config.get_timing().await;
+    let reconnect_time = chrono::Duration::from_std(timing.reconnect_after())
+        .expect("reconnect time should be clamped to a small duration");
+    let mut reconnect_interval = tokio::time::interval(timing.mdns_update_interval());
     let mut resolved_services: HashMap<String, ResolvedServiceInfo> = HashMap::new();
 
     loop {
         tokio::select! {
-            event = service_receiver.recv_async() => {
+            event = service_receiver.recv_async(), if !paused && discoverable => {
                 match event {
-                    Ok(ev) => handle_mdns_event(&ev, &server_handle, &my_hostname, &mut resolved_services).await,
+                    Ok(ev) => handle_mdns_event(&ev, &server_handle, &peer_id, &my_hostname, &mut resolved_services).await,
                     Err(err) => error!("Event received was error: {}", err)
                 }
             }
@@ -83,40 +176,163 @@ pub async fn start_mdns(
                     }
 
                     MessageToMdns::SwitchedNetwork(new_addr) => {
-                        let ip = new_addr.ip();
-                        let port = new_addr.port();
-                        let my_name = peer_id.to_string();
-                        let host_name = my_name.clone() + ".local.";
+                        if let Some(previous_service) = registered.take() {
+                            let _ = mdns.unregister(previous_service.get_fullname());
+                        }
 
-                        let service = ServiceInfo::new(
-                            SERVICE_TYPE, &my_name, &host_name, ip, port, None
-                        ).unwrap();
+                        unregistered_addr = Some(new_addr);
+                        if let Some(service) = create_service(&peer_id, new_addr) {
+                            unregistered_addr = None;
+                            my_hostname = Some(service.get_hostname().to_string());
+                            registered = Some(service.clone());
 
-                        if let Some(previous_service) = fullname {
-                            let _ = mdns.unregister(&previous_service);
+                            if !paused && discoverable {
+                                let _ = mdns.register(service);
+                            }
                         }
+                    }
 
-                        my_hostname = Some(service.get_hostname().to_string());
-                        fullname = Some(service.get_fullname().to_string());
+                    MessageToMdns::Rescan => {
+                        info!("Rescanning network");
 
-                        let _ = mdns.register(service);
+                        // Browsing stays off while hidden from new peers
+                        if discoverable {
+                            let _ = mdns.stop_browse(SERVICE_TYPE);
+                            match mdns.browse(SERVICE_TYPE) {
+                                Ok(receiver) => service_receiver = receiver,
+                                Err(e) => error!("Could not restart mDNS browse: {}", e),
+                            }
+                        }
+
+                        reconnect_services(&resolved_services, &server_handle, chrono::Duration::zero()).await;
+                    }
+
+                    MessageToMdns::SetPaused(pause) if pause != paused => {
+                        paused = pause;
+
+                        if paused {
+                            info!("Pausing discovery");
+
+                            if discoverable {
+                                if let Some(service) = &registered {
+                                    let _ = mdns.unregister(service.get_fullname());
+                                }
+                                let _ = mdns.stop_browse(SERVICE_TYPE);
+                            }
+                        } else {
+                            info!("Resuming discovery");
+
+                            if discoverable {
+                                if let Some(service) = &registered {
+                                    let _ = mdns.register(service.clone());
+                                }
+                                match mdns.browse(SERVICE_TYPE) {
+                                    Ok(receiver) => service_receiver = receiver,
+                                    Err(e) => error!("Could not restart mDNS browse: {}", e),
+                                }
+                            }
+
+                            reconnect_services(&resolved_services, &server_handle, chrono::Duration::zero()).await;
+                        }
+                    }
+
+                    MessageToMdns::SetPaused(_) => (),
+
+                    MessageToMdns::SetDiscoverable(visible) if visible != discoverable => {
+                        discoverable = visible;
+
+                        // Discovery stays off until the network is resumed
+                        if !paused {
+                            if discoverable {
+                                info!("Advertising service again");
+
+                                if let Some(service) = &registered {
+                                    let _ = mdns.register(service.clone());
+                                }
+                                match mdns.browse(SERVICE_TYPE) {
+                                    Ok(receiver) => service_receiver = receiver,
+                                    Err(e) => error!("Could not restart mDNS browse: {}", e),
+                                }
+                            } else {
+                                info!("Hiding service from new peers");
+
+                                if let Some(service) = &registered {
+                                    let _ = mdns.unregister(service.get_fullname());
+                                }
+                                let _ = mdns.stop_browse(SERVICE_TYPE);
+                            }
+                        }
+                    }
+
+                    MessageToMdns::SetDiscoverable(_) => (),
+
+                    MessageToMdns::IdlePeer(peer) => {
+                        for (fullname, service) in resolved_services.iter_mut() {
+                            if parse_service_peer_id(fullname).as_ref() == Some(&peer) {
+                                service.status = ServiceStatus::Idle;
+                            }
+                        }
+                    }
+
+                    MessageToMdns::Reconnect(peers) => {
+                        for (fullname, service) in resolved_services.iter_mut() {
+                            let is_connected = matches!(service.status, ServiceStatus::Connected);
+                            let wanted = parse_service_peer_id(fullname).map_or(false, |peer| peers.contains(&peer));
+
+                            if !is_connected && wanted {
+                                info!("Reconnecting to service {}", fullname);
+                                service.status = ServiceStatus::Disconnected(Utc::now());
+
+                                let _ = server_handle
+                                    .channel
+                                    .send(MessageToServer::ServiceFound(service.service_info.clone()))
+                                    .await;
+                            }
+                        }
                     }
                 }
             }
-            _ = reconnect_interval.tick() => {
-                for (_, rsv) in resolved_services.iter() {
-                    match rsv.status {
-                        ServiceStatus::Connected => (),
-                        ServiceStatus::Disconnected(disconnect_time) => {
-                            let current_time = Utc::now();
-                            let time_diff = current_time - disconnect_time;
+            _ = reconnect_interval.tick(), if !paused => {
+                if let Some(addr) = unregistered_addr {
+                    if let Some(service) = create_service(&peer_id, addr) {
+                        info!("Advertising service after earlier failure");
 
-                            if time_diff >= reconnect_time {
-                                let _ = server_handle.channel.send(MessageToServer::ServiceFound(rsv.service_info.clone())).await;
-                            }
+                        unregistered_addr = None;
+                        my_hostname = Some(service.get_hostname().to_string());
+                        registered = Some(service.clone());
+
+                        if discoverable {
+                            let _ = mdns.register(service);
                         }
                     }
                 }
+
+                reconnect_services(&resolved_services, &server_handle, reconnect_time).await;
+            }
+        }
+    }
+}
+
+/// Asks the server to reconnect to services that have been disconnected for
+/// at least `min_disconnected`. Idle services are left alone until needed.
+async fn reconnect_services(
+    resolved_services: &HashMap<String, ResolvedServiceInfo>,
+    server_handle: &ServerHandle,
+    min_disconnected: chrono::Duration,
+) {
+    for (_, rsv) in resolved_services.iter() {
+        match rsv.status {
+            ServiceStatus::Connected | ServiceStatus::Idle => (),
+            ServiceStatus::Disconnected(disconnect_time) => {
+                let current_time = Utc::now();
+                let time_diff = current_time - disconnect_time;
+
+                if time_diff >= min_disconnected {
+                    let _ = server_handle
+                        .channel
+                        .send(MessageToServer::ServiceFound(rsv.service_info.clone()))
+                        .await;
+                }
             }
         }
     }
@@ -125,16 +341,20 @@ pub async fn start_mdns(
 async fn handle_mdns_event(
     event: &ServiceEvent,
     server_handle: &ServerHandle,
+    peer_id: &PeerId,
     my_hostname: &Option<String>,
     resolved_services: &mut HashMap<String, ResolvedServiceInfo>,
 ) {
     if let ServiceEvent::ServiceResolved(service) = event {
         info!("Resolved service {:?}", service);
 
-        if let Some(hostname) = my_hostname {
-            if service.get_hostname() == hostname {
-                return;
-            }
+        if is_own_service(
+            service.get_fullname(),
+            service.get_hostname(),
+            peer_id,
+            my_hostname,
+        ) {
+            return;
         }
 
         let existing_service = resolved_services.get(service.get_fullname());
@@ -151,3 +371,125 @@ async fn handle_mdns_event(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    mod service_name_tests {
+        use uuid::Uuid;
+
+        use crate::mdns::parse_service_peer_id;
+
+        #[test]
+        fn parse_service_peer_id_given_full_name_returns_peer() {
+            let uuid = Uuid::new_v4();
+            let fullname = format!("my-host;{}._ktu_fileshare._tcp.local.", uuid);
+
+            let peer = parse_service_peer_id(&fullname).unwrap();
+
+            assert_eq!(peer.hostname, "my-host");
+            assert_eq!(peer.uuid, uuid);
+        }
+
+        #[test]
+        fn parse_service_peer_id_same_hostname_distinct_peers() {
+            let first = format!("my-host;{}._ktu_fileshare._tcp.local.", Uuid::new_v4());
+            let second = format!("my-host;{}._ktu_fileshare._tcp.local.", Uuid::new_v4());
+
+            assert_ne!(
+                parse_service_peer_id(&first),
+                parse_service_peer_id(&second)
+            );
+        }
+
+        #[test]
+        fn parse_service_peer_id_given_other_service_type_returns_none() {
+            let fullname = format!("my-host;{}._other._tcp.local.", Uuid::new_v4());
+
+            assert!(parse_service_peer_id(&fullname).is_none());
+        }
+    }
+
+    mod advertised_name_tests {
+        use std::net::{Ipv4Addr, SocketAddrV4};
+
+        use uuid::Uuid;
+
+        use crate::{
+            data::PeerId,
+            mdns::{create_service, parse_service_peer_id, MAX_LABEL_LEN, SERVICE_TYPE},
+        };
+
+        #[test]
+        fn create_service_given_pathological_hostname_advertises_valid_names() {
+            let peer_id = PeerId {
+                hostname: format!("..;{}; Jürgen's laptop.local.", "x".repeat(100)),
+                uuid: Uuid::new_v4(),
+            };
+
+            let service =
+                create_service(&peer_id, SocketAddrV4::new(Ipv4Addr::LOCALHOST, 4000)).unwrap();
+
+            let instance = service.get_fullname().strip_suffix(SERVICE_TYPE).unwrap();
+            let instance = instance.strip_suffix('.').unwrap();
+            assert!(instance.len() <= MAX_LABEL_LEN);
+
+            let host_label = service.get_hostname().strip_suffix(".local.").unwrap();
+            assert!(host_label.len() <= MAX_LABEL_LEN);
+            assert!(host_label
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-'));
+            assert!(!host_label.starts_with('-'));
+
+            assert_eq!(parse_service_peer_id(service.get_fullname()), Some(peer_id));
+        }
+
+        #[test]
+        fn create_service_given_empty_hostname_uses_placeholder() {
+            let peer_id = PeerId {
+                hostname: "".to_owned(),
+                uuid: Uuid::new_v4(),
+            };
+
+            let service =
+                create_service(&peer_id, SocketAddrV4::new(Ipv4Addr::LOCALHOST, 4000)).unwrap();
+
+            let parsed = parse_service_peer_id(service.get_fullname()).unwrap();
+            assert_eq!(parsed.hostname, "peer");
+            assert_eq!(parsed.uuid, peer_id.uuid);
+        }
+    }
+
+    mod own_service_tests {
+        use uuid::Uuid;
+
+        use crate::{data::PeerId, mdns::is_own_service};
+
+        fn peer() -> PeerId {
+            PeerId {
+                hostname: "my-host".to_string(),
+                uuid: Uuid::new_v4(),
+            }
+        }
+
+        #[test]
+        fn is_own_service_given_own_uuid_before_registering_returns_true() {
+            let myself = peer();
+            let fullname = format!("{}._ktu_fileshare._tcp.local.", myself);
+            let hostname = format!("{}.local.", myself);
+
+            assert!(is_own_service(&fullname, &hostname, &myself, &None));
+        }
+
+        #[test]
+        fn is_own_service_given_other_peer_on_same_host_returns_false() {
+            let myself = peer();
+            let other = peer();
+            let fullname = format!("{}._ktu_fileshare._tcp.local.", other);
+            let hostname = format!("{}.local.", other);
+            let my_hostname = Some(format!("{}.local.", myself));
+
+            assert!(!is_own_service(&fullname, &hostname, &myself, &my_hostname));
+        }
+    }
+}
@@ -1,53 +1,122 @@
 use std::{
-    collections::HashMap,
-    net::{IpAddr, SocketAddr, SocketAddrV4},
-    path::PathBuf,
+    collections::{HashMap, HashSet, VecDeque},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::{Path, PathBuf},
     str::FromStr,
     sync::Arc,
+    time::Duration,
 };
 
 use anyhow::{anyhow, bail, Result};
 use chrono::{DateTime, Utc};
 use cryptohelpers::crc::compute_stream;
+use futures::StreamExt;
 use mdns_sd::ServiceInfo;
 use tauri::async_runtime::JoinHandle;
-use tokio::{net::TcpStream, sync::mpsc};
+use tokio::{
+    net::TcpStream,
+    sync::{
+        mpsc::{self, error::SendTimeoutError},
+        oneshot,
+    },
+};
 use uuid::Uuid;
 
 use crate::{
-    client::{client_loop, ClientData, DownloadError, MessageToClient},
-    config::StoredConfig,
-    data::{ContentLocation, PeerId, ShareDirectory, ShareDirectorySignature, SharedFile},
-    mdns::MessageToMdns,
+    client::{
+        client_loop, download_path_fits, partial_path, shorten_download_path, ClientData,
+        Compression, DownloadError, MessageToClient, ProtocolMetrics,
+    },
+    config::{DownloadLimits, DownloadNotifications, StoredConfig},
+    data::{
+        ContentLocation, DirectoryDigest, LocalOwnership, PeerId, ShareDirectory,
+        ShareDirectorySignature, SharedFile,
+    },
+    encryption::{open_plain, path_for_opening, FileKey},
+    folder::{scan_folder, ExcludePatterns},
+    link::ShareLink,
+    mdns::{parse_service_peer_id, MessageToMdns},
+    stream::{media_content_type, MediaStreams, StreamSource},
     window::{
-        BackendError, Download, DownloadCanceled, DownloadUpdate, WindowManager, WindowRequest,
+        display_name, AlreadyDownloaded, BackendError, ConnectionFailed, DirectoryDownloadUpdate,
+        DirectoryFingerprint, DirectoryLeft, DirectoryListing, DirectoryShared, Download,
+        DownloadCanceled, DownloadDestination, DownloadSpace, DownloadUpdate, ErrorCode,
+        FolderAdded, InterruptedDownload, NetworkStatus, PeerDisconnected, PeerFingerprint,
+        PeerProtocolMetrics, QueuedDownload, ShareDirectoryView, ShareOffer, SpeedTestResult,
+        SyncStatus, VerificationProgress, VerificationResult, WindowManager, WindowRequest,
         WindowResponse,
     },
 };
 
-pub type ClientConnectionId = IpAddr;
+pub type ClientConnectionId = SocketAddr;
 
+/// Queue size from the server to each client. Clients only receive control
+/// messages and directory updates, and a client that lets its queue fill up
+/// is dropped by `broadcast` rather than waited on.
 const CHANNEL_SIZE: usize = 16;
+const GOODBYE_TIMEOUT_MILLIS: u64 = 1000;
+const VERIFY_WORKERS: usize = 4;
+const BROADCAST_TIMEOUT_MILLIS: u64 = 500;
+const BROADCAST_RETRIES: usize = 3;
+const METRICS_LOG_INTERVAL_SECS: u64 = 60;
+const DOWNLOAD_DIRECTORY_CHECK_SECS: u64 = 5;
 
 #[derive(Clone)]
 pub struct ServerHandle {
     pub channel: mpsc::Sender<MessageToServer>,
+    pub progress: mpsc::Sender<MessageToServer>,
     pub peer_id: PeerId,
+    pub metrics: Arc<ProtocolMetrics>,
+}
+
+impl ServerHandle {
+    /// Reports progress without waiting. Progress updates are dropped while
+    /// the progress queue is full, since a newer one will follow and the
+    /// final state is always sent through `channel`.
+    pub fn report_progress(&self, msg: MessageToServer) {
+        let _ = self.progress.try_send(msg);
+    }
 }
 
 pub struct ClientHandle {
     pub id: Option<PeerId>,
+    /// Agreed on with the peer along with its id.
+    pub compression: Compression,
     pub sender: mpsc::Sender<MessageToClient>,
     pub join: JoinHandle<()>,
     pub service_info: Option<ServiceInfo>,
 }
 
+#[derive(Debug, Clone)]
+pub enum DisconnectReason {
+    Left(String),
+    Lost(String),
+    /// Closed after exchanging no messages for a while. The peer is still
+    /// around and is reconnected when needed.
+    Idle(String),
+}
+
 #[derive(Debug)]
 pub enum MessageToServer {
-    SetPeerId(ClientConnectionId, PeerId),
+    SetPeerId(ClientConnectionId, PeerId, Compression),
     ServiceFound(ServiceInfo),
     ConnectionAccepted(TcpStream, SocketAddr),
-    KillClient(ClientConnectionId),
+    KillClient(ClientConnectionId, DisconnectReason),
+    Shutdown(oneshot::Sender<()>),
+    ListenFailed {
+        port: u16,
+        reason: String,
+    },
+    /// The configured network interface is missing, so `fallback` is
+    /// listened on instead.
+    InterfaceNotFound {
+        selection: String,
+        fallback: Option<Ipv4Addr>,
+    },
+    ClockSkew {
+        peer_id: PeerId,
+        skew_seconds: i64,
+    },
 
     LeftDirectory {
         directory_identifier: Uuid,
@@ -57,6 +126,14 @@ pub enum MessageToServer {
 
     SynchronizeDirectories(Vec<ShareDirectory>, PeerId),
     UpdatedDirectory(Uuid),
+    /// A peer has the version of a directory last modified at
+    /// `last_modified`, with `fingerprint` if the peer reported it.
+    DirectoryVersion {
+        peer_id: PeerId,
+        directory_identifier: Uuid,
+        last_modified: DateTime<Utc>,
+        fingerprint: Option<String>,
+    },
 
     StartedDownload {
         download_info: Download,
@@ -72,10 +149,82 @@ pub enum MessageToServer {
     },
     CanceledDownload {
         download_id: Uuid,
-        cancel_reason: String,
+        cancel_reason: DownloadError,
     },
 
-    SharedDirectory(ShareDirectory),
+    SharedDirectory(ShareDirectory, PeerId),
+
+    VerificationProgress(VerificationProgress),
+    VerificationFinished {
+        result: VerificationResult,
+        repair: bool,
+    },
+
+    SpeedTestFinished(SpeedTestResult),
+    SpeedTestFailed {
+        peer_id: PeerId,
+        reason: String,
+    },
+}
+
+/// Downloads waiting for a free download slot, an owner to reconnect or the
+/// download directory, started in order. See `DownloadLimits`.
+#[derive(Default)]
+struct DownloadQueue {
+    waiting: VecDeque<QueuedDownload>,
+    running: HashMap<Uuid, QueuedDownload>,
+    directories: HashMap<Uuid, DirectoryDownloadUpdate>,
+    /// Set when the download directory went missing. Waiting downloads stay
+    /// queued until it is available again.
+    directory_unavailable: bool,
+}
+
+impl DownloadQueue {
+    fn contains_file(&self, dir_id: Uuid, file_id: Uuid) -> bool {
+        self.waiting
+            .iter()
+            .chain(self.running.values())
+            .any(|d| d.directory_identifier == dir_id && d.file_identifier == file_id)
+    }
+
+    /// Moves a waiting download to `new_position`, or to the back if the
+    /// position is past the end. Returns false if the download isn't waiting.
+    fn reorder(&mut self, download_id: Uuid, new_position: usize) -> bool {
+        let index = self
+            .waiting
+            .iter()
+            .position(|d| d.download_id == download_id);
+
+        match index.and_then(|i| self.waiting.remove(i)) {
+            None => false,
+            Some(download) => {
+                let new_position = new_position.min(self.waiting.len());
+                self.waiting.insert(new_position, download);
+
+                true
+            }
+        }
+    }
+
+    /// Takes a download out of the queue before it started, counting it as
+    /// failed in its directory download. Returns false if it isn't waiting.
+    fn cancel_waiting(&mut self, download_id: Uuid) -> bool {
+        let index = self
+            .waiting
+            .iter()
+            .position(|d| d.download_id == download_id);
+
+        match index.and_then(|i| self.waiting.remove(i)) {
+            None => false,
+            Some(download) => {
+                if let Some(progress) = self.directories.get_mut(&download.directory_identifier) {
+                    progress.files_failed += 1;
+                }
+
+                true
+            }
+        }
+    }
 }
 
 struct ServerData<'a, M>
@@ -87,39 +236,311 @@ where
     clients: &'a mut HashMap<ClientConnectionId, ClientHandle>,
     mdns_sender: &'a mpsc::Sender<MessageToMdns>,
     config: &'a Arc<StoredConfig>,
+    downloads: &'a mut HashMap<Uuid, Download>,
+    download_queue: &'a mut DownloadQueue,
+    peer_addresses: &'a mut HashMap<PeerId, Ipv4Addr>,
+    streams: &'a MediaStreams,
+    network_paused: &'a mut bool,
+    discoverable: &'a mut bool,
+    idle_peers: &'a mut HashSet<PeerId>,
+    peer_versions: &'a mut HashMap<Uuid, HashMap<PeerId, PeerVersion>>,
+}
+
+/// The version of a directory a peer last reported having.
+#[derive(Debug, Clone)]
+pub struct PeerVersion {
+    pub last_modified: DateTime<Utc>,
+    pub fingerprint: Option<String>,
 }
 
 impl<M> ServerData<'_, M>
 where
     M: WindowManager,
 {
+    /// Sends a directory to the window along with how many owners of each
+    /// file are connected.
+    pub fn send_directory(&self, directory: ShareDirectory) {
+        let view = self.directory_view(directory, &connected_peers(self.clients));
+
+        let _ = self
+            .window_manager
+            .send(WindowRequest::UpdateDirectory(view));
+    }
+
+    /// Whether the file is being downloaded, or waits in the queue to be.
+    fn is_downloading(&self, dir_id: Uuid, file_id: Uuid) -> bool {
+        self.downloads
+            .values()
+            .any(|d| d.directory_identifier == dir_id && d.file_identifier == file_id)
+            || self.download_queue.contains_file(dir_id, file_id)
+    }
+
+    fn downloads_from(&self, peer: &PeerId) -> usize {
+        self.downloads.values().filter(|d| &d.peer == peer).count()
+    }
+
+    /// Returns where a running download of the file is being written to.
+    fn download_destination(&self, dir_id: Uuid, file_id: Uuid) -> Option<PathBuf> {
+        self.downloads
+            .values()
+            .find(|d| d.directory_identifier == dir_id && d.file_identifier == file_id)
+            .map(|d| d.file_path.clone())
+    }
+
+    async fn directory_listing(
+        &self,
+        directory_identifier: Option<&str>,
+    ) -> Result<DirectoryListing> {
+        match directory_identifier {
+            None => {
+                let signatures = self
+                    .config
+                    .get_directories()
+                    .await
+                    .into_iter()
+                    .map(|directory| directory.signature)
+                    .collect();
+
+                Ok(DirectoryListing::Signatures(signatures))
+            }
+            Some(directory_identifier) => {
+                let dir_id = Uuid::parse_str(directory_identifier)?;
+                let directory = self
+                    .config
+                    .get_directory(dir_id)
+                    .await
+                    .ok_or_else(|| anyhow!("Directory not found {}", dir_id))?;
+
+                Ok(DirectoryListing::Directory(
+                    self.directory_view(directory, &connected_peers(self.clients)),
+                ))
+            }
+        }
+    }
+
+    /// Our fingerprint of a directory, compared with the ones its connected
+    /// peers last reported.
+    async fn directory_fingerprint(
+        &self,
+        directory_identifier: &str,
+    ) -> Result<DirectoryFingerprint> {
+        let dir_id = Uuid::parse_str(directory_identifier)?;
+        let directory = self
+            .config
+            .get_directory(dir_id)
+            .await
+            .ok_or_else(|| anyhow!("Directory not found {}", dir_id))?;
+        let fingerprint = directory
+            .signature
+            .fingerprint
+            .clone()
+            .unwrap_or_else(|| directory.compute_fingerprint());
+
+        let online_peers = connected_peers(self.clients);
+        let mut peers: Vec<PeerFingerprint> = self
+            .peer_versions
+            .get(&dir_id)
+            .into_iter()
+            .flatten()
+            .filter(|(peer, _)| {
+                online_peers.contains(peer) && directory.signature.shared_peers.contains(peer)
+            })
+            .filter_map(|(peer, version)| {
+                let peer_fingerprint = version.fingerprint.clone()?;
+
+                Some(PeerFingerprint {
+                    peer: peer.clone(),
+                    in_sync: peer_fingerprint == fingerprint,
+                    fingerprint: peer_fingerprint,
+                })
+            })
+            .collect();
+        peers.sort_by_key(|p| p.peer.uuid);
+
+        Ok(DirectoryFingerprint {
+            directory_identifier: dir_id,
+            file_count: directory.shared_files.len(),
+            fingerprint,
+            peers,
+        })
+    }
+
+    fn send_network_status(&self) {
+        let _ = self
+            .window_manager
+            .send(WindowRequest::NetworkStatus(NetworkStatus {
+                paused: *self.network_paused,
+                discoverable: *self.discoverable,
+            }));
+    }
+
+    pub fn send_directories(&self, directories: Vec<ShareDirectory>) {
+        let online_peers = connected_peers(self.clients);
+        let views = directories
+            .into_iter()
+            .map(|directory| self.directory_view(directory, &online_peers))
+            .collect();
+
+        let _ = self
+            .window_manager
+            .send(WindowRequest::UpdateShareDirectories(views));
+    }
+
+    /// Compares the directory with the versions its connected peers last
+    /// reported having.
+    fn directory_view(
+        &self,
+        directory: ShareDirectory,
+        online_peers: &[PeerId],
+    ) -> ShareDirectoryView {
+        let peer_versions: Vec<(PeerId, DateTime<Utc>)> = self
+            .peer_versions
+            .get(&directory.signature.identifier)
+            .into_iter()
+            .flatten()
+            .filter(|(peer, _)| {
+                online_peers.contains(peer) && directory.signature.shared_peers.contains(peer)
+            })
+            .map(|(peer, version)| (peer.clone(), version.last_modified))
+            .collect();
+        let sync_status = SyncStatus::new(directory.signature.last_modified, &peer_versions);
+
+        let idle_peers: Vec<PeerId> = self.idle_peers.iter().cloned().collect();
+
+        let mut view = ShareDirectoryView::new(
+            directory,
+            online_peers,
+            &idle_peers,
+            &self.server_handle.peer_id,
+        );
+        view.sync_status = sync_status;
+
+        view
+    }
+
     pub async fn broadcast(&self, peers: &[PeerId], msg: MessageToClient) {
-        let found_clients: Vec<_> = self
-            .clients
+        self.wake_idle_peers(peers).await;
+
+        let found_clients = self.clients.iter().filter(|(_, c)| match &c.id {
+            Some(id) => peers.contains(id),
+            None => false,
+        });
+
+        self.send_to_clients(found_clients, msg).await;
+    }
+
+    pub async fn broadcast_all(&self, msg: MessageToClient) {
+        self.send_to_clients(self.clients.iter(), msg).await;
+    }
+
+    /// Reconnects to any of `peers` whose connection was closed for being
+    /// idle. Returns whether any of them were idle.
+    async fn wake_idle_peers(&self, peers: &[PeerId]) -> bool {
+        let idle: Vec<PeerId> = peers
             .iter()
-            .filter(|(_, c)| match &c.id {
-                Some(id) => peers.contains(id),
-                None => false,
-            })
+            .filter(|peer| self.idle_peers.contains(peer))
+            .cloned()
             .collect();
 
-        for (_, c) in found_clients {
-            let _ = c.sender.send(msg.clone()).await;
+        if idle.is_empty() {
+            return false;
+        }
+
+        let _ = self.mdns_sender.send(MessageToMdns::Reconnect(idle)).await;
+
+        true
+    }
+
+    /// Sends to all given clients concurrently so that one client with a full
+    /// queue can't hold up the rest. Clients that don't accept the message in
+    /// time get it retried in the background, and are disconnected if they
+    /// still don't accept it. Both sides send their directories when they
+    /// reconnect, so a change missed this way still reaches the peer.
+    async fn send_to_clients<'c, I>(&self, clients: I, msg: MessageToClient)
+    where
+        I: Iterator<Item = (&'c ClientConnectionId, &'c ClientHandle)>,
+    {
+        let timeout = Duration::from_millis(BROADCAST_TIMEOUT_MILLIS);
+        let sends = clients.map(|(addr, c)| {
+            let msg = msg.clone();
+
+            async move {
+                (
+                    *addr,
+                    c.sender.clone(),
+                    c.sender.send_timeout(msg, timeout).await,
+                )
+            }
+        });
+
+        for (addr, sender, result) in futures::future::join_all(sends).await {
+            if let Err(SendTimeoutError::Timeout(msg)) = result {
+                let server = self.server_handle.channel.clone();
+
+                tauri::async_runtime::spawn(retry_send(addr, sender, msg, server));
+            }
+        }
+    }
+}
+
+/// Keeps offering a message to a client whose queue was full, so a peer that
+/// is only slow for a moment isn't disconnected over it.
+async fn retry_send(
+    addr: ClientConnectionId,
+    sender: mpsc::Sender<MessageToClient>,
+    mut msg: MessageToClient,
+    server: mpsc::Sender<MessageToServer>,
+) {
+    let timeout = Duration::from_millis(BROADCAST_TIMEOUT_MILLIS);
+
+    for attempt in 1..=BROADCAST_RETRIES {
+        debug!("Retrying message to client {}, attempt {}", addr, attempt);
+
+        match sender.send_timeout(msg, timeout).await {
+            Err(SendTimeoutError::Timeout(returned)) => msg = returned,
+            // Sent, or the client is gone already
+            _ => return,
         }
     }
+
+    warn!("Client {} stopped responding, disconnecting", addr);
+
+    let reason = DisconnectReason::Lost("Client stopped responding".to_owned());
+    let _ = server.send(MessageToServer::KillClient(addr, reason)).await;
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn server_loop<M>(
     window_manager: M,
     mut client_receiver: mpsc::Receiver<MessageToServer>,
+    mut progress_receiver: mpsc::Receiver<MessageToServer>,
     mut window_receiver: mpsc::Receiver<WindowResponse>,
     mdns_sender: mpsc::Sender<MessageToMdns>,
     server_handle: ServerHandle,
     config: Arc<StoredConfig>,
+    streams: Arc<MediaStreams>,
 ) where
     M: WindowManager,
 {
     let mut clients: HashMap<ClientConnectionId, ClientHandle> = HashMap::new();
+    let mut downloads: HashMap<Uuid, Download> = HashMap::new();
+    let mut download_queue = DownloadQueue::default();
+    let mut peer_addresses: HashMap<PeerId, Ipv4Addr> = HashMap::new();
+    let mut network_paused = false;
+    let mut discoverable = true;
+    let mut idle_peers: HashSet<PeerId> = HashSet::new();
+    let mut peer_versions = HashMap::new();
+    let mut metrics_log = tokio::time::interval(Duration::from_secs(METRICS_LOG_INTERVAL_SECS));
+    let mut directory_check =
+        tokio::time::interval(Duration::from_secs(DOWNLOAD_DIRECTORY_CHECK_SECS));
+    // Peers synchronize when they connect, so the first pass can wait
+    let anti_entropy_interval = config.get_timing().await.anti_entropy_interval();
+    let mut anti_entropy = tokio::time::interval_at(
+        tokio::time::Instant::now() + anti_entropy_interval,
+        anti_entropy_interval,
+    );
+
+    log_ownership_drift(&config, &server_handle.peer_id).await;
 
     loop {
         let server_data = ServerData {
@@ -128,9 +549,21 @@ pub async fn server_loop<M>(
             clients: &mut clients,
             mdns_sender: &mdns_sender,
             config: &config,
+            downloads: &mut downloads,
+            download_queue: &mut download_queue,
+            peer_addresses: &mut peer_addresses,
+            streams: &streams,
+            network_paused: &mut network_paused,
+            discoverable: &mut discoverable,
+            idle_peers: &mut idle_peers,
+            peer_versions: &mut peer_versions,
         };
 
+        // Control messages and user requests are always handled before
+        // queued progress updates.
         tokio::select! {
+            biased;
+
             Some(msg) = client_receiver.recv() => {
                 let result = handle_message(msg, server_data).await;
 
@@ -145,64 +578,139 @@ pub async fn server_loop<M>(
                     error!("{}", e);
                 }
             }
+            Some(msg) = progress_receiver.recv() => {
+                let result = handle_message(msg, server_data).await;
+
+                if let Err(e) = result {
+                    error!("{}", e);
+                }
+            }
+            _ = metrics_log.tick() => {
+                for metrics in protocol_metrics(server_data.clients, &server_handle.metrics) {
+                    info!(
+                        "Protocol metrics for {} ({} compression): {} bytes sent, {} bytes received, sent {:?}, received {:?}",
+                        metrics.address,
+                        metrics.compression.name(),
+                        metrics.counters.bytes_sent,
+                        metrics.counters.bytes_received,
+                        metrics.counters.sent,
+                        metrics.counters.received
+                    );
+                }
+            }
+            _ = directory_check.tick(), if server_data.download_queue.directory_unavailable => {
+                resume_if_directory_available(server_data).await;
+            }
+            _ = anti_entropy.tick() => {
+                compare_directories(&server_data).await;
+            }
         }
     }
 }
 
-async fn handle_message<'a, M>(msg: MessageToServer, server_data: ServerData<'_, M>) -> Result<()>
+async fn handle_message<'a, M>(
+    msg: MessageToServer,
+    mut server_data: ServerData<'_, M>,
+) -> Result<()>
 where
     M: WindowManager,
 {
     match msg {
         MessageToServer::ServiceFound(service) => {
-            let ip_addr = service.get_addresses().iter().next();
-
-            match ip_addr {
-                Some(ip) => {
-                    let ipv4 = IpAddr::V4(*ip);
-                    let socket_addr = SocketAddr::V4(SocketAddrV4::new(*ip, service.get_port()));
-
-                    if !server_data.clients.contains_key(&ipv4) {
-                        let tcp_stream = TcpStream::connect(socket_addr).await?;
-
-                        add_client(
-                            server_data.server_handle.clone(),
-                            server_data.clients,
-                            tcp_stream,
-                            ipv4,
-                            Some(service.clone()),
-                            server_data.config.clone(),
-                        )
-                        .await?;
+            if *server_data.network_paused {
+                return Ok(());
+            }
 
-                        server_data
-                            .mdns_sender
-                            .send(MessageToMdns::ConnectedService(service))
-                            .await?;
+            let service_peer = parse_service_peer_id(service.get_fullname());
+            let port = service.get_port();
+            let preferred = service_peer
+                .as_ref()
+                .and_then(|peer| server_data.peer_addresses.get(peer));
+            let addresses = candidate_addresses(service.get_addresses(), preferred);
 
-                        Ok(())
-                    } else {
-                        server_data
-                            .mdns_sender
-                            .send(MessageToMdns::ConnectedService(service))
-                            .await?;
+            if addresses.is_empty() {
+                return Err(anyhow!("Service had no associated IP addresses"));
+            }
 
-                        Err(anyhow!("Service client already connected: {}", socket_addr))
-                    }
+            let already_connected = addresses.iter().any(|ip| {
+                let socket_addr = SocketAddr::V4(SocketAddrV4::new(*ip, port));
+                server_data.clients.contains_key(&socket_addr)
+            }) || server_data
+                .clients
+                .values()
+                .any(|c| c.id.is_some() && c.id == service_peer);
+
+            if already_connected {
+                server_data
+                    .mdns_sender
+                    .send(MessageToMdns::ConnectedService(service.clone()))
+                    .await?;
+
+                return Err(anyhow!(
+                    "Service client already connected: {}",
+                    service.get_fullname()
+                ));
+            }
+
+            let timeout = server_data.config.get_connect_timeout().await;
+            let (tcp_stream, socket_addr) = match connect_to_any(&addresses, port, timeout).await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    let _ = server_data
+                        .window_manager
+                        .send(WindowRequest::ConnectionFailed(ConnectionFailed {
+                            peer: service_peer,
+                            addresses: addresses
+                                .iter()
+                                .map(|ip| SocketAddrV4::new(*ip, port))
+                                .collect(),
+                            reason: e.to_string(),
+                            timed_out: e.kind() == std::io::ErrorKind::TimedOut,
+                        }));
+
+                    return Err(anyhow!(
+                        "Could not connect to service {}: {}",
+                        service.get_fullname(),
+                        e
+                    ));
                 }
-                None => Err(anyhow!("Service had no associated IP addresses")),
+            };
+            if let Some(peer) = service_peer {
+                server_data.peer_addresses.insert(peer, *socket_addr.ip());
             }
+
+            add_client(
+                server_data.server_handle.clone(),
+                server_data.clients,
+                tcp_stream,
+                SocketAddr::V4(socket_addr),
+                Some(service.clone()),
+                server_data.config.clone(),
+            )
+            .await?;
+
+            server_data
+                .mdns_sender
+                .send(MessageToMdns::ConnectedService(service))
+                .await?;
+
+            Ok(())
         }
 
         MessageToServer::ConnectionAccepted(tcp, addr) => {
-            let ip_addr = addr.ip();
+            if *server_data.network_paused {
+                info!("Network is paused, refusing connection from {}", addr);
+                drop(tcp);
+
+                return Ok(());
+            }
 
-            if !server_data.clients.contains_key(&ip_addr) {
+            if !server_data.clients.contains_key(&addr) {
                 add_client(
                     server_data.server_handle.clone(),
                     server_data.clients,
                     tcp,
-                    ip_addr,
+                    addr,
                     None,
                     server_data.config.clone(),
                 )
@@ -217,21 +725,84 @@ where
             }
         }
 
-        MessageToServer::SetPeerId(addr, id) => {
-            let clients = server_data.clients;
-            let mut peer_ids: Vec<PeerId> =
-                clients.iter().filter_map(|(_, c)| c.id.clone()).collect();
+        MessageToServer::SetPeerId(addr, id, compression) => {
+            let clients = &mut *server_data.clients;
+
+            let duplicate = clients
+                .iter()
+                .find(|(other_addr, c)| **other_addr != addr && c.id.as_ref() == Some(&id))
+                .map(|(other_addr, c)| (*other_addr, c.service_info.is_some()));
+
+            if let Some((existing_addr, existing_is_outbound)) = duplicate {
+                let new_is_outbound = match clients.get(&addr) {
+                    Some(client) => client.service_info.is_some(),
+                    None => bail!("No such client for {}", addr),
+                };
+                let keep_existing = keep_existing_connection(
+                    &server_data.server_handle.peer_id,
+                    &id,
+                    existing_is_outbound,
+                    new_is_outbound,
+                );
+
+                info!(
+                    "Peer {} connected twice ({} and {}), keeping {}",
+                    id,
+                    existing_addr,
+                    addr,
+                    if keep_existing { existing_addr } else { addr }
+                );
+
+                if keep_existing {
+                    if let Some(client) = clients.remove(&addr) {
+                        disconnected_client(client, server_data.mdns_sender).await;
+                    }
+
+                    return Ok(());
+                } else if let Some(client) = clients.remove(&existing_addr) {
+                    disconnected_client(client, server_data.mdns_sender).await;
+                }
+            }
+
             let client = clients.get_mut(&addr);
 
             match client {
                 Some(client) => {
                     client.id = Some(id.clone());
-                    peer_ids.push(id);
+                    client.compression = compression;
+                    let sender = client.sender.clone();
+                    let online_peers = connected_peers(clients);
+
+                    // Directories might still list the peer's old hostname.
+                    for dir in server_data.config.rename_peer(&id).await {
+                        server_data.send_directory(dir);
+                    }
 
                     let _ = server_data
                         .window_manager
-                        .send(WindowRequest::GetPeers(peer_ids));
-                    client.sender.send(MessageToClient::Synchronize).await?;
+                        .send(WindowRequest::GetPeers(online_peers));
+                    sender.send(MessageToClient::Synchronize).await?;
+
+                    for dir_id in server_data.config.get_shared_with_all().await {
+                        if let Err(e) =
+                            share_directory(&server_data, dir_id, vec![id.clone()]).await
+                        {
+                            warn!("Could not share directory {} with {}: {}", dir_id, id, e);
+                        }
+                    }
+
+                    if server_data.idle_peers.remove(&id) {
+                        // Lets mDNS reconnect to the peer again if the connection is lost
+                        let _ = server_data
+                            .mdns_sender
+                            .send(MessageToMdns::Reconnect(vec![id]))
+                            .await;
+
+                        // Downloads may have been waiting for the peer to reconnect
+                        if !server_data.download_queue.waiting.is_empty() {
+                            start_waiting_downloads(&mut server_data).await;
+                        }
+                    }
 
                     Ok(())
                 }
@@ -239,11 +810,12 @@ where
             }
         }
 
-        MessageToServer::KillClient(client_addr) => {
-            let clients = server_data.clients;
-            let mut peer_ids: Vec<PeerId> =
-                clients.iter().filter_map(|(_, c)| c.id.clone()).collect();
+        MessageToServer::KillClient(client_addr, reason) => {
+            server_data.server_handle.metrics.remove(&client_addr);
+
+            let clients = &mut *server_data.clients;
             let client = clients.remove(&client_addr);
+            let peer_ids = connected_peers(clients);
 
             match client {
                 Some(client) => {
@@ -251,9 +823,48 @@ where
                     disconnected_client(client, server_data.mdns_sender).await;
 
                     match disconnected_peer_id {
+                        // Still reachable through another connection
+                        Some(id) if peer_ids.contains(&id) => (),
                         None => (),
                         Some(id) => {
-                            peer_ids.retain(|peer| peer != &id);
+                            // Versions are reported again after reconnecting
+                            for versions in server_data.peer_versions.values_mut() {
+                                versions.remove(&id);
+                            }
+                            cancel_downloads_from(&mut server_data, &id).await;
+
+                            match reason {
+                                DisconnectReason::Left(reason) => {
+                                    info!("Peer {} left: {}", id, reason);
+
+                                    let _ =
+                                        server_data.window_manager.send(WindowRequest::PeerLeft(
+                                            PeerDisconnected { peer: id, reason },
+                                        ));
+                                }
+                                DisconnectReason::Lost(reason) => {
+                                    warn!("Lost connection to peer {}: {}", id, reason);
+
+                                    let _ =
+                                        server_data.window_manager.send(WindowRequest::PeerLost(
+                                            PeerDisconnected { peer: id, reason },
+                                        ));
+                                }
+                                // Not an error, the peer is reconnected once it's needed
+                                DisconnectReason::Idle(reason) => {
+                                    info!("Closed idle connection to peer {}: {}", id, reason);
+
+                                    server_data.idle_peers.insert(id.clone());
+                                    let _ = server_data
+                                        .mdns_sender
+                                        .send(MessageToMdns::IdlePeer(id))
+                                        .await;
+                                }
+                            }
+
+                            // Owner counts of its files changed
+                            server_data
+                                .send_directories(server_data.config.get_directories().await);
                         }
                     }
 
@@ -267,82 +878,287 @@ where
             }
         }
 
-        MessageToServer::SharedDirectory(directory) => {
-            server_data
-                .config
-                .shared_directory(directory.clone())
-                .await?;
-
+        MessageToServer::ListenFailed { port, reason } => {
             let _ = server_data
                 .window_manager
-                .send(WindowRequest::UpdateDirectory(directory));
+                .send(WindowRequest::Error(BackendError {
+                    code: ErrorCode::PortUnavailable,
+                    title: "Network Error".to_owned(),
+                    error: format!(
+                        "Could not listen on port {}: {}. Choose a different port in settings.",
+                        port, reason
+                    ),
+                }));
 
             Ok(())
         }
 
-        MessageToServer::SynchronizeDirectories(directories, peer) => {
-            let clients = server_data.clients;
-            let myself = &server_data.server_handle.peer_id;
+        MessageToServer::InterfaceNotFound {
+            selection,
+            fallback,
+        } => {
+            let fallback = match fallback {
+                Some(address) => format!("Listening on {} instead.", address),
+                None => "No other network is available.".to_owned(),
+            };
 
-            let client = clients.iter_mut().find(|(_, cdata)| match &cdata.id {
-                Some(pid) => pid == &peer,
-                None => false,
-            });
+            let _ = server_data
+                .window_manager
+                .send(WindowRequest::Error(BackendError {
+                    code: ErrorCode::InterfaceUnavailable,
+                    title: "Network Error".to_owned(),
+                    error: format!(
+                        "Network interface {} was not found. {} Choose another one in settings.",
+                        selection, fallback
+                    ),
+                }));
 
-            match client {
-                Some((_, _)) => {
-                    let new_dirs = server_data.config.synchronize(directories, myself).await;
+            Ok(())
+        }
 
-                    let _ = server_data
-                        .window_manager
-                        .send(WindowRequest::UpdateShareDirectories(new_dirs));
+        MessageToServer::ClockSkew {
+            peer_id,
+            skew_seconds,
+        } => {
+            let direction = if skew_seconds > 0 { "ahead" } else { "behind" };
 
-                    Ok(())
-                }
-                None => Err(anyhow!("No client found")),
-            }
+            let _ = server_data
+                .window_manager
+                .send(WindowRequest::Error(BackendError {
+                    code: ErrorCode::ClockSkew,
+                    title: "Clock Skew".to_owned(),
+                    error: format!(
+                        "Clock of {} is {} seconds {} of this device. Directory changes may be merged incorrectly until clocks are synchronized.",
+                        peer_id.hostname,
+                        skew_seconds.abs(),
+                        direction
+                    ),
+                }));
+
+            Ok(())
         }
 
-        MessageToServer::UpdatedDirectory(directory_id) => {
-            let dir = server_data.config.get_directory(directory_id).await;
+        MessageToServer::SpeedTestFinished(result) => {
+            info!(
+                "Speed test to {}: {} ms latency, {} bytes/s",
+                result.peer, result.latency_millis, result.bytes_per_second
+            );
 
-            if let Some(dir) = dir {
-                let _ = server_data
-                    .window_manager
-                    .send(WindowRequest::UpdateDirectory(dir));
-            }
+            let _ = server_data
+                .window_manager
+                .send(WindowRequest::SpeedTestResult(result));
 
             Ok(())
         }
 
-        MessageToServer::StartedDownload { download_info } => {
+        MessageToServer::SpeedTestFailed { peer_id, reason } => {
             let _ = server_data
                 .window_manager
-                .send(WindowRequest::DownloadStarted(download_info));
+                .send(WindowRequest::Error(BackendError {
+                    code: ErrorCode::PeerUnavailable,
+                    title: "Speed Test Failed".to_owned(),
+                    error: format!(
+                        "Could not test connection to {}: {}",
+                        peer_id.hostname, reason
+                    ),
+                }));
 
             Ok(())
         }
 
-        MessageToServer::FinishedDownload {
-            download_id,
-            directory_identifier,
-            file_identifier,
-        } => {
-            let myself = server_data.server_handle.peer_id.clone();
-            let directory = server_data.config.get_directory(directory_identifier).await;
+        MessageToServer::Shutdown(done) => {
+            let clients: Vec<ClientHandle> = server_data
+                .clients
+                .drain()
+                .map(|(_, client)| client)
+                .collect();
 
-            match directory {
-                None => {
-                    let msg = WindowRequest::DownloadCanceled(DownloadCanceled {
-                        reason: "Could not update other clients.".to_owned(),
-                        download_id,
-                    });
-                    let _ = server_data.window_manager.send(msg);
+            for client in clients.iter() {
+                let _ = client
+                    .sender
+                    .send(MessageToClient::Shutdown(
+                        "Application was closed".to_owned(),
+                    ))
+                    .await;
+            }
+
+            for client in clients {
+                let timeout = Duration::from_millis(GOODBYE_TIMEOUT_MILLIS);
+
+                if tokio::time::timeout(timeout, client.join).await.is_err() {
+                    warn!("Client did not finish saying goodbye in time");
                 }
-                Some(directory) => {
-                    server_data
-                        .broadcast(
-                            &directory.signature.shared_peers,
+            }
+
+            let _ = done.send(());
+
+            Ok(())
+        }
+
+        MessageToServer::SharedDirectory(directory, peer) => {
+            let accepted = hold_offered_shares(&server_data, vec![directory], &peer).await;
+
+            for directory in accepted {
+                server_data
+                    .config
+                    .shared_directory(directory.clone())
+                    .await?;
+
+                let _ = server_data
+                    .window_manager
+                    .send(WindowRequest::DirectoryShared(DirectoryShared {
+                        signature: directory.signature.clone(),
+                        from_peer: peer.clone(),
+                    }));
+                server_data.send_directory(directory);
+            }
+
+            Ok(())
+        }
+
+        MessageToServer::SynchronizeDirectories(directories, peer) => {
+            let myself = &server_data.server_handle.peer_id;
+
+            let client = server_data.clients.values().find(|cdata| match &cdata.id {
+                Some(pid) => pid == &peer,
+                None => false,
+            });
+
+            match client {
+                Some(_) => {
+                    let directories = hold_offered_shares(&server_data, directories, &peer).await;
+
+                    let mut shared = vec![];
+                    let mut merged = vec![];
+                    for directory in directories.iter() {
+                        let id = directory.signature.identifier;
+                        let version = directory.signature.last_modified;
+                        server_data.peer_versions.entry(id).or_default().insert(
+                            peer.clone(),
+                            PeerVersion {
+                                last_modified: version,
+                                fingerprint: directory.signature.fingerprint.clone(),
+                            },
+                        );
+
+                        match server_data.config.get_directory(id).await {
+                            None => {
+                                shared.push(directory.signature.clone());
+                                merged.push(id);
+                            }
+                            Some(local) if local.signature.last_modified < version => {
+                                merged.push(id)
+                            }
+                            Some(_) => (),
+                        }
+                    }
+
+                    let new_dirs = server_data.config.synchronize(directories, myself).await;
+
+                    // Lets the other peers know this version was taken, so
+                    // they can tell whether they are in sync with it
+                    for dir in new_dirs.iter() {
+                        if merged.contains(&dir.signature.identifier) {
+                            server_data
+                                .broadcast(
+                                    &dir.signature.shared_peers,
+                                    MessageToClient::SendDirectories(vec![dir.clone()]),
+                                )
+                                .await;
+                        }
+                    }
+
+                    server_data.send_directories(new_dirs);
+
+                    for signature in shared {
+                        let _ = server_data
+                            .window_manager
+                            .send(WindowRequest::DirectoryShared(DirectoryShared {
+                                signature,
+                                from_peer: peer.clone(),
+                            }));
+                    }
+
+                    Ok(())
+                }
+                None => Err(anyhow!("No client found")),
+            }
+        }
+
+        MessageToServer::DirectoryVersion {
+            peer_id,
+            directory_identifier,
+            last_modified,
+            fingerprint,
+        } => {
+            server_data
+                .peer_versions
+                .entry(directory_identifier)
+                .or_default()
+                .insert(
+                    peer_id,
+                    PeerVersion {
+                        last_modified,
+                        fingerprint,
+                    },
+                );
+
+            if let Some(dir) = server_data.config.get_directory(directory_identifier).await {
+                server_data.send_directory(dir);
+            }
+
+            Ok(())
+        }
+
+        MessageToServer::UpdatedDirectory(directory_id) => {
+            let dir = server_data.config.get_directory(directory_id).await;
+
+            if let Some(dir) = dir {
+                server_data.send_directory(dir);
+            }
+
+            Ok(())
+        }
+
+        MessageToServer::StartedDownload { download_info } => {
+            server_data
+                .downloads
+                .insert(download_info.download_id, download_info.clone());
+            server_data
+                .config
+                .record_download(download_info.clone())
+                .await;
+
+            let _ = server_data
+                .window_manager
+                .send(WindowRequest::DownloadStarted(download_info));
+
+            Ok(())
+        }
+
+        MessageToServer::FinishedDownload {
+            download_id,
+            directory_identifier,
+            file_identifier,
+        } => {
+            let myself = server_data.server_handle.peer_id.clone();
+            let directory = server_data.config.get_directory(directory_identifier).await;
+            let finished_download = server_data.downloads.remove(&download_id);
+            server_data.config.forget_download(download_id).await;
+
+            match directory {
+                None => {
+                    let msg = WindowRequest::DownloadCanceled(DownloadCanceled::new(
+                        download_id,
+                        DownloadError::DirectoryMissing,
+                        finished_download,
+                    ));
+                    let _ = server_data.window_manager.send(msg);
+                }
+                Some(directory) => {
+                    server_data
+                        .broadcast(
+                            &directory.signature.shared_peers,
                             MessageToClient::UpdateOwners {
                                 peer_id: myself,
                                 directory_identifier,
@@ -352,18 +1168,20 @@ where
                         )
                         .await;
 
-                    let _ = server_data
-                        .window_manager
-                        .send(WindowRequest::UpdateDirectory(directory));
+                    server_data.send_directory(directory);
                     let _ = server_data
                         .window_manager
                         .send(WindowRequest::DownloadUpdate(DownloadUpdate {
                             progress: 100,
                             download_id,
                         }));
+
+                    notify_download_finished(&server_data, finished_download).await;
                 }
             }
 
+            queued_download_ended(&mut server_data, download_id, true).await;
+
             Ok(())
         }
 
@@ -371,6 +1189,12 @@ where
             download_id,
             new_progress,
         } => {
+            // Progress is queued separately and can arrive after the
+            // download already finished or was canceled.
+            if !server_data.downloads.contains_key(&download_id) {
+                return Ok(());
+            }
+
             let _ = server_data
                 .window_manager
                 .send(WindowRequest::DownloadUpdate(DownloadUpdate {
@@ -385,12 +1209,96 @@ where
             download_id,
             cancel_reason,
         } => {
+            let canceled_download = server_data.downloads.remove(&download_id);
+            server_data.config.forget_download(download_id).await;
+
+            if let Some(download) = &canceled_download {
+                server_data
+                    .config
+                    .clear_partial_location(
+                        download.directory_identifier,
+                        download.file_identifier,
+                        &partial_path(&download.file_path),
+                    )
+                    .await;
+                server_data.streams.remove(&download.file_path).await;
+            }
+
             let _ = server_data
                 .window_manager
-                .send(WindowRequest::DownloadCanceled(DownloadCanceled {
+                .send(WindowRequest::DownloadCanceled(DownloadCanceled::new(
                     download_id,
-                    reason: cancel_reason,
-                }));
+                    cancel_reason,
+                    canceled_download,
+                )));
+
+            queued_download_ended(&mut server_data, download_id, false).await;
+
+            Ok(())
+        }
+
+        MessageToServer::VerificationProgress(progress) => {
+            let _ = server_data
+                .window_manager
+                .send(WindowRequest::VerificationProgress(progress));
+
+            Ok(())
+        }
+
+        MessageToServer::VerificationFinished { mut result, repair } => {
+            let dir_id = result.directory_identifier;
+            let bad_files: Vec<Uuid> = result
+                .mismatched_files
+                .iter()
+                .chain(result.missing_files.iter())
+                .cloned()
+                .collect();
+
+            if repair && !bad_files.is_empty() {
+                let myself = &server_data.server_handle.peer_id;
+
+                let mut signature = None;
+                server_data
+                    .config
+                    .mutate_dir(dir_id, |dir| {
+                        for file_id in bad_files.iter() {
+                            if let Some(file) = dir.shared_files.get_mut(file_id) {
+                                file.content_location = ContentLocation::NetworkOnly;
+                            }
+                        }
+
+                        dir.remove_files(myself, server_data.config.now(), bad_files.clone());
+
+                        signature = Some(dir.signature.clone());
+                        true
+                    })
+                    .await;
+
+                if let Some(signature) = signature {
+                    for file_id in bad_files {
+                        server_data
+                            .broadcast(
+                                &signature.shared_peers,
+                                MessageToClient::DeleteFile(
+                                    myself.clone(),
+                                    signature.clone(),
+                                    file_id,
+                                ),
+                            )
+                            .await;
+                    }
+
+                    if let Some(dir) = server_data.config.get_directory(dir_id).await {
+                        server_data.send_directory(dir);
+                    }
+
+                    result.repaired = true;
+                }
+            }
+
+            let _ = server_data
+                .window_manager
+                .send(WindowRequest::VerificationFinished(result));
 
             Ok(())
         }
@@ -404,15 +1312,15 @@ where
                 .config
                 .mutate_dir(directory_identifier, |dir| {
                     dir.remove_peer(&peer_id, date_modified);
+
+                    true
                 })
                 .await;
 
             let dir = server_data.config.get_directory(directory_identifier).await;
 
             if let Some(dir) = dir {
-                let _ = server_data
-                    .window_manager
-                    .send(WindowRequest::UpdateDirectory(dir));
+                server_data.send_directory(dir);
             }
 
             Ok(())
@@ -420,25 +1328,37 @@ where
     }
 }
 
-async fn handle_request<M>(msg: WindowResponse, server_data: ServerData<'_, M>) -> Result<()>
+async fn handle_request<M>(msg: WindowResponse, mut server_data: ServerData<'_, M>) -> Result<()>
 where
     M: WindowManager,
 {
     match msg {
         WindowResponse::CreateShareDirectory(name) => {
             let id = Uuid::new_v4();
-            let signature = ShareDirectorySignature {
-                name,
-                identifier: id,
-                last_modified: Utc::now(),
-                shared_peers: vec![server_data.server_handle.peer_id.clone()],
-            };
-            let sd = ShareDirectory {
-                signature: signature.clone(),
+            let mut sd = ShareDirectory {
+                signature: ShareDirectorySignature {
+                    name,
+                    identifier: id,
+                    last_modified: server_data.config.now(),
+                    shared_peers: vec![server_data.server_handle.peer_id.clone()],
+                    fingerprint: None,
+                },
                 shared_files: HashMap::new(),
             };
+            sd.refresh_fingerprint();
+            let signature = sd.signature.clone();
+
+            if let Err(e) = server_data.config.add_directory(sd).await {
+                let _ = server_data
+                    .window_manager
+                    .send(WindowRequest::Error(BackendError {
+                        code: ErrorCode::ShareLimitReached,
+                        title: "Share Limit".to_owned(),
+                        error: e.to_string(),
+                    }));
 
-            server_data.config.add_directory(sd).await;
+                return Ok(());
+            }
 
             let _ = server_data
                 .window_manager
@@ -448,9 +1368,7 @@ where
         }
 
         WindowResponse::GetPeers(_) => {
-            let clients = server_data.clients;
-
-            let ids: Vec<PeerId> = clients.iter().filter_map(|(_, c)| c.id.clone()).collect();
+            let ids = connected_peers(server_data.clients);
 
             let _ = server_data
                 .window_manager
@@ -459,18 +1377,76 @@ where
             Ok(())
         }
 
-        WindowResponse::GetAllShareDirectoryData(_) => {
+        WindowResponse::GetProtocolMetrics => {
+            let metrics = protocol_metrics(server_data.clients, &server_data.server_handle.metrics);
+
             let _ = server_data
                 .window_manager
-                .send(WindowRequest::UpdateShareDirectories(
-                    server_data.config.get_directories().await,
-                ));
+                .send(WindowRequest::ProtocolMetrics(metrics));
+
+            Ok(())
+        }
+
+        WindowResponse::GetAllShareDirectoryData(_) => {
+            server_data.send_directories(server_data.config.get_directories().await);
+
+            Ok(())
+        }
+
+        WindowResponse::GetDirectory {
+            directory_identifier,
+            reply,
+        } => {
+            let listing = server_data
+                .directory_listing(directory_identifier.as_deref())
+                .await;
+
+            match reply {
+                Some(reply) => {
+                    let _ = reply.send(listing.map_err(|e| e.to_string()));
+                }
+                // Sent as a plain network command, so the answer goes out
+                // as the usual update events
+                None => match listing? {
+                    DirectoryListing::Directory(view) => {
+                        let _ = server_data
+                            .window_manager
+                            .send(WindowRequest::UpdateDirectory(view));
+                    }
+                    DirectoryListing::Signatures(_) => {
+                        server_data.send_directories(server_data.config.get_directories().await)
+                    }
+                },
+            }
+
+            Ok(())
+        }
+
+        WindowResponse::GetDirectoryFingerprint {
+            directory_identifier,
+            reply,
+        } => {
+            let fingerprint = server_data
+                .directory_fingerprint(&directory_identifier)
+                .await;
+
+            match reply {
+                Some(reply) => {
+                    let _ = reply.send(fingerprint.map_err(|e| e.to_string()));
+                }
+                None => {
+                    let _ = server_data
+                        .window_manager
+                        .send(WindowRequest::DirectoryFingerprint(fingerprint?));
+                }
+            }
 
             Ok(())
         }
 
         WindowResponse::LeaveDirectory {
             directory_identifier,
+            delete_local_files,
         } => {
             let dir_id = Uuid::parse_str(&directory_identifier)?;
             let directory = server_data.config.remove_directory(dir_id).await;
@@ -492,11 +1468,21 @@ where
                 )
                 .await;
 
+            let (deleted_files, freed_bytes) = if delete_local_files {
+                remove_local_copies(server_data.config, &directory).await
+            } else {
+                (0, 0)
+            };
+
+            server_data.send_directories(server_data.config.get_directories().await);
+
             let _ = server_data
                 .window_manager
-                .send(WindowRequest::UpdateShareDirectories(
-                    server_data.config.get_directories().await,
-                ));
+                .send(WindowRequest::DirectoryLeft(DirectoryLeft {
+                    directory_identifier: dir_id,
+                    deleted_files,
+                    freed_bytes,
+                }));
 
             Ok(())
         }
@@ -508,116 +1494,238 @@ where
             let id = Uuid::from_str(&directory_identifier)?;
             let mut shared_files = vec![];
             for file_path in file_paths {
-                let shared_file =
-                    create_shared_file(file_path, &server_data.server_handle.peer_id).await?;
+                let shared_file = create_shared_file(
+                    file_path,
+                    &server_data.server_handle.peer_id,
+                    server_data.config.now(),
+                )
+                .await?;
 
                 shared_files.push(shared_file);
             }
 
-            let mut signature = None;
-            server_data
-                .config
-                .mutate_dir(id, |directory| {
-                    let add_result = directory.add_files(shared_files.clone(), Utc::now());
-
-                    if let Ok(()) = add_result {
-                        let _ = server_data
-                            .window_manager
-                            .send(WindowRequest::UpdateDirectory(directory.clone()));
-
-                        signature = Some(directory.signature.clone());
-                    }
-                })
-                .await;
+            let skipped = add_shared_files(&server_data, id, shared_files)
+                .await
+                .unwrap_or_default();
 
-            if let Some(signature) = signature {
-                server_data
-                    .broadcast(
-                        &signature.shared_peers,
-                        MessageToClient::AddedFiles(signature.clone(), shared_files),
-                    )
-                    .await;
-            } else {
+            if !skipped.is_empty() {
+                let names = skipped
+                    .iter()
+                    .map(|file| file.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ");
                 let _ = server_data
                     .window_manager
                     .send(WindowRequest::Error(BackendError {
-                        title: "File Error".to_owned(),
-                        error: "File has already been added to this directory".to_owned(),
+                        code: ErrorCode::FileAlreadyShared,
+                        title: "Files Skipped".to_owned(),
+                        error: format!(
+                            "These files are already shared under the same name: {}",
+                            names
+                        ),
                     }));
             }
 
             Ok(())
         }
 
-        WindowResponse::ShareDirectoryToPeers {
-            peers,
+        WindowResponse::AddFolder {
             directory_identifier,
+            folder_path,
+            exclude_patterns,
         } => {
             let id = Uuid::from_str(&directory_identifier)?;
-            let mut success = false;
-            server_data
-                .config
-                .mutate_dir(id, |dir| {
-                    dir.add_peers(peers, Utc::now());
+            let mut patterns = server_data.config.get_exclude_patterns().await;
+            patterns.extend(exclude_patterns);
 
-                    success = true;
-                })
-                .await;
+            let excludes = match ExcludePatterns::new(&patterns) {
+                Ok(excludes) => excludes,
+                Err(e) => {
+                    let _ = server_data
+                        .window_manager
+                        .send(WindowRequest::Error(BackendError {
+                            code: ErrorCode::InvalidExcludePattern,
+                            title: "Invalid Pattern".to_owned(),
+                            error: e.to_string(),
+                        }));
 
-            if success {
-                let dir = server_data.config.get_directory(id).await.unwrap();
+                    return Ok(());
+                }
+            };
 
-                server_data
-                    .broadcast(
-                        &dir.signature.shared_peers,
-                        MessageToClient::SendDirectories(vec![dir.clone()]),
-                    )
-                    .await;
+            let root = PathBuf::from(&folder_path);
+            let scan = tokio::task::spawn_blocking(move || scan_folder(&root, &excludes)).await?;
+            let scan = match scan {
+                Ok(scan) => scan,
+                Err(e) => {
+                    let _ = server_data
+                        .window_manager
+                        .send(WindowRequest::Error(BackendError {
+                            code: ErrorCode::FilesUnavailable,
+                            title: "Folder Error".to_owned(),
+                            error: format!("Could not read folder {}: {}", folder_path, e),
+                        }));
 
-                let _ = server_data
-                    .window_manager
-                    .send(WindowRequest::UpdateDirectory(dir.clone()));
+                    return Ok(());
+                }
+            };
 
-                return Ok(());
+            let mut shared_files = vec![];
+            let mut skipped_files = 0;
+            for path in scan.files {
+                let path = path.to_string_lossy().into_owned();
+
+                match create_shared_file(
+                    path.clone(),
+                    &server_data.server_handle.peer_id,
+                    server_data.config.now(),
+                )
+                .await
+                {
+                    Ok(shared_file) => shared_files.push(shared_file),
+                    Err(e) => {
+                        warn!("Skipping {} while adding folder: {}", path, e);
+                        skipped_files += 1;
+                    }
+                }
             }
 
-            Err(anyhow!("Directory not found"))
+            let mut added_files = shared_files.len();
+            if !shared_files.is_empty() {
+                match add_shared_files(&server_data, id, shared_files).await {
+                    Some(skipped) => {
+                        added_files -= skipped.len();
+                        skipped_files += skipped.len();
+                    }
+                    None => return Ok(()),
+                }
+            }
+
+            let _ = server_data
+                .window_manager
+                .send(WindowRequest::FolderAdded(FolderAdded {
+                    directory_identifier: id,
+                    added_files,
+                    excluded: scan.excluded,
+                    skipped_files,
+                }));
+
+            Ok(())
+        }
+
+        WindowResponse::ShareDirectoryToPeers {
+            peers,
+            directory_identifier,
+        } => {
+            let id = Uuid::from_str(&directory_identifier)?;
+
+            if !share_directory(&server_data, id, peers).await? {
+                let _ = server_data
+                    .window_manager
+                    .send(WindowRequest::Error(BackendError {
+                        code: ErrorCode::DirectoryAlreadyShared,
+                        title: "Already Shared".to_owned(),
+                        error: "All selected devices already have access to this directory"
+                            .to_owned(),
+                    }));
+            }
+
+            Ok(())
+        }
+
+        WindowResponse::ShareDirectoryToAllPeers {
+            directory_identifier,
+            keep_shared,
+        } => {
+            let id = Uuid::from_str(&directory_identifier)?;
+            let peers = connected_peers(server_data.clients);
+
+            if peers.is_empty() && !keep_shared {
+                let _ = server_data
+                    .window_manager
+                    .send(WindowRequest::Error(BackendError {
+                        code: ErrorCode::PeerUnavailable,
+                        title: "No Devices Online".to_owned(),
+                        error: "There are no connected devices to share this directory with"
+                            .to_owned(),
+                    }));
+
+                return Ok(());
+            }
+
+            share_directory(&server_data, id, peers).await?;
+            server_data
+                .config
+                .set_shared_with_all(id, keep_shared)
+                .await;
+
+            Ok(())
+        }
+
+        WindowResponse::RepairOwnership {
+            directory_identifier,
+            file_identifier,
+        } => {
+            let dir_id = Uuid::parse_str(&directory_identifier)?;
+            let file_id = Uuid::parse_str(&file_identifier)?;
+
+            repair_ownership(&server_data, dir_id, file_id).await
         }
 
         WindowResponse::DeleteFile {
             directory_identifier,
             file_identifier,
+            keep_local_file,
         } => {
             let dir_id = Uuid::from_str(&directory_identifier)?;
             let file_id = Uuid::from_str(&file_identifier)?;
 
-            let mut success_delete = false;
-            server_data
+            let mut delete_error = None;
+            let success_delete = server_data
                 .config
                 .mutate_file(dir_id, file_id, |file| {
                     if let ContentLocation::LocalPath(path) = &file.content_location {
-                        if path.exists() {
-                            let _ = std::fs::remove_file(path);
+                        if !keep_local_file && path.exists() {
+                            if let Err(e) = std::fs::remove_file(path) {
+                                delete_error = Some((file.name.clone(), path.clone(), e));
+                            }
                         }
                     }
 
                     file.content_location = ContentLocation::NetworkOnly;
-                    success_delete = true;
+                    true
                 })
                 .await;
 
+            // Files on read-only media can still be shared, so they are
+            // unshared even if they can't be deleted
+            if let Some((name, path, e)) = delete_error {
+                warn!("Could not delete shared file {:?}: {}", path, e);
+
+                let _ = server_data
+                    .window_manager
+                    .send(WindowRequest::Error(BackendError {
+                        code: ErrorCode::FileNotDeleted,
+                        title: "File Error".to_owned(),
+                        error: format!(
+                            "{} is no longer shared, but it could not be deleted. It might be on read-only media. ({})",
+                            display_name(&name),
+                            e
+                        ),
+                    }));
+            }
+
             if success_delete {
-                let mut success_remove = false;
-                server_data
+                let success_remove = server_data
                     .config
                     .mutate_dir(dir_id, |dir| {
                         dir.remove_files(
                             &server_data.server_handle.peer_id,
-                            Utc::now(),
+                            server_data.config.now(),
                             vec![file_id],
                         );
 
-                        success_remove = true;
+                        true
                     })
                     .await;
 
@@ -636,9 +1744,7 @@ where
                             )
                             .await;
 
-                        let _ = server_data
-                            .window_manager
-                            .send(WindowRequest::UpdateDirectory(dir.clone()));
+                        server_data.send_directory(dir.clone());
                     }
                 }
             }
@@ -649,71 +1755,314 @@ where
         WindowResponse::DownloadFile {
             directory_identifier,
             file_identifier,
+            destination,
+            overwrite,
         } => {
             let dir_id = Uuid::parse_str(&directory_identifier)?;
             let file_id = Uuid::parse_str(&file_identifier)?;
+            let destination = destination.map(|path| DownloadDestination { path, overwrite });
 
-            let owners = server_data.config.get_owners(dir_id, file_id).await;
-            let result = match owners {
+            download_file(&mut server_data, dir_id, file_id, destination, false).await
+        }
+
+        WindowResponse::OpenShareLink { link } => {
+            let link = match link.parse::<ShareLink>() {
+                Ok(link) => link,
+                Err(e) => {
+                    warn!("Could not open link {:?}: {}", link, e);
+
+                    let _ = server_data
+                        .window_manager
+                        .send(WindowRequest::Error(BackendError {
+                            code: ErrorCode::InvalidLink,
+                            title: "Invalid Link".to_owned(),
+                            error: e.to_string(),
+                        }));
+
+                    return Ok(());
+                }
+            };
+            let dir_id = link.directory_identifier;
+            let file_id = link.file_identifier;
+
+            let file = match server_data.config.get_file(dir_id, file_id).await {
+                Some(file) => file,
                 None => {
-                    error!("File missing {}", file_id);
-                    Err(DownloadError::FileMissing)
+                    let _ = server_data
+                        .window_manager
+                        .send(WindowRequest::Error(BackendError {
+                            code: ErrorCode::FilesUnavailable,
+                            title: "File Unavailable".to_owned(),
+                            error: "The linked file is not in a directory shared with this device"
+                                .to_owned(),
+                        }));
+
+                    return Ok(());
                 }
-                Some(owners) => {
-                    let client = server_data.clients.iter().find(|(_, c)| {
-                        if let Some(id) = &c.id {
-                            return owners.contains(id);
-                        }
+            };
 
-                        false
-                    });
+            // Other owners are still used if the linked peer can't be reached
+            let online_peers = connected_peers(server_data.clients);
+            let owner = file
+                .owned_peers
+                .into_iter()
+                .find(|peer| peer.uuid == link.peer && !online_peers.contains(peer));
+            let owner_reconnecting = match owner {
+                None => false,
+                Some(owner) => server_data
+                    .mdns_sender
+                    .send(MessageToMdns::Reconnect(vec![owner]))
+                    .await
+                    .is_ok(),
+            };
 
-                    match client {
-                        None => {
-                            error!("Clients to download from not found");
-                            Err(DownloadError::NoClientsConnected)
-                        }
-                        Some((_, c)) => {
-                            let download_id = Uuid::new_v4();
-                            let download_path = server_data
-                                .config
-                                .generate_filepath(dir_id, file_id, download_id)
-                                .await;
+            download_file(&mut server_data, dir_id, file_id, None, owner_reconnecting).await
+        }
 
-                            match download_path {
-                                None => {
-                                    error!("File missing {}", file_id);
-                                    Err(DownloadError::FileMissing)
-                                }
-                                Some(path) => {
-                                    c.sender
-                                        .send(MessageToClient::StartDownload {
-                                            download_id,
-                                            file_identifier: file_id,
-                                            directory_identifier: dir_id,
-                                            destination: path,
-                                        })
-                                        .await?;
-
-                                    Ok(())
-                                }
-                            }
-                        }
+        WindowResponse::StreamFile {
+            directory_identifier,
+            file_identifier,
+        } => {
+            let dir_id = Uuid::parse_str(&directory_identifier)?;
+            let file_id = Uuid::parse_str(&file_identifier)?;
+
+            if !server_data.config.get_stream_media().await {
+                bail!("Media streaming is disabled");
+            }
+            if *server_data.network_paused {
+                bail!("Network is paused");
+            }
+
+            let file = match server_data.config.get_file(dir_id, file_id).await {
+                None => bail!("File not found {}", file_id),
+                Some(file) => file,
+            };
+            let content_type = match media_content_type(&file.name) {
+                None => bail!("File {} is not an audio or video file", file.name),
+                Some(content_type) => content_type,
+            };
+
+            if let ContentLocation::LocalPath(path) = &file.content_location {
+                let key = server_data.config.get_file_key().await;
+                opener::open(path_for_opening(path, key.as_ref()).await?)?;
+
+                return Ok(());
+            }
+            // Partial files are served as they are on disk
+            if server_data.config.get_download_key().await.is_some() {
+                bail!("Media can't be played while downloading when downloads are encrypted");
+            }
+
+            let destination = match server_data.download_destination(dir_id, file_id) {
+                // Plays the download that is already running
+                Some(destination) => destination,
+                None => {
+                    let destination = match server_data
+                        .config
+                        .generate_filepath(dir_id, file_id, Uuid::new_v4())
+                        .await
+                        .and_then(|path| shorten_download_path(&path))
+                    {
+                        None => bail!("Could not pick a download path for {}", file.name),
+                        Some(destination) => destination,
+                    };
+
+                    // Started right away, the user is waiting to watch it
+                    let download_id = Uuid::new_v4();
+                    let result = start_download(
+                        &mut server_data,
+                        download_id,
+                        dir_id,
+                        file_id,
+                        Some(DownloadDestination {
+                            path: destination.clone(),
+                            overwrite: false,
+                        }),
+                        None,
+                    )
+                    .await;
+
+                    if let Err(e) = result {
+                        error!("{}", e);
+
+                        let _ =
+                            server_data
+                                .window_manager
+                                .send(WindowRequest::Error(BackendError {
+                                    code: download_error_code(&e),
+                                    error: e.to_string(),
+                                    title: "Could not start streaming".to_string(),
+                                }));
+
+                        return Ok(());
                     }
+
+                    destination
                 }
             };
 
-            if let Err(e) = result {
-                error!("{}", e);
+            let url = server_data
+                .streams
+                .register(StreamSource {
+                    destination,
+                    size: file.size,
+                    content_type,
+                })
+                .await;
+            opener::open(&url)?;
+
+            Ok(())
+        }
+
+        WindowResponse::DownloadDirectory {
+            directory_identifier,
+        } => {
+            let dir_id = Uuid::parse_str(&directory_identifier)?;
+            let directory = match server_data.config.get_directory(dir_id).await {
+                None => bail!("Directory not found {}", dir_id),
+                Some(directory) => directory,
+            };
 
+            let connected_peers: Vec<&PeerId> = server_data
+                .clients
+                .values()
+                .filter_map(|c| c.id.as_ref())
+                .collect();
+
+            let mut files: Vec<&SharedFile> = directory.shared_files.values().collect();
+            files.sort_by(|a, b| a.name.cmp(&b.name));
+
+            let queue = &mut *server_data.download_queue;
+            let mut unavailable = vec![];
+            let mut queued = 0;
+            for file in files {
+                if queue.contains_file(dir_id, file.identifier)
+                    || matches!(file.content_location, ContentLocation::LocalPath(_))
+                {
+                    continue;
+                }
+
+                if file
+                    .owned_peers
+                    .iter()
+                    .any(|owner| connected_peers.contains(&owner))
+                {
+                    queued += 1;
+                    queue.waiting.push_back(QueuedDownload {
+                        download_id: Uuid::new_v4(),
+                        directory_identifier: dir_id,
+                        file_identifier: file.identifier,
+                        file_name: display_name(&file.name),
+                        size: file.size,
+                        destination: None,
+                    });
+                } else {
+                    unavailable.push(file.name.clone());
+                }
+            }
+
+            // Empty directories, or ones with every file already here, would
+            // otherwise report a download of zero files that never finishes
+            if queued > 0 {
+                let progress =
+                    queue
+                        .directories
+                        .entry(dir_id)
+                        .or_insert_with(|| DirectoryDownloadUpdate {
+                            directory_identifier: dir_id,
+                            files_total: 0,
+                            files_done: 0,
+                            files_failed: 0,
+                        });
+                progress.files_total += queued;
+            }
+
+            if !unavailable.is_empty() {
                 let _ = server_data
                     .window_manager
                     .send(WindowRequest::Error(BackendError {
-                        error: e.to_string(),
-                        title: "Could not start download".to_string(),
+                        code: ErrorCode::FilesUnavailable,
+                        title: "Some files could not be downloaded".to_owned(),
+                        error: format!(
+                            "No connected device has these files: {}",
+                            unavailable.join(", ")
+                        ),
                     }));
             }
 
+            start_waiting_downloads(&mut server_data).await;
+
+            Ok(())
+        }
+
+        WindowResponse::ReorderDownload {
+            download_identifier,
+            new_position,
+        } => {
+            let download_id = Uuid::parse_str(&download_identifier)?;
+
+            if !server_data
+                .download_queue
+                .reorder(download_id, new_position)
+            {
+                bail!("Download {} is not waiting in the queue", download_id);
+            }
+
+            send_download_queue(&server_data);
+
+            Ok(())
+        }
+
+        WindowResponse::GetDownloadSpace {
+            directory_identifier,
+        } => {
+            let download_directory = server_data.config.get_download_directory().await;
+
+            let queue = &*server_data.download_queue;
+            let queued_bytes = queue
+                .waiting
+                .iter()
+                .chain(queue.running.values())
+                .map(|d| d.size)
+                .sum();
+
+            let selected_bytes = match directory_identifier {
+                None => 0,
+                Some(directory_identifier) => {
+                    let dir_id = Uuid::parse_str(&directory_identifier)?;
+
+                    match server_data.config.get_directory(dir_id).await {
+                        None => bail!("Directory not found {}", dir_id),
+                        Some(directory) => directory
+                            .shared_files
+                            .values()
+                            .filter(|f| matches!(f.content_location, ContentLocation::NetworkOnly))
+                            .map(|f| f.size)
+                            .sum(),
+                    }
+                }
+            };
+
+            let free_bytes = match fs2::available_space(&download_directory) {
+                Ok(free_bytes) => Some(free_bytes),
+                Err(e) => {
+                    warn!(
+                        "Could not get free space of {:?}: {}",
+                        download_directory, e
+                    );
+                    None
+                }
+            };
+
+            let _ = server_data
+                .window_manager
+                .send(WindowRequest::DownloadSpace(DownloadSpace {
+                    download_directory,
+                    free_bytes,
+                    queued_bytes,
+                    selected_bytes,
+                }));
+
             Ok(())
         }
 
@@ -722,100 +2071,4064 @@ where
             peer,
         } => {
             let download_id = Uuid::parse_str(&download_identifier)?;
+
+            // Nothing was requested from the peer yet
+            if server_data.download_queue.cancel_waiting(download_id) {
+                let _ = server_data
+                    .window_manager
+                    .send(WindowRequest::DownloadCanceled(DownloadCanceled::new(
+                        download_id,
+                        DownloadError::Canceled,
+                        None,
+                    )));
+
+                start_waiting_downloads(&mut server_data).await;
+
+                return Ok(());
+            }
+
             let peers = vec![peer];
+            let canceled_download = server_data.downloads.remove(&download_id);
+            if let Some(download) = &canceled_download {
+                server_data.streams.remove(&download.file_path).await;
+            }
+
             server_data
                 .broadcast(&peers, MessageToClient::CancelDownload { download_id })
                 .await;
 
             let _ = server_data
                 .window_manager
-                .send(WindowRequest::DownloadCanceled(DownloadCanceled {
+                .send(WindowRequest::DownloadCanceled(DownloadCanceled::new(
                     download_id,
-                    reason: DownloadError::Canceled.to_string(),
-                }));
+                    DownloadError::Canceled,
+                    canceled_download,
+                )));
+
+            queued_download_ended(&mut server_data, download_id, false).await;
 
             Ok(())
         }
-    }
-}
-
-async fn add_client<'a>(
-    server_handle: ServerHandle,
-    clients: &mut HashMap<IpAddr, ClientHandle>,
-    tcp: TcpStream,
-    addr: ClientConnectionId,
-    service_info: Option<ServiceInfo>,
-    config: Arc<StoredConfig>,
-) -> Result<()> {
-    info!("Adding client with address {}", addr);
-
-    let (sender, receiver) = mpsc::channel(CHANNEL_SIZE);
 
-    let client_data = ClientData {
-        server: server_handle,
-        receiver,
-        addr,
-        config,
-    };
+        WindowResponse::VerifyDirectory {
+            directory_identifier,
+            repair,
+        } => {
+            let dir_id = Uuid::parse_str(&directory_identifier)?;
+            let directory = server_data.config.get_directory(dir_id).await;
 
-    let pid = match &service_info {
-        Some(service) => {
-            let name = service.get_fullname();
+            match directory {
+                None => Err(anyhow!("Directory not found")),
+                Some(directory) => {
+                    tauri::async_runtime::spawn(verify_directory(
+                        directory,
+                        server_data.server_handle.clone(),
+                        server_data.config.get_file_key().await,
+                        repair,
+                    ));
 
-            PeerId::parse(name)
+                    Ok(())
+                }
+            }
         }
-        None => None,
-    };
 
-    let join = tauri::async_runtime::spawn(client_loop(client_data, tcp, pid.clone()));
+        WindowResponse::CancelUpload {
+            download_identifier,
+        } => {
+            let download_id = Uuid::parse_str(&download_identifier)?;
 
-    let client = ClientHandle {
-        id: pid,
-        sender,
-        join,
-        service_info,
-    };
+            server_data
+                .broadcast_all(MessageToClient::CancelUpload { download_id })
+                .await;
 
-    let _ = clients.insert(addr, client);
+            Ok(())
+        }
 
-    Ok(())
-}
+        WindowResponse::CancelFileUploads {
+            directory_identifier,
+            file_identifier,
+        } => {
+            let dir_id = Uuid::parse_str(&directory_identifier)?;
+            let file_id = Uuid::parse_str(&file_identifier)?;
 
-async fn disconnected_client<'a>(client: ClientHandle, mdns_sender: &mpsc::Sender<MessageToMdns>) {
-    client.join.abort();
+            server_data
+                .broadcast_all(MessageToClient::CancelFileUploads {
+                    directory_identifier: dir_id,
+                    file_identifier: file_id,
+                })
+                .await;
 
-    if let Some(service) = client.service_info {
-        let _ = mdns_sender
-            .send(MessageToMdns::RemoveService(service))
-            .await;
-    }
-}
+            Ok(())
+        }
 
-async fn create_shared_file(file_path: String, this_peer: &PeerId) -> Result<SharedFile> {
-    let path = PathBuf::from_str(&file_path)?;
+        WindowResponse::AcceptShare {
+            directory_identifier,
+        } => {
+            let dir_id = Uuid::parse_str(&directory_identifier)?;
+            let share = match server_data.config.take_pending_share(dir_id).await {
+                None => return Err(anyhow!("No pending share for directory {}", dir_id)),
+                Some(share) => share,
+            };
 
-    let mut file = tokio::fs::File::open(&path).await?;
-    let metadata = file.metadata().await?;
-    let checksum = compute_stream(&mut file).await?;
+            if let Err(e) = server_data
+                .config
+                .check_share_limits(&share.directory)
+                .await
+            {
+                let _ = server_data
+                    .window_manager
+                    .send(WindowRequest::Error(BackendError {
+                        code: ErrorCode::ShareLimitReached,
+                        title: "Share Limit".to_owned(),
+                        error: e.to_string(),
+                    }));
 
-    let identifier = Uuid::new_v4();
-    let name = match path.file_name() {
-        Some(name) => match name.to_str() {
-            Some(os_name) => os_name.to_string(),
-            None => bail!("Invalid file path: {:?}", path),
-        },
-        None => bail!("Invalid file path: {:?}", path),
+                return Ok(());
+            }
+
+            let myself = &server_data.server_handle.peer_id;
+            let directories = server_data
+                .config
+                .synchronize(vec![share.directory], myself)
+                .await;
+
+            server_data.send_directories(directories);
+
+            Ok(())
+        }
+
+        WindowResponse::RejectShare {
+            directory_identifier,
+        } => {
+            let dir_id = Uuid::parse_str(&directory_identifier)?;
+            let share = match server_data.config.take_pending_share(dir_id).await {
+                None => return Err(anyhow!("No pending share for directory {}", dir_id)),
+                Some(share) => share,
+            };
+
+            server_data
+                .broadcast(
+                    &[share.peer],
+                    MessageToClient::LeftDirectory {
+                        directory_identifier: dir_id,
+                    },
+                )
+                .await;
+
+            Ok(())
+        }
+
+        WindowResponse::DisconnectPeer { peer } => {
+            let connected = server_data
+                .clients
+                .values()
+                .any(|c| c.id.as_ref() == Some(&peer));
+
+            if !connected {
+                return Err(anyhow!("Peer {} is not connected", peer));
+            }
+
+            server_data
+                .broadcast(
+                    &[peer],
+                    MessageToClient::Goodbye("Disconnected by peer".to_owned()),
+                )
+                .await;
+
+            Ok(())
+        }
+
+        WindowResponse::PeerSpeedTest { peer } => {
+            if *server_data.network_paused {
+                bail!("Network is paused");
+            }
+
+            let client = server_data
+                .clients
+                .values()
+                .find(|c| c.id.as_ref() == Some(&peer));
+
+            match client {
+                None => Err(anyhow!("Peer {} is not connected", peer)),
+                Some(client) => {
+                    client
+                        .sender
+                        .send(MessageToClient::SpeedTest {
+                            test_id: Uuid::new_v4(),
+                        })
+                        .await?;
+
+                    Ok(())
+                }
+            }
+        }
+
+        WindowResponse::RescanNetwork => {
+            if *server_data.network_paused {
+                bail!("Network is paused");
+            }
+
+            server_data.mdns_sender.send(MessageToMdns::Rescan).await?;
+
+            Ok(())
+        }
+
+        WindowResponse::SetNetworkPaused(paused) => {
+            if *server_data.network_paused != paused {
+                info!("Network {}", if paused { "paused" } else { "resumed" });
+
+                *server_data.network_paused = paused;
+                server_data
+                    .broadcast_all(MessageToClient::SetPaused(paused))
+                    .await;
+                server_data
+                    .mdns_sender
+                    .send(MessageToMdns::SetPaused(paused))
+                    .await?;
+
+                if !paused {
+                    start_waiting_downloads(&mut server_data).await;
+                }
+            }
+
+            server_data.send_network_status();
+
+            Ok(())
+        }
+
+        WindowResponse::SetDiscoverable(discoverable) => {
+            if *server_data.discoverable != discoverable {
+                info!(
+                    "{} discovery by new peers",
+                    if discoverable {
+                        "Allowing"
+                    } else {
+                        "Hiding from"
+                    }
+                );
+
+                *server_data.discoverable = discoverable;
+                server_data
+                    .mdns_sender
+                    .send(MessageToMdns::SetDiscoverable(discoverable))
+                    .await?;
+            }
+
+            server_data.send_network_status();
+
+            Ok(())
+        }
+
+        WindowResponse::GetInterruptedDownloads => {
+            let mut interrupted = vec![];
+
+            for download in server_data.config.get_interrupted_downloads().await {
+                let bytes_done = tokio::fs::metadata(partial_path(&download.file_path))
+                    .await
+                    .map(|metadata| metadata.len())
+                    .unwrap_or(0);
+
+                interrupted.push(InterruptedDownload {
+                    download,
+                    bytes_done,
+                });
+            }
+
+            let _ = server_data
+                .window_manager
+                .send(WindowRequest::InterruptedDownloads(interrupted));
+
+            Ok(())
+        }
+
+        WindowResponse::RetryInterruptedDownload {
+            download_identifier,
+        } => {
+            let download_id = Uuid::parse_str(&download_identifier)?;
+
+            let download = match server_data
+                .config
+                .take_interrupted_download(download_id)
+                .await
+            {
+                None => bail!("Interrupted download not found {}", download_id),
+                Some(download) => download,
+            };
+
+            // Transfers can't continue from an offset, so the file is
+            // downloaded again into the same destination.
+            let limits = server_data.config.get_download_limits().await;
+            let new_download_id = Uuid::new_v4();
+            let destination = DownloadDestination {
+                path: download.file_path.clone(),
+                overwrite: false,
+            };
+            let result = start_download(
+                &mut server_data,
+                new_download_id,
+                download.directory_identifier,
+                download.file_identifier,
+                Some(destination.clone()),
+                Some(limits),
+            )
+            .await;
+
+            if let Err(DownloadError::Busy) = result {
+                queue_download(
+                    &mut server_data,
+                    new_download_id,
+                    download.directory_identifier,
+                    download.file_identifier,
+                    Some(destination),
+                )
+                .await?;
+            } else if let Err(e) = result {
+                error!("{}", e);
+
+                let _ = tokio::fs::remove_file(partial_path(&download.file_path)).await;
+                let _ = server_data
+                    .window_manager
+                    .send(WindowRequest::Error(BackendError {
+                        code: download_error_code(&e),
+                        error: e.to_string(),
+                        title: "Could not start download".to_string(),
+                    }));
+            }
+
+            Ok(())
+        }
+
+        WindowResponse::DiscardInterruptedDownload {
+            download_identifier,
+        } => {
+            let download_id = Uuid::parse_str(&download_identifier)?;
+
+            if let Some(download) = server_data
+                .config
+                .take_interrupted_download(download_id)
+                .await
+            {
+                let path = partial_path(&download.file_path);
+
+                if let Err(e) = tokio::fs::remove_file(&path).await {
+                    warn!("Could not remove partial download {:?}: {}", path, e);
+                }
+
+                server_data
+                    .config
+                    .clear_partial_location(
+                        download.directory_identifier,
+                        download.file_identifier,
+                        &path,
+                    )
+                    .await;
+            }
+
+            Ok(())
+        }
+
+        WindowResponse::ForgetPeer { peer } => {
+            let myself = server_data.server_handle.peer_id.clone();
+            let now = server_data.config.now();
+            let mut left_directories = vec![];
+
+            for dir in server_data.config.get_directories().await {
+                if !dir.signature.shared_peers.contains(&peer) {
+                    continue;
+                }
+
+                let shared_only_with_peer = dir
+                    .signature
+                    .shared_peers
+                    .iter()
+                    .all(|p| p == &peer || p == &myself);
+                if shared_only_with_peer {
+                    left_directories.push(dir.signature.identifier);
+                }
+
+                server_data
+                    .config
+                    .mutate_dir(dir.signature.identifier, |dir| {
+                        dir.remove_peer(&peer, now);
+
+                        true
+                    })
+                    .await;
+            }
+
+            for directory_identifier in left_directories {
+                server_data
+                    .broadcast(
+                        std::slice::from_ref(&peer),
+                        MessageToClient::LeftDirectory {
+                            directory_identifier,
+                        },
+                    )
+                    .await;
+            }
+
+            server_data
+                .broadcast(
+                    &[peer],
+                    MessageToClient::Goodbye("Forgotten by peer".to_owned()),
+                )
+                .await;
+
+            server_data.send_directories(server_data.config.get_directories().await);
+
+            Ok(())
+        }
+    }
+}
+
+async fn notify_download_finished<M>(server_data: &ServerData<'_, M>, download: Option<Download>)
+where
+    M: WindowManager,
+{
+    let should_notify = match server_data.config.get_download_notifications().await {
+        DownloadNotifications::Disabled => false,
+        DownloadNotifications::EveryDownload => true,
+        DownloadNotifications::LastDownload => server_data.downloads.is_empty(),
     };
-    let now = Utc::now();
-    let size = metadata.len();
 
-    Ok(SharedFile {
-        name,
-        identifier,
-        content_hash: checksum,
-        last_modified: now,
-        content_location: ContentLocation::LocalPath(path),
-        owned_peers: vec![this_peer.clone()],
-        size,
-    })
-}
\ No newline at end of file
+    if !should_notify {
+        return;
+    }
+
+    let body = match download {
+        Some(download) => format!(
+            "{} was saved to {}",
+            download.file_name,
+            download.file_path.display()
+        ),
+        None => "File was downloaded successfully".to_owned(),
+    };
+
+    if let Err(e) = server_data
+        .window_manager
+        .notify("Download finished", &body)
+    {
+        warn!("Could not show download notification: {}", e);
+    }
+}
+
+/// Returns the directories from `peer` that can be applied right away. When
+/// shares aren't auto-accepted, directories we don't have yet are held back
+/// and offered to the user instead.
+async fn hold_offered_shares<M>(
+    server_data: &ServerData<'_, M>,
+    directories: Vec<ShareDirectory>,
+    peer: &PeerId,
+) -> Vec<ShareDirectory>
+where
+    M: WindowManager,
+{
+    if server_data.config.get_auto_accept_shares().await {
+        return directories;
+    }
+
+    let (known, offered) = server_data.config.hold_new_shares(directories, peer).await;
+
+    for directory in offered {
+        info!(
+            "Peer {} offered directory {}",
+            peer, directory.signature.identifier
+        );
+
+        let _ = server_data
+            .window_manager
+            .send(WindowRequest::ShareOffered(ShareOffer {
+                directory: directory.signature,
+                peer: peer.clone(),
+            }));
+    }
+
+    known
+}
+
+fn download_error_code(error: &DownloadError) -> ErrorCode {
+    match error {
+        DownloadError::NoClientsConnected | DownloadError::Disconnected => {
+            ErrorCode::PeerUnavailable
+        }
+        DownloadError::WriteError
+        | DownloadError::PathTooLong
+        | DownloadError::DownloadDirectoryUnavailable => ErrorCode::DownloadLocationUnwritable,
+        _ => ErrorCode::DownloadFailed,
+    }
+}
+
+/// Starts downloading a file, or queues it while the network is paused or
+/// its owners are reconnecting or the download directory is unavailable.
+/// Files that are already downloaded or being downloaded are left alone.
+async fn download_file<M>(
+    server_data: &mut ServerData<'_, M>,
+    dir_id: Uuid,
+    file_id: Uuid,
+    destination: Option<DownloadDestination>,
+    owner_reconnecting: bool,
+) -> Result<()>
+where
+    M: WindowManager,
+{
+    if let Some(path) = server_data.config.get_filepath(dir_id, file_id).await {
+        if tokio::fs::metadata(&path).await.is_ok() {
+            info!("File {} has already been downloaded to {:?}", file_id, path);
+
+            let _ = server_data
+                .window_manager
+                .send(WindowRequest::AlreadyDownloaded(AlreadyDownloaded {
+                    directory_identifier: dir_id,
+                    file_identifier: file_id,
+                    file_path: path,
+                }));
+
+            return Ok(());
+        }
+    }
+
+    if server_data.is_downloading(dir_id, file_id) {
+        info!("File {} is already being downloaded", file_id);
+
+        let _ = server_data
+            .window_manager
+            .send(WindowRequest::Error(BackendError {
+                code: ErrorCode::AlreadyDownloading,
+                title: "Already Downloading".to_owned(),
+                error: "This file is already being downloaded".to_owned(),
+            }));
+
+        return Ok(());
+    }
+
+    let download_id = Uuid::new_v4();
+
+    if !*server_data.network_paused {
+        let limits = server_data.config.get_download_limits().await;
+        let result = start_download(
+            server_data,
+            download_id,
+            dir_id,
+            file_id,
+            destination.clone(),
+            Some(limits),
+        )
+        .await;
+        let reconnecting = matches!(result, Err(DownloadError::NoClientsConnected))
+            && (owner_reconnecting || wake_idle_owners(server_data, dir_id, file_id).await);
+
+        match result {
+            Ok(()) => return Ok(()),
+            // Queued until one of the owners reconnects
+            Err(_) if reconnecting => (),
+            // Queued until another download ends
+            Err(DownloadError::Busy) => (),
+            Err(e) => {
+                error!("{}", e);
+
+                let _ = server_data
+                    .window_manager
+                    .send(WindowRequest::Error(BackendError {
+                        code: download_error_code(&e),
+                        error: e.to_string(),
+                        title: "Could not start download".to_string(),
+                    }));
+
+                if e != DownloadError::DownloadDirectoryUnavailable {
+                    return Ok(());
+                }
+
+                // Queued until the directory is available again
+                server_data.download_queue.directory_unavailable = true;
+            }
+        }
+    }
+
+    queue_download(server_data, download_id, dir_id, file_id, destination).await
+}
+
+/// Adds a download to the back of the queue, to start once an owner is
+/// connected and a download slot is free.
+async fn queue_download<M>(
+    server_data: &mut ServerData<'_, M>,
+    download_id: Uuid,
+    dir_id: Uuid,
+    file_id: Uuid,
+    destination: Option<DownloadDestination>,
+) -> Result<()>
+where
+    M: WindowManager,
+{
+    let file = match server_data.config.get_file(dir_id, file_id).await {
+        None => bail!("File not found {}", file_id),
+        Some(file) => file,
+    };
+
+    server_data
+        .download_queue
+        .waiting
+        .push_back(QueuedDownload {
+            download_id,
+            directory_identifier: dir_id,
+            file_identifier: file_id,
+            file_name: display_name(&file.name),
+            size: file.size,
+            destination,
+        });
+    send_download_queue(server_data);
+
+    Ok(())
+}
+
+/// Asks a connected owner of the file to send it to `destination`, or to a
+/// generated path in the download directory.
+async fn start_download<M>(
+    server_data: &mut ServerData<'_, M>,
+    download_id: Uuid,
+    dir_id: Uuid,
+    file_id: Uuid,
+    destination: Option<DownloadDestination>,
+    limits: Option<DownloadLimits>,
+) -> Result<(), DownloadError>
+where
+    M: WindowManager,
+{
+    if server_data.config.is_encryption_locked().await {
+        return Err(DownloadError::EncryptionLocked);
+    }
+
+    let file = match server_data.config.get_file(dir_id, file_id).await {
+        None => {
+            error!("File missing {}", file_id);
+            return Err(DownloadError::FileMissing);
+        }
+        Some(file) => file,
+    };
+
+    let owners: Vec<(&ClientHandle, &PeerId)> = server_data
+        .clients
+        .values()
+        .filter_map(|c| match &c.id {
+            Some(id) if file.owned_peers.contains(id) => Some((c, id)),
+            _ => None,
+        })
+        .collect();
+    if owners.is_empty() {
+        error!("Clients to download from not found");
+        return Err(DownloadError::NoClientsConnected);
+    }
+
+    // Owners we are downloading the least from go first
+    let source = match limits {
+        None => owners.first().copied(),
+        Some(limits) if server_data.downloads.len() >= limits.total => None,
+        Some(limits) => owners
+            .iter()
+            .map(|&(client, peer)| ((client, peer), server_data.downloads_from(peer)))
+            .filter(|(_, running)| *running < limits.per_peer)
+            .min_by_key(|(_, running)| *running)
+            .map(|(owner, _)| owner),
+    };
+    let (sender, peer) = match source {
+        Some((client, peer)) => (client.sender.clone(), peer.clone()),
+        None => {
+            info!("No download slot free for {}", file_id);
+            return Err(DownloadError::Busy);
+        }
+    };
+
+    let download_path = match destination {
+        Some(destination) => {
+            check_destination(&destination).await?;
+            destination.path
+        }
+        None if !download_directory_exists(server_data.config).await => {
+            error!("Download directory is unavailable");
+            return Err(DownloadError::DownloadDirectoryUnavailable);
+        }
+        None => match server_data
+            .config
+            .generate_filepath(dir_id, file_id, download_id)
+            .await
+        {
+            None => {
+                error!("File missing {}", file_id);
+                return Err(DownloadError::FileMissing);
+            }
+            Some(path) => match shorten_download_path(&path) {
+                None => {
+                    error!("Download path {:?} is too long", path);
+                    return Err(DownloadError::PathTooLong);
+                }
+                Some(path) => path,
+            },
+        },
+    };
+
+    sender
+        .send(MessageToClient::StartDownload {
+            download_id,
+            file_identifier: file_id,
+            directory_identifier: dir_id,
+            destination: download_path.clone(),
+        })
+        .await
+        .map_err(|_| DownloadError::Disconnected)?;
+
+    // Tracked right away, so the file isn't requested again before the
+    // client reports the download as started
+    server_data.downloads.insert(
+        download_id,
+        Download {
+            peer,
+            download_id,
+            file_identifier: file_id,
+            directory_identifier: dir_id,
+            progress: 0,
+            file_name: display_name(&file.name),
+            file_path: download_path,
+        },
+    );
+
+    Ok(())
+}
+
+/// Reconnects to owners of a file whose connections were closed for being
+/// idle. Returns whether any owner is on its way back.
+async fn wake_idle_owners<M>(server_data: &ServerData<'_, M>, dir_id: Uuid, file_id: Uuid) -> bool
+where
+    M: WindowManager,
+{
+    match server_data.config.get_owners(dir_id, file_id).await {
+        None => false,
+        Some(owners) => server_data.wake_idle_peers(&owners).await,
+    }
+}
+
+/// Starts queued downloads in order while there are free download slots,
+/// then reports the queue and the progress of each directory download.
+/// Downloads from idle owners stay queued until the owners reconnect, and
+/// ones whose owners are at the per-peer limit until one of them is free.
+async fn start_waiting_downloads<M>(server_data: &mut ServerData<'_, M>)
+where
+    M: WindowManager,
+{
+    let limits = server_data.config.get_download_limits().await;
+    let mut reconnecting = vec![];
+    // Downloads before this one wait for a slot with their owners
+    let mut next = 0;
+
+    while !*server_data.network_paused
+        && !server_data.download_queue.directory_unavailable
+        && server_data.downloads.len() < limits.total
+    {
+        let download = match server_data.download_queue.waiting.get(next) {
+            None => break,
+            Some(download) => download.clone(),
+        };
+
+        let result = start_download(
+            server_data,
+            download.download_id,
+            download.directory_identifier,
+            download.file_identifier,
+            download.destination.clone(),
+            Some(limits),
+        )
+        .await;
+        if let Err(DownloadError::Busy) = result {
+            next += 1;
+            continue;
+        }
+        server_data.download_queue.waiting.remove(next);
+
+        let owners_reconnecting = matches!(result, Err(DownloadError::NoClientsConnected))
+            && wake_idle_owners(
+                server_data,
+                download.directory_identifier,
+                download.file_identifier,
+            )
+            .await;
+
+        let queue = &mut *server_data.download_queue;
+        match result {
+            Ok(()) => {
+                queue.running.insert(download.download_id, download);
+            }
+            Err(_) if owners_reconnecting => reconnecting.push(download),
+            Err(DownloadError::DownloadDirectoryUnavailable) => {
+                warn!("Pausing queued downloads until the download directory is available");
+
+                queue.directory_unavailable = true;
+                queue.waiting.insert(next, download);
+            }
+            Err(e) => {
+                warn!("Could not download {}: {}", download.file_name, e);
+
+                if let Some(progress) = queue.directories.get_mut(&download.directory_identifier) {
+                    progress.files_failed += 1;
+                }
+            }
+        }
+    }
+
+    for download in reconnecting.into_iter().rev() {
+        server_data.download_queue.waiting.insert(next, download);
+    }
+
+    send_download_queue(server_data);
+
+    let queue = &mut *server_data.download_queue;
+    for progress in queue.directories.values() {
+        let _ = server_data
+            .window_manager
+            .send(WindowRequest::DirectoryDownloadUpdate(progress.clone()));
+    }
+
+    queue
+        .directories
+        .retain(|_, p| p.files_done + p.files_failed < p.files_total);
+}
+
+/// Shares a directory with `peers` and sends it to everyone it is shared
+/// with. Returns false if all of them already had it.
+async fn share_directory<M>(
+    server_data: &ServerData<'_, M>,
+    dir_id: Uuid,
+    peers: Vec<PeerId>,
+) -> Result<bool>
+where
+    M: WindowManager,
+{
+    let added = server_data
+        .config
+        .mutate_dir(dir_id, |dir| dir.add_peers(peers, server_data.config.now()))
+        .await;
+
+    let dir = match server_data.config.get_directory(dir_id).await {
+        None => bail!("Directory not found"),
+        Some(dir) => dir,
+    };
+
+    if added {
+        server_data
+            .broadcast(
+                &dir.signature.shared_peers,
+                MessageToClient::SendDirectories(vec![dir.clone()]),
+            )
+            .await;
+
+        server_data.send_directory(dir);
+    }
+
+    Ok(added)
+}
+
+/// Adds files to a directory and tells the peers it is shared with. Returns
+/// the files that were skipped because they are already shared, or `None` if
+/// none could be added, in which case the window is told why.
+async fn add_shared_files<M>(
+    server_data: &ServerData<'_, M>,
+    dir_id: Uuid,
+    mut shared_files: Vec<SharedFile>,
+) -> Option<Vec<SharedFile>>
+where
+    M: WindowManager,
+{
+    let max_files = server_data.config.get_max_files_per_directory().await;
+    let mut signature = None;
+    let mut limit_error = None;
+    let mut skipped = vec![];
+    server_data
+        .config
+        .mutate_dir(dir_id, |directory| {
+            if let Err(e) = directory.check_file_limit(shared_files.len(), max_files) {
+                limit_error = Some(e);
+                return false;
+            }
+
+            let add_result = directory.add_files(shared_files.clone(), server_data.config.now());
+
+            match add_result {
+                Ok(skipped_files) => {
+                    server_data.send_directory(directory.clone());
+
+                    signature = Some(directory.signature.clone());
+                    skipped = skipped_files;
+                    true
+                }
+                Err(_) => false,
+            }
+        })
+        .await;
+
+    if let Some(signature) = signature {
+        shared_files.retain(|file| !skipped.iter().any(|s| s.identifier == file.identifier));
+
+        if !shared_files.is_empty() {
+            server_data
+                .broadcast(
+                    &signature.shared_peers,
+                    MessageToClient::AddedFiles(signature.clone(), shared_files),
+                )
+                .await;
+        }
+
+        return Some(skipped);
+    }
+
+    let error = match limit_error {
+        Some(e) => BackendError {
+            code: ErrorCode::ShareLimitReached,
+            title: "Share Limit".to_owned(),
+            error: e.to_string(),
+        },
+        None => BackendError {
+            code: ErrorCode::FileAlreadyShared,
+            title: "File Error".to_owned(),
+            error: "File has already been added to this directory".to_owned(),
+        },
+    };
+    let _ = server_data.window_manager.send(WindowRequest::Error(error));
+
+    None
+}
+
+/// Sends every connected peer the digests of the directories it shares with
+/// us. The peer answers with its copy of each directory whose digest differs,
+/// which is merged like any other synchronization, so directories that
+/// drifted apart through missed messages converge without resending all of
+/// them.
+async fn compare_directories<M>(server_data: &ServerData<'_, M>)
+where
+    M: WindowManager,
+{
+    if *server_data.network_paused {
+        return;
+    }
+
+    let directories = server_data.config.get_directories().await;
+    for (addr, client) in server_data.clients.iter() {
+        let peer = match &client.id {
+            Some(peer) => peer,
+            None => continue,
+        };
+
+        let digests: Vec<DirectoryDigest> = directories
+            .iter()
+            .filter(|dir| dir.signature.shared_peers.contains(peer))
+            .map(|dir| dir.digest())
+            .collect();
+
+        if !digests.is_empty() {
+            server_data
+                .send_to_clients(
+                    std::iter::once((addr, client)),
+                    MessageToClient::CompareDirectories(digests),
+                )
+                .await;
+        }
+    }
+}
+
+/// Warns about files whose owner list disagrees with whether they are
+/// stored locally. They can be fixed with `RepairOwnership`.
+async fn log_ownership_drift(config: &StoredConfig, myself: &PeerId) {
+    for dir in config.get_directories().await {
+        for file in dir.shared_files.values() {
+            match file.local_ownership(myself) {
+                LocalOwnership::Owned | LocalOwnership::NotOwned => (),
+                drift => warn!(
+                    "File {} in directory {} has inconsistent ownership: {:?}",
+                    file.identifier, dir.signature.identifier, drift
+                ),
+            }
+        }
+    }
+}
+
+/// Removes us from the owners of a file we don't have, or lists us as an
+/// owner of a file we do have, and tells peers about the change.
+async fn repair_ownership<M>(
+    server_data: &ServerData<'_, M>,
+    dir_id: Uuid,
+    file_id: Uuid,
+) -> Result<()>
+where
+    M: WindowManager,
+{
+    let myself = server_data.server_handle.peer_id.clone();
+    let file = match server_data.config.get_file(dir_id, file_id).await {
+        None => bail!("File not found {}", file_id),
+        Some(file) => file,
+    };
+
+    match (file.local_ownership(&myself), &file.content_location) {
+        (LocalOwnership::ListedWithoutContent, _) => {
+            warn!(
+                "Listed as an owner of {} without having it, unlisting",
+                file_id
+            );
+
+            server_data
+                .config
+                .mutate_dir(dir_id, |dir| {
+                    dir.remove_files(&myself, server_data.config.now(), vec![file_id]);
+
+                    true
+                })
+                .await;
+
+            if let Some(dir) = server_data.config.get_directory(dir_id).await {
+                server_data
+                    .broadcast(
+                        &dir.signature.shared_peers,
+                        MessageToClient::DeleteFile(myself, dir.signature.clone(), file_id),
+                    )
+                    .await;
+                server_data.send_directory(dir);
+            }
+        }
+        (LocalOwnership::StoredWithoutListing, ContentLocation::LocalPath(path))
+            if tokio::fs::metadata(path).await.is_ok() =>
+        {
+            warn!(
+                "Stored {} without being listed as an owner, listing",
+                file_id
+            );
+
+            server_data
+                .config
+                .mutate_dir(dir_id, |dir| {
+                    dir.add_owner(&myself, server_data.config.now(), vec![file_id], None);
+
+                    true
+                })
+                .await;
+
+            if let Some(dir) = server_data.config.get_directory(dir_id).await {
+                server_data
+                    .broadcast(
+                        &dir.signature.shared_peers,
+                        MessageToClient::UpdateOwners {
+                            peer_id: myself,
+                            directory_identifier: dir_id,
+                            file_identifier: file_id,
+                            date_modified: dir.signature.last_modified,
+                        },
+                    )
+                    .await;
+                server_data.send_directory(dir);
+            }
+        }
+        (LocalOwnership::StoredWithoutListing, _) => {
+            warn!("Stored file {} is gone, forgetting its location", file_id);
+
+            server_data
+                .config
+                .mutate_file(dir_id, file_id, |file| {
+                    file.content_location = ContentLocation::NetworkOnly;
+
+                    true
+                })
+                .await;
+
+            if let Some(dir) = server_data.config.get_directory(dir_id).await {
+                server_data.send_directory(dir);
+            }
+        }
+        (LocalOwnership::Owned | LocalOwnership::NotOwned, _) => (),
+    }
+
+    Ok(())
+}
+
+/// Checked before every download rather than once at startup, since the
+/// directory can be on a drive that is removed while the app runs.
+async fn download_directory_exists(config: &StoredConfig) -> bool {
+    match tokio::fs::metadata(config.get_download_directory().await).await {
+        Ok(metadata) => metadata.is_dir(),
+        Err(_) => false,
+    }
+}
+
+/// Starts downloads that were paused because the download directory was
+/// missing, once it is back or another directory was chosen.
+async fn resume_if_directory_available<M>(mut server_data: ServerData<'_, M>)
+where
+    M: WindowManager,
+{
+    if !download_directory_exists(server_data.config).await {
+        return;
+    }
+
+    info!("Download directory is available again, resuming queued downloads");
+    server_data.download_queue.directory_unavailable = false;
+    start_waiting_downloads(&mut server_data).await;
+}
+
+fn send_download_queue<M>(server_data: &ServerData<'_, M>)
+where
+    M: WindowManager,
+{
+    let waiting = server_data.download_queue.waiting.iter().cloned().collect();
+
+    let _ = server_data
+        .window_manager
+        .send(WindowRequest::DownloadQueue(waiting));
+}
+
+/// Cancels the downloads from a peer that has no connection left. Its
+/// client was aborted, so nothing else reports them as ended.
+async fn cancel_downloads_from<M>(server_data: &mut ServerData<'_, M>, peer: &PeerId)
+where
+    M: WindowManager,
+{
+    let download_ids: Vec<Uuid> = server_data
+        .downloads
+        .values()
+        .filter(|d| &d.peer == peer)
+        .map(|d| d.download_id)
+        .collect();
+
+    for download_id in download_ids {
+        let canceled_download = server_data.downloads.remove(&download_id);
+        if let Some(download) = &canceled_download {
+            server_data.streams.remove(&download.file_path).await;
+        }
+
+        let _ = server_data
+            .window_manager
+            .send(WindowRequest::DownloadCanceled(DownloadCanceled::new(
+                download_id,
+                DownloadError::Disconnected,
+                canceled_download,
+            )));
+
+        queued_download_ended(server_data, download_id, false).await;
+    }
+}
+
+/// Records the end of a download if it was started from the queue and
+/// starts waiting downloads in the slot it freed.
+async fn queued_download_ended<M>(
+    server_data: &mut ServerData<'_, M>,
+    download_id: Uuid,
+    succeeded: bool,
+) where
+    M: WindowManager,
+{
+    let queue = &mut *server_data.download_queue;
+    let download = match queue.running.remove(&download_id) {
+        // Downloads started without the queue free a slot for it too
+        None if !queue.waiting.is_empty() => return start_waiting_downloads(server_data).await,
+        None => return,
+        Some(download) => download,
+    };
+
+    if let Some(progress) = queue.directories.get_mut(&download.directory_identifier) {
+        if succeeded {
+            progress.files_done += 1;
+        } else {
+            progress.files_failed += 1;
+        }
+    }
+
+    start_waiting_downloads(server_data).await;
+}
+
+/// Checks that a user-chosen download destination names a file inside an
+/// existing, writable directory. The finished download is renamed over the
+/// destination, so an existing file is only replaced once the user confirmed
+/// it, and never when it's a link to somewhere else.
+async fn check_destination(destination: &DownloadDestination) -> Result<(), DownloadError> {
+    let path = &destination.path;
+    if !path.is_absolute() || path.file_name().is_none() {
+        error!("Invalid download destination {:?}", path);
+        return Err(DownloadError::WriteError);
+    }
+
+    if !download_path_fits(path) {
+        error!("Download destination {:?} is too long", path);
+        return Err(DownloadError::PathTooLong);
+    }
+
+    if let Ok(metadata) = tokio::fs::symlink_metadata(path).await {
+        if !destination.overwrite || !metadata.is_file() || metadata.permissions().readonly() {
+            error!("Cannot overwrite download destination {:?}", path);
+            return Err(DownloadError::WriteError);
+        }
+    }
+
+    let parent = path.parent().ok_or(DownloadError::WriteError)?;
+    match tokio::fs::metadata(parent).await {
+        Ok(metadata) if metadata.is_dir() && !metadata.permissions().readonly() => Ok(()),
+        _ => {
+            error!(
+                "Download destination directory {:?} is not writable",
+                parent
+            );
+            Err(DownloadError::WriteError)
+        }
+    }
+}
+
+/// Deletes the downloaded copies of files from a directory we left. Files
+/// outside the download directory, like the ones we shared ourselves, and
+/// files another joined directory still points to are kept. Returns how many
+/// files were deleted and how many bytes that freed.
+async fn remove_local_copies(config: &StoredConfig, directory: &ShareDirectory) -> (usize, u64) {
+    let download_directory = match config.get_download_directory().await.canonicalize() {
+        Ok(download_directory) => download_directory,
+        Err(_) => return (0, 0),
+    };
+
+    let still_used: Vec<PathBuf> = config
+        .get_directories()
+        .await
+        .iter()
+        .flat_map(|dir| dir.shared_files.values())
+        .filter_map(|file| match &file.content_location {
+            ContentLocation::NetworkOnly | ContentLocation::Partial { .. } => None,
+            ContentLocation::LocalPath(path) => path.canonicalize().ok(),
+        })
+        .collect();
+
+    let mut deleted_files = 0;
+    let mut freed_bytes = 0;
+    for file in directory.shared_files.values() {
+        let path = match &file.content_location {
+            // Running downloads remove their own partial files
+            ContentLocation::NetworkOnly | ContentLocation::Partial { .. } => continue,
+            ContentLocation::LocalPath(path) => match path.canonicalize() {
+                Ok(path) => path,
+                Err(_) => continue,
+            },
+        };
+
+        if !path.starts_with(&download_directory) || still_used.contains(&path) {
+            continue;
+        }
+
+        let size = tokio::fs::metadata(&path)
+            .await
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => {
+                info!("Removed local copy {:?}", path);
+                deleted_files += 1;
+                freed_bytes += size;
+            }
+            Err(e) => warn!("Could not remove local copy {:?}: {}", path, e),
+        }
+    }
+
+    (deleted_files, freed_bytes)
+}
+
+/// Orders the addresses a service advertises so that the one that worked
+/// last time is tried first, followed by the rest in a stable order.
+fn candidate_addresses(
+    addresses: &HashSet<Ipv4Addr>,
+    preferred: Option<&Ipv4Addr>,
+) -> Vec<Ipv4Addr> {
+    let mut candidates: Vec<Ipv4Addr> = addresses.iter().copied().collect();
+    candidates.sort_by_key(|ip| (Some(ip) != preferred, *ip));
+
+    candidates
+}
+
+/// Connects to the first of `addresses` that accepts a connection in time.
+/// Peers often advertise addresses that are unreachable from here, like VPN
+/// or container bridge addresses. Fails with the error of the last attempt.
+async fn connect_to_any(
+    addresses: &[Ipv4Addr],
+    port: u16,
+    timeout: Duration,
+) -> std::io::Result<(TcpStream, SocketAddrV4)> {
+    let mut last_error = std::io::Error::new(
+        std::io::ErrorKind::AddrNotAvailable,
+        "no addresses to connect to",
+    );
+
+    for ip in addresses {
+        let socket_addr = SocketAddrV4::new(*ip, port);
+        let connect = TcpStream::connect(socket_addr);
+
+        last_error = match tokio::time::timeout(timeout, connect).await {
+            Ok(Ok(tcp_stream)) => return Ok((tcp_stream, socket_addr)),
+            Ok(Err(e)) => e,
+            Err(_) => std::io::Error::new(std::io::ErrorKind::TimedOut, "connection timed out"),
+        };
+
+        warn!("Could not connect to {}: {}", socket_addr, last_error);
+    }
+
+    Err(last_error)
+}
+
+/// Lists identified peers sorted by hostname, then uuid, so the UI gets the
+/// same order every time regardless of connection order.
+fn connected_peers(clients: &HashMap<ClientConnectionId, ClientHandle>) -> Vec<PeerId> {
+    let mut peers: Vec<PeerId> = clients.values().filter_map(|c| c.id.clone()).collect();
+    peers.sort_by(|a, b| (&a.hostname, a.uuid).cmp(&(&b.hostname, b.uuid)));
+    peers.dedup();
+
+    peers
+}
+
+/// Message counters of every connection, sorted by address.
+fn protocol_metrics(
+    clients: &HashMap<ClientConnectionId, ClientHandle>,
+    metrics: &ProtocolMetrics,
+) -> Vec<PeerProtocolMetrics> {
+    let mut metrics: Vec<PeerProtocolMetrics> = metrics
+        .snapshot()
+        .into_iter()
+        .map(|(address, counters)| PeerProtocolMetrics {
+            peer: clients.get(&address).and_then(|c| c.id.clone()),
+            compression: clients
+                .get(&address)
+                .map(|c| c.compression)
+                .unwrap_or_default(),
+            address,
+            counters,
+        })
+        .collect();
+    metrics.sort_by_key(|m| m.address);
+
+    metrics
+}
+
+/// Decides which of two connections to the same peer survives.
+///
+/// Two instances that discover each other at the same time both dial out,
+/// leaving one inbound and one outbound connection on each side. Both sides
+/// keep the connection initiated by the peer with the lower uuid so they
+/// agree without any extra messages.
+fn keep_existing_connection(
+    myself: &PeerId,
+    peer: &PeerId,
+    existing_is_outbound: bool,
+    new_is_outbound: bool,
+) -> bool {
+    if existing_is_outbound == new_is_outbound {
+        return false;
+    }
+
+    let lower_initiator_is_me = myself.uuid < peer.uuid;
+
+    existing_is_outbound == lower_initiator_is_me
+}
+
+async fn add_client<'a>(
+    server_handle: ServerHandle,
+    clients: &mut HashMap<ClientConnectionId, ClientHandle>,
+    tcp: TcpStream,
+    addr: ClientConnectionId,
+    service_info: Option<ServiceInfo>,
+    config: Arc<StoredConfig>,
+) -> Result<()> {
+    info!("Adding client with address {}", addr);
+
+    let (sender, receiver) = mpsc::channel(CHANNEL_SIZE);
+
+    let client_data = ClientData {
+        server: server_handle,
+        receiver,
+        addr,
+        config,
+    };
+
+    let pid = match &service_info {
+        Some(service) => parse_service_peer_id(service.get_fullname()),
+        None => None,
+    };
+
+    let join = tauri::async_runtime::spawn(client_loop(client_data, tcp, pid.clone()));
+
+    let client = ClientHandle {
+        id: pid,
+        compression: Compression::None,
+        sender,
+        join,
+        service_info,
+    };
+
+    let _ = clients.insert(addr, client);
+
+    Ok(())
+}
+
+async fn disconnected_client<'a>(client: ClientHandle, mdns_sender: &mpsc::Sender<MessageToMdns>) {
+    client.join.abort();
+
+    if let Some(service) = client.service_info {
+        let _ = mdns_sender
+            .send(MessageToMdns::RemoveService(service))
+            .await;
+    }
+}
+
+/// `now` is the file's last modified time, taken from the config clock.
+async fn create_shared_file(
+    file_path: String,
+    this_peer: &PeerId,
+    now: DateTime<Utc>,
+) -> Result<SharedFile> {
+    let path = PathBuf::from_str(&file_path)?;
+    let location = resolve_symlink(&path).await?;
+
+    let mut file = tokio::fs::File::open(&location).await?;
+    let metadata = file.metadata().await?;
+    let checksum = compute_stream(&mut file).await?;
+
+    let identifier = Uuid::new_v4();
+    let name = match path.file_name() {
+        Some(name) => match name.to_str() {
+            Some(os_name) => os_name.to_string(),
+            None => bail!("Invalid file path: {:?}", path),
+        },
+        None => bail!("Invalid file path: {:?}", path),
+    };
+    let size = metadata.len();
+
+    Ok(SharedFile {
+        name,
+        identifier,
+        content_hash: checksum,
+        last_modified: now,
+        content_location: ContentLocation::LocalPath(location),
+        owned_peers: vec![this_peer.clone()],
+        size,
+        uploads: Default::default(),
+    })
+}
+
+/// Shared symlinks are stored by their canonical target path, so serving,
+/// hashing and deleting always act on the real file. The link's own name is
+/// still used as the shared file name.
+async fn resolve_symlink(path: &Path) -> Result<PathBuf> {
+    let metadata = tokio::fs::symlink_metadata(path).await?;
+
+    if metadata.file_type().is_symlink() {
+        let target = tokio::fs::canonicalize(path).await?;
+
+        if !target.is_file() {
+            bail!("Symlink {:?} does not point to a file", path);
+        }
+
+        Ok(target)
+    } else {
+        Ok(path.to_path_buf())
+    }
+}
+
+enum FileVerification {
+    Valid,
+    Mismatched,
+    Missing,
+}
+
+async fn verify_directory(
+    directory: ShareDirectory,
+    server_handle: ServerHandle,
+    key: Option<FileKey>,
+    repair: bool,
+) {
+    let dir_id = directory.signature.identifier;
+    let local_files: Vec<(Uuid, PathBuf, u64)> = directory
+        .shared_files
+        .values()
+        .filter_map(|file| match &file.content_location {
+            ContentLocation::LocalPath(path) => {
+                Some((file.identifier, path.clone(), file.content_hash))
+            }
+            ContentLocation::NetworkOnly | ContentLocation::Partial { .. } => None,
+        })
+        .collect();
+
+    let files_total = local_files.len();
+    let mut result = VerificationResult {
+        directory_identifier: dir_id,
+        files_checked: 0,
+        mismatched_files: vec![],
+        missing_files: vec![],
+        repaired: false,
+    };
+
+    let mut checks = futures::stream::iter(local_files)
+        .map(|(file_id, path, hash)| {
+            let key = key.clone();
+            async move { (file_id, verify_file(&path, hash, key.as_ref()).await) }
+        })
+        .buffer_unordered(VERIFY_WORKERS);
+
+    while let Some((file_id, verification)) = checks.next().await {
+        result.files_checked += 1;
+
+        match verification {
+            FileVerification::Valid => (),
+            FileVerification::Mismatched => result.mismatched_files.push(file_id),
+            FileVerification::Missing => result.missing_files.push(file_id),
+        }
+
+        server_handle.report_progress(MessageToServer::VerificationProgress(
+            VerificationProgress {
+                directory_identifier: dir_id,
+                files_checked: result.files_checked,
+                files_total,
+            },
+        ));
+    }
+
+    info!(
+        "Verified directory {}: {} mismatched, {} missing",
+        dir_id,
+        result.mismatched_files.len(),
+        result.missing_files.len()
+    );
+
+    let _ = server_handle
+        .channel
+        .send(MessageToServer::VerificationFinished { result, repair })
+        .await;
+}
+
+async fn verify_file(path: &Path, expected_hash: u64, key: Option<&FileKey>) -> FileVerification {
+    let file = open_plain(path, key).await;
+
+    match file {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => FileVerification::Missing,
+        Err(_) => FileVerification::Mismatched,
+        Ok(mut file) => match compute_stream(&mut file).await {
+            Ok(hash) if hash == expected_hash => FileVerification::Valid,
+            _ => FileVerification::Mismatched,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod broadcast_tests {
+        use std::{
+            collections::{HashMap, HashSet},
+            sync::Arc,
+            time::Duration,
+        };
+
+        use tokio::sync::mpsc;
+        use uuid::Uuid;
+
+        use crate::{
+            client::{Compression, MessageToClient},
+            config::{AppConfig, StoredConfig},
+            data::PeerId,
+            server::{
+                handle_message, ClientHandle, DisconnectReason, DownloadQueue, MessageToServer,
+                ServerData, ServerHandle,
+            },
+            stream::MediaStreams,
+            window::{Download, WindowManager, WindowRequest},
+        };
+
+        struct NullWindowManager;
+
+        impl WindowManager for NullWindowManager {
+            fn send(&self, _action: WindowRequest) -> Result<(), tauri::Error> {
+                Ok(())
+            }
+
+            fn notify(&self, _title: &str, _body: &str) -> Result<(), tauri::api::Error> {
+                Ok(())
+            }
+        }
+
+        fn client(sender: mpsc::Sender<MessageToClient>) -> ClientHandle {
+            ClientHandle {
+                id: Some(PeerId {
+                    hostname: "test".to_owned(),
+                    uuid: Uuid::new_v4(),
+                }),
+                compression: Compression::None,
+                sender,
+                join: tauri::async_runtime::spawn(async {}),
+                service_info: None,
+            }
+        }
+
+        #[tokio::test]
+        async fn broadcast_all_given_stuck_client_reaches_others_and_drops_stuck() {
+            let (server_sender, mut server_receiver) = mpsc::channel(16);
+            let (mdns_sender, _mdns_receiver) = mpsc::channel(16);
+            let (progress_sender, _progress_receiver) = mpsc::channel(16);
+            let server_handle = ServerHandle {
+                channel: server_sender,
+                progress: progress_sender,
+                peer_id: PeerId {
+                    hostname: "test".to_owned(),
+                    uuid: Uuid::new_v4(),
+                },
+                metrics: Arc::default(),
+            };
+            let config = Arc::new(StoredConfig::new(AppConfig::default(), HashMap::new()));
+
+            let (stuck_sender, _stuck_receiver) = mpsc::channel(1);
+            stuck_sender.try_send(MessageToClient::Synchronize).unwrap();
+            let (healthy_sender, mut healthy_receiver) = mpsc::channel(1);
+
+            let stuck_addr = "127.0.0.1:1000".parse().unwrap();
+            let healthy_addr = "127.0.0.1:1001".parse().unwrap();
+            let mut clients = HashMap::new();
+            clients.insert(stuck_addr, client(stuck_sender));
+            clients.insert(healthy_addr, client(healthy_sender));
+            let mut downloads = HashMap::new();
+            let mut download_queue = DownloadQueue::default();
+            let mut peer_addresses = HashMap::new();
+
+            let server_data = ServerData {
+                window_manager: &NullWindowManager,
+                server_handle: &server_handle,
+                clients: &mut clients,
+                mdns_sender: &mdns_sender,
+                config: &config,
+                downloads: &mut downloads,
+                download_queue: &mut download_queue,
+                peer_addresses: &mut peer_addresses,
+                streams: &MediaStreams::new(0),
+                network_paused: &mut false,
+                discoverable: &mut true,
+                idle_peers: &mut HashSet::new(),
+                peer_versions: &mut HashMap::new(),
+            };
+
+            tokio::time::timeout(
+                Duration::from_secs(2),
+                server_data.broadcast_all(MessageToClient::Synchronize),
+            )
+            .await
+            .expect("broadcast should not wait on a stuck client forever");
+
+            assert!(matches!(
+                healthy_receiver.try_recv(),
+                Ok(MessageToClient::Synchronize)
+            ));
+            match server_receiver.recv().await {
+                Some(MessageToServer::KillClient(addr, _)) => assert_eq!(addr, stuck_addr),
+                other => panic!("Expected stuck client to be killed, got {:?}", other),
+            }
+        }
+
+        #[tokio::test]
+        async fn broadcast_all_given_slow_client_retries_without_dropping_it() {
+            let (server_sender, mut server_receiver) = mpsc::channel(16);
+            let (mdns_sender, _mdns_receiver) = mpsc::channel(16);
+            let (progress_sender, _progress_receiver) = mpsc::channel(16);
+            let server_handle = ServerHandle {
+                channel: server_sender,
+                progress: progress_sender,
+                peer_id: PeerId::generate(),
+                metrics: Arc::default(),
+            };
+            let config = Arc::new(StoredConfig::new(AppConfig::default(), HashMap::new()));
+
+            let (slow_sender, mut slow_receiver) = mpsc::channel(1);
+            slow_sender.try_send(MessageToClient::Synchronize).unwrap();
+
+            let mut clients =
+                HashMap::from([("127.0.0.1:1000".parse().unwrap(), client(slow_sender))]);
+            let mut downloads = HashMap::new();
+            let mut download_queue = DownloadQueue::default();
+            let mut peer_addresses = HashMap::new();
+
+            let server_data = ServerData {
+                window_manager: &NullWindowManager,
+                server_handle: &server_handle,
+                clients: &mut clients,
+                mdns_sender: &mdns_sender,
+                config: &config,
+                downloads: &mut downloads,
+                download_queue: &mut download_queue,
+                peer_addresses: &mut peer_addresses,
+                streams: &MediaStreams::new(0),
+                network_paused: &mut false,
+                discoverable: &mut true,
+                idle_peers: &mut HashSet::new(),
+                peer_versions: &mut HashMap::new(),
+            };
+
+            server_data
+                .broadcast_all(MessageToClient::Synchronize)
+                .await;
+
+            // The client catches up while the message is being retried
+            for _ in 0..2 {
+                let received = tokio::time::timeout(Duration::from_secs(2), slow_receiver.recv())
+                    .await
+                    .expect("retried message should arrive");
+                assert!(matches!(received, Some(MessageToClient::Synchronize)));
+            }
+            assert!(server_receiver.try_recv().is_err());
+        }
+
+        #[tokio::test]
+        async fn kill_client_cancels_downloads_once_peer_has_no_connection() {
+            let (server_sender, _server_receiver) = mpsc::channel(16);
+            let (mdns_sender, _mdns_receiver) = mpsc::channel(16);
+            let (progress_sender, _progress_receiver) = mpsc::channel(16);
+            let server_handle = ServerHandle {
+                channel: server_sender,
+                progress: progress_sender,
+                peer_id: PeerId::generate(),
+                metrics: Arc::default(),
+            };
+            let config = Arc::new(StoredConfig::new(AppConfig::default(), HashMap::new()));
+
+            let (sender, _receiver) = mpsc::channel(1);
+            let first = client(sender.clone());
+            let mut second = client(sender);
+            second.id = first.id.clone();
+            let peer = first.id.clone().unwrap();
+
+            let first_addr = "127.0.0.1:1000".parse().unwrap();
+            let second_addr = "127.0.0.1:1001".parse().unwrap();
+            let mut clients = HashMap::from([(first_addr, first), (second_addr, second)]);
+            let download_id = Uuid::new_v4();
+            let mut downloads = HashMap::from([(
+                download_id,
+                Download {
+                    peer,
+                    download_id,
+                    file_identifier: Uuid::new_v4(),
+                    directory_identifier: Uuid::new_v4(),
+                    progress: 0,
+                    file_name: "file".to_owned(),
+                    file_path: "file".into(),
+                },
+            )]);
+            let mut download_queue = DownloadQueue::default();
+            let mut peer_addresses = HashMap::new();
+            let streams = MediaStreams::new(0);
+
+            for (addr, remaining) in [(first_addr, 1), (second_addr, 0)] {
+                let server_data = ServerData {
+                    window_manager: &NullWindowManager,
+                    server_handle: &server_handle,
+                    clients: &mut clients,
+                    mdns_sender: &mdns_sender,
+                    config: &config,
+                    downloads: &mut downloads,
+                    download_queue: &mut download_queue,
+                    peer_addresses: &mut peer_addresses,
+                    streams: &streams,
+                    network_paused: &mut false,
+                    discoverable: &mut true,
+                    idle_peers: &mut HashSet::new(),
+                    peer_versions: &mut HashMap::new(),
+                };
+
+                let reason = DisconnectReason::Lost("test".to_owned());
+                handle_message(MessageToServer::KillClient(addr, reason), server_data)
+                    .await
+                    .unwrap();
+                assert_eq!(downloads.len(), remaining);
+            }
+        }
+    }
+
+    mod queue_tests {
+        use uuid::Uuid;
+
+        use crate::{server::DownloadQueue, window::QueuedDownload};
+
+        fn queue(count: u128) -> DownloadQueue {
+            let mut queue = DownloadQueue::default();
+            for n in 0..count {
+                queue.waiting.push_back(QueuedDownload {
+                    download_id: Uuid::from_u128(n),
+                    directory_identifier: Uuid::nil(),
+                    file_identifier: Uuid::from_u128(n),
+                    file_name: n.to_string(),
+                    size: 0,
+                    destination: None,
+                });
+            }
+
+            queue
+        }
+
+        fn order(queue: &DownloadQueue) -> Vec<u128> {
+            queue
+                .waiting
+                .iter()
+                .map(|d| d.download_id.as_u128())
+                .collect()
+        }
+
+        #[test]
+        fn reorder_moves_download_to_front() {
+            let mut queue = queue(3);
+
+            assert!(queue.reorder(Uuid::from_u128(2), 0));
+            assert_eq!(order(&queue), vec![2, 0, 1]);
+        }
+
+        #[test]
+        fn reorder_past_end_moves_download_to_back() {
+            let mut queue = queue(3);
+
+            assert!(queue.reorder(Uuid::from_u128(0), 10));
+            assert_eq!(order(&queue), vec![1, 2, 0]);
+        }
+
+        #[test]
+        fn reorder_unknown_download_keeps_order() {
+            let mut queue = queue(3);
+
+            assert!(!queue.reorder(Uuid::from_u128(7), 0));
+            assert_eq!(order(&queue), vec![0, 1, 2]);
+        }
+
+        #[test]
+        fn cancel_waiting_removes_download() {
+            let mut queue = queue(3);
+
+            assert!(queue.cancel_waiting(Uuid::from_u128(1)));
+            assert_eq!(order(&queue), vec![0, 2]);
+            assert!(!queue.cancel_waiting(Uuid::from_u128(1)));
+        }
+    }
+
+    mod peer_list_tests {
+        use std::{collections::HashMap, net::SocketAddr};
+
+        use tokio::sync::mpsc;
+        use uuid::Uuid;
+
+        use crate::{
+            client::Compression,
+            data::PeerId,
+            server::{connected_peers, ClientHandle},
+        };
+
+        fn peer(hostname: &str, uuid: u128) -> PeerId {
+            PeerId {
+                hostname: hostname.to_owned(),
+                uuid: Uuid::from_u128(uuid),
+            }
+        }
+
+        fn clients(peers: &[PeerId]) -> HashMap<SocketAddr, ClientHandle> {
+            peers
+                .iter()
+                .enumerate()
+                .map(|(n, id)| {
+                    let (sender, _) = mpsc::channel(1);
+                    let addr = format!("127.0.0.1:{}", 1000 + n).parse().unwrap();
+
+                    let client = ClientHandle {
+                        id: Some(id.clone()),
+                        compression: Compression::None,
+                        sender,
+                        join: tauri::async_runtime::spawn(async {}),
+                        service_info: None,
+                    };
+
+                    (addr, client)
+                })
+                .collect()
+        }
+
+        #[tokio::test]
+        async fn connected_peers_given_any_connection_order_is_sorted() {
+            let expected = vec![peer("alpha", 2), peer("bravo", 1), peer("bravo", 3)];
+            let mut connection_order = vec![peer("bravo", 3), peer("alpha", 2), peer("bravo", 1)];
+
+            for _ in 0..connection_order.len() {
+                let clients = clients(&connection_order);
+
+                assert_eq!(connected_peers(&clients), expected);
+                assert_eq!(connected_peers(&clients), expected);
+
+                connection_order.rotate_left(1);
+            }
+        }
+    }
+
+    mod address_tests {
+        use std::{collections::HashSet, net::Ipv4Addr, time::Duration};
+
+        use tokio::net::TcpListener;
+
+        use crate::server::{candidate_addresses, connect_to_any};
+
+        #[test]
+        fn candidate_addresses_tries_preferred_first() {
+            let addresses = HashSet::from([
+                Ipv4Addr::new(172, 17, 0, 1),
+                Ipv4Addr::new(10, 8, 0, 2),
+                Ipv4Addr::new(192, 168, 1, 5),
+            ]);
+            let preferred = Ipv4Addr::new(192, 168, 1, 5);
+
+            assert_eq!(
+                candidate_addresses(&addresses, Some(&preferred)),
+                vec![
+                    Ipv4Addr::new(192, 168, 1, 5),
+                    Ipv4Addr::new(10, 8, 0, 2),
+                    Ipv4Addr::new(172, 17, 0, 1),
+                ]
+            );
+        }
+
+        #[tokio::test]
+        async fn connect_to_any_skips_unreachable_addresses() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let addresses = [Ipv4Addr::new(127, 0, 0, 2), Ipv4Addr::new(127, 0, 0, 1)];
+
+            let (_, connected) = connect_to_any(&addresses, port, Duration::from_secs(2))
+                .await
+                .unwrap();
+
+            assert_eq!(*connected.ip(), Ipv4Addr::new(127, 0, 0, 1));
+        }
+
+        #[tokio::test]
+        async fn connect_to_any_given_no_reachable_address_fails() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            drop(listener);
+
+            let result =
+                connect_to_any(&[Ipv4Addr::new(127, 0, 0, 1)], port, Duration::from_secs(2)).await;
+
+            assert!(result.is_err());
+        }
+    }
+
+    mod connection_tests {
+        use uuid::Uuid;
+
+        use crate::{data::PeerId, server::keep_existing_connection};
+
+        fn peer(uuid: u128) -> PeerId {
+            PeerId {
+                hostname: "localhost".to_owned(),
+                uuid: Uuid::from_u128(uuid),
+            }
+        }
+
+        #[test]
+        fn keep_existing_connection_both_sides_keep_same_connection() {
+            let low = peer(1);
+            let high = peer(2);
+
+            // Low side dialed first, then accepted the high side's connection
+            let low_keeps_outbound = keep_existing_connection(&low, &high, true, false);
+            // High side dialed first, then accepted the low side's connection
+            let high_keeps_outbound = keep_existing_connection(&high, &low, true, false);
+
+            assert!(low_keeps_outbound);
+            assert!(!high_keeps_outbound);
+        }
+
+        #[test]
+        fn keep_existing_connection_order_of_arrival_does_not_matter() {
+            let low = peer(1);
+            let high = peer(2);
+
+            assert!(!keep_existing_connection(&low, &high, false, true));
+            assert!(keep_existing_connection(&high, &low, false, true));
+        }
+
+        #[test]
+        fn keep_existing_connection_same_direction_replaces_existing() {
+            let low = peer(1);
+            let high = peer(2);
+
+            assert!(!keep_existing_connection(&low, &high, true, true));
+            assert!(!keep_existing_connection(&low, &high, false, false));
+        }
+    }
+
+    mod transfer_tests {
+        use std::{
+            collections::HashMap,
+            fs,
+            path::{Path, PathBuf},
+            sync::Arc,
+            time::Duration,
+        };
+
+        use chrono::{DateTime, Utc};
+        use cryptohelpers::crc::compute_stream;
+        use tokio::{
+            io::AsyncReadExt,
+            net::{TcpListener, TcpStream},
+            sync::{mpsc, oneshot},
+        };
+        use uuid::Uuid;
+
+        use crate::{
+            client::DownloadError,
+            config::{AppConfig, Clock, StoredConfig, SystemClock},
+            data::{ContentLocation, LocalOwnership, PeerId, ShareDirectory, SharedFile},
+            encryption::{is_encrypted, open_plain},
+            link::ShareLink,
+            mdns::MessageToMdns,
+            server::{check_destination, server_loop, MessageToServer, ServerHandle},
+            stream::MediaStreams,
+            window::{
+                BackendError, DirectoryListing, DownloadDestination, ErrorCode, SyncStatus,
+                WindowManager, WindowRequest, WindowResponse,
+            },
+        };
+
+        const WAIT_TIMEOUT_SECS: u64 = 10;
+
+        struct SkewedClock(chrono::Duration);
+
+        impl Clock for SkewedClock {
+            fn now(&self) -> DateTime<Utc> {
+                Utc::now() + self.0
+            }
+        }
+
+        struct RecordingWindowManager {
+            sender: mpsc::UnboundedSender<WindowRequest>,
+        }
+
+        impl WindowManager for RecordingWindowManager {
+            fn send(&self, action: WindowRequest) -> Result<(), tauri::Error> {
+                let _ = self.sender.send(action);
+
+                Ok(())
+            }
+
+            fn notify(&self, _title: &str, _body: &str) -> Result<(), tauri::api::Error> {
+                Ok(())
+            }
+        }
+
+        struct Node {
+            peer_id: PeerId,
+            server: mpsc::Sender<MessageToServer>,
+            window: mpsc::Sender<WindowResponse>,
+            requests: mpsc::UnboundedReceiver<WindowRequest>,
+            config: Arc<StoredConfig>,
+            mdns: mpsc::Receiver<MessageToMdns>,
+        }
+
+        impl Node {
+            fn start(dir: &Path) -> Self {
+                Self::start_with(dir, |_| ())
+            }
+
+            fn start_with<F>(dir: &Path, configure: F) -> Self
+            where
+                F: FnOnce(&mut AppConfig),
+            {
+                Self::start_with_clock(dir, configure, Arc::new(SystemClock))
+            }
+
+            fn start_with_clock<F>(dir: &Path, configure: F, clock: Arc<dyn Clock>) -> Self
+            where
+                F: FnOnce(&mut AppConfig),
+            {
+                let peer_id = PeerId {
+                    hostname: "localhost".to_owned(),
+                    uuid: Uuid::new_v4(),
+                };
+                let download_directory = dir.join(peer_id.uuid.to_string());
+                fs::create_dir_all(&download_directory).unwrap();
+
+                let mut app_config = AppConfig {
+                    peer_id: Some(peer_id.clone()),
+                    download_directory,
+                    ..Default::default()
+                };
+                configure(&mut app_config);
+                let config = Arc::new(StoredConfig::with_clock(app_config, HashMap::new(), clock));
+
+                let (server, server_receiver) = mpsc::channel(64);
+                let (progress, progress_receiver) = mpsc::channel(64);
+                let (window, window_receiver) = mpsc::channel(64);
+                let (mdns_sender, mdns_receiver) = mpsc::channel(64);
+                let (request_sender, requests) = mpsc::unbounded_channel();
+
+                let server_handle = ServerHandle {
+                    channel: server.clone(),
+                    progress,
+                    peer_id: peer_id.clone(),
+                    metrics: Arc::default(),
+                };
+
+                tokio::spawn(server_loop(
+                    RecordingWindowManager {
+                        sender: request_sender,
+                    },
+                    server_receiver,
+                    progress_receiver,
+                    window_receiver,
+                    mdns_sender,
+                    server_handle,
+                    config.clone(),
+                    Arc::new(MediaStreams::new(0)),
+                ));
+
+                Self {
+                    peer_id,
+                    server,
+                    window,
+                    requests,
+                    config,
+                    mdns: mdns_receiver,
+                }
+            }
+
+            async fn request(&self, response: WindowResponse) {
+                self.window.send(response).await.unwrap();
+            }
+
+            async fn wait_for<F>(&mut self, mut predicate: F) -> WindowRequest
+            where
+                F: FnMut(&WindowRequest) -> bool,
+            {
+                let wait = async {
+                    loop {
+                        let request = self.requests.recv().await.unwrap();
+
+                        if predicate(&request) {
+                            return request;
+                        }
+                    }
+                };
+
+                tokio::time::timeout(Duration::from_secs(WAIT_TIMEOUT_SECS), wait)
+                    .await
+                    .expect("timed out waiting for window request")
+            }
+        }
+
+        async fn connect(first: &mut Node, second: &mut Node) {
+            open_connection(first, second).await;
+
+            let second_id = second.peer_id.clone();
+            first
+                .wait_for(|r| matches!(r, WindowRequest::GetPeers(p) if p.contains(&second_id)))
+                .await;
+            let first_id = first.peer_id.clone();
+            second
+                .wait_for(|r| matches!(r, WindowRequest::GetPeers(p) if p.contains(&first_id)))
+                .await;
+        }
+
+        /// Hands both ends of a loopback connection to the nodes without
+        /// waiting for the handshake.
+        async fn open_connection(first: &Node, second: &Node) {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let outbound = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (inbound, inbound_addr) = listener.accept().await.unwrap();
+            let outbound_addr = outbound.peer_addr().unwrap();
+
+            first
+                .server
+                .send(MessageToServer::ConnectionAccepted(inbound, inbound_addr))
+                .await
+                .unwrap();
+            second
+                .server
+                .send(MessageToServer::ConnectionAccepted(outbound, outbound_addr))
+                .await
+                .unwrap();
+        }
+
+        async fn share_files(seed: &mut Node, leech: &Node, paths: &[PathBuf]) -> Uuid {
+            seed.request(WindowResponse::CreateShareDirectory("shared".to_owned()))
+                .await;
+            let signature = match seed
+                .wait_for(|r| matches!(r, WindowRequest::NewShareDirectory(_)))
+                .await
+            {
+                WindowRequest::NewShareDirectory(signature) => signature,
+                _ => unreachable!(),
+            };
+            let dir_id = signature.identifier;
+
+            seed.request(WindowResponse::AddFiles {
+                directory_identifier: dir_id.to_string(),
+                file_paths: paths
+                    .iter()
+                    .map(|p| p.to_str().unwrap().to_owned())
+                    .collect(),
+            })
+            .await;
+            seed.wait_for(
+                |r| matches!(r, WindowRequest::UpdateDirectory(d) if !d.shared_files.is_empty()),
+            )
+            .await;
+
+            seed.request(WindowResponse::ShareDirectoryToPeers {
+                directory_identifier: dir_id.to_string(),
+                peers: vec![leech.peer_id.clone()],
+            })
+            .await;
+
+            wait_until(&leech.config, |dirs| {
+                dirs.iter().any(|d| {
+                    d.signature.identifier == dir_id && d.shared_files.len() == paths.len()
+                })
+            })
+            .await;
+
+            dir_id
+        }
+
+        async fn wait_until<F>(config: &StoredConfig, mut predicate: F)
+        where
+            F: FnMut(&[ShareDirectory]) -> bool,
+        {
+            tokio::time::timeout(Duration::from_secs(WAIT_TIMEOUT_SECS), async {
+                while !predicate(&config.get_directories().await) {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .expect("timed out waiting for directory state");
+        }
+
+        async fn assert_downloaded(leech: &Node, dir_id: Uuid, file_id: Uuid, content: &[u8]) {
+            let downloaded = leech
+                .config
+                .get_directory(dir_id)
+                .await
+                .unwrap()
+                .shared_files
+                .remove(&file_id)
+                .unwrap();
+            let downloaded_path = match downloaded.content_location {
+                ContentLocation::NetworkOnly | ContentLocation::Partial { .. } => {
+                    panic!("File should be stored locally")
+                }
+                ContentLocation::LocalPath(path) => path,
+            };
+
+            assert!(downloaded.owned_peers.contains(&leech.peer_id));
+            assert_eq!(fs::read(&downloaded_path).unwrap(), content);
+
+            let mut downloaded_file = tokio::fs::File::open(&downloaded_path).await.unwrap();
+            let downloaded_hash = compute_stream(&mut downloaded_file).await.unwrap();
+            assert_eq!(downloaded_hash, downloaded.content_hash);
+        }
+
+        #[tokio::test]
+        async fn two_nodes_share_and_download_file() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+
+            let source_path = dir.join("shared.bin");
+            let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+            fs::write(&source_path, &content).unwrap();
+
+            let mut seed = Node::start(&dir);
+            let mut leech = Node::start(&dir);
+            connect(&mut seed, &mut leech).await;
+
+            let dir_id = share_files(&mut seed, &leech, &[source_path]).await;
+            let file_id = *leech
+                .config
+                .get_directory(dir_id)
+                .await
+                .unwrap()
+                .shared_files
+                .keys()
+                .next()
+                .unwrap();
+
+            leech
+                .request(WindowResponse::DownloadFile {
+                    directory_identifier: dir_id.to_string(),
+                    file_identifier: file_id.to_string(),
+                    destination: None,
+                    overwrite: false,
+                })
+                .await;
+            // Progress reaches 100 with the last part, before the file end
+            // arrives and the file is stored
+            leech
+                .wait_for(|r| match r {
+                    WindowRequest::UpdateDirectory(d) => d
+                        .shared_files
+                        .get(&file_id)
+                        .map(|f| matches!(f.file.content_location, ContentLocation::LocalPath(_)))
+                        .unwrap_or(false),
+                    _ => false,
+                })
+                .await;
+
+            assert_downloaded(&leech, dir_id, file_id, &content).await;
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn handshake_with_skewed_peer_reports_clock_skew() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+
+            let mut local = Node::start(&dir);
+            let clock = Arc::new(SkewedClock(chrono::Duration::minutes(10)));
+            let mut ahead = Node::start_with_clock(&dir, |_| (), clock);
+            open_connection(&local, &ahead).await;
+
+            let is_skew =
+                |r: &WindowRequest| matches!(r, WindowRequest::Error(e) if e.title == "Clock Skew");
+            let warnings = [
+                (local.wait_for(is_skew).await, "ahead"),
+                (ahead.wait_for(is_skew).await, "behind"),
+            ];
+
+            for (warning, direction) in warnings {
+                match warning {
+                    WindowRequest::Error(e) => assert!(
+                        e.error.contains(&format!("{} of this device", direction)),
+                        "unexpected warning: {}",
+                        e.error
+                    ),
+                    _ => unreachable!(),
+                }
+            }
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn two_nodes_share_and_download_empty_file() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+
+            let source_path = dir.join("empty.bin");
+            fs::write(&source_path, b"").unwrap();
+
+            let mut seed = Node::start(&dir);
+            let mut leech = Node::start(&dir);
+            connect(&mut seed, &mut leech).await;
+
+            let dir_id = share_files(&mut seed, &leech, &[source_path]).await;
+            let file_id = *leech
+                .config
+                .get_directory(dir_id)
+                .await
+                .unwrap()
+                .shared_files
+                .keys()
+                .next()
+                .unwrap();
+
+            leech
+                .request(WindowResponse::DownloadFile {
+                    directory_identifier: dir_id.to_string(),
+                    file_identifier: file_id.to_string(),
+                    destination: None,
+                    overwrite: false,
+                })
+                .await;
+            leech
+                .wait_for(|r| {
+                    assert!(
+                        !matches!(r, WindowRequest::DownloadCanceled(_)),
+                        "empty download was canceled: {:?}",
+                        r
+                    );
+                    matches!(r, WindowRequest::DownloadUpdate(update) if update.progress == 100)
+                })
+                .await;
+
+            assert_downloaded(&leech, dir_id, file_id, b"").await;
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn download_of_file_changed_on_disk_since_sharing_is_refused() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+
+            let source_path = dir.join("edited.txt");
+            fs::write(&source_path, b"first draft").unwrap();
+
+            let mut seed = Node::start(&dir);
+            let mut leech = Node::start(&dir);
+            connect(&mut seed, &mut leech).await;
+
+            let dir_id = share_files(&mut seed, &leech, std::slice::from_ref(&source_path)).await;
+            let file_id = *leech
+                .config
+                .get_directory(dir_id)
+                .await
+                .unwrap()
+                .shared_files
+                .keys()
+                .next()
+                .unwrap();
+
+            // Same size, so only the contents tell the versions apart
+            fs::write(&source_path, b"final draft").unwrap();
+
+            leech
+                .request(WindowResponse::DownloadFile {
+                    directory_identifier: dir_id.to_string(),
+                    file_identifier: file_id.to_string(),
+                    destination: None,
+                    overwrite: false,
+                })
+                .await;
+            leech
+                .wait_for(|r| {
+                    matches!(r, WindowRequest::DownloadCanceled(c) if c.reason == DownloadError::HashMismatch)
+                })
+                .await;
+
+            let file = &leech
+                .config
+                .get_directory(dir_id)
+                .await
+                .unwrap()
+                .shared_files[&file_id];
+            assert!(!matches!(
+                file.content_location,
+                ContentLocation::LocalPath(_)
+            ));
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn finished_download_counts_upload_only_on_serving_owner() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+
+            let source_path = dir.join("popular.txt");
+            fs::write(&source_path, b"popular").unwrap();
+
+            let mut seed = Node::start(&dir);
+            let mut leech = Node::start(&dir);
+            connect(&mut seed, &mut leech).await;
+
+            let dir_id = share_files(&mut seed, &leech, &[source_path]).await;
+            let file_id = *leech
+                .config
+                .get_directory(dir_id)
+                .await
+                .unwrap()
+                .shared_files
+                .keys()
+                .next()
+                .unwrap();
+
+            leech
+                .request(WindowResponse::DownloadFile {
+                    directory_identifier: dir_id.to_string(),
+                    file_identifier: file_id.to_string(),
+                    destination: None,
+                    overwrite: false,
+                })
+                .await;
+            wait_until(&seed.config, |dirs| {
+                dirs.iter()
+                    .flat_map(|dir| dir.shared_files.values())
+                    .any(|file| file.uploads.count == 1)
+            })
+            .await;
+
+            let served = seed
+                .config
+                .get_directory(dir_id)
+                .await
+                .unwrap()
+                .shared_files[&file_id]
+                .uploads
+                .clone();
+            assert!(served.last_served.is_some());
+            let downloaded = leech
+                .config
+                .get_directory(dir_id)
+                .await
+                .unwrap()
+                .shared_files[&file_id]
+                .uploads
+                .clone();
+            assert_eq!(downloaded.count, 0);
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn files_added_while_peer_is_offline_reach_it_on_reconnect() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+            let first_path = dir.join("first.txt");
+            let second_path = dir.join("second.txt");
+            fs::write(&first_path, b"first").unwrap();
+            fs::write(&second_path, b"second").unwrap();
+
+            let mut seed = Node::start(&dir);
+            let mut leech = Node::start(&dir);
+            connect(&mut seed, &mut leech).await;
+            let dir_id = share_files(&mut seed, &leech, &[first_path]).await;
+
+            seed.request(WindowResponse::DisconnectPeer {
+                peer: leech.peer_id.clone(),
+            })
+            .await;
+            let leech_id = leech.peer_id.clone();
+            seed.wait_for(|r| matches!(r, WindowRequest::GetPeers(p) if !p.contains(&leech_id)))
+                .await;
+
+            seed.request(WindowResponse::AddFiles {
+                directory_identifier: dir_id.to_string(),
+                file_paths: vec![second_path.to_str().unwrap().to_owned()],
+            })
+            .await;
+            wait_until(&seed.config, |dirs| {
+                dirs.iter()
+                    .any(|d| d.signature.identifier == dir_id && d.shared_files.len() == 2)
+            })
+            .await;
+            let leech_files = leech
+                .config
+                .get_directory(dir_id)
+                .await
+                .unwrap()
+                .shared_files;
+            assert_eq!(leech_files.len(), 1);
+
+            connect(&mut seed, &mut leech).await;
+            wait_until(&leech.config, |dirs| {
+                dirs.iter()
+                    .any(|d| d.signature.identifier == dir_id && d.shared_files.len() == 2)
+            })
+            .await;
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn anti_entropy_sends_changes_that_were_missed() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("file.txt");
+            fs::write(&path, b"content").unwrap();
+
+            let fast_sync = |c: &mut AppConfig| c.timing.anti_entropy_secs = 5;
+            let mut seed = Node::start_with(&dir, fast_sync);
+            let mut leech = Node::start_with(&dir, fast_sync);
+            connect(&mut seed, &mut leech).await;
+            let dir_id = share_files(&mut seed, &leech, &[path]).await;
+
+            // Changed without telling the leech, as if the message was lost
+            let missed = SharedFile {
+                name: "missed.txt".to_owned(),
+                identifier: Uuid::new_v4(),
+                content_hash: 1,
+                last_modified: seed.config.now(),
+                content_location: ContentLocation::NetworkOnly,
+                owned_peers: vec![seed.peer_id.clone()],
+                size: 1,
+                uploads: Default::default(),
+            };
+            let now = seed.config.now();
+            seed.config
+                .mutate_dir(dir_id, |d| {
+                    d.add_files(vec![missed.clone()], now).unwrap();
+
+                    true
+                })
+                .await;
+
+            wait_until(&leech.config, |dirs| {
+                dirs.iter().any(|d| {
+                    d.signature.identifier == dir_id
+                        && d.shared_files.contains_key(&missed.identifier)
+                })
+            })
+            .await;
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn directory_fingerprint_matches_after_sharing() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("file.txt");
+            fs::write(&path, b"content").unwrap();
+
+            let mut seed = Node::start(&dir);
+            let mut leech = Node::start(&dir);
+            connect(&mut seed, &mut leech).await;
+            let dir_id = share_files(&mut seed, &leech, &[path]).await;
+
+            let in_sync = async {
+                loop {
+                    seed.request(WindowResponse::GetDirectoryFingerprint {
+                        directory_identifier: dir_id.to_string(),
+                        reply: None,
+                    })
+                    .await;
+                    let fingerprint = match seed
+                        .wait_for(|r| matches!(r, WindowRequest::DirectoryFingerprint(_)))
+                        .await
+                    {
+                        WindowRequest::DirectoryFingerprint(fingerprint) => fingerprint,
+                        _ => unreachable!(),
+                    };
+
+                    if fingerprint
+                        .peers
+                        .iter()
+                        .any(|p| p.peer == leech.peer_id && p.in_sync)
+                    {
+                        return fingerprint;
+                    }
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            };
+            let fingerprint = tokio::time::timeout(Duration::from_secs(WAIT_TIMEOUT_SECS), in_sync)
+                .await
+                .expect("timed out waiting for peer fingerprint");
+
+            assert_eq!(fingerprint.file_count, 1);
+            let leech_dir = leech.config.get_directory(dir_id).await.unwrap();
+            assert_eq!(
+                leech_dir.signature.fingerprint.as_deref(),
+                Some(fingerprint.fingerprint.as_str())
+            );
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn share_directory_to_all_peers_keeps_sharing_with_new_peers() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+
+            let mut seed = Node::start(&dir);
+            let mut first = Node::start(&dir);
+            let mut later = Node::start(&dir);
+            connect(&mut seed, &mut first).await;
+
+            seed.request(WindowResponse::CreateShareDirectory("everyone".to_owned()))
+                .await;
+            let dir_id = match seed
+                .wait_for(|r| matches!(r, WindowRequest::NewShareDirectory(_)))
+                .await
+            {
+                WindowRequest::NewShareDirectory(signature) => signature.identifier,
+                _ => unreachable!(),
+            };
+
+            seed.request(WindowResponse::ShareDirectoryToAllPeers {
+                directory_identifier: dir_id.to_string(),
+                keep_shared: true,
+            })
+            .await;
+            wait_until(&first.config, |dirs| {
+                dirs.iter().any(|d| d.signature.identifier == dir_id)
+            })
+            .await;
+
+            connect(&mut seed, &mut later).await;
+            wait_until(&later.config, |dirs| {
+                dirs.iter().any(|d| d.signature.identifier == dir_id)
+            })
+            .await;
+
+            let shared_peers = seed
+                .config
+                .get_directory(dir_id)
+                .await
+                .unwrap()
+                .signature
+                .shared_peers;
+            assert!(shared_peers.contains(&first.peer_id));
+            assert!(shared_peers.contains(&later.peer_id));
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn download_file_requested_twice_downloads_once() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+
+            let source_path = dir.join("shared.bin");
+            let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+            fs::write(&source_path, &content).unwrap();
+
+            let mut seed = Node::start(&dir);
+            let mut leech = Node::start(&dir);
+            connect(&mut seed, &mut leech).await;
+
+            let dir_id = share_files(&mut seed, &leech, &[source_path]).await;
+            let file_id = *leech
+                .config
+                .get_directory(dir_id)
+                .await
+                .unwrap()
+                .shared_files
+                .keys()
+                .next()
+                .unwrap();
+
+            for _ in 0..2 {
+                leech
+                    .request(WindowResponse::DownloadFile {
+                        directory_identifier: dir_id.to_string(),
+                        file_identifier: file_id.to_string(),
+                        destination: None,
+                        overwrite: false,
+                    })
+                    .await;
+            }
+
+            let mut started = 0;
+            let mut rejected = 0;
+            leech
+                .wait_for(|r| match r {
+                    WindowRequest::DownloadStarted(_) => {
+                        started += 1;
+                        false
+                    }
+                    WindowRequest::Error(BackendError {
+                        code: ErrorCode::AlreadyDownloading,
+                        ..
+                    }) => {
+                        rejected += 1;
+                        false
+                    }
+                    WindowRequest::UpdateDirectory(d) => d
+                        .shared_files
+                        .get(&file_id)
+                        .map(|f| matches!(f.file.content_location, ContentLocation::LocalPath(_)))
+                        .unwrap_or(false),
+                    _ => false,
+                })
+                .await;
+
+            assert_eq!(started, 1);
+            assert_eq!(rejected, 1);
+            assert_downloaded(&leech, dir_id, file_id, &content).await;
+            let downloaded = fs::read_dir(leech.config.get_download_directory().await)
+                .unwrap()
+                .count();
+            assert_eq!(downloaded, 1);
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn download_file_given_missing_download_directory_waits_for_it() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+
+            let source_path = dir.join("shared.bin");
+            let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+            fs::write(&source_path, &content).unwrap();
+
+            let download_directory = dir.join("unmounted");
+            let mut seed = Node::start(&dir);
+            let mut leech =
+                Node::start_with(&dir, |c| c.download_directory = download_directory.clone());
+            connect(&mut seed, &mut leech).await;
+
+            let dir_id = share_files(&mut seed, &leech, &[source_path]).await;
+            let file_id = *leech
+                .config
+                .get_directory(dir_id)
+                .await
+                .unwrap()
+                .shared_files
+                .keys()
+                .next()
+                .unwrap();
+
+            leech
+                .request(WindowResponse::DownloadFile {
+                    directory_identifier: dir_id.to_string(),
+                    file_identifier: file_id.to_string(),
+                    destination: None,
+                    overwrite: false,
+                })
+                .await;
+
+            let mut started = false;
+            leech
+                .wait_for(|r| match r {
+                    WindowRequest::DownloadStarted(_) => {
+                        started = true;
+                        false
+                    }
+                    WindowRequest::Error(BackendError {
+                        code: ErrorCode::DownloadLocationUnwritable,
+                        ..
+                    }) => true,
+                    _ => false,
+                })
+                .await;
+            leech
+                .wait_for(|r| matches!(r, WindowRequest::DownloadQueue(q) if q.len() == 1))
+                .await;
+            assert!(!started);
+
+            fs::create_dir_all(&download_directory).unwrap();
+            leech
+                .wait_for(|r| match r {
+                    WindowRequest::UpdateDirectory(d) => d
+                        .shared_files
+                        .get(&file_id)
+                        .map(|f| matches!(f.file.content_location, ContentLocation::LocalPath(_)))
+                        .unwrap_or(false),
+                    _ => false,
+                })
+                .await;
+
+            assert_downloaded(&leech, dir_id, file_id, &content).await;
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn open_share_link_downloads_linked_file() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+
+            let source_path = dir.join("shared.bin");
+            let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+            fs::write(&source_path, &content).unwrap();
+
+            let mut seed = Node::start(&dir);
+            let mut leech = Node::start(&dir);
+            connect(&mut seed, &mut leech).await;
+
+            let dir_id = share_files(&mut seed, &leech, &[source_path]).await;
+            let file_id = *leech
+                .config
+                .get_directory(dir_id)
+                .await
+                .unwrap()
+                .shared_files
+                .keys()
+                .next()
+                .unwrap();
+            let link = ShareLink {
+                peer: seed.peer_id.uuid,
+                directory_identifier: dir_id,
+                file_identifier: file_id,
+            };
+
+            leech
+                .request(WindowResponse::OpenShareLink {
+                    link: "fileshare://not-a-link".to_owned(),
+                })
+                .await;
+            leech
+                .wait_for(|r| {
+                    matches!(
+                        r,
+                        WindowRequest::Error(BackendError {
+                            code: ErrorCode::InvalidLink,
+                            ..
+                        })
+                    )
+                })
+                .await;
+
+            leech
+                .request(WindowResponse::OpenShareLink {
+                    link: link.to_string(),
+                })
+                .await;
+            leech
+                .wait_for(|r| match r {
+                    WindowRequest::UpdateDirectory(d) => d
+                        .shared_files
+                        .get(&file_id)
+                        .map(|f| matches!(f.file.content_location, ContentLocation::LocalPath(_)))
+                        .unwrap_or(false),
+                    _ => false,
+                })
+                .await;
+
+            assert_downloaded(&leech, dir_id, file_id, &content).await;
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn encrypted_download_is_stored_encrypted() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+
+            let source_path = dir.join("shared.bin");
+            let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+            fs::write(&source_path, &content).unwrap();
+
+            let mut seed = Node::start(&dir);
+            let mut leech = Node::start_with(&dir, |c| c.encrypt_downloads = true);
+            leech.config.unlock_encryption("passphrase").await.unwrap();
+            connect(&mut seed, &mut leech).await;
+
+            let dir_id = share_files(&mut seed, &leech, &[source_path]).await;
+            let file_id = *leech
+                .config
+                .get_directory(dir_id)
+                .await
+                .unwrap()
+                .shared_files
+                .keys()
+                .next()
+                .unwrap();
+
+            leech
+                .request(WindowResponse::DownloadFile {
+                    directory_identifier: dir_id.to_string(),
+                    file_identifier: file_id.to_string(),
+                    destination: None,
+                    overwrite: false,
+                })
+                .await;
+            leech
+                .wait_for(|r| match r {
+                    WindowRequest::UpdateDirectory(d) => d
+                        .shared_files
+                        .get(&file_id)
+                        .map(|f| matches!(f.file.content_location, ContentLocation::LocalPath(_)))
+                        .unwrap_or(false),
+                    _ => false,
+                })
+                .await;
+
+            let downloaded_path = match leech
+                .config
+                .get_file(dir_id, file_id)
+                .await
+                .unwrap()
+                .content_location
+            {
+                ContentLocation::NetworkOnly | ContentLocation::Partial { .. } => {
+                    panic!("File should be stored locally")
+                }
+                ContentLocation::LocalPath(path) => path,
+            };
+            assert!(is_encrypted(&downloaded_path).await.unwrap());
+
+            let key = leech.config.get_file_key().await;
+            let mut reader = open_plain(&downloaded_path, key.as_ref()).await.unwrap();
+            let mut decrypted = vec![];
+            reader.read_to_end(&mut decrypted).await.unwrap();
+            assert_eq!(decrypted, content);
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn download_file_writes_to_chosen_destination() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            let chosen_dir = dir.join("chosen");
+            fs::create_dir_all(&chosen_dir).unwrap();
+
+            let source_path = dir.join("shared.bin");
+            let content: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+            fs::write(&source_path, &content).unwrap();
+
+            let mut seed = Node::start(&dir);
+            let mut leech = Node::start(&dir);
+            connect(&mut seed, &mut leech).await;
+
+            let dir_id = share_files(&mut seed, &leech, &[source_path]).await;
+            let file_id = *leech
+                .config
+                .get_directory(dir_id)
+                .await
+                .unwrap()
+                .shared_files
+                .keys()
+                .next()
+                .unwrap();
+
+            let destination = chosen_dir.join("renamed.bin");
+            leech
+                .request(WindowResponse::DownloadFile {
+                    directory_identifier: dir_id.to_string(),
+                    file_identifier: file_id.to_string(),
+                    destination: Some(destination.clone()),
+                    overwrite: false,
+                })
+                .await;
+            leech
+                .wait_for(|r| match r {
+                    WindowRequest::UpdateDirectory(d) => d
+                        .shared_files
+                        .get(&file_id)
+                        .map(|f| matches!(f.file.content_location, ContentLocation::LocalPath(_)))
+                        .unwrap_or(false),
+                    _ => false,
+                })
+                .await;
+
+            assert_downloaded(&leech, dir_id, file_id, &content).await;
+            assert_eq!(
+                leech.config.get_filepath(dir_id, file_id).await,
+                Some(destination)
+            );
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn check_destination_rejects_missing_directory() {
+            let destination = std::env::temp_dir()
+                .join(Uuid::new_v4().to_string())
+                .join("file.bin");
+
+            assert!(matches!(
+                check_destination(&DownloadDestination {
+                    path: destination,
+                    overwrite: false,
+                })
+                .await,
+                Err(DownloadError::WriteError)
+            ));
+        }
+
+        #[tokio::test]
+        async fn check_destination_only_replaces_confirmed_regular_files() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+            let existing = dir.join("existing.bin");
+            fs::write(&existing, b"keep").unwrap();
+
+            let check = |path: &std::path::Path, overwrite| {
+                let destination = DownloadDestination {
+                    path: path.to_owned(),
+                    overwrite,
+                };
+
+                async move { check_destination(&destination).await }
+            };
+
+            assert!(check(&dir.join("new.bin"), false).await.is_ok());
+            assert!(matches!(
+                check(&existing, false).await,
+                Err(DownloadError::WriteError)
+            ));
+            assert!(check(&existing, true).await.is_ok());
+            assert!(matches!(
+                check(&dir, true).await,
+                Err(DownloadError::WriteError)
+            ));
+
+            let too_long = dir.join("a".repeat(300));
+            assert!(matches!(
+                check(&too_long, false).await,
+                Err(DownloadError::PathTooLong)
+            ));
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn concurrent_uploads_do_not_block_directory_changes() {
+            const FILE_COUNT: u32 = 4;
+            const DIRECTORY_COUNT: usize = 20;
+
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+
+            let mut contents = HashMap::new();
+            let mut paths = vec![];
+            for n in 0..FILE_COUNT {
+                let path = dir.join(format!("shared_{}.bin", n));
+                let content: Vec<u8> = (0..1_000_000u32).map(|i| ((i + n) % 251) as u8).collect();
+                fs::write(&path, &content).unwrap();
+
+                contents.insert(format!("shared_{}.bin", n), content);
+                paths.push(path);
+            }
+
+            let mut seed = Node::start(&dir);
+            let mut leech = Node::start(&dir);
+            connect(&mut seed, &mut leech).await;
+
+            let dir_id = share_files(&mut seed, &leech, &paths).await;
+            let files = leech
+                .config
+                .get_directory(dir_id)
+                .await
+                .unwrap()
+                .shared_files;
+
+            for file_id in files.keys() {
+                leech
+                    .request(WindowResponse::DownloadFile {
+                        directory_identifier: dir_id.to_string(),
+                        file_identifier: file_id.to_string(),
+                        destination: None,
+                        overwrite: false,
+                    })
+                    .await;
+            }
+
+            for n in 0..DIRECTORY_COUNT {
+                seed.request(WindowResponse::CreateShareDirectory(format!("extra {}", n)))
+                    .await;
+                leech
+                    .request(WindowResponse::GetAllShareDirectoryData(true))
+                    .await;
+            }
+            wait_until(&seed.config, |dirs| dirs.len() == DIRECTORY_COUNT + 1).await;
+
+            wait_until(&leech.config, |dirs| {
+                dirs.iter()
+                    .filter(|d| d.signature.identifier == dir_id)
+                    .flat_map(|d| d.shared_files.values())
+                    .all(|f| matches!(f.content_location, ContentLocation::LocalPath(_)))
+            })
+            .await;
+
+            for (file_id, file) in files {
+                assert_downloaded(&leech, dir_id, file_id, &contents[&file.name]).await;
+            }
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn download_directory_downloads_every_file_within_transfer_limit() {
+            const FILE_COUNT: u32 = 3;
+
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+
+            let mut contents = HashMap::new();
+            let mut paths = vec![];
+            for n in 0..FILE_COUNT {
+                let path = dir.join(format!("batch_{}.bin", n));
+                let content: Vec<u8> = (0..100_000u32).map(|i| ((i + n) % 251) as u8).collect();
+                fs::write(&path, &content).unwrap();
+
+                contents.insert(format!("batch_{}.bin", n), content);
+                paths.push(path);
+            }
+
+            let mut seed = Node::start(&dir);
+            let mut leech = Node::start_with(&dir, |c| c.max_transfers_per_peer = 1);
+            connect(&mut seed, &mut leech).await;
+
+            let dir_id = share_files(&mut seed, &leech, &paths).await;
+
+            leech
+                .request(WindowResponse::DownloadDirectory {
+                    directory_identifier: dir_id.to_string(),
+                })
+                .await;
+            leech
+                .wait_for(|r| match r {
+                    WindowRequest::DirectoryDownloadUpdate(update) => {
+                        update.files_done == FILE_COUNT as usize
+                    }
+                    _ => false,
+                })
+                .await;
+
+            let files = leech
+                .config
+                .get_directory(dir_id)
+                .await
+                .unwrap()
+                .shared_files;
+            for (file_id, file) in files {
+                assert_downloaded(&leech, dir_id, file_id, &contents[&file.name]).await;
+            }
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn download_file_queues_downloads_beyond_peer_limit() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+
+            let mut contents = HashMap::new();
+            let mut paths = vec![];
+            for n in 0..3u32 {
+                let path = dir.join(format!("limited_{}.bin", n));
+                let content: Vec<u8> = (0..2_000_000u32).map(|i| ((i + n) % 251) as u8).collect();
+                fs::write(&path, &content).unwrap();
+
+                contents.insert(format!("limited_{}.bin", n), content);
+                paths.push(path);
+            }
+
+            let mut seed = Node::start(&dir);
+            let mut leech = Node::start_with(&dir, |c| c.max_downloads_per_peer = 1);
+            connect(&mut seed, &mut leech).await;
+
+            let dir_id = share_files(&mut seed, &leech, &paths).await;
+            let files = leech
+                .config
+                .get_directory(dir_id)
+                .await
+                .unwrap()
+                .shared_files;
+
+            for file_id in files.keys() {
+                leech
+                    .request(WindowResponse::DownloadFile {
+                        directory_identifier: dir_id.to_string(),
+                        file_identifier: file_id.to_string(),
+                        destination: None,
+                        overwrite: false,
+                    })
+                    .await;
+            }
+            leech
+                .wait_for(
+                    |r| matches!(r, WindowRequest::DownloadQueue(waiting) if waiting.len() == 2),
+                )
+                .await;
+
+            wait_until(&leech.config, |dirs| {
+                dirs.iter().any(|d| {
+                    d.signature.identifier == dir_id
+                        && d.shared_files
+                            .values()
+                            .all(|f| matches!(f.content_location, ContentLocation::LocalPath(_)))
+                })
+            })
+            .await;
+            for (file_id, file) in files {
+                assert_downloaded(&leech, dir_id, file_id, &contents[&file.name]).await;
+            }
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn download_directory_downloads_empty_files() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+            let empty_path = dir.join("empty.txt");
+            let full_path = dir.join("full.txt");
+            fs::write(&empty_path, b"").unwrap();
+            fs::write(&full_path, b"content").unwrap();
+
+            let mut seed = Node::start(&dir);
+            let mut leech = Node::start(&dir);
+            connect(&mut seed, &mut leech).await;
+
+            let dir_id = share_files(&mut seed, &leech, &[empty_path, full_path]).await;
+
+            leech
+                .request(WindowResponse::DownloadDirectory {
+                    directory_identifier: dir_id.to_string(),
+                })
+                .await;
+            let update = match leech
+                .wait_for(|r| match r {
+                    WindowRequest::DirectoryDownloadUpdate(update) => {
+                        update.files_done + update.files_failed == update.files_total
+                    }
+                    _ => false,
+                })
+                .await
+            {
+                WindowRequest::DirectoryDownloadUpdate(update) => update,
+                _ => unreachable!(),
+            };
+            assert_eq!(update.files_done, 2);
+            assert_eq!(update.files_failed, 0);
+
+            let files = leech
+                .config
+                .get_directory(dir_id)
+                .await
+                .unwrap()
+                .shared_files;
+            for (file_id, file) in files {
+                let content: &[u8] = if file.name == "empty.txt" {
+                    b""
+                } else {
+                    b"content"
+                };
+                assert_downloaded(&leech, dir_id, file_id, content).await;
+            }
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        async fn offer_share(seed: &mut Node, leech: &mut Node) -> Uuid {
+            seed.request(WindowResponse::CreateShareDirectory("offered".to_owned()))
+                .await;
+            let dir_id = match seed
+                .wait_for(|r| matches!(r, WindowRequest::NewShareDirectory(_)))
+                .await
+            {
+                WindowRequest::NewShareDirectory(signature) => signature.identifier,
+                _ => unreachable!(),
+            };
+
+            seed.request(WindowResponse::ShareDirectoryToPeers {
+                directory_identifier: dir_id.to_string(),
+                peers: vec![leech.peer_id.clone()],
+            })
+            .await;
+
+            let seed_id = seed.peer_id.clone();
+            leech
+                .wait_for(|r| {
+                    matches!(r, WindowRequest::ShareOffered(offer)
+                        if offer.directory.identifier == dir_id && offer.peer == seed_id)
+                })
+                .await;
+
+            dir_id
+        }
+
+        #[tokio::test]
+        async fn rescan_network_restarts_discovery() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+
+            let mut node = Node::start(&dir);
+            node.request(WindowResponse::RescanNetwork).await;
+
+            let wait = async {
+                loop {
+                    if let Some(MessageToMdns::Rescan) = node.mdns.recv().await {
+                        return;
+                    }
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(WAIT_TIMEOUT_SECS), wait)
+                .await
+                .expect("timed out waiting for rescan");
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn get_directory_answers_through_reply() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+
+            let mut node = Node::start(&dir);
+            node.request(WindowResponse::CreateShareDirectory("shared".to_owned()))
+                .await;
+            let dir_id = match node
+                .wait_for(|r| matches!(r, WindowRequest::NewShareDirectory(_)))
+                .await
+            {
+                WindowRequest::NewShareDirectory(signature) => signature.identifier,
+                _ => unreachable!(),
+            };
+
+            let get_directory = |directory_identifier: Option<String>| {
+                let (reply, listing) = oneshot::channel();
+                let request = WindowResponse::GetDirectory {
+                    directory_identifier,
+                    reply: Some(reply),
+                };
+
+                (request, listing)
+            };
+
+            let (request, listing) = get_directory(Some(dir_id.to_string()));
+            node.request(request).await;
+            match listing.await.unwrap() {
+                Ok(DirectoryListing::Directory(view)) => {
+                    assert_eq!(view.signature.identifier, dir_id)
+                }
+                other => panic!("Unexpected listing {:?}", other),
+            }
+
+            let (request, listing) = get_directory(None);
+            node.request(request).await;
+            match listing.await.unwrap() {
+                Ok(DirectoryListing::Signatures(signatures)) => {
+                    assert_eq!(signatures.len(), 1);
+                    assert_eq!(signatures[0].identifier, dir_id);
+                }
+                other => panic!("Unexpected listing {:?}", other),
+            }
+
+            let (request, listing) = get_directory(Some(Uuid::new_v4().to_string()));
+            node.request(request).await;
+            assert!(listing.await.unwrap().is_err());
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn hiding_from_discovery_keeps_peers_connected() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+
+            let mut first = Node::start(&dir);
+            let mut second = Node::start(&dir);
+            connect(&mut first, &mut second).await;
+
+            first.request(WindowResponse::SetDiscoverable(false)).await;
+            first
+                .wait_for(|r| {
+                    matches!(r, WindowRequest::NetworkStatus(status)
+                        if !status.discoverable && !status.paused)
+                })
+                .await;
+
+            let wait = async {
+                loop {
+                    if let Some(MessageToMdns::SetDiscoverable(false)) = first.mdns.recv().await {
+                        return;
+                    }
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(WAIT_TIMEOUT_SECS), wait)
+                .await
+                .expect("timed out waiting for discovery to stop");
+
+            first.request(WindowResponse::GetPeers(true)).await;
+            let second_id = second.peer_id.clone();
+            first
+                .wait_for(
+                    |r| matches!(r, WindowRequest::GetPeers(peers) if peers.contains(&second_id)),
+                )
+                .await;
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn shared_directory_reports_synced_with_peer() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("synced.bin");
+            fs::write(&path, b"synced").unwrap();
+
+            let mut seed = Node::start(&dir);
+            let mut leech = Node::start(&dir);
+            connect(&mut seed, &mut leech).await;
+            let dir_id = share_files(&mut seed, &leech, &[path]).await;
+
+            for node in [&mut seed, &mut leech] {
+                node.wait_for(|r| match r {
+                    WindowRequest::UpdateShareDirectories(dirs) => dirs.iter().any(|d| {
+                        d.signature.identifier == dir_id
+                            && d.sync_status == Some(SyncStatus::Synced)
+                    }),
+                    _ => false,
+                })
+                .await;
+            }
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn idle_connection_is_closed_without_losing_peer() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+
+            let mut first = Node::start_with(&dir, |c| c.timing.idle_timeout_secs = Some(1));
+            let mut second = Node::start_with(&dir, |c| c.timing.idle_timeout_secs = Some(1));
+            connect(&mut first, &mut second).await;
+
+            for node in [&mut first, &mut second] {
+                node.wait_for(|r| matches!(r, WindowRequest::GetPeers(p) if p.is_empty()))
+                    .await;
+            }
+
+            let second_id = second.peer_id.clone();
+            let wait = async {
+                loop {
+                    if let Some(MessageToMdns::IdlePeer(peer)) = first.mdns.recv().await {
+                        return peer;
+                    }
+                }
+            };
+            let idle_peer = tokio::time::timeout(Duration::from_secs(WAIT_TIMEOUT_SECS), wait)
+                .await
+                .expect("timed out waiting for idle peer");
+
+            assert_eq!(idle_peer, second_id);
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn peer_speed_test_reports_throughput() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+
+            let mut first = Node::start(&dir);
+            let mut second = Node::start(&dir);
+            connect(&mut first, &mut second).await;
+
+            first
+                .request(WindowResponse::PeerSpeedTest {
+                    peer: second.peer_id.clone(),
+                })
+                .await;
+            let result = first
+                .wait_for(|r| matches!(r, WindowRequest::SpeedTestResult(_)))
+                .await;
+
+            match result {
+                WindowRequest::SpeedTestResult(result) => {
+                    assert_eq!(result.peer, second.peer_id);
+                    assert!(result.bytes_sent > 0);
+                    assert!(result.bytes_per_second > 0);
+                }
+                _ => unreachable!(),
+            }
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn paused_network_queues_downloads_until_resumed() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+
+            let source_path = dir.join("shared.bin");
+            let content: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+            fs::write(&source_path, &content).unwrap();
+
+            let mut seed = Node::start(&dir);
+            let mut leech = Node::start(&dir);
+            connect(&mut seed, &mut leech).await;
+
+            let dir_id = share_files(&mut seed, &leech, &[source_path]).await;
+            let file_id = *leech
+                .config
+                .get_directory(dir_id)
+                .await
+                .unwrap()
+                .shared_files
+                .keys()
+                .next()
+                .unwrap();
+
+            leech.request(WindowResponse::SetNetworkPaused(true)).await;
+            leech
+                .wait_for(|r| matches!(r, WindowRequest::NetworkStatus(status) if status.paused))
+                .await;
+
+            leech
+                .request(WindowResponse::DownloadFile {
+                    directory_identifier: dir_id.to_string(),
+                    file_identifier: file_id.to_string(),
+                    destination: None,
+                    overwrite: false,
+                })
+                .await;
+            leech
+                .wait_for(|r| match r {
+                    WindowRequest::DownloadQueue(queue) => {
+                        queue.iter().any(|d| d.file_identifier == file_id)
+                    }
+                    _ => false,
+                })
+                .await;
+            assert!(leech.config.get_filepath(dir_id, file_id).await.is_none());
+
+            leech.request(WindowResponse::SetNetworkPaused(false)).await;
+            leech
+                .wait_for(|r| match r {
+                    WindowRequest::UpdateDirectory(d) => d
+                        .shared_files
+                        .get(&file_id)
+                        .map(|f| matches!(f.file.content_location, ContentLocation::LocalPath(_)))
+                        .unwrap_or(false),
+                    _ => false,
+                })
+                .await;
+
+            assert_downloaded(&leech, dir_id, file_id, &content).await;
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn shared_directory_reports_sharing_peer() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+
+            let mut seed = Node::start(&dir);
+            let mut leech = Node::start(&dir);
+            connect(&mut seed, &mut leech).await;
+
+            seed.request(WindowResponse::CreateShareDirectory("shared".to_owned()))
+                .await;
+            let dir_id = match seed
+                .wait_for(|r| matches!(r, WindowRequest::NewShareDirectory(_)))
+                .await
+            {
+                WindowRequest::NewShareDirectory(signature) => signature.identifier,
+                _ => unreachable!(),
+            };
+
+            seed.request(WindowResponse::ShareDirectoryToPeers {
+                directory_identifier: dir_id.to_string(),
+                peers: vec![leech.peer_id.clone()],
+            })
+            .await;
+
+            let seed_id = seed.peer_id.clone();
+            leech
+                .wait_for(|r| {
+                    matches!(r, WindowRequest::DirectoryShared(shared)
+                        if shared.signature.identifier == dir_id && shared.from_peer == seed_id)
+                })
+                .await;
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn accept_share_adds_offered_directory() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+
+            let mut seed = Node::start(&dir);
+            let mut leech = Node::start_with(&dir, |c| c.auto_accept_shares = false);
+            connect(&mut seed, &mut leech).await;
+
+            let dir_id = offer_share(&mut seed, &mut leech).await;
+            assert!(leech.config.get_directory(dir_id).await.is_none());
+
+            leech
+                .request(WindowResponse::AcceptShare {
+                    directory_identifier: dir_id.to_string(),
+                })
+                .await;
+            wait_until(&leech.config, |dirs| {
+                dirs.iter().any(|d| d.signature.identifier == dir_id)
+            })
+            .await;
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn reject_share_removes_peer_on_sender() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+
+            let mut seed = Node::start(&dir);
+            let mut leech = Node::start_with(&dir, |c| c.auto_accept_shares = false);
+            connect(&mut seed, &mut leech).await;
+
+            let dir_id = offer_share(&mut seed, &mut leech).await;
+
+            leech
+                .request(WindowResponse::RejectShare {
+                    directory_identifier: dir_id.to_string(),
+                })
+                .await;
+            let leech_id = leech.peer_id.clone();
+            wait_until(&seed.config, |dirs| {
+                dirs.iter().any(|d| {
+                    d.signature.identifier == dir_id
+                        && !d.signature.shared_peers.contains(&leech_id)
+                })
+            })
+            .await;
+            assert!(leech.config.get_directory(dir_id).await.is_none());
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn add_folder_skips_excluded_files() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            let folder = dir.join("folder");
+            fs::create_dir_all(folder.join("nested")).unwrap();
+            fs::create_dir_all(folder.join("node_modules")).unwrap();
+            for (path, content) in [
+                ("first.txt", "first"),
+                ("nested/second.txt", "second"),
+                ("nested/scratch.tmp", "scratch"),
+                ("node_modules/index.js", "index"),
+            ] {
+                fs::write(folder.join(path), content).unwrap();
+            }
+
+            let mut node =
+                Node::start_with(&dir, |c| c.exclude_patterns = vec!["*.tmp".to_owned()]);
+            node.request(WindowResponse::CreateShareDirectory("folder".to_owned()))
+                .await;
+            let dir_id = match node
+                .wait_for(|r| matches!(r, WindowRequest::NewShareDirectory(_)))
+                .await
+            {
+                WindowRequest::NewShareDirectory(signature) => signature.identifier,
+                _ => unreachable!(),
+            };
+
+            node.request(WindowResponse::AddFolder {
+                directory_identifier: dir_id.to_string(),
+                folder_path: folder.to_str().unwrap().to_owned(),
+                exclude_patterns: vec!["node_modules/".to_owned()],
+            })
+            .await;
+            let added = match node
+                .wait_for(|r| matches!(r, WindowRequest::FolderAdded(_)))
+                .await
+            {
+                WindowRequest::FolderAdded(added) => added,
+                _ => unreachable!(),
+            };
+
+            assert_eq!(added.added_files, 2);
+            assert_eq!(added.excluded, 2);
+            assert_eq!(added.skipped_files, 0);
+            let mut names: Vec<String> = node
+                .config
+                .get_directory(dir_id)
+                .await
+                .unwrap()
+                .shared_files
+                .into_values()
+                .map(|f| f.name)
+                .collect();
+            names.sort();
+            assert_eq!(names, vec!["first.txt", "second.txt"]);
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        async fn share_local_file(node: &mut Node, path: &Path) -> (Uuid, Uuid) {
+            node.request(WindowResponse::CreateShareDirectory("local".to_owned()))
+                .await;
+            let dir_id = match node
+                .wait_for(|r| matches!(r, WindowRequest::NewShareDirectory(_)))
+                .await
+            {
+                WindowRequest::NewShareDirectory(signature) => signature.identifier,
+                _ => unreachable!(),
+            };
+
+            node.request(WindowResponse::AddFiles {
+                directory_identifier: dir_id.to_string(),
+                file_paths: vec![path.to_str().unwrap().to_owned()],
+            })
+            .await;
+            match node
+                .wait_for(
+                    |r| matches!(r, WindowRequest::UpdateDirectory(d) if !d.shared_files.is_empty()),
+                )
+                .await
+            {
+                WindowRequest::UpdateDirectory(d) => {
+                    (dir_id, *d.shared_files.keys().next().unwrap())
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        #[tokio::test]
+        async fn delete_file_given_undeletable_file_still_unshares_it() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("read_only.txt");
+            fs::write(&path, b"content").unwrap();
+
+            let mut node = Node::start(&dir);
+            let (dir_id, file_id) = share_local_file(&mut node, &path).await;
+
+            // Tests can run as root, which ignores permissions, so a
+            // directory in place of the file stands in for read-only media
+            fs::remove_file(&path).unwrap();
+            fs::create_dir(&path).unwrap();
+
+            node.request(WindowResponse::DeleteFile {
+                directory_identifier: dir_id.to_string(),
+                file_identifier: file_id.to_string(),
+                keep_local_file: false,
+            })
+            .await;
+            node.wait_for(|r| {
+                matches!(
+                    r,
+                    WindowRequest::Error(BackendError {
+                        code: ErrorCode::FileNotDeleted,
+                        ..
+                    })
+                )
+            })
+            .await;
+
+            wait_until(&node.config, |dirs| {
+                dirs.iter()
+                    .any(|d| d.signature.identifier == dir_id && d.shared_files.is_empty())
+            })
+            .await;
+            assert!(path.exists());
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn delete_file_keeping_local_file_only_unshares_it() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("kept.txt");
+            fs::write(&path, b"content").unwrap();
+
+            let mut node = Node::start(&dir);
+            let (dir_id, file_id) = share_local_file(&mut node, &path).await;
+
+            node.request(WindowResponse::DeleteFile {
+                directory_identifier: dir_id.to_string(),
+                file_identifier: file_id.to_string(),
+                keep_local_file: true,
+            })
+            .await;
+
+            wait_until(&node.config, |dirs| {
+                dirs.iter()
+                    .any(|d| d.signature.identifier == dir_id && d.shared_files.is_empty())
+            })
+            .await;
+            assert_eq!(fs::read(&path).unwrap(), b"content");
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn repair_ownership_lists_owner_of_stored_file() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("drifted.txt");
+            fs::write(&path, b"content").unwrap();
+
+            let mut node = Node::start(&dir);
+            let (dir_id, file_id) = share_local_file(&mut node, &path).await;
+            node.config
+                .mutate_file(dir_id, file_id, |file| {
+                    file.owned_peers.clear();
+
+                    true
+                })
+                .await;
+
+            node.request(WindowResponse::RepairOwnership {
+                directory_identifier: dir_id.to_string(),
+                file_identifier: file_id.to_string(),
+            })
+            .await;
+
+            node.wait_for(|r| match r {
+                WindowRequest::UpdateDirectory(d) => d
+                    .shared_files
+                    .get(&file_id)
+                    .map(|f| f.local_ownership == LocalOwnership::Owned)
+                    .unwrap_or(false),
+                _ => false,
+            })
+            .await;
+            let file = node.config.get_file(dir_id, file_id).await.unwrap();
+            assert_eq!(file.owned_peers, vec![node.peer_id.clone()]);
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[cfg(unix)]
+    mod leave_directory_tests {
+        use std::{collections::HashMap, fs, path::Path};
+
+        use chrono::Utc;
+        use uuid::Uuid;
+
+        use crate::{
+            config::{AppConfig, StoredConfig},
+            data::{ContentLocation, PeerId, ShareDirectory, ShareDirectorySignature, SharedFile},
+            server::remove_local_copies,
+        };
+
+        fn directory(paths: &[&Path]) -> ShareDirectory {
+            let owner = PeerId::generate();
+            let shared_files = paths
+                .iter()
+                .map(|path| {
+                    let file = SharedFile {
+                        name: path.file_name().unwrap().to_str().unwrap().to_owned(),
+                        identifier: Uuid::new_v4(),
+                        content_hash: 0,
+                        last_modified: Utc::now(),
+                        content_location: ContentLocation::LocalPath(path.to_path_buf()),
+                        owned_peers: vec![owner.clone()],
+                        size: 7,
+                        uploads: Default::default(),
+                    };
+
+                    (file.identifier, file)
+                })
+                .collect();
+
+            ShareDirectory {
+                signature: ShareDirectorySignature {
+                    name: "directory".to_owned(),
+                    identifier: Uuid::new_v4(),
+                    last_modified: Utc::now(),
+                    shared_peers: vec![owner],
+                    fingerprint: None,
+                },
+                shared_files,
+            }
+        }
+
+        #[tokio::test]
+        async fn remove_local_copies_keeps_files_still_in_use() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            let downloads = dir.join("downloads");
+            fs::create_dir_all(&downloads).unwrap();
+
+            let downloaded = downloads.join("downloaded.txt");
+            let still_shared = downloads.join("still_shared.txt");
+            let original = dir.join("original.txt");
+            for path in [&downloaded, &still_shared, &original] {
+                fs::write(path, b"content").unwrap();
+            }
+
+            let left = directory(&[&downloaded, &still_shared, &original]);
+            let remaining = directory(&[&still_shared]);
+            let app_config = AppConfig {
+                download_directory: downloads,
+                ..Default::default()
+            };
+            let config = StoredConfig::new(
+                app_config,
+                HashMap::from([(remaining.signature.identifier, remaining)]),
+            );
+
+            let (deleted_files, freed_bytes) = remove_local_copies(&config, &left).await;
+
+            assert_eq!((deleted_files, freed_bytes), (1, 7));
+            assert!(!downloaded.exists());
+            assert!(still_shared.exists());
+            assert!(original.exists());
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    mod symlink_tests {
+        use std::{fs, os::unix::fs::symlink, path::PathBuf};
+
+        use chrono::{TimeZone, Utc};
+        use uuid::Uuid;
+
+        use crate::{
+            data::{ContentLocation, PeerId},
+            server::create_shared_file,
+        };
+
+        fn setup() -> PathBuf {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+
+            fs::write(dir.join("target.txt"), b"shared content").unwrap();
+
+            dir
+        }
+
+        fn peer() -> PeerId {
+            PeerId {
+                hostname: "test".to_string(),
+                uuid: Uuid::nil(),
+            }
+        }
+
+        #[tokio::test]
+        async fn create_shared_file_given_symlink_stores_target_path() {
+            let dir = setup();
+            let target = dir.join("target.txt");
+            let link = dir.join("link.txt");
+            symlink(&target, &link).unwrap();
+
+            let file =
+                create_shared_file(link.to_str().unwrap().to_string(), &peer(), Utc::now()).await;
+
+            let file = file.unwrap();
+            assert_eq!(file.name, "link.txt");
+            match file.content_location {
+                ContentLocation::NetworkOnly | ContentLocation::Partial { .. } => {
+                    panic!("File should be stored locally")
+                }
+                ContentLocation::LocalPath(path) => {
+                    assert_eq!(path, target.canonicalize().unwrap())
+                }
+            }
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn create_shared_file_given_regular_file_keeps_path() {
+            let dir = setup();
+            let target = dir.join("target.txt");
+
+            let modified = Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap();
+            let file = create_shared_file(target.to_str().unwrap().to_string(), &peer(), modified)
+                .await
+                .unwrap();
+
+            assert_eq!(file.last_modified, modified);
+            match file.content_location {
+                ContentLocation::NetworkOnly | ContentLocation::Partial { .. } => {
+                    panic!("File should be stored locally")
+                }
+                ContentLocation::LocalPath(path) => assert_eq!(path, target),
+            }
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn create_shared_file_given_dangling_symlink_returns_error() {
+            let dir = setup();
+            let link = dir.join("dangling.txt");
+            symlink(dir.join("missing.txt"), &link).unwrap();
+
+            let file =
+                create_shared_file(link.to_str().unwrap().to_string(), &peer(), Utc::now()).await;
+
+            assert!(file.is_err());
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn create_shared_file_given_symlink_to_directory_returns_error() {
+            let dir = setup();
+            let link = dir.join("directory_link");
+            symlink(&dir, &link).unwrap();
+
+            let file =
+                create_shared_file(link.to_str().unwrap().to_string(), &peer(), Utc::now()).await;
+
+            assert!(file.is_err());
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,440 @@
+use std::{
+    collections::HashMap,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use tauri::async_runtime::Mutex;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use uuid::Uuid;
+
+use crate::client::partial_path;
+
+const STREAM_CHUNK_SIZE: usize = 1024 * 64; // 64 KB
+const MAX_REQUEST_HEAD_SIZE: usize = 1024 * 8;
+const DATA_POLL_MILLIS: u64 = 100;
+const DATA_WAIT_SECS: u64 = 30;
+const STREAM_EXPIRY_SECS: u64 = 12 * 60 * 60;
+
+const MEDIA_TYPES: [(&str, &str); 13] = [
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("webm", "video/webm"),
+    ("mkv", "video/x-matroska"),
+    ("mov", "video/quicktime"),
+    ("ogv", "video/ogg"),
+    ("mp3", "audio/mpeg"),
+    ("m4a", "audio/mp4"),
+    ("aac", "audio/aac"),
+    ("flac", "audio/flac"),
+    ("wav", "audio/wav"),
+    ("ogg", "audio/ogg"),
+    ("opus", "audio/opus"),
+];
+
+/// Returns the content type of an audio or video file, or `None` for files
+/// that can't be streamed.
+pub fn media_content_type(file_name: &str) -> Option<&'static str> {
+    let extension = Path::new(file_name).extension()?.to_str()?.to_lowercase();
+
+    MEDIA_TYPES
+        .iter()
+        .find(|(ext, _)| *ext == extension)
+        .map(|(_, content_type)| *content_type)
+}
+
+/// A file that is being downloaded and can be played while it arrives.
+#[derive(Clone, Debug)]
+pub struct StreamSource {
+    pub destination: PathBuf,
+    pub size: u64,
+    pub content_type: &'static str,
+}
+
+/// Serves downloads in progress over HTTP on localhost, so a media player can
+/// start playing a file before it has fully downloaded.
+pub struct MediaStreams {
+    port: u16,
+    sources: Mutex<HashMap<Uuid, (StreamSource, Instant)>>,
+    expiry: Duration,
+}
+
+impl MediaStreams {
+    pub fn new(port: u16) -> Self {
+        Self {
+            port,
+            sources: Mutex::new(HashMap::new()),
+            expiry: Duration::from_secs(STREAM_EXPIRY_SECS),
+        }
+    }
+
+    /// Makes `source` available and returns the URL it can be played from.
+    /// URLs stop working once nothing requested them for
+    /// `STREAM_EXPIRY_SECS`. They outlive the download, since players request
+    /// the file again to seek.
+    pub async fn register(&self, source: StreamSource) -> String {
+        let token = Uuid::new_v4();
+        let now = Instant::now();
+
+        let mut sources = self.sources.lock().await;
+        sources.retain(|_, (_, last_used)| now.duration_since(*last_used) < self.expiry);
+        sources.insert(token, (source, now));
+
+        format!("http://127.0.0.1:{}/{}", self.port, token)
+    }
+
+    /// Stops serving a download that was canceled.
+    pub async fn remove(&self, destination: &Path) {
+        self.sources
+            .lock()
+            .await
+            .retain(|_, (source, _)| source.destination != destination);
+    }
+
+    async fn get(&self, token: &str) -> Option<StreamSource> {
+        let token = Uuid::parse_str(token).ok()?;
+        let now = Instant::now();
+
+        match self.sources.lock().await.get_mut(&token) {
+            Some((source, last_used)) if now.duration_since(*last_used) < self.expiry => {
+                *last_used = now;
+                Some(source.clone())
+            }
+            _ => None,
+        }
+    }
+}
+
+pub async fn serve_streams(listener: TcpListener, streams: Arc<MediaStreams>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let streams = streams.clone();
+
+                tokio::spawn(async move {
+                    if let Err(e) = serve_request(stream, &streams).await {
+                        warn!("Could not serve media stream: {}", e);
+                    }
+                });
+            }
+            Err(e) => error!("Could not accept media stream connection: {}", e),
+        }
+    }
+}
+
+async fn serve_request(mut stream: TcpStream, streams: &MediaStreams) -> Result<()> {
+    let head = read_request_head(&mut stream).await?;
+    let mut lines = head.lines();
+
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().unwrap_or_default();
+
+    if method != "GET" && method != "HEAD" {
+        return write_status(&mut stream, "405 Method Not Allowed").await;
+    }
+
+    let source = match streams.get(target.trim_start_matches('/')).await {
+        None => return write_status(&mut stream, "404 Not Found").await,
+        Some(source) => source,
+    };
+
+    let range = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("range"))
+        .map(|(_, value)| parse_range(value.trim(), source.size));
+
+    let (start, end) = match range {
+        None if source.size == 0 => return write_status(&mut stream, "200 OK").await,
+        None => (0, source.size - 1),
+        Some(Some(range)) => range,
+        Some(None) => {
+            let status = "416 Range Not Satisfiable";
+            let head = format!(
+                "HTTP/1.1 {}\r\nContent-Range: bytes */{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status, source.size
+            );
+            stream.write_all(head.as_bytes()).await?;
+
+            return Ok(());
+        }
+    };
+
+    let mut head = if range.is_some() {
+        format!(
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n",
+            start, end, source.size
+        )
+    } else {
+        "HTTP/1.1 200 OK\r\n".to_owned()
+    };
+    head.push_str(&format!(
+        "Content-Type: {}\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\nConnection: close\r\n\r\n",
+        source.content_type,
+        end - start + 1
+    ));
+    stream.write_all(head.as_bytes()).await?;
+
+    if method == "GET" {
+        write_body(&mut stream, &source.destination, start, end).await?;
+    }
+
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+async fn read_request_head(stream: &mut TcpStream) -> Result<String> {
+    let mut head = Vec::new();
+    let mut buffer = [0u8; 1024];
+
+    while !head.ends_with(b"\r\n\r\n") {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Err(anyhow!("Connection closed before request was complete"));
+        }
+
+        head.extend_from_slice(&buffer[..read]);
+        if head.len() > MAX_REQUEST_HEAD_SIZE {
+            return Err(anyhow!("Request head is too large"));
+        }
+    }
+
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+async fn write_status(stream: &mut TcpStream, status: &str) -> Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    );
+    stream.write_all(head.as_bytes()).await?;
+
+    Ok(())
+}
+
+/// Parses a single `bytes=` range into inclusive start and end offsets.
+/// Returns `None` if the range can't be satisfied for a file of `size` bytes.
+fn parse_range(value: &str, size: u64) -> Option<(u64, u64)> {
+    let (start, end) = value.strip_prefix("bytes=")?.split_once('-')?;
+    let last = size.checked_sub(1)?;
+
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", "") => return None,
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (size.saturating_sub(suffix), last)
+        }
+        (start, "") => (start.parse().ok()?, last),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(last)),
+    };
+
+    if start > end || start > last {
+        return None;
+    }
+
+    Some((start, end))
+}
+
+/// Writes bytes `start..=end` of a download, waiting for bytes that haven't
+/// arrived yet. The partial file is reopened for every chunk, so it can still
+/// be renamed once the download completes.
+async fn write_body(
+    stream: &mut TcpStream,
+    destination: &Path,
+    start: u64,
+    end: u64,
+) -> Result<()> {
+    let mut position = start;
+    let mut buffer = vec![0u8; STREAM_CHUNK_SIZE];
+    let mut last_progress = Instant::now();
+
+    while position <= end {
+        let wanted = STREAM_CHUNK_SIZE.min((end - position + 1) as usize);
+        let read = read_chunk(destination, position, &mut buffer[..wanted]).await?;
+
+        if read == 0 {
+            if last_progress.elapsed() > Duration::from_secs(DATA_WAIT_SECS) {
+                return Err(anyhow!("Download of {:?} stopped progressing", destination));
+            }
+
+            tokio::time::sleep(Duration::from_millis(DATA_POLL_MILLIS)).await;
+            continue;
+        }
+
+        stream.write_all(&buffer[..read]).await?;
+        position += read as u64;
+        last_progress = Instant::now();
+    }
+
+    Ok(())
+}
+
+async fn read_chunk(destination: &Path, position: u64, buffer: &mut [u8]) -> Result<usize> {
+    let mut file = match File::open(partial_path(destination)).await {
+        Ok(file) => file,
+        Err(_) => File::open(destination).await?,
+    };
+
+    file.seek(SeekFrom::Start(position)).await?;
+
+    Ok(file.read(buffer).await?)
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod range_tests {
+        use crate::stream::{media_content_type, parse_range};
+
+        #[test]
+        fn parse_range_handles_open_and_suffix_ranges() {
+            assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 99)));
+            assert_eq!(parse_range("bytes=500-", 1000), Some((500, 999)));
+            assert_eq!(parse_range("bytes=-100", 1000), Some((900, 999)));
+            assert_eq!(parse_range("bytes=900-5000", 1000), Some((900, 999)));
+        }
+
+        #[test]
+        fn parse_range_given_unsatisfiable_range_returns_none() {
+            assert_eq!(parse_range("bytes=1000-", 1000), None);
+            assert_eq!(parse_range("bytes=20-10", 1000), None);
+            assert_eq!(parse_range("items=0-10", 1000), None);
+            assert_eq!(parse_range("bytes=0-10", 0), None);
+        }
+
+        #[test]
+        fn media_content_type_only_accepts_audio_and_video() {
+            assert_eq!(media_content_type("Movie.MP4"), Some("video/mp4"));
+            assert_eq!(media_content_type("song.flac"), Some("audio/flac"));
+            assert_eq!(media_content_type("notes.txt"), None);
+            assert_eq!(media_content_type("no_extension"), None);
+        }
+    }
+
+    mod token_tests {
+        use std::{path::PathBuf, time::Duration};
+
+        use crate::stream::{MediaStreams, StreamSource};
+
+        fn source(name: &str) -> StreamSource {
+            StreamSource {
+                destination: PathBuf::from(name),
+                size: 1,
+                content_type: "video/mp4",
+            }
+        }
+
+        fn token(url: &str) -> &str {
+            url.rsplit('/').next().unwrap()
+        }
+
+        #[tokio::test]
+        async fn remove_stops_serving_only_that_destination() {
+            let streams = MediaStreams::new(0);
+            let canceled = streams.register(source("canceled.mp4")).await;
+            let kept = streams.register(source("kept.mp4")).await;
+
+            streams.remove(&PathBuf::from("canceled.mp4")).await;
+
+            assert!(streams.get(token(&canceled)).await.is_none());
+            assert!(streams.get(token(&kept)).await.is_some());
+        }
+
+        #[tokio::test]
+        async fn unused_streams_expire() {
+            let streams = MediaStreams {
+                expiry: Duration::from_millis(400),
+                ..MediaStreams::new(0)
+            };
+            let watched = streams.register(source("watched.mp4")).await;
+            let forgotten = streams.register(source("forgotten.mp4")).await;
+
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            assert!(streams.get(token(&watched)).await.is_some());
+            tokio::time::sleep(Duration::from_millis(250)).await;
+
+            assert!(streams.get(token(&watched)).await.is_some());
+            assert!(streams.get(token(&forgotten)).await.is_none());
+
+            // Expired streams are dropped when the next one is registered
+            streams.register(source("next.mp4")).await;
+            assert_eq!(streams.sources.lock().await.len(), 2);
+        }
+    }
+
+    mod serve_tests {
+        use std::{fs, sync::Arc, time::Duration};
+
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::{TcpListener, TcpStream},
+        };
+        use uuid::Uuid;
+
+        use crate::{
+            client::partial_path,
+            stream::{serve_streams, MediaStreams, StreamSource},
+        };
+
+        #[tokio::test]
+        async fn stream_waits_for_bytes_still_downloading() {
+            let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+            let destination = dir.join("video.mp4");
+            let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+            fs::write(partial_path(&destination), &content[..50_000]).unwrap();
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let streams = Arc::new(MediaStreams::new(listener.local_addr().unwrap().port()));
+            let url = streams
+                .register(StreamSource {
+                    destination: destination.clone(),
+                    size: content.len() as u64,
+                    content_type: "video/mp4",
+                })
+                .await;
+            tokio::spawn(serve_streams(listener, streams));
+
+            let rest = content[50_000..].to_vec();
+            let partial = partial_path(&destination);
+            let final_path = destination.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                let mut file = fs::OpenOptions::new().append(true).open(&partial).unwrap();
+                std::io::Write::write_all(&mut file, &rest).unwrap();
+                drop(file);
+                fs::rename(partial, final_path).unwrap();
+            });
+
+            let address = url.trim_start_matches("http://");
+            let (host, path) = address.split_once('/').unwrap();
+            let mut stream = TcpStream::connect(host).await.unwrap();
+            let request = format!(
+                "GET /{} HTTP/1.1\r\nHost: {}\r\nRange: bytes=10000-\r\n\r\n",
+                path, host
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+
+            let mut response = vec![];
+            stream.read_to_end(&mut response).await.unwrap();
+            let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+            let head = String::from_utf8_lossy(&response[..split]);
+            let body = &response[split + 4..];
+
+            assert!(head.starts_with("HTTP/1.1 206"));
+            assert!(head.contains("Content-Range: bytes 10000-199999/200000"));
+            assert_eq!(body, &content[10_000..]);
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
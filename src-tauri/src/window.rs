@@ -1,10 +1,23 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, SocketAddrV4},
+    path::PathBuf,
+};
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use tauri::{AppHandle, Manager};
+use tauri::{api::notification::Notification, AppHandle, Manager};
+use tokio::sync::oneshot;
 use uuid::Uuid;
 
-use crate::data::{PeerId, ShareDirectory, ShareDirectorySignature};
+use crate::{
+    client::{Compression, DownloadError, MessageCounters},
+    config::Settings,
+    data::{
+        ContentLocation, LocalOwnership, PeerId, ShareDirectory, ShareDirectorySignature,
+        SharedFile,
+    },
+};
 
 pub mod commands;
 
@@ -20,6 +33,16 @@ pub struct Download {
     pub file_path: PathBuf,
 }
 
+/// A download that was still running when the app last stopped. Its partial
+/// file is kept until the user retries or discards it.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct InterruptedDownload {
+    #[serde(flatten)]
+    pub download: Download,
+    pub bytes_done: u64,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct DownloadUpdate {
@@ -30,8 +53,325 @@ pub struct DownloadUpdate {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct DownloadCanceled {
-    pub reason: String,
+    pub reason: DownloadError,
     pub download_id: Uuid,
+    pub file_name: Option<String>,
+    pub peer: Option<PeerId>,
+}
+
+impl DownloadCanceled {
+    pub fn new(download_id: Uuid, reason: DownloadError, download: Option<Download>) -> Self {
+        let (file_name, peer) = match download {
+            None => (None, None),
+            Some(download) => (Some(download.file_name), Some(download.peer)),
+        };
+
+        Self {
+            reason,
+            download_id,
+            file_name,
+            peer,
+        }
+    }
+}
+
+/// Longest file name shown in the window, in characters.
+const MAX_DISPLAY_NAME_CHARS: usize = 128;
+
+/// Makes a file name received from a peer safe to show. Control and
+/// bidirectional formatting characters are dropped, since they can break
+/// rendering or make a name look like something else, and long names are
+/// shortened. The real name must still be used for anything on disk.
+pub fn display_name(name: &str) -> String {
+    let cleaned: Vec<char> = name
+        .chars()
+        .filter(|c| !c.is_control() && !is_bidi_control(*c))
+        .collect();
+
+    if cleaned.len() > MAX_DISPLAY_NAME_CHARS {
+        let mut shortened: String = cleaned[..MAX_DISPLAY_NAME_CHARS - 1].iter().collect();
+        shortened.push('…');
+
+        shortened
+    } else {
+        cleaned.into_iter().collect()
+    }
+}
+
+/// Formats a byte count with the largest unit that keeps it above one.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+/// A shared file as shown in the window, with how many of its owners are
+/// connected right now and whether this device owns it.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SharedFileView {
+    #[serde(flatten)]
+    pub file: SharedFile,
+    pub display_name: String,
+    pub online_owner_count: usize,
+    pub local_ownership: LocalOwnership,
+    pub availability: FileAvailability,
+}
+
+/// Whether a file can be downloaded right now, so the window can tell files
+/// without a reachable owner apart before the user tries.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum FileAvailability {
+    /// Stored on this device.
+    Local,
+    /// A connected owner can send it.
+    Downloadable,
+    /// Owners' connections were closed for being idle. Downloading opens
+    /// them again first.
+    OwnersIdle,
+    /// None of its owners is connected.
+    OwnersOffline,
+    /// No device has the contents any more.
+    NoOwners,
+}
+
+impl FileAvailability {
+    pub fn of(
+        file: &SharedFile,
+        online_peers: &[PeerId],
+        idle_peers: &[PeerId],
+        myself: &PeerId,
+    ) -> Self {
+        if let ContentLocation::LocalPath(_) = file.content_location {
+            return Self::Local;
+        }
+
+        let owners: Vec<&PeerId> = file
+            .owned_peers
+            .iter()
+            .filter(|owner| *owner != myself)
+            .collect();
+
+        if owners.iter().any(|owner| online_peers.contains(owner)) {
+            Self::Downloadable
+        } else if owners.iter().any(|owner| idle_peers.contains(owner)) {
+            Self::OwnersIdle
+        } else if !owners.is_empty() {
+            Self::OwnersOffline
+        } else {
+            Self::NoOwners
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ShareDirectoryView {
+    pub signature: ShareDirectorySignature,
+    pub shared_files: HashMap<Uuid, SharedFileView>,
+    /// `None` while no connected peer has said which version it has.
+    pub sync_status: Option<SyncStatus>,
+}
+
+/// Answer to `WindowResponse::GetDirectoryFingerprint`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DirectoryFingerprint {
+    pub directory_identifier: Uuid,
+    pub fingerprint: String,
+    pub file_count: usize,
+    /// Fingerprints that connected peers last reported, sorted by peer.
+    /// Peers that haven't reported one since connecting are left out.
+    pub peers: Vec<PeerFingerprint>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerFingerprint {
+    pub peer: PeerId,
+    pub fingerprint: String,
+    pub in_sync: bool,
+}
+
+/// Answer to `WindowResponse::GetDirectory`.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum DirectoryListing {
+    Directory(ShareDirectoryView),
+    Signatures(Vec<ShareDirectorySignature>),
+}
+
+impl ShareDirectoryView {
+    pub fn new(
+        directory: ShareDirectory,
+        online_peers: &[PeerId],
+        idle_peers: &[PeerId],
+        myself: &PeerId,
+    ) -> Self {
+        let shared_files = directory
+            .shared_files
+            .into_iter()
+            .map(|(id, file)| {
+                let online_owner_count = file
+                    .owned_peers
+                    .iter()
+                    .filter(|owner| online_peers.contains(owner))
+                    .count();
+
+                (
+                    id,
+                    SharedFileView {
+                        display_name: display_name(&file.name),
+                        local_ownership: file.local_ownership(myself),
+                        availability: FileAvailability::of(&file, online_peers, idle_peers, myself),
+                        file,
+                        online_owner_count,
+                    },
+                )
+            })
+            .collect();
+
+        Self {
+            signature: directory.signature,
+            shared_files,
+            sync_status: None,
+        }
+    }
+}
+
+/// How a directory compares with the versions connected peers last reported
+/// having.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", tag = "state")]
+pub enum SyncStatus {
+    Synced,
+    /// A peer has a newer version that is still being merged.
+    Syncing,
+    /// These peers have an older version and haven't caught up yet.
+    OutOfDate {
+        peers: Vec<PeerId>,
+    },
+}
+
+impl SyncStatus {
+    /// Compares the local version of a directory with the versions of peers.
+    /// Returns `None` if no peer versions are known.
+    pub fn new(local: DateTime<Utc>, peer_versions: &[(PeerId, DateTime<Utc>)]) -> Option<Self> {
+        if peer_versions.is_empty() {
+            return None;
+        }
+
+        if peer_versions.iter().any(|(_, version)| *version > local) {
+            return Some(SyncStatus::Syncing);
+        }
+
+        let peers: Vec<PeerId> = peer_versions
+            .iter()
+            .filter(|(_, version)| *version < local)
+            .map(|(peer, _)| peer.clone())
+            .collect();
+
+        if peers.is_empty() {
+            Some(SyncStatus::Synced)
+        } else {
+            Some(SyncStatus::OutOfDate { peers })
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DirectoryDownloadUpdate {
+    pub directory_identifier: Uuid,
+    pub files_total: usize,
+    pub files_done: usize,
+    pub files_failed: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct QueuedDownload {
+    pub download_id: Uuid,
+    pub directory_identifier: Uuid,
+    pub file_identifier: Uuid,
+    pub file_name: String,
+    pub size: u64,
+    #[serde(skip)]
+    pub destination: Option<DownloadDestination>,
+}
+
+/// Where to save a download instead of a generated path in the download
+/// directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DownloadDestination {
+    pub path: PathBuf,
+    /// The user confirmed replacing a file that is already there.
+    pub overwrite: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NetworkStatus {
+    pub paused: bool,
+    /// Whether new peers can find this device. Peers that are already
+    /// known stay connected either way.
+    pub discoverable: bool,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerProtocolMetrics {
+    pub peer: Option<PeerId>,
+    pub address: SocketAddr,
+    pub compression: Compression,
+    #[serde(flatten)]
+    pub counters: MessageCounters,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadSpace {
+    pub download_directory: PathBuf,
+    pub free_bytes: Option<u64>,
+    pub queued_bytes: u64,
+    pub selected_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AlreadyDownloaded {
+    pub directory_identifier: Uuid,
+    pub file_identifier: Uuid,
+    pub file_path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareOffer {
+    pub directory: ShareDirectorySignature,
+    pub peer: PeerId,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DirectoryShared {
+    pub signature: ShareDirectorySignature,
+    pub from_peer: PeerId,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -40,8 +380,95 @@ pub struct DownloadNotStarted {
     pub reason: String,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DirectoryLeft {
+    pub directory_identifier: Uuid,
+    pub deleted_files: usize,
+    pub freed_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderAdded {
+    pub directory_identifier: Uuid,
+    pub added_files: usize,
+    /// Files and directories left out by an exclude pattern.
+    pub excluded: usize,
+    /// Files that couldn't be read, or were already shared.
+    pub skipped_files: usize,
+}
+
+/// A discovered peer could not be reached on any address it advertised.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionFailed {
+    pub peer: Option<PeerId>,
+    pub addresses: Vec<SocketAddrV4>,
+    pub reason: String,
+    pub timed_out: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerDisconnected {
+    pub peer: PeerId,
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct VerificationProgress {
+    pub directory_identifier: Uuid,
+    pub files_checked: usize,
+    pub files_total: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct VerificationResult {
+    pub directory_identifier: Uuid,
+    pub files_checked: usize,
+    pub mismatched_files: Vec<Uuid>,
+    pub missing_files: Vec<Uuid>,
+    pub repaired: bool,
+}
+
+/// Result of a speed test to a connected peer. Only throwaway data is sent,
+/// no files are read or written.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SpeedTestResult {
+    pub peer: PeerId,
+    pub latency_millis: u64,
+    pub bytes_sent: u64,
+    pub bytes_per_second: u64,
+}
+
+/// Kind of error shown to the user, so the frontend can react to it without
+/// matching on the message text.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+    PortUnavailable,
+    ClockSkew,
+    FileAlreadyShared,
+    PeerUnavailable,
+    DownloadLocationUnwritable,
+    DownloadFailed,
+    FilesUnavailable,
+    FileNotDeleted,
+    DirectoryAlreadyShared,
+    ShareLimitReached,
+    AlreadyDownloading,
+    InvalidLink,
+    InvalidExcludePattern,
+    InterfaceUnavailable,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BackendError {
+    pub code: ErrorCode,
     pub error: String,
     pub title: String,
 }
@@ -51,43 +478,168 @@ pub struct BackendError {
 pub enum WindowResponse {
     CreateShareDirectory(String),
     GetAllShareDirectoryData(bool),
+    /// Answered through `reply` rather than an event, with the directory or,
+    /// without an identifier, the signatures of every directory.
+    GetDirectory {
+        #[serde(default)]
+        directory_identifier: Option<String>,
+        #[serde(skip)]
+        reply: Option<oneshot::Sender<Result<DirectoryListing, String>>>,
+    },
+    /// Answered through `reply` if set, otherwise with a
+    /// `DirectoryFingerprint` event.
+    GetDirectoryFingerprint {
+        directory_identifier: String,
+        #[serde(skip)]
+        reply: Option<oneshot::Sender<Result<DirectoryFingerprint, String>>>,
+    },
     GetPeers(bool),
     AddFiles {
         directory_identifier: String,
         file_paths: Vec<String>,
     },
+    /// Adds every file in a folder and its subdirectories, except those
+    /// matching `exclude_patterns` or the patterns saved in the config.
+    /// Answered with `FolderAdded`.
+    AddFolder {
+        directory_identifier: String,
+        folder_path: String,
+        #[serde(default)]
+        exclude_patterns: Vec<String>,
+    },
     ShareDirectoryToPeers {
         directory_identifier: String,
         peers: Vec<PeerId>,
     },
+    /// Shares a directory with every peer connected right now. With
+    /// `keep_shared` it is also shared with peers that connect later, until
+    /// this is sent again without it.
+    ShareDirectoryToAllPeers {
+        directory_identifier: String,
+        #[serde(default)]
+        keep_shared: bool,
+    },
     DownloadFile {
         directory_identifier: String,
         file_identifier: String,
+        #[serde(default)]
+        destination: Option<PathBuf>,
+        /// Set once the user confirmed replacing an existing file at
+        /// `destination`, which is refused otherwise.
+        #[serde(default)]
+        overwrite: bool,
+    },
+    DownloadDirectory {
+        directory_identifier: String,
+    },
+    ReorderDownload {
+        download_identifier: String,
+        new_position: usize,
+    },
+    GetDownloadSpace {
+        #[serde(default)]
+        directory_identifier: Option<String>,
+    },
+    /// Makes the owner list agree with whether the file is stored locally,
+    /// for files whose `local_ownership` shows they have drifted apart.
+    RepairOwnership {
+        directory_identifier: String,
+        file_identifier: String,
     },
     DeleteFile {
         directory_identifier: String,
         file_identifier: String,
+        /// Only stops sharing the file, for files on read-only media or
+        /// that should stay where they are.
+        #[serde(default)]
+        keep_local_file: bool,
     },
     CancelDownload {
         peer: PeerId,
         download_identifier: String,
     },
+    CancelUpload {
+        download_identifier: String,
+    },
+    CancelFileUploads {
+        directory_identifier: String,
+        file_identifier: String,
+    },
     LeaveDirectory {
         directory_identifier: String,
+        #[serde(default)]
+        delete_local_files: bool,
+    },
+    VerifyDirectory {
+        directory_identifier: String,
+        #[serde(default)]
+        repair: bool,
+    },
+    AcceptShare {
+        directory_identifier: String,
+    },
+    RejectShare {
+        directory_identifier: String,
+    },
+    DisconnectPeer {
+        peer: PeerId,
+    },
+    ForgetPeer {
+        peer: PeerId,
+    },
+    RescanNetwork,
+    SetNetworkPaused(bool),
+    SetDiscoverable(bool),
+    GetProtocolMetrics,
+    GetInterruptedDownloads,
+    RetryInterruptedDownload {
+        download_identifier: String,
+    },
+    DiscardInterruptedDownload {
+        download_identifier: String,
+    },
+    StreamFile {
+        directory_identifier: String,
+        file_identifier: String,
+    },
+    PeerSpeedTest {
+        peer: PeerId,
+    },
+    OpenShareLink {
+        link: String,
     },
 }
 
 #[derive(Serialize, Clone, Debug)]
 #[serde(untagged)]
 pub enum WindowRequest {
-    UpdateDirectory(ShareDirectory),
-    UpdateShareDirectories(Vec<ShareDirectory>),
+    UpdateDirectory(ShareDirectoryView),
+    UpdateShareDirectories(Vec<ShareDirectoryView>),
     GetPeers(Vec<PeerId>),
     NewShareDirectory(ShareDirectorySignature),
     Error(BackendError),
     DownloadStarted(Download),
     DownloadUpdate(DownloadUpdate),
     DownloadCanceled(DownloadCanceled),
+    AlreadyDownloaded(AlreadyDownloaded),
+    DirectoryDownloadUpdate(DirectoryDownloadUpdate),
+    DownloadQueue(Vec<QueuedDownload>),
+    InterruptedDownloads(Vec<InterruptedDownload>),
+    DownloadSpace(DownloadSpace),
+    ShareOffered(ShareOffer),
+    DirectoryShared(DirectoryShared),
+    DirectoryLeft(DirectoryLeft),
+    FolderAdded(FolderAdded),
+    DirectoryFingerprint(DirectoryFingerprint),
+    SettingsUpdated(Settings),
+    PeerLeft(PeerDisconnected),
+    PeerLost(PeerDisconnected),
+    ConnectionFailed(ConnectionFailed),
+    NetworkStatus(NetworkStatus),
+    ProtocolMetrics(Vec<PeerProtocolMetrics>),
+    VerificationProgress(VerificationProgress),
+    VerificationFinished(VerificationResult),
+    SpeedTestResult(SpeedTestResult),
 }
 
 impl WindowRequest {
@@ -101,12 +653,34 @@ impl WindowRequest {
             Self::DownloadStarted(_) => "DownloadStarted",
             Self::DownloadUpdate(_) => "DownloadUpdate",
             Self::DownloadCanceled(_) => "DownloadCanceled",
+            Self::AlreadyDownloaded(_) => "AlreadyDownloaded",
+            Self::DirectoryDownloadUpdate(_) => "DirectoryDownloadUpdate",
+            Self::DownloadQueue(_) => "DownloadQueue",
+            Self::InterruptedDownloads(_) => "InterruptedDownloads",
+            Self::DownloadSpace(_) => "DownloadSpace",
+            Self::ShareOffered(_) => "ShareOffered",
+            Self::DirectoryShared(_) => "DirectoryShared",
+            Self::DirectoryLeft(_) => "DirectoryLeft",
+            Self::FolderAdded(_) => "FolderAdded",
+            Self::DirectoryFingerprint(_) => "DirectoryFingerprint",
+            Self::SettingsUpdated(_) => "SettingsUpdated",
+            Self::PeerLeft(_) => "PeerLeft",
+            Self::PeerLost(_) => "PeerLost",
+            Self::ConnectionFailed(_) => "ConnectionFailed",
+            Self::NetworkStatus(_) => "NetworkStatus",
+            Self::ProtocolMetrics(_) => "ProtocolMetrics",
+            Self::VerificationProgress(_) => "VerificationProgress",
+            Self::VerificationFinished(_) => "VerificationFinished",
+            Self::SpeedTestResult(_) => "SpeedTestResult",
         }
     }
 }
 
 pub trait WindowManager {
     fn send(&self, action: WindowRequest) -> Result<(), tauri::Error>;
+    /// Shows a desktop notification. Tauri 1 notifications can't react to
+    /// being clicked, so anything the user needs has to be in `body`.
+    fn notify(&self, title: &str, body: &str) -> Result<(), tauri::api::Error>;
 }
 
 pub struct MainWindowManager {
@@ -119,4 +693,203 @@ impl WindowManager for MainWindowManager {
         self.app_handle
             .emit_to(self.window_label, action.to_string(), action)
     }
+
+    fn notify(&self, title: &str, body: &str) -> Result<(), tauri::api::Error> {
+        let identifier = &self.app_handle.config().tauri.bundle.identifier;
+
+        Notification::new(identifier).title(title).body(body).show()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    mod display_name_tests {
+        use crate::window::{display_name, MAX_DISPLAY_NAME_CHARS};
+
+        #[test]
+        fn display_name_keeps_ordinary_names() {
+            assert_eq!(
+                display_name("Report (final) <v2>.pdf"),
+                "Report (final) <v2>.pdf"
+            );
+        }
+
+        #[test]
+        fn display_name_strips_control_characters() {
+            assert_eq!(
+                display_name("evil\n\r\t\u{1b}[31mname\0.txt"),
+                "evil[31mname.txt"
+            );
+        }
+
+        #[test]
+        fn display_name_strips_bidi_overrides() {
+            // Renders as "invoice_exe.pdf" when the override is kept
+            assert_eq!(display_name("invoice_\u{202E}fdp.exe"), "invoice_fdp.exe");
+        }
+
+        #[test]
+        fn display_name_caps_length() {
+            let name = display_name(&"a".repeat(1000));
+
+            assert_eq!(name.chars().count(), MAX_DISPLAY_NAME_CHARS);
+            assert!(name.ends_with('…'));
+        }
+    }
+
+    mod format_size_tests {
+        use crate::window::format_size;
+
+        #[test]
+        fn format_size_picks_largest_unit() {
+            assert_eq!(format_size(0), "0 B");
+            assert_eq!(format_size(1023), "1023 B");
+            assert_eq!(format_size(1536), "1.5 KB");
+            assert_eq!(format_size(5 * 1024 * 1024 * 1024), "5.0 GB");
+        }
+    }
+
+    mod directory_view_tests {
+        use std::collections::HashMap;
+
+        use chrono::Utc;
+        use uuid::Uuid;
+
+        use crate::{
+            data::{
+                ContentLocation, LocalOwnership, PeerId, ShareDirectory, ShareDirectorySignature,
+                SharedFile,
+            },
+            window::{FileAvailability, ShareDirectoryView},
+        };
+
+        fn peer(uuid: u128) -> PeerId {
+            PeerId {
+                hostname: "test".to_owned(),
+                uuid: Uuid::from_u128(uuid),
+            }
+        }
+
+        #[test]
+        fn share_directory_view_counts_only_connected_owners() {
+            let file = SharedFile {
+                name: "file".to_owned(),
+                identifier: Uuid::new_v4(),
+                content_hash: 0,
+                last_modified: Utc::now(),
+                content_location: ContentLocation::NetworkOnly,
+                owned_peers: vec![peer(1), peer(2), peer(3)],
+                size: 0,
+                uploads: Default::default(),
+            };
+            let file_id = file.identifier;
+            let directory = ShareDirectory {
+                signature: ShareDirectorySignature {
+                    name: "directory".to_owned(),
+                    identifier: Uuid::new_v4(),
+                    last_modified: Utc::now(),
+                    shared_peers: vec![peer(1), peer(2), peer(3)],
+                    fingerprint: None,
+                },
+                shared_files: HashMap::from([(file_id, file)]),
+            };
+
+            let view =
+                ShareDirectoryView::new(directory, &[peer(2), peer(3), peer(4)], &[], &peer(1));
+
+            assert_eq!(view.shared_files[&file_id].online_owner_count, 2);
+            assert_eq!(
+                view.shared_files[&file_id].local_ownership,
+                LocalOwnership::ListedWithoutContent
+            );
+            assert_eq!(
+                view.shared_files[&file_id].availability,
+                FileAvailability::Downloadable
+            );
+        }
+
+        #[test]
+        fn file_availability_given_no_connected_owners_tells_why() {
+            let mut file = SharedFile {
+                name: "file".to_owned(),
+                identifier: Uuid::new_v4(),
+                content_hash: 0,
+                last_modified: Utc::now(),
+                content_location: ContentLocation::NetworkOnly,
+                owned_peers: vec![peer(1), peer(2)],
+                size: 0,
+                uploads: Default::default(),
+            };
+
+            assert_eq!(
+                FileAvailability::of(&file, &[peer(3)], &[peer(2)], &peer(1)),
+                FileAvailability::OwnersIdle
+            );
+            assert_eq!(
+                FileAvailability::of(&file, &[peer(3)], &[], &peer(1)),
+                FileAvailability::OwnersOffline
+            );
+
+            file.owned_peers = vec![peer(1)];
+            assert_eq!(
+                FileAvailability::of(&file, &[peer(3)], &[], &peer(1)),
+                FileAvailability::NoOwners
+            );
+
+            file.content_location = ContentLocation::LocalPath("file".into());
+            assert_eq!(
+                FileAvailability::of(&file, &[], &[], &peer(1)),
+                FileAvailability::Local
+            );
+        }
+    }
+
+    mod sync_status_tests {
+        use chrono::{Duration, TimeZone, Utc};
+        use uuid::Uuid;
+
+        use crate::{data::PeerId, window::SyncStatus};
+
+        fn peer(uuid: u128) -> PeerId {
+            PeerId {
+                hostname: "test".to_owned(),
+                uuid: Uuid::from_u128(uuid),
+            }
+        }
+
+        #[test]
+        fn sync_status_given_equal_versions_is_synced() {
+            let local = Utc.with_ymd_and_hms(2023, 5, 1, 12, 0, 0).unwrap();
+
+            let status = SyncStatus::new(local, &[(peer(1), local), (peer(2), local)]);
+
+            assert_eq!(status, Some(SyncStatus::Synced));
+        }
+
+        #[test]
+        fn sync_status_given_newer_peer_is_syncing() {
+            let local = Utc.with_ymd_and_hms(2023, 5, 1, 12, 0, 0).unwrap();
+            let versions = [
+                (peer(1), local - Duration::seconds(5)),
+                (peer(2), local + Duration::seconds(5)),
+            ];
+
+            assert_eq!(SyncStatus::new(local, &versions), Some(SyncStatus::Syncing));
+        }
+
+        #[test]
+        fn sync_status_given_older_peers_lists_them() {
+            let local = Utc.with_ymd_and_hms(2023, 5, 1, 12, 0, 0).unwrap();
+            let versions = [(peer(1), local - Duration::seconds(5)), (peer(2), local)];
+
+            assert_eq!(
+                SyncStatus::new(local, &versions),
+                Some(SyncStatus::OutOfDate {
+                    peers: vec![peer(1)]
+                })
+            );
+            assert_eq!(SyncStatus::new(local, &[]), None);
+        }
+    }
 }
@@ -1,12 +1,21 @@
 use std::{path::PathBuf, sync::Arc};
 
-use serde::Deserialize;
-use tauri::async_runtime::Mutex;
-use tokio::sync::mpsc;
+use serde::{Deserialize, Serialize};
+use tauri::{async_runtime::Mutex, AppHandle, Manager};
+use tokio::sync::{mpsc, oneshot};
+use uuid::Uuid;
 
-use crate::config::{Settings, StoredConfig};
+use crate::{
+    config::{app_dirs, Settings, StoredConfig, Theme},
+    data::ContentLocation,
+    encryption::path_for_opening,
+    folder::validate_exclude_pattern,
+    link::ShareLink,
+    listen::InterfaceSelection,
+    logging::LogHandle,
+};
 
-use super::WindowResponse;
+use super::{DirectoryFingerprint, DirectoryListing, WindowRequest, WindowResponse};
 
 #[derive(Deserialize, Debug)]
 pub struct OpenFile {
@@ -14,10 +23,27 @@ pub struct OpenFile {
 }
 
 #[tauri::command]
-pub async fn open_file(message: OpenFile) -> Result<(), String> {
+pub async fn open_file(
+    message: OpenFile,
+    state: tauri::State<'_, Arc<StoredConfig>>,
+) -> Result<(), String> {
     info!("{:?}", message);
 
-    let result = opener::open(&message.file_path);
+    if !state.is_known_path(&message.file_path).await {
+        warn!("Refused to open unknown path {:?}", message.file_path);
+
+        return Err(format!(
+            "Could not open file {}: only shared and downloaded files can be opened",
+            message.file_path.display()
+        ));
+    }
+
+    let key = state.get_file_key().await;
+    let path = path_for_opening(&message.file_path, key.as_ref())
+        .await
+        .map_err(|e| format!("Could not open file {}: {}", message.file_path.display(), e))?;
+
+    let result = opener::open(&path);
 
     if let Err(e) = result {
         return Err(format!(
@@ -30,26 +56,189 @@ pub async fn open_file(message: OpenFile) -> Result<(), String> {
     Ok(())
 }
 
+#[derive(Deserialize, Debug)]
+pub struct GetAppDirectories {
+    #[serde(default)]
+    pub open: bool,
+}
+
+/// Where `config.json` and the cached directories are stored.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AppDirectories {
+    pub config_dir: PathBuf,
+    pub data_dir: PathBuf,
+}
+
+/// Returns the directories used for stored data, and optionally opens them
+/// in the file manager. On some platforms these are the same directory.
 #[tauri::command]
-pub async fn get_settings(
-    _message: String,
-    state: tauri::State<'_, Arc<StoredConfig>>,
-) -> Result<Settings, String> {
+pub async fn get_app_directories(message: GetAppDirectories) -> Result<AppDirectories, String> {
+    let dirs = app_dirs();
+    let directories = AppDirectories {
+        config_dir: dirs.config_dir,
+        data_dir: dirs.data_dir,
+    };
+
+    if message.open {
+        let mut to_open = vec![&directories.config_dir];
+        if directories.data_dir != directories.config_dir {
+            to_open.push(&directories.data_dir);
+        }
+
+        for dir in to_open {
+            if let Err(e) = opener::open(dir) {
+                return Err(format!("Could not open directory {}: {}", dir.display(), e));
+            }
+        }
+    }
+
+    Ok(directories)
+}
+
+#[tauri::command]
+pub async fn get_settings(state: tauri::State<'_, Arc<StoredConfig>>) -> Result<Settings, ()> {
     Ok(state.get_settings().await)
 }
 
 #[tauri::command]
 pub async fn save_settings(
-    message: Settings,
+    settings: Settings,
+    state: tauri::State<'_, Arc<StoredConfig>>,
+    app_handle: AppHandle,
+) -> Result<(), String> {
+    info!("Received new settings {:#?}", settings);
+
+    state
+        .set_settings(settings)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let updated = WindowRequest::SettingsUpdated(state.get_settings().await);
+    if let Err(e) = app_handle.emit_all(updated.to_string(), updated) {
+        warn!("Could not send updated settings: {}", e);
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct UnlockEncryption {
+    pub passphrase: String,
+}
+
+#[tauri::command]
+pub async fn unlock_encryption(
+    message: UnlockEncryption,
+    state: tauri::State<'_, Arc<StoredConfig>>,
+) -> Result<(), String> {
+    state
+        .unlock_encryption(&message.passphrase)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn get_log_filter(state: tauri::State<'_, Arc<StoredConfig>>) -> Result<String, ()> {
+    Ok(state.get_log_filter().await)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetLogFilter {
+    pub filter: String,
+}
+
+/// Changes which messages are logged right away and saves the filter for
+/// the next start.
+#[tauri::command]
+pub async fn set_log_filter(
+    message: SetLogFilter,
+    state: tauri::State<'_, Arc<StoredConfig>>,
+    log_handle: tauri::State<'_, LogHandle>,
+) -> Result<(), String> {
+    let filter = message.filter.trim().to_owned();
+    log_handle.set_filter(&filter).map_err(|e| e.to_string())?;
+
+    info!("Log filter set to {:?}", filter);
+    state.set_log_filter(filter).await;
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_exclude_patterns(
+    state: tauri::State<'_, Arc<StoredConfig>>,
+) -> Result<Vec<String>, ()> {
+    Ok(state.get_exclude_patterns().await)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetExcludePatterns {
+    pub patterns: Vec<String>,
+}
+
+/// Saves the patterns left out of every added folder. Blank lines are
+/// dropped, anything else has to be a valid pattern.
+#[tauri::command]
+pub async fn set_exclude_patterns(
+    message: SetExcludePatterns,
     state: tauri::State<'_, Arc<StoredConfig>>,
 ) -> Result<(), String> {
-    info!("Received new settings {:#?}", message);
+    let patterns: Vec<String> = message
+        .patterns
+        .iter()
+        .map(|pattern| pattern.trim().to_owned())
+        .filter(|pattern| !pattern.is_empty())
+        .collect();
 
-    let _ = state.set_settings(message).await;
+    for pattern in patterns.iter() {
+        validate_exclude_pattern(pattern).map_err(|e| e.to_string())?;
+    }
+
+    state.set_exclude_patterns(patterns).await;
 
     Ok(())
 }
 
+#[derive(Deserialize, Debug)]
+pub struct GetShareLink {
+    pub directory_identifier: Uuid,
+    pub file_identifier: Uuid,
+}
+
+/// Returns a link that other devices in the directory can open to download
+/// the file from this device.
+#[tauri::command]
+pub async fn get_share_link(
+    message: GetShareLink,
+    state: tauri::State<'_, Arc<StoredConfig>>,
+) -> Result<String, String> {
+    let file = state
+        .get_file(message.directory_identifier, message.file_identifier)
+        .await
+        .ok_or_else(|| "File is not shared".to_owned())?;
+    if !matches!(file.content_location, ContentLocation::LocalPath(_)) {
+        return Err("Only files stored on this device can be linked to".to_owned());
+    }
+
+    let peer = state
+        .get_peer_id()
+        .await
+        .ok_or_else(|| "This device has no peer ID yet".to_owned())?;
+
+    Ok(ShareLink {
+        peer: peer.uuid,
+        directory_identifier: message.directory_identifier,
+        file_identifier: message.file_identifier,
+    }
+    .to_string())
+}
+
+#[tauri::command]
+pub async fn get_themes() -> Result<Vec<Theme>, ()> {
+    Ok(Theme::ALL.to_vec())
+}
+
 pub struct Window {
     pub server: Mutex<mpsc::Sender<WindowResponse>>,
 }
@@ -64,3 +253,88 @@ pub async fn network_command(
 
     sender.send(message).await.map_err(|e| e.to_string())
 }
+
+#[derive(Deserialize, Debug)]
+pub struct GetDirectory {
+    #[serde(default)]
+    pub directory_identifier: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GetDirectoryFingerprint {
+    pub directory_identifier: String,
+}
+
+/// Returns the directory's fingerprint and how it compares with the
+/// fingerprints of connected peers.
+#[tauri::command]
+pub async fn get_directory_fingerprint(
+    message: GetDirectoryFingerprint,
+    state: tauri::State<'_, Window>,
+) -> Result<DirectoryFingerprint, String> {
+    let (reply, fingerprint) = oneshot::channel();
+    let request = WindowResponse::GetDirectoryFingerprint {
+        directory_identifier: message.directory_identifier,
+        reply: Some(reply),
+    };
+
+    {
+        let sender = state.server.lock().await;
+        sender.send(request).await.map_err(|e| e.to_string())?;
+    }
+
+    fingerprint
+        .await
+        .map_err(|_| "Server stopped before answering".to_owned())?
+}
+
+/// Returns a directory, or the signatures of all directories, as the result
+/// of the call. Changes after that still arrive as events.
+#[tauri::command]
+pub async fn get_directory(
+    message: GetDirectory,
+    state: tauri::State<'_, Window>,
+) -> Result<DirectoryListing, String> {
+    let (reply, listing) = oneshot::channel();
+    let request = WindowResponse::GetDirectory {
+        directory_identifier: message.directory_identifier,
+        reply: Some(reply),
+    };
+
+    {
+        let sender = state.server.lock().await;
+        sender.send(request).await.map_err(|e| e.to_string())?;
+    }
+
+    listing
+        .await
+        .map_err(|_| "Server stopped before answering".to_owned())?
+}
+
+#[tauri::command]
+pub async fn get_network_interface(
+    state: tauri::State<'_, Arc<StoredConfig>>,
+) -> Result<String, ()> {
+    Ok(state.get_network_interface().await)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetNetworkInterface {
+    pub interface: String,
+}
+
+/// Saves the interface to listen on. The listener moves to it within a few
+/// seconds, or stays on the first interface if it isn't found.
+#[tauri::command]
+pub async fn set_network_interface(
+    message: SetNetworkInterface,
+    state: tauri::State<'_, Arc<StoredConfig>>,
+) -> Result<(), String> {
+    let selection = message.interface.trim().to_owned();
+    InterfaceSelection::parse(&selection).map_err(|e| e.to_string())?;
+
+    info!("Network interface set to {:?}", selection);
+    state.set_network_interface(selection).await;
+
+    Ok(())
+}
@@ -15,14 +15,36 @@ import { ShareDirectoryProvider } from "./RustCommands/ShareDirectoryContext";
 import { ConnectedDevicesProvider } from "./RustCommands/ConnectedDevicesContext";
 import { DownloadsManager } from "./RustCommands/DownloadsManager";
 import { listen } from "@tauri-apps/api/event";
-import { message } from "@tauri-apps/api/dialog";
+import { ask, message } from "@tauri-apps/api/dialog";
 import { invoke } from "@tauri-apps/api";
 
+type ErrorCode =
+  | "portUnavailable"
+  | "clockSkew"
+  | "fileAlreadyShared"
+  | "peerUnavailable"
+  | "downloadLocationUnwritable"
+  | "downloadFailed"
+  | "filesUnavailable"
+  | "fileNotDeleted"
+  | "directoryAlreadyShared"
+  | "shareLimitReached"
+  | "alreadyDownloading"
+  | "invalidLink"
+  | "invalidExcludePattern"
+  | "interfaceUnavailable";
+
 type BackendError = {
+  code: ErrorCode;
   title: string;
   error: string;
 };
 
+const SETTINGS_ERRORS: ErrorCode[] = [
+  "portUnavailable",
+  "downloadLocationUnwritable",
+];
+
 type ThemeContextValue = {
   toggleTheme: () => void;
   mode: "light" | "dark";
@@ -32,12 +54,24 @@ type Settings = {
   minimizeOnClose: boolean;
   theme: "light" | "dark";
   downloadDirectory: string;
+  autoAcceptShares: boolean;
+  streamMedia: boolean;
+  cleanupDownloads: boolean;
+  cleanupAgeDays: number;
+  encryptDownloads: boolean;
+  downloadNotifications: "disabled" | "everyDownload" | "lastDownload";
 };
 
 const initialSettings: Settings = {
   minimizeOnClose: false,
   theme: "dark",
   downloadDirectory: "",
+  autoAcceptShares: true,
+  streamMedia: false,
+  cleanupDownloads: false,
+  cleanupAgeDays: 7,
+  encryptDownloads: false,
+  downloadNotifications: "disabled",
 };
 const SettingsContext = React.createContext({
   updateSettings: (settings: Settings) => {},
@@ -92,29 +126,44 @@ function App() {
 
         setLastError(input);
 
-        await message(input.error, { title: input.title, type: "error" });
+        if (SETTINGS_ERRORS.includes(input.code)) {
+          const openSettings = await ask(`${input.error}\n\nOpen settings?`, {
+            title: input.title,
+            type: "error",
+          });
+
+          if (openSettings) {
+            navigate("/settings");
+          }
+        } else {
+          await message(input.error, { title: input.title, type: "error" });
+        }
       });
     };
 
-    const getSettings = async () => {
-      const loadedSettings = await invoke<Settings | string>("get_settings", {
-        message: "",
+    const startListenSettings = async () => {
+      const _ = await listen<Settings>("SettingsUpdated", (event) => {
+        const updated = event.payload;
+
+        setMode(updated.theme);
+        setSettings(updated);
       });
+    };
 
-      if (typeof loadedSettings == "string") {
-        console.error(loadedSettings);
-      } else {
-        if (loadedSettings.theme !== mode) {
-          toggleTheme.toggleTheme();
-        }
+    const getSettings = async () => {
+      const loadedSettings = await invoke<Settings>("get_settings");
 
-        setSettings(loadedSettings);
+      if (loadedSettings.theme !== mode) {
+        toggleTheme.toggleTheme();
       }
+
+      setSettings(loadedSettings);
     };
 
     getSettings();
 
     startListenErrors();
+    startListenSettings();
     navigate("/directories");
 
     loaded.current = true;
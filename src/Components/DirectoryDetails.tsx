@@ -24,6 +24,8 @@ import {
   TableFooter,
   TableHead,
   TableRow,
+  TextField,
+  Tooltip,
   Typography,
 } from "@mui/material";
 import InfoRoundedIcon from "@mui/icons-material/InfoRounded";
@@ -32,24 +34,81 @@ import FolderIcon from "@mui/icons-material/Folder";
 import DownloadIcon from "@mui/icons-material/Download";
 import DeleteIcon from "@mui/icons-material/Delete";
 import DownloadDoneIcon from "@mui/icons-material/DownloadDone";
+import SaveAsIcon from "@mui/icons-material/SaveAs";
+import PlayArrowIcon from "@mui/icons-material/PlayArrow";
 import { PeerId, SharedFile } from "../RustCommands/ShareDirectoryContext";
 
-import { open } from "@tauri-apps/api/dialog";
+import { ask, message, open, save } from "@tauri-apps/api/dialog";
 import {
   AddFiles,
+  AddFolder,
   DeleteFile,
+  DownloadDirectory,
   DownloadFile,
+  GetDownloadSpace,
+  RepairOwnership,
+  StreamFile,
   invokeNetworkCommand,
 } from "../RustCommands/networkCommands";
 import React from "react";
 import { invoke } from "@tauri-apps/api";
-import { ErrorContext } from "../App";
+import { listen } from "@tauri-apps/api/event";
+import { ErrorContext, SettingsContext } from "../App";
+
+const MEDIA_EXTENSIONS = [
+  "mp4",
+  "m4v",
+  "webm",
+  "mkv",
+  "mov",
+  "ogv",
+  "mp3",
+  "m4a",
+  "aac",
+  "flac",
+  "wav",
+  "ogg",
+  "opus",
+];
+
+function isMediaFile(name: string): boolean {
+  const extension = name.split(".").pop()?.toLowerCase();
+
+  return extension !== undefined && MEDIA_EXTENSIONS.includes(extension);
+}
+
+type DirectoryDownloadUpdate = {
+  directoryIdentifier: string;
+  filesTotal: number;
+  filesDone: number;
+  filesFailed: number;
+};
+
+type DownloadSpace = {
+  downloadDirectory: string;
+  freeBytes: number | null;
+  queuedBytes: number;
+  selectedBytes: number;
+};
+
+type FolderAdded = {
+  directoryIdentifier: string;
+  addedFiles: number;
+  excluded: number;
+  skippedFiles: number;
+};
+
+type DirectoryFingerprint = {
+  directoryIdentifier: string;
+  fingerprint: string;
+  fileCount: number;
+  peers: Array<{ peer: PeerId; fingerprint: string; inSync: boolean }>;
+};
 
 type DirectoryDetailsProps = {
   files: Map<string, SharedFile>;
   directoryName: string;
   directoryIdentifier: string;
-  currentPeers: Array<PeerId>;
 };
 
 function toLargestDenominator(size: number): string {
@@ -57,6 +116,11 @@ function toLargestDenominator(size: number): string {
   const k = 1024;
   const decimals = 1;
 
+  // log(0) is -Infinity, which has no unit
+  if (size <= 0) {
+    return `0 ${sizes[0]}`;
+  }
+
   const i = Math.floor(Math.log(size) / Math.log(k));
 
   return `${parseFloat((size / Math.pow(k, i)).toFixed(decimals))} ${sizes[i]}`;
@@ -66,12 +130,17 @@ function DirectoryDetails({
   files,
   directoryName,
   directoryIdentifier,
-  currentPeers,
 }: DirectoryDetailsProps) {
   const [addingFiles, setAddingFiles] = React.useState(false);
+  const [folderPath, setFolderPath] = React.useState<string | null>(null);
+  const [folderExcludes, setFolderExcludes] = React.useState("");
   const [fileDetails, setFileDetails] = React.useState<SharedFile | null>(null);
   const detailsOpen = Boolean(fileDetails);
+  const downloadAllRequested = React.useRef(false);
+  const [directoryDownload, setDirectoryDownload] =
+    React.useState<DirectoryDownloadUpdate | null>(null);
   const error = React.useContext(ErrorContext);
+  const { settings } = React.useContext(SettingsContext);
 
   React.useEffect(() => {
     setAddingFiles(false);
@@ -81,6 +150,91 @@ function DirectoryDetails({
     setAddingFiles(false);
   }, [error]);
 
+  React.useEffect(() => {
+    const unlisten = listen<FolderAdded>("FolderAdded", async (event) => {
+      const added = event.payload;
+      if (added.directoryIdentifier !== directoryIdentifier) return;
+
+      setAddingFiles(false);
+
+      let summary = `Added ${added.addedFiles} file(s).`;
+      if (added.excluded > 0) {
+        summary += ` Left out ${added.excluded} excluded file(s) or folder(s).`;
+      }
+      if (added.skippedFiles > 0) {
+        summary += ` Skipped ${added.skippedFiles} file(s) that were unreadable or already shared.`;
+      }
+
+      await message(summary, { title: "Folder added", type: "info" });
+    });
+
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, [directoryIdentifier]);
+
+  React.useEffect(() => {
+    setDirectoryDownload(null);
+
+    const unlisten = listen<DirectoryDownloadUpdate>(
+      "DirectoryDownloadUpdate",
+      (event) => {
+        if (event.payload.directoryIdentifier === directoryIdentifier) {
+          setDirectoryDownload(event.payload);
+        }
+      }
+    );
+
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, [directoryIdentifier]);
+
+  React.useEffect(() => {
+    const unlisten = listen<DownloadSpace>("DownloadSpace", async (event) => {
+      if (!downloadAllRequested.current) return;
+      downloadAllRequested.current = false;
+
+      const space = event.payload;
+      const needed = space.queuedBytes + space.selectedBytes;
+
+      if (space.freeBytes !== null && needed > space.freeBytes) {
+        const downloadAnyway = await ask(
+          `These files need ${toLargestDenominator(needed)} but only ${toLargestDenominator(
+            space.freeBytes
+          )} is free in ${space.downloadDirectory}. Download anyway?`,
+          { title: "Not enough space", type: "warning" }
+        );
+
+        if (!downloadAnyway) return;
+      }
+
+      const request: DownloadDirectory = {
+        downloadDirectory: {
+          directory_identifier: directoryIdentifier,
+        },
+      };
+
+      await invokeNetworkCommand(request);
+    });
+
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, [directoryIdentifier]);
+
+  const handleDownloadAll = async () => {
+    downloadAllRequested.current = true;
+
+    const request: GetDownloadSpace = {
+      getDownloadSpace: {
+        directory_identifier: directoryIdentifier,
+      },
+    };
+
+    await invokeNetworkCommand(request);
+  };
+
   const handleAddFiles = async () => {
     const selected = await open({
       multiple: true,
@@ -101,6 +255,37 @@ function DirectoryDetails({
     }
   };
 
+  const handleAddFolder = async () => {
+    const selected = await open({
+      directory: true,
+      multiple: false,
+    });
+
+    if (typeof selected == "string") {
+      setFolderExcludes("");
+      setFolderPath(selected);
+    }
+  };
+
+  const handleConfirmAddFolder = async () => {
+    if (folderPath === null) return;
+
+    const request: AddFolder = {
+      addFolder: {
+        directory_identifier: directoryIdentifier,
+        folder_path: folderPath,
+        exclude_patterns: folderExcludes
+          .split(",")
+          .map((pattern) => pattern.trim())
+          .filter((pattern) => pattern.length > 0),
+      },
+    };
+
+    setFolderPath(null);
+    await invokeNetworkCommand(request);
+    setAddingFiles(true);
+  };
+
   const handleOpenFileDetails = (file: SharedFile) => () => {
     setFileDetails(file);
   };
@@ -124,15 +309,64 @@ function DirectoryDetails({
     await invokeNetworkCommand(request);
   };
 
-  const handleDelete = (fileId: string) => () => {
+  const handleDownloadTo = (fileId: string) => async () => {
     const file = files.get(fileId);
 
     if (!file) return;
 
-    const request: DeleteFile = {
-      deleteFile: {
+    const destination = await save({ defaultPath: file.displayName });
+
+    if (!destination) return;
+
+    // The save dialog already asked before picking an existing file
+    const request: DownloadFile = {
+      downloadFile: {
         file_identifier: fileId,
         directory_identifier: directoryIdentifier,
+        destination: destination,
+        overwrite: true,
+      },
+    };
+
+    await invokeNetworkCommand(request);
+  };
+
+  const handleStream = (fileId: string) => async () => {
+    const request: StreamFile = {
+      streamFile: {
+        file_identifier: fileId,
+        directory_identifier: directoryIdentifier,
+      },
+    };
+
+    await invokeNetworkCommand(request);
+  };
+
+  const handleDelete =
+    (fileId: string, keepLocalFile = false) =>
+    () => {
+      const file = files.get(fileId);
+
+      if (!file) return;
+
+      const request: DeleteFile = {
+        deleteFile: {
+          file_identifier: fileId,
+          directory_identifier: directoryIdentifier,
+          keep_local_file: keepLocalFile,
+        },
+      };
+
+      invokeNetworkCommand(request).finally(() => {
+        handleCloseFileDetails();
+      });
+    };
+
+  const handleRepairOwnership = (fileId: string) => () => {
+    const request: RepairOwnership = {
+      repairOwnership: {
+        directory_identifier: directoryIdentifier,
+        file_identifier: fileId,
       },
     };
 
@@ -141,6 +375,46 @@ function DirectoryDetails({
     });
   };
 
+  const handleCopyLink = (fileId: string) => async () => {
+    try {
+      const link = await invoke<string>("get_share_link", {
+        message: {
+          directory_identifier: directoryIdentifier,
+          file_identifier: fileId,
+        },
+      });
+
+      await navigator.clipboard.writeText(link);
+    } catch (e) {
+      await message(`${e}`, { title: "Could not copy link", type: "error" });
+    }
+  };
+
+  const handleCompareFingerprints = async () => {
+    try {
+      const result = await invoke<DirectoryFingerprint>(
+        "get_directory_fingerprint",
+        { message: { directory_identifier: directoryIdentifier } }
+      );
+
+      const peers = result.peers.map(
+        (p) => `${p.peer.hostname}: ${p.inSync ? "in sync" : "different"}`
+      );
+      await message(
+        [
+          `${result.fileCount} files, fingerprint ${result.fingerprint.slice(0, 16)}`,
+          ...(peers.length > 0 ? peers : ["No connected peer has reported one"]),
+        ].join("\n"),
+        { title: "Directory fingerprint" }
+      );
+    } catch (e) {
+      await message(`${e}`, {
+        title: "Could not compute fingerprint",
+        type: "error",
+      });
+    }
+  };
+
   const handleOpenFile = (file: SharedFile) => async () => {
     if (file?.contentLocation?.localPath) {
       const result = await invoke("open_file", {
@@ -153,27 +427,41 @@ function DirectoryDetails({
 
   let rows = [];
   for (const [id, file] of files.entries()) {
-    const fileIsDownloadable = file.ownedPeers.find((peer) => {
-      for (const connectedPeer of currentPeers) {
-        if (
-          connectedPeer.hostname === peer.hostname &&
-          connectedPeer.uuid === peer.uuid
-        ) {
-          return true;
-        }
-      }
+    const fileIsDownloadable =
+      file.availability === "downloadable" ||
+      file.availability === "ownersIdle";
 
-      return false;
-    });
+    const streamButton =
+      settings.streamMedia && isMediaFile(file.name) ? (
+        <IconButton onClick={handleStream(file.identifier)}>
+          <PlayArrowIcon />
+        </IconButton>
+      ) : null;
 
     const downloadButton = fileIsDownloadable ? (
-      <IconButton onClick={handleDownload(file.identifier)} color="success">
-        <DownloadIcon />
-      </IconButton>
+      <React.Fragment>
+        {streamButton}
+        <IconButton onClick={handleDownloadTo(file.identifier)}>
+          <SaveAsIcon />
+        </IconButton>
+        <IconButton onClick={handleDownload(file.identifier)} color="success">
+          <DownloadIcon />
+        </IconButton>
+      </React.Fragment>
     ) : (
-      <IconButton color="error">
-        <DownloadIcon />
-      </IconButton>
+      <Tooltip
+        title={
+          file.availability === "noOwners"
+            ? "No device has this file any more"
+            : "No device with this file is online"
+        }
+      >
+        <span>
+          <IconButton color="error" disabled>
+            <DownloadIcon />
+          </IconButton>
+        </span>
+      </Tooltip>
     );
 
     const fileButton = file?.contentLocation?.localPath ? (
@@ -186,7 +474,7 @@ function DirectoryDetails({
 
     const row = (
       <TableRow key={id}>
-        <TableCell variant="body">{file.name}</TableCell>
+        <TableCell variant="body">{file.displayName}</TableCell>
         <TableCell variant="body" align="right">
           {toLargestDenominator(file.size)}
         </TableCell>
@@ -247,9 +535,37 @@ function DirectoryDetails({
               {directoryName}
             </Link>
           </Breadcrumbs>
-          <Button variant="contained" onClick={handleAddFiles} size="small">
-            Add Files
-          </Button>
+          <Box display={"flex"} alignItems={"center"} gap={"0.5em"}>
+            {directoryDownload && directoryDownload.filesTotal > 0 && (
+              <Typography variant="caption" color={"GrayText"}>
+                Downloaded{" "}
+                {directoryDownload.filesDone + directoryDownload.filesFailed}/
+                {directoryDownload.filesTotal}
+                {directoryDownload.filesFailed > 0 &&
+                  ` (${directoryDownload.filesFailed} failed)`}
+              </Typography>
+            )}
+            <Button
+              variant="outlined"
+              onClick={handleDownloadAll}
+              size="small"
+            >
+              Download All
+            </Button>
+            <Button
+              variant="outlined"
+              onClick={handleCompareFingerprints}
+              size="small"
+            >
+              Compare
+            </Button>
+            <Button variant="outlined" onClick={handleAddFolder} size="small">
+              Add Folder
+            </Button>
+            <Button variant="contained" onClick={handleAddFiles} size="small">
+              Add Files
+            </Button>
+          </Box>
         </Box>
         <TableContainer component={Paper} elevation={2} variant="elevation">
           <Table
@@ -281,10 +597,32 @@ function DirectoryDetails({
           </Table>
         </TableContainer>
       </Box>
+      <Dialog open={folderPath !== null} onClose={() => setFolderPath(null)}>
+        <DialogTitle>Add folder</DialogTitle>
+        <DialogContent>
+          <DialogContentText>
+            Adds every file in {folderPath} and its subfolders. Files matching
+            these patterns are left out, along with the ones in Settings,
+            e.g. "*.tmp, node_modules/, .*".
+          </DialogContentText>
+          <TextField
+            fullWidth
+            size="small"
+            label="Exclude"
+            value={folderExcludes}
+            onChange={(e) => setFolderExcludes(e.target.value)}
+            style={{ marginTop: "1em" }}
+          />
+        </DialogContent>
+        <DialogActions>
+          <Button onClick={() => setFolderPath(null)}>Cancel</Button>
+          <Button onClick={handleConfirmAddFolder}>Add</Button>
+        </DialogActions>
+      </Dialog>
       {fileDetails && (
         <Dialog open={detailsOpen} onClose={handleCloseFileDetails}>
           <div>
-            <DialogTitle>Details for {fileDetails.name}</DialogTitle>
+            <DialogTitle>Details for {fileDetails.displayName}</DialogTitle>
             <DialogContent>
               {fileDetails.contentLocation &&
                 fileDetails.contentLocation.localPath && (
@@ -297,10 +635,57 @@ function DirectoryDetails({
                     </Typography>
                   </Box>
                 )}
+              {fileDetails.contentLocation &&
+                fileDetails.contentLocation.partial && (
+                  <Box marginBottom={"1em"}>
+                    <Typography variant="caption" color={"GrayText"}>
+                      Partially downloaded to:
+                    </Typography>
+                    <Typography variant="body1">
+                      {fileDetails.contentLocation.partial.path}
+                    </Typography>
+                    {fileDetails.contentLocation.partial.bytes > 0 && (
+                      <Typography variant="body2" color={"GrayText"}>
+                        {toLargestDenominator(
+                          fileDetails.contentLocation.partial.bytes
+                        )}{" "}
+                        of {toLargestDenominator(fileDetails.size)}
+                      </Typography>
+                    )}
+                  </Box>
+                )}
+              {fileDetails.localOwnership === "listedWithoutContent" && (
+                <Typography color="error" marginBottom={"1em"}>
+                  Other devices think this device has this file, but it is not
+                  stored here.
+                </Typography>
+              )}
+              {fileDetails.localOwnership === "storedWithoutListing" && (
+                <Typography color="error" marginBottom={"1em"}>
+                  This file is stored here, but other devices don't know they
+                  can download it from this device.
+                </Typography>
+              )}
+              {fileDetails.uploads && fileDetails.uploads.count > 0 && (
+                <Box marginBottom={"1em"}>
+                  <Typography variant="caption" color={"GrayText"}>
+                    Downloaded from this device:
+                  </Typography>
+                  <Typography variant="body1">
+                    {fileDetails.uploads.count} time
+                    {fileDetails.uploads.count === 1 ? "" : "s"}
+                    {fileDetails.uploads.lastServed &&
+                      `, last on ${new Date(
+                        fileDetails.uploads.lastServed
+                      ).toLocaleString()}`}
+                  </Typography>
+                </Box>
+              )}
               {fileDetails.ownedPeers && fileDetails.ownedPeers.length > 0 && (
                 <Box>
                   <Typography variant="caption" color={"GrayText"}>
-                    Devices that have this file:
+                    Devices that have this file ({fileDetails.onlineOwnerCount}{" "}
+                    online):
                   </Typography>
                   {fileDetails.ownedPeers.map((peer) => {
                     return (
@@ -312,14 +697,34 @@ function DirectoryDetails({
             </DialogContent>
             <DialogActions>
               <Button onClick={handleCloseFileDetails}>Close</Button>
+              {(fileDetails.localOwnership === "listedWithoutContent" ||
+                fileDetails.localOwnership === "storedWithoutListing") && (
+                <Button
+                  onClick={handleRepairOwnership(fileDetails.identifier)}
+                  color="warning"
+                >
+                  Repair
+                </Button>
+              )}
               {fileDetails.contentLocation &&
                 fileDetails.contentLocation.localPath && (
-                  <Button
-                    onClick={handleDelete(fileDetails.identifier)}
-                    color="error"
-                  >
-                    <DeleteIcon />
-                  </Button>
+                  <React.Fragment>
+                    <Button onClick={handleCopyLink(fileDetails.identifier)}>
+                      Copy link
+                    </Button>
+                    <Button
+                      onClick={handleDelete(fileDetails.identifier, true)}
+                      color="warning"
+                    >
+                      Unshare
+                    </Button>
+                    <Button
+                      onClick={handleDelete(fileDetails.identifier)}
+                      color="error"
+                    >
+                      <DeleteIcon />
+                    </Button>
+                  </React.Fragment>
                 )}
             </DialogActions>
           </div>
@@ -329,4 +734,5 @@ function DirectoryDetails({
   );
 }
 
+export { toLargestDenominator };
 export default DirectoryDetails;
@@ -1,26 +1,165 @@
 import React from "react";
+import { listen } from "@tauri-apps/api/event";
+import { message } from "@tauri-apps/api/dialog";
 
 import "./Menu.css";
 import { ThemeContext } from "../App";
 import { useNavigate } from "react-router-dom";
-import { Button, Menu as MaterialMenu, MenuItem } from "@mui/material";
+import {
+  Button,
+  Dialog,
+  DialogActions,
+  DialogContent,
+  DialogTitle,
+  Menu as MaterialMenu,
+  MenuItem,
+  TextField,
+} from "@mui/material";
+import { ConnectedDevicesContext } from "../RustCommands/ConnectedDevicesContext";
+import {
+  GetProtocolMetrics,
+  OpenShareLink,
+  PeerSpeedTest,
+  RescanNetwork,
+  SetDiscoverable,
+  SetNetworkPaused,
+  invokeNetworkCommand,
+} from "../RustCommands/networkCommands";
+
+type NetworkStatus = {
+  paused: boolean;
+  discoverable: boolean;
+};
+
+type PeerProtocolMetrics = {
+  peer: { hostname: string; uuid: string } | null;
+  address: string;
+  compression: string;
+  sent: Record<string, number>;
+  received: Record<string, number>;
+  bytesSent: number;
+  bytesReceived: number;
+};
+
+type SpeedTestResult = {
+  peer: { hostname: string; uuid: string };
+  latencyMillis: number;
+  bytesSent: number;
+  bytesPerSecond: number;
+};
 
 function Menu() {
   const navigate = useNavigate();
 
   const [anchorEl, setAnchorEl] = React.useState<null | HTMLElement>(null);
+  const [paused, setPaused] = React.useState(false);
+  const [discoverable, setDiscoverable] = React.useState(true);
+  const [linkOpen, setLinkOpen] = React.useState(false);
+  const [link, setLink] = React.useState("");
+  const peers = React.useContext(ConnectedDevicesContext);
   const open = Boolean(anchorEl);
+
+  React.useEffect(() => {
+    const unlisten = listen<NetworkStatus>("NetworkStatus", (event) => {
+      setPaused(event.payload.paused);
+      setDiscoverable(event.payload.discoverable);
+    });
+
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, []);
+
+  React.useEffect(() => {
+    const unlisten = listen<PeerProtocolMetrics[]>(
+      "ProtocolMetrics",
+      (event) => {
+        for (const metrics of event.payload) {
+          console.log(
+            `Protocol metrics for ${metrics.peer?.hostname ?? "unknown peer"} (${metrics.address}, ${metrics.compression} compression): ` +
+              `${metrics.bytesSent} bytes sent, ${metrics.bytesReceived} bytes received`
+          );
+          console.table({ sent: metrics.sent, received: metrics.received });
+        }
+      }
+    );
+
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, []);
+
+  React.useEffect(() => {
+    const unlisten = listen<SpeedTestResult>("SpeedTestResult", (event) => {
+      const result = event.payload;
+      const megabits = ((result.bytesPerSecond * 8) / 1_000_000).toFixed(1);
+
+      message(
+        `Latency: ${result.latencyMillis} ms\nThroughput: ${megabits} Mbit/s`,
+        { title: `Connection to ${result.peer.hostname}` }
+      );
+    });
+
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, []);
+
   const handleClick = (event: React.MouseEvent<HTMLButtonElement>) => {
     setAnchorEl(event.currentTarget);
   };
   const handleClose = () => {
     setAnchorEl(null);
   };
+  const handleRescan = async () => {
+    handleClose();
+
+    const request: RescanNetwork = { rescanNetwork: null };
+    await invokeNetworkCommand(request);
+  };
+  const handleLogMetrics = async () => {
+    handleClose();
+
+    const request: GetProtocolMetrics = { getProtocolMetrics: null };
+    await invokeNetworkCommand(request);
+  };
+  const handleSpeedTest = async () => {
+    handleClose();
+
+    for (const peer of peers) {
+      const request: PeerSpeedTest = { peerSpeedTest: { peer } };
+      await invokeNetworkCommand(request);
+    }
+  };
+  const handleToggleDiscoverable = async () => {
+    handleClose();
+
+    const request: SetDiscoverable = { setDiscoverable: !discoverable };
+    await invokeNetworkCommand(request);
+  };
+  const handleOpenLinkDialog = () => {
+    handleClose();
+
+    setLink("");
+    setLinkOpen(true);
+  };
+  const handleOpenLink = async () => {
+    setLinkOpen(false);
+
+    const request: OpenShareLink = { openShareLink: { link: link.trim() } };
+    await invokeNetworkCommand(request);
+  };
+  const handleTogglePause = async () => {
+    handleClose();
+
+    const request: SetNetworkPaused = { setNetworkPaused: !paused };
+    await invokeNetworkCommand(request);
+  };
 
   return (
     <div className="navbar-left">
       <Button id="menu-button" color="info" onClick={handleClick}>
-        Menu
+        {paused ? "Menu (paused)" : discoverable ? "Menu" : "Menu (hidden)"}
       </Button>
       <MaterialMenu
         id="basic-menu"
@@ -44,7 +183,47 @@ function Menu() {
         >
           Settings
         </MenuItem>
+        <MenuItem onClick={handleRescan} disabled={paused}>
+          Rescan network
+        </MenuItem>
+        <MenuItem onClick={handleTogglePause}>
+          {paused ? "Resume network" : "Pause network"}
+        </MenuItem>
+        <MenuItem onClick={handleToggleDiscoverable}>
+          {discoverable ? "Hide from new devices" : "Show to new devices"}
+        </MenuItem>
+        <MenuItem
+          onClick={handleSpeedTest}
+          disabled={paused || peers.length === 0}
+        >
+          Test connection speed
+        </MenuItem>
+        <MenuItem onClick={handleOpenLinkDialog} disabled={paused}>
+          Open file link
+        </MenuItem>
+        <MenuItem onClick={handleLogMetrics}>Log protocol metrics</MenuItem>
       </MaterialMenu>
+      <Dialog open={linkOpen} onClose={() => setLinkOpen(false)}>
+        <div>
+          <DialogTitle>Open File Link</DialogTitle>
+          <DialogContent>
+            <TextField
+              id="share-link"
+              label="fileshare:// link"
+              variant="standard"
+              fullWidth
+              value={link}
+              onChange={(event) => setLink(event.target.value)}
+            />
+          </DialogContent>
+          <DialogActions>
+            <Button onClick={() => setLinkOpen(false)}>Cancel</Button>
+            <Button onClick={handleOpenLink} disabled={link.trim() === ""}>
+              Download
+            </Button>
+          </DialogActions>
+        </div>
+      </Dialog>
     </div>
   );
 }
@@ -1,12 +1,20 @@
 import React from "react";
 import { PeerId } from "./ShareDirectoryContext";
 import { listen } from "@tauri-apps/api/event";
+import { message } from "@tauri-apps/api/dialog";
 import { invokeNetworkCommand } from "./networkCommands";
 
 type GetPeers = {
   getPeers: boolean;
 };
 
+type ConnectionFailed = {
+  peer: PeerId | null;
+  addresses: Array<string>;
+  reason: string;
+  timedOut: boolean;
+};
+
 const initialState: Array<PeerId> = [];
 const ConnectedDevicesContext =
   React.createContext<Array<PeerId>>(initialState);
@@ -15,6 +23,7 @@ function ConnectedDevicesProvider({ children }: any) {
   const [peers, setPeers] = React.useState(initialState);
   const peersRef = React.useRef(peers);
   const loaded = React.useRef(false);
+  const reportedFailures = React.useRef(new Set<string>());
 
   React.useEffect(() => {
     peersRef.current = peers;
@@ -34,6 +43,35 @@ function ConnectedDevicesProvider({ children }: any) {
       });
     };
 
+    const startListenConnectionFailed = async () => {
+      const _ = await listen<ConnectionFailed>(
+        "ConnectionFailed",
+        async (event) => {
+          const input = event.payload;
+          const device = input.peer?.hostname ?? "A discovered device";
+          console.warn(
+            `Could not connect to ${device} at ${input.addresses.join(", ")}: ${input.reason}`
+          );
+
+          // Lost peers are retried periodically, so each failure is only
+          // shown once.
+          const key = input.peer?.uuid ?? input.addresses.join(",");
+          if (reportedFailures.current.has(key)) return;
+          reportedFailures.current.add(key);
+
+          const addresses = input.addresses.join(", ");
+          const text = input.timedOut
+            ? `${device} did not answer at ${addresses}. It might be on a different network or subnet.`
+            : `${device} could not be reached at ${addresses} (${input.reason}). Check that a firewall isn't blocking the connection.`;
+
+          await message(text, {
+            title: input.timedOut ? "Connection timed out" : "Connection failed",
+            type: "warning",
+          });
+        }
+      );
+    };
+
     const loadPeers = async () => {
       const request: GetPeers = {
         getPeers: true,
@@ -43,6 +81,7 @@ function ConnectedDevicesProvider({ children }: any) {
     };
 
     startListenPeers();
+    startListenConnectionFailed();
     loadPeers();
 
     loaded.current = true;
@@ -1,6 +1,9 @@
 import React from "react";
 import { listen } from "@tauri-apps/api/event";
+import { ask } from "@tauri-apps/api/dialog";
+import { invoke } from "@tauri-apps/api";
 import ClearIcon from "@mui/icons-material/Clear";
+import VerticalAlignTopIcon from "@mui/icons-material/VerticalAlignTop";
 import {
   Box,
   IconButton,
@@ -9,8 +12,17 @@ import {
   Stack,
   Typography,
 } from "@mui/material";
-import { CancelDownload, invokeNetworkCommand } from "./networkCommands";
+import {
+  CancelDownload,
+  DiscardInterruptedDownload,
+  GetInterruptedDownloads,
+  invokeNetworkCommand,
+  ReorderDownload,
+  RetryInterruptedDownload,
+} from "./networkCommands";
 import { PeerId } from "./ShareDirectoryContext";
+import { describeDownloadError, DownloadError } from "./downloadErrors";
+import { toLargestDenominator } from "../Components/DirectoryDetails";
 
 type Download = {
   peer: PeerId;
@@ -21,6 +33,7 @@ type Download = {
   fileName: string;
   filePath: string;
   canceled: boolean;
+  cancelReason?: string;
 };
 
 type DownloadUpdate = {
@@ -29,12 +42,35 @@ type DownloadUpdate = {
 };
 
 type DownloadCanceled = {
-  reason: string;
+  reason: DownloadError;
+  downloadId: string;
+  fileName: string | null;
+  peer: PeerId | null;
+};
+
+type QueuedDownload = {
   downloadId: string;
+  directoryIdentifier: string;
+  fileIdentifier: string;
+  fileName: string;
+};
+
+type InterruptedDownload = {
+  downloadId: string;
+  fileName: string;
+  filePath: string;
+  bytesDone: number;
+};
+
+type AlreadyDownloaded = {
+  directoryIdentifier: string;
+  fileIdentifier: string;
+  filePath: string;
 };
 
 function DownloadsManager({ children }: any) {
   const [downloads, setDownloads] = React.useState<Download[]>([]);
+  const [queue, setQueue] = React.useState<QueuedDownload[]>([]);
   const downloadsRef = React.useRef(downloads);
   const loaded = React.useRef(false);
 
@@ -94,6 +130,10 @@ function DownloadsManager({ children }: any) {
 
         if (alreadyDownloading) {
           alreadyDownloading.canceled = true;
+          alreadyDownloading.cancelReason = describeDownloadError(
+            input.reason,
+            input
+          );
         }
 
         setDownloads([...downloadsRef.current]);
@@ -108,9 +148,81 @@ function DownloadsManager({ children }: any) {
       });
     };
 
+    const startListenAlreadyDownloaded = async () => {
+      const _ = await listen<AlreadyDownloaded>(
+        "AlreadyDownloaded",
+        async (event) => {
+          const input = event.payload;
+
+          const openFile = await ask(
+            `File is already downloaded to ${input.filePath}. Open it?`,
+            { title: "Already downloaded", type: "info" }
+          );
+
+          if (openFile) {
+            await invoke("open_file", {
+              message: {
+                file_path: input.filePath,
+              },
+            });
+          }
+        }
+      );
+    };
+
+    const startListenDownloadQueue = async () => {
+      const _ = await listen<QueuedDownload[]>("DownloadQueue", (event) => {
+        setQueue(event.payload);
+      });
+    };
+
+    const startListenInterruptedDownloads = async () => {
+      const _ = await listen<InterruptedDownload[]>(
+        "InterruptedDownloads",
+        async (event) => {
+          for (const download of event.payload) {
+            const done =
+              download.bytesDone > 0
+                ? ` after ${toLargestDenominator(download.bytesDone)}`
+                : "";
+            const retry = await ask(
+              `Download of ${download.fileName} was interrupted${done} when the app closed. Download it again? Choosing no removes the partial file.`,
+              { title: "Interrupted download", type: "info" }
+            );
+
+            const request: RetryInterruptedDownload | DiscardInterruptedDownload =
+              retry
+                ? {
+                    retryInterruptedDownload: {
+                      download_identifier: download.downloadId,
+                    },
+                  }
+                : {
+                    discardInterruptedDownload: {
+                      download_identifier: download.downloadId,
+                    },
+                  };
+
+            await invokeNetworkCommand(request);
+          }
+        }
+      );
+    };
+
+    const loadInterruptedDownloads = async () => {
+      const request: GetInterruptedDownloads = {
+        getInterruptedDownloads: null,
+      };
+
+      await invokeNetworkCommand(request);
+    };
+
     startListenDownloadStart();
+    startListenDownloadQueue();
     startListenDownloadUpdate();
     startListenDownloadCanceled();
+    startListenAlreadyDownloaded();
+    startListenInterruptedDownloads().then(loadInterruptedDownloads);
 
     loaded.current = true;
   }, []);
@@ -130,6 +242,17 @@ function DownloadsManager({ children }: any) {
     }
   };
 
+  const handleMoveToFront = (downloadId: string) => async () => {
+    const request: ReorderDownload = {
+      reorderDownload: {
+        download_identifier: downloadId,
+        new_position: 0,
+      },
+    };
+
+    await invokeNetworkCommand(request);
+  };
+
   const downloadIndicators = downloads.map((download) => {
     const startColor = download.progress === 100 ? "success" : "primary";
     const color = download.canceled ? "error" : startColor;
@@ -156,6 +279,41 @@ function DownloadsManager({ children }: any) {
           value={download.progress}
           color={color}
         />
+        {download.cancelReason && (
+          <Typography variant="caption" color={"error"}>
+            {download.cancelReason}
+          </Typography>
+        )}
+      </Paper>
+    );
+  });
+
+  const queueIndicators = queue.map((queued, position) => {
+    return (
+      <Paper
+        key={queued.downloadId}
+        elevation={1}
+        style={{
+          padding: "0.25em 1em",
+        }}
+      >
+        <Box
+          display={"flex"}
+          justifyContent={"space-between"}
+          alignItems={"center"}
+        >
+          <Typography variant="caption" color={"GrayText"}>
+            {queued.fileName} (waiting)
+          </Typography>
+          {position > 0 && (
+            <IconButton
+              size="small"
+              onClick={handleMoveToFront(queued.downloadId)}
+            >
+              <VerticalAlignTopIcon fontSize="small" />
+            </IconButton>
+          )}
+        </Box>
       </Paper>
     );
   });
@@ -171,7 +329,10 @@ function DownloadsManager({ children }: any) {
         bottom={0}
         right={0}
       >
-        <Stack spacing={1}>{downloadIndicators}</Stack>
+        <Stack spacing={1}>
+          {downloadIndicators}
+          {queueIndicators}
+        </Stack>
       </Box>
     </React.Fragment>
   );
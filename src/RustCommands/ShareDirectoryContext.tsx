@@ -1,11 +1,19 @@
 import React from "react";
 import { listen } from "@tauri-apps/api/event";
-import { GetShareDirectories, invokeNetworkCommand } from "./networkCommands";
+import { invoke } from "@tauri-apps/api/tauri";
+import { ask, message } from "@tauri-apps/api/dialog";
+import {
+  AcceptShare,
+  GetShareDirectories,
+  RejectShare,
+  invokeNetworkCommand,
+} from "./networkCommands";
 import { v4, validate as validateUuid } from "uuid";
 
 type SerialisedShareDirectory = {
   signature: ShareDirectorySignature;
   shared_files: any;
+  sync_status: SyncStatus | null;
 };
 
 type ShareDirectories = Array<ShareDirectory>;
@@ -13,27 +21,55 @@ type ShareDirectories = Array<ShareDirectory>;
 type ShareDirectory = {
   signature: ShareDirectorySignature;
   shared_files: Map<string, SharedFile>;
+  sync_status: SyncStatus | null;
 };
 
+type SyncStatus =
+  | { state: "synced" }
+  | { state: "syncing" }
+  | { state: "outOfDate"; peers: Array<PeerId> };
+
 type ShareDirectorySignature = {
   name: string;
   identifier: string;
   lastTransactionId: string;
   sharedPeers: Array<PeerId>;
+  fingerprint?: string;
 };
 
 type SharedFile = {
   name: string;
+  displayName: string;
   identifier: string;
   contentHash: number;
   lastModified: string;
   contentLocation:
     | {
-        localPath: string;
+        localPath?: string;
+        partial?: {
+          path: string;
+          bytes: number;
+        };
       }
     | undefined;
   ownedPeers: Array<PeerId>;
   size: number;
+  uploads?: {
+    count: number;
+    lastServed?: string | null;
+  };
+  onlineOwnerCount: number;
+  localOwnership:
+    | "owned"
+    | "notOwned"
+    | "listedWithoutContent"
+    | "storedWithoutListing";
+  availability:
+    | "local"
+    | "downloadable"
+    | "ownersIdle"
+    | "ownersOffline"
+    | "noOwners";
 };
 
 type PeerId = {
@@ -41,11 +77,71 @@ type PeerId = {
   uuid: string;
 };
 
+type ShareOffer = {
+  directory: ShareDirectorySignature;
+  peer: PeerId;
+};
+
+type DirectoryShared = {
+  signature: ShareDirectorySignature;
+  fromPeer: PeerId;
+};
+
 type AddedFiles = {
   directoryIdentifier: string;
   sharedFiles: Array<SharedFile>;
 };
 
+type DirectoryListing =
+  | { directory: SerialisedShareDirectory }
+  | { signatures: Array<ShareDirectorySignature> };
+
+function deserialiseDirectory(dir: SerialisedShareDirectory): ShareDirectory {
+  const fileMap = new Map<string, SharedFile>();
+
+  Object.keys(dir.shared_files).forEach((key) => {
+    if (validateUuid(key)) {
+      fileMap.set(key, dir.shared_files[key] as SharedFile);
+    }
+  });
+
+  return {
+    signature: dir.signature,
+    shared_files: fileMap,
+    sync_status: dir.sync_status,
+  };
+}
+
+// Fetches a single directory as it is now. Later changes still arrive
+// through the UpdateDirectory event.
+async function getDirectory(
+  directoryIdentifier: string
+): Promise<ShareDirectory> {
+  const listing = await invoke<DirectoryListing>("get_directory", {
+    message: { directory_identifier: directoryIdentifier },
+  });
+
+  if (!("directory" in listing)) {
+    throw new Error("Expected a directory");
+  }
+
+  return deserialiseDirectory(listing.directory);
+}
+
+async function getDirectorySignatures(): Promise<
+  Array<ShareDirectorySignature>
+> {
+  const listing = await invoke<DirectoryListing>("get_directory", {
+    message: {},
+  });
+
+  if (!("signatures" in listing)) {
+    throw new Error("Expected directory signatures");
+  }
+
+  return listing.signatures;
+}
+
 const initialState: ShareDirectories = [];
 const ShareDirectoryContext =
   React.createContext<ShareDirectories>(initialState);
@@ -69,7 +165,7 @@ function ShareDirectoryProvider({ children }: any) {
           const input = event.payload;
           const newDirs: ShareDirectories = [
             ...directoriesRef.current,
-            { signature: input, shared_files: new Map() },
+            { signature: input, shared_files: new Map(), sync_status: null },
           ];
 
           setDirectories(newDirs);
@@ -82,23 +178,7 @@ function ShareDirectoryProvider({ children }: any) {
         "UpdateShareDirectories",
         (event) => {
           console.log("sync orig " + JSON.stringify(event.payload));
-          const input = event.payload;
-          const dirs = input.map((dir) => {
-            const fileMap = new Map<string, SharedFile>();
-
-            Object.keys(dir.shared_files).forEach((key) => {
-              if (validateUuid(key)) {
-                fileMap.set(key, dir.shared_files[key] as SharedFile);
-              }
-            });
-
-            const directory: ShareDirectory = {
-              signature: dir.signature,
-              shared_files: fileMap,
-            };
-
-            return directory;
-          });
+          const dirs = event.payload.map(deserialiseDirectory);
 
           setDirectories(dirs);
         }
@@ -110,20 +190,7 @@ function ShareDirectoryProvider({ children }: any) {
         "UpdateDirectory",
         (event) => {
           const input = event.payload;
-          const fileMap = new Map<string, SharedFile>();
-
-          Object.keys(input.shared_files).forEach((key) => {
-            if (validateUuid(key)) {
-              fileMap.set(key, input.shared_files[key] as SharedFile);
-
-              console.debug("setting file " + JSON.stringify(input.shared_files[key]));
-            }
-          });
-
-          const newDirectory: ShareDirectory = {
-            signature: input.signature,
-            shared_files: fileMap,
-          };
+          const newDirectory = deserialiseDirectory(input);
           let updatedDirectories = [newDirectory];
 
           for (const directory of directoriesRef.current) {
@@ -151,6 +218,46 @@ function ShareDirectoryProvider({ children }: any) {
       );
     };
 
+    const startListenShareOffered = async () => {
+      const _ = await listen<ShareOffer>("ShareOffered", async (event) => {
+        const input = event.payload;
+        const directoryIdentifier = input.directory.identifier;
+
+        const accepted = await ask(
+          `${input.peer.hostname} wants to share "${input.directory.name}" with you. Accept?`,
+          { title: "Incoming share", type: "info" }
+        );
+
+        if (accepted) {
+          const request: AcceptShare = {
+            acceptShare: { directory_identifier: directoryIdentifier },
+          };
+
+          await invokeNetworkCommand(request);
+        } else {
+          const request: RejectShare = {
+            rejectShare: { directory_identifier: directoryIdentifier },
+          };
+
+          await invokeNetworkCommand(request);
+        }
+      });
+    };
+
+    const startListenDirectoryShared = async () => {
+      const _ = await listen<DirectoryShared>(
+        "DirectoryShared",
+        async (event) => {
+          const input = event.payload;
+
+          await message(
+            `${input.fromPeer.hostname} shared "${input.signature.name}" with you.`,
+            { title: "New shared directory", type: "info" }
+          );
+        }
+      );
+    };
+
     const loadDirectories = async () => {
       const request: GetShareDirectories = {
         getAllShareDirectoryData: false,
@@ -162,6 +269,8 @@ function ShareDirectoryProvider({ children }: any) {
     startListenSync();
     startListenNewDir();
     startListenUpdateDirectory();
+    startListenShareOffered();
+    startListenDirectoryShared();
     loadDirectories();
 
     loaded.current = true;
@@ -174,5 +283,16 @@ function ShareDirectoryProvider({ children }: any) {
   );
 }
 
-export { ShareDirectoryProvider, ShareDirectoryContext };
-export type { SharedFile, ShareDirectory, ShareDirectorySignature, PeerId };
+export {
+  ShareDirectoryProvider,
+  ShareDirectoryContext,
+  getDirectory,
+  getDirectorySignatures,
+};
+export type {
+  SharedFile,
+  ShareDirectory,
+  ShareDirectorySignature,
+  PeerId,
+  SyncStatus,
+};
@@ -0,0 +1,67 @@
+import { PeerId } from "./ShareDirectoryContext";
+
+type DownloadError =
+  | "noClientsConnected"
+  | "directoryMissing"
+  | "fileMissing"
+  | "fileNotOwned"
+  | "fileTooLarge"
+  | "disconnected"
+  | "canceled"
+  | "readError"
+  | "writeError"
+  | "busy"
+  | "hashMismatch"
+  | "pathTooLong"
+  | "encryptionLocked"
+  | "downloadDirectoryUnavailable"
+  | "unknown";
+
+type DownloadErrorParams = {
+  fileName?: string | null;
+  peer?: PeerId | null;
+};
+
+function describeDownloadError(
+  error: DownloadError,
+  { fileName, peer }: DownloadErrorParams
+): string {
+  const file = fileName ?? "File";
+  const device = peer?.hostname ?? "the other device";
+
+  switch (error) {
+    case "noClientsConnected":
+      return "No clients to download from. Reconnect other devices to network";
+    case "directoryMissing":
+      return `${file} could not be saved because its directory was removed.`;
+    case "fileMissing":
+      return `${file} is missing. It might have been removed while downloading.`;
+    case "fileNotOwned":
+      return `${device} does not have ${file} locally.`;
+    case "fileTooLarge":
+      return `${file} changed size while it was being downloaded.`;
+    case "disconnected":
+      return `${file} was canceled since ${device} disconnected.`;
+    case "canceled":
+      return `${file} was manually canceled.`;
+    case "readError":
+      return `${device} could not read ${file}.`;
+    case "writeError":
+      return `${file} could not be written.`;
+    case "busy":
+      return `${device} is handling too many transfers. Try again later.`;
+    case "hashMismatch":
+      return `${device} has a different version of ${file}. Try again once directories have synchronized.`;
+    case "pathTooLong":
+      return `${file} could not be saved because its path is too long. Choose a shorter download directory.`;
+    case "encryptionLocked":
+      return `${file} was not downloaded because downloads are encrypted. Enter the encryption passphrase in settings first.`;
+    case "downloadDirectoryUnavailable":
+      return `${file} is waiting for the download directory. Reconnect the drive it is on or choose another download directory.`;
+    case "unknown":
+      return `${file} failed on ${device} for a reason this version does not recognize. Try updating both devices.`;
+  }
+}
+
+export type { DownloadError };
+export { describeDownloadError };
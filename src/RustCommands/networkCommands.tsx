@@ -18,6 +18,15 @@ interface AddFiles extends BackendCommand {
   };
 }
 
+// Files matching exclude_patterns or the patterns saved in settings are left out
+interface AddFolder extends BackendCommand {
+  addFolder: {
+    directory_identifier: string;
+    folder_path: string;
+    exclude_patterns: string[];
+  };
+}
+
 interface ShareDirectoryToPeers extends BackendCommand {
   shareDirectoryToPeers: {
     directory_identifier: string;
@@ -25,10 +34,26 @@ interface ShareDirectoryToPeers extends BackendCommand {
   };
 }
 
+// With keep_shared, peers that connect later get the directory too
+interface ShareDirectoryToAllPeers extends BackendCommand {
+  shareDirectoryToAllPeers: {
+    directory_identifier: string;
+    keep_shared: boolean;
+  };
+}
+
 interface DownloadFile extends BackendCommand {
   downloadFile: {
     directory_identifier: string;
     file_identifier: string;
+    destination?: string;
+    overwrite?: boolean;
+  };
+}
+
+interface DownloadDirectory extends BackendCommand {
+  downloadDirectory: {
+    directory_identifier: string;
   };
 }
 
@@ -36,6 +61,14 @@ interface DeleteFile extends BackendCommand {
   deleteFile: {
     directory_identifier: string;
     file_identifier: string;
+    keep_local_file?: boolean;
+  };
+}
+
+interface RepairOwnership extends BackendCommand {
+  repairOwnership: {
+    directory_identifier: string;
+    file_identifier: string;
   };
 }
 
@@ -46,9 +79,98 @@ interface CancelDownload extends BackendCommand {
   };
 }
 
+interface ReorderDownload extends BackendCommand {
+  reorderDownload: {
+    download_identifier: string;
+    new_position: number;
+  };
+}
+
+interface GetDownloadSpace extends BackendCommand {
+  getDownloadSpace: {
+    directory_identifier?: string;
+  };
+}
+
 interface LeaveDirectory extends BackendCommand {
   leaveDirectory: {
     directory_identifier: string;
+    delete_local_files?: boolean;
+  };
+}
+
+interface AcceptShare extends BackendCommand {
+  acceptShare: {
+    directory_identifier: string;
+  };
+}
+
+interface RejectShare extends BackendCommand {
+  rejectShare: {
+    directory_identifier: string;
+  };
+}
+
+interface DisconnectPeer extends BackendCommand {
+  disconnectPeer: {
+    peer: PeerId;
+  };
+}
+
+interface ForgetPeer extends BackendCommand {
+  forgetPeer: {
+    peer: PeerId;
+  };
+}
+
+interface PeerSpeedTest extends BackendCommand {
+  peerSpeedTest: {
+    peer: PeerId;
+  };
+}
+
+interface SetDiscoverable extends BackendCommand {
+  setDiscoverable: boolean;
+}
+
+interface OpenShareLink extends BackendCommand {
+  openShareLink: {
+    link: string;
+  };
+}
+
+interface RescanNetwork extends BackendCommand {
+  rescanNetwork: null;
+}
+
+interface SetNetworkPaused extends BackendCommand {
+  setNetworkPaused: boolean;
+}
+
+interface GetProtocolMetrics extends BackendCommand {
+  getProtocolMetrics: null;
+}
+
+interface GetInterruptedDownloads extends BackendCommand {
+  getInterruptedDownloads: null;
+}
+
+interface RetryInterruptedDownload extends BackendCommand {
+  retryInterruptedDownload: {
+    download_identifier: string;
+  };
+}
+
+interface DiscardInterruptedDownload extends BackendCommand {
+  discardInterruptedDownload: {
+    download_identifier: string;
+  };
+}
+
+interface StreamFile extends BackendCommand {
+  streamFile: {
+    directory_identifier: string;
+    file_identifier: string;
   };
 }
 
@@ -65,10 +187,30 @@ export type {
   CreateShareDirectory,
   GetShareDirectories,
   AddFiles,
+  AddFolder,
   ShareDirectoryToPeers,
+  ShareDirectoryToAllPeers,
   DownloadFile,
+  DownloadDirectory,
   DeleteFile,
+  RepairOwnership,
   CancelDownload,
+  ReorderDownload,
+  GetDownloadSpace,
   LeaveDirectory,
+  AcceptShare,
+  RejectShare,
+  DisconnectPeer,
+  ForgetPeer,
+  PeerSpeedTest,
+  OpenShareLink,
+  RescanNetwork,
+  SetNetworkPaused,
+  SetDiscoverable,
+  GetProtocolMetrics,
+  GetInterruptedDownloads,
+  RetryInterruptedDownload,
+  DiscardInterruptedDownload,
+  StreamFile,
 };
 export { invokeBackendCommand as invokeNetworkCommand };
@@ -24,10 +24,14 @@ import {
   DialogContentText,
   Checkbox,
   ListItemIcon,
+  FormControlLabel,
 } from "@mui/material";
+import { listen } from "@tauri-apps/api/event";
+import { message } from "@tauri-apps/api/dialog";
 import {
   CreateShareDirectory,
   LeaveDirectory,
+  ShareDirectoryToAllPeers,
   ShareDirectoryToPeers,
   invokeNetworkCommand,
 } from "../RustCommands/networkCommands";
@@ -36,19 +40,43 @@ import {
   ShareDirectory,
   ShareDirectoryContext,
   SharedFile,
+  SyncStatus,
 } from "../RustCommands/ShareDirectoryContext";
-import DirectoryDetails from "../Components/DirectoryDetails";
+import DirectoryDetails, {
+  toLargestDenominator,
+} from "../Components/DirectoryDetails";
 import {
   ConnectedDevicesContext,
   GetPeers,
 } from "../RustCommands/ConnectedDevicesContext";
 
+type DirectoryLeft = {
+  directoryIdentifier: string;
+  deletedFiles: number;
+  freedBytes: number;
+};
+
 type SharePeer = {
   peer: PeerId;
   sharedBefore: boolean;
   checked: boolean;
 };
 
+function describeSyncStatus(status: SyncStatus | null): string | undefined {
+  switch (status?.state) {
+    case "synced":
+      return "Synced";
+    case "syncing":
+      return "Syncing";
+    case "outOfDate":
+      return `Out of date with ${status.peers
+        .map((peer) => peer.hostname)
+        .join(", ")}`;
+    default:
+      return undefined;
+  }
+}
+
 function Directories() {
   const shareDirectories = React.useContext(ShareDirectoryContext);
   const [selectedDirectory, setSelectedDirectory] =
@@ -113,7 +141,27 @@ function Directories() {
   }, [peers, optDirectory]);
 
   const [shareOpen, setShareOpen] = React.useState(false);
+  const [keepSharedWithAll, setKeepSharedWithAll] = React.useState(false);
   const [leaveOpen, setLeaveOpen] = React.useState(false);
+  const [deleteLocalFiles, setDeleteLocalFiles] = React.useState(false);
+
+  React.useEffect(() => {
+    const unlisten = listen<DirectoryLeft>("DirectoryLeft", async (event) => {
+      const input = event.payload;
+      if (input.deletedFiles === 0) return;
+
+      await message(
+        `Deleted ${input.deletedFiles} downloaded file(s), freeing ${toLargestDenominator(
+          input.freedBytes
+        )}.`,
+        { title: "Left directory", type: "info" }
+      );
+    });
+
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, []);
 
   const handleOpenCreate = () => {
     setShareCreationName("");
@@ -238,6 +286,22 @@ function Directories() {
   const handleShareClose = () => {
     setOptDirectory(null);
     setShareOpen(false);
+    setKeepSharedWithAll(false);
+  };
+
+  const handleShareWithAll = async () => {
+    if (!optDirectory) return;
+
+    const request: ShareDirectoryToAllPeers = {
+      shareDirectoryToAllPeers: {
+        directory_identifier: optDirectory.signature.identifier,
+        keep_shared: keepSharedWithAll,
+      },
+    };
+
+    await invokeNetworkCommand(request);
+
+    handleShareClose();
   };
 
   const handleShare = async () => {
@@ -278,6 +342,7 @@ function Directories() {
   const handleLeaveClose = () => {
     setOptDirectory(null);
     setLeaveOpen(false);
+    setDeleteLocalFiles(false);
   };
 
   const handleLeave = async () => {
@@ -285,6 +350,7 @@ function Directories() {
       const request: LeaveDirectory = {
         leaveDirectory: {
           directory_identifier: selectedDirectory.signature.identifier,
+          delete_local_files: deleteLocalFiles,
         },
       };
 
@@ -325,7 +391,10 @@ function Directories() {
             style={{ maxHeight: "3em" }}
             onClick={() => handleListClick(val.signature.identifier)}
           >
-            <ListItemText id={val.signature.name}>
+            <ListItemText
+              id={val.signature.name}
+              secondary={describeSyncStatus(val.sync_status)}
+            >
               {val.signature.name}
               {usedCount && (
                 <Typography
@@ -377,7 +446,6 @@ function Directories() {
       files={selectedDirectory.shared_files}
       directoryName={selectedDirectory.signature.name}
       directoryIdentifier={selectedDirectory.signature.identifier}
-      currentPeers={peers}
     />
   ) : null;
 
@@ -419,9 +487,19 @@ function Directories() {
               Select connected devices to reveal directory to.
             </DialogContentText>
             <List>{peerList}</List>
+            <FormControlLabel
+              control={
+                <Checkbox
+                  checked={keepSharedWithAll}
+                  onChange={(e) => setKeepSharedWithAll(e.target.checked)}
+                />
+              }
+              label="Also share with devices that connect later"
+            />
           </DialogContent>
           <DialogActions>
             <Button onClick={handleShareClose}>Cancel</Button>
+            <Button onClick={handleShareWithAll}>Share with everyone</Button>
             <Button onClick={handleShare}>Share</Button>
           </DialogActions>
         </div>
@@ -435,6 +513,15 @@ function Directories() {
               Are you sure you want to leave directory{" "}
               {selectedDirectory?.signature.name}?
             </DialogContentText>
+            <FormControlLabel
+              control={
+                <Checkbox
+                  checked={deleteLocalFiles}
+                  onChange={(e) => setDeleteLocalFiles(e.target.checked)}
+                />
+              }
+              label="Also delete downloaded files"
+            />
           </DialogContent>
           <DialogActions>
             <Button onClick={handleLeaveClose}>Cancel</Button>
@@ -4,9 +4,11 @@ import {
   Container,
   Divider,
   FormGroup,
+  MenuItem,
   Paper,
   Stack,
   Switch,
+  TextField,
   Typography,
 } from "@mui/material";
 import { invoke } from "@tauri-apps/api";
@@ -14,10 +16,42 @@ import { open } from "@tauri-apps/api/dialog";
 import React from "react";
 import { Settings, SettingsContext, ThemeContext } from "../App";
 
+type AppDirectories = {
+  configDir: string;
+  dataDir: string;
+};
+
 
 function SettingsPage() {
   const { mode, toggleTheme } = React.useContext(ThemeContext);
   const { settings, updateSettings } = React.useContext(SettingsContext);
+  const [appDirectories, setAppDirectories] =
+    React.useState<AppDirectories | null>(null);
+  const [passphrase, setPassphrase] = React.useState("");
+  const [unlockStatus, setUnlockStatus] = React.useState<string | null>(null);
+  const [logFilter, setLogFilter] = React.useState("");
+  const [logFilterStatus, setLogFilterStatus] = React.useState<string | null>(
+    null
+  );
+  const [excludePatterns, setExcludePatterns] = React.useState("");
+  const [excludeStatus, setExcludeStatus] = React.useState<string | null>(
+    null
+  );
+  const [networkInterface, setNetworkInterface] = React.useState("");
+  const [interfaceStatus, setInterfaceStatus] = React.useState<
+    string | null
+  >(null);
+
+  React.useEffect(() => {
+    invoke<AppDirectories>("get_app_directories", {
+      message: { open: false },
+    }).then(setAppDirectories);
+    invoke<string>("get_log_filter").then(setLogFilter);
+    invoke<string[]>("get_exclude_patterns").then((patterns) =>
+      setExcludePatterns(patterns.join("\n"))
+    );
+    invoke<string>("get_network_interface").then(setNetworkInterface);
+  }, []);
 
   const handleSave = async () => {
     const newSettings = {
@@ -27,7 +61,7 @@ function SettingsPage() {
 
     updateSettings(newSettings);
 
-    await invoke("save_settings", { message: newSettings });
+    await invoke("save_settings", { settings: newSettings });
   };
 
   const handleSaveDirectoryChange = async () => {
@@ -56,6 +90,10 @@ function SettingsPage() {
     }
   };
 
+  const handleShowAppDirectories = async () => {
+    await invoke("get_app_directories", { message: { open: true } });
+  };
+
   const handleChangeMinimize = async () => {
     const newMinimizeOption = !settings.minimizeOnClose;
 
@@ -67,6 +105,110 @@ function SettingsPage() {
     updateSettings(newSettings);
   };
 
+  const handleChangeAutoAccept = async () => {
+    const newSettings: Settings = {
+      ...settings,
+      autoAcceptShares: !settings.autoAcceptShares,
+    };
+
+    updateSettings(newSettings);
+  };
+
+  const handleChangeStreamMedia = async () => {
+    const newSettings: Settings = {
+      ...settings,
+      streamMedia: !settings.streamMedia,
+    };
+
+    updateSettings(newSettings);
+  };
+
+  const handleChangeEncryptDownloads = async () => {
+    const newSettings: Settings = {
+      ...settings,
+      encryptDownloads: !settings.encryptDownloads,
+    };
+
+    updateSettings(newSettings);
+  };
+
+  const handleChangeDownloadNotifications = (
+    event: React.ChangeEvent<HTMLInputElement>
+  ) => {
+    const option = event.target.value as Settings["downloadNotifications"];
+
+    const newSettings: Settings = {
+      ...settings,
+      downloadNotifications: option,
+    };
+
+    updateSettings(newSettings);
+  };
+
+  const handleUnlockEncryption = async () => {
+    try {
+      await invoke("unlock_encryption", { message: { passphrase } });
+      setUnlockStatus("Passphrase accepted");
+    } catch (e) {
+      setUnlockStatus(String(e));
+    }
+
+    setPassphrase("");
+  };
+
+  const handleApplyLogFilter = async () => {
+    try {
+      await invoke("set_log_filter", { message: { filter: logFilter } });
+      setLogFilterStatus("Log filter applied");
+    } catch (e) {
+      setLogFilterStatus(String(e));
+    }
+  };
+
+  const handleApplyExcludePatterns = async () => {
+    try {
+      await invoke("set_exclude_patterns", {
+        message: { patterns: excludePatterns.split("\n") },
+      });
+      setExcludeStatus("Exclusions saved");
+    } catch (e) {
+      setExcludeStatus(String(e));
+    }
+  };
+
+  const handleApplyNetworkInterface = async () => {
+    try {
+      await invoke("set_network_interface", {
+        message: { interface: networkInterface },
+      });
+      setInterfaceStatus("Network interface saved");
+    } catch (e) {
+      setInterfaceStatus(String(e));
+    }
+  };
+
+  const handleChangeCleanup = async () => {
+    const newSettings: Settings = {
+      ...settings,
+      cleanupDownloads: !settings.cleanupDownloads,
+    };
+
+    updateSettings(newSettings);
+  };
+
+  const handleChangeCleanupAge = (
+    event: React.ChangeEvent<HTMLInputElement>
+  ) => {
+    const days = Math.max(0, Math.floor(Number(event.target.value)));
+
+    const newSettings: Settings = {
+      ...settings,
+      cleanupAgeDays: days,
+    };
+
+    updateSettings(newSettings);
+  };
+
   return (
     <Container>
       <Paper elevation={2}>
@@ -96,6 +238,90 @@ function SettingsPage() {
                     onChange={() => toggleTheme()}
                   />
                 </FormGroup>
+                <FormGroup>
+                  <Typography>Auto-accept Shares</Typography>
+                  <Switch
+                    checked={settings.autoAcceptShares}
+                    onChange={handleChangeAutoAccept}
+                  />
+                </FormGroup>
+                <FormGroup>
+                  <Typography>Play Media While Downloading</Typography>
+                  <Switch
+                    checked={settings.streamMedia}
+                    onChange={handleChangeStreamMedia}
+                  />
+                </FormGroup>
+                <FormGroup>
+                  <Typography>Notify When Downloads Finish</Typography>
+                  <TextField
+                    select
+                    size="small"
+                    value={settings.downloadNotifications}
+                    onChange={handleChangeDownloadNotifications}
+                    style={{ marginTop: "0.5em" }}
+                  >
+                    <MenuItem value="disabled">Never</MenuItem>
+                    <MenuItem value="everyDownload">For every download</MenuItem>
+                    <MenuItem value="lastDownload">
+                      When all downloads are done
+                    </MenuItem>
+                  </TextField>
+                </FormGroup>
+                <FormGroup>
+                  <Typography>Clean Up Old Partial Downloads</Typography>
+                  <Switch
+                    checked={settings.cleanupDownloads}
+                    onChange={handleChangeCleanup}
+                  />
+                  <TextField
+                    type="number"
+                    size="small"
+                    label="Older than (days)"
+                    disabled={!settings.cleanupDownloads}
+                    value={settings.cleanupAgeDays}
+                    onChange={handleChangeCleanupAge}
+                    inputProps={{ min: 0 }}
+                  />
+                </FormGroup>
+                <FormGroup>
+                  <Typography>Encrypt Downloads At Rest</Typography>
+                  <Switch
+                    checked={settings.encryptDownloads}
+                    onChange={handleChangeEncryptDownloads}
+                  />
+                  <Typography
+                    variant="caption"
+                    color={"GrayText"}
+                    maxWidth={"22em"}
+                  >
+                    Protects downloaded files if someone copies the download
+                    folder without the passphrase. Files are decrypted while
+                    the app runs, opened files are decrypted to a temporary
+                    folder, and file names and sizes stay visible. Playing
+                    media while downloading is unavailable. The passphrase
+                    is entered after every start and can't be recovered.
+                  </Typography>
+                  <TextField
+                    type="password"
+                    size="small"
+                    label="Passphrase"
+                    value={passphrase}
+                    onChange={(e) => setPassphrase(e.target.value)}
+                    style={{ marginTop: "0.5em" }}
+                  />
+                  <Button
+                    variant="contained"
+                    style={{ margin: "0.5em 0em" }}
+                    disabled={passphrase.length === 0}
+                    onClick={handleUnlockEncryption}
+                  >
+                    Enter Passphrase
+                  </Button>
+                  {unlockStatus && (
+                    <Typography variant="caption">{unlockStatus}</Typography>
+                  )}
+                </FormGroup>
               </Stack>
               <Stack>
                 <FormGroup>
@@ -115,6 +341,117 @@ function SettingsPage() {
                     Set Directory
                   </Button>
                 </FormGroup>
+                <FormGroup>
+                  <Typography>App Data</Typography>
+                  {appDirectories && (
+                    <Typography variant="caption" color={"GrayText"}>
+                      {appDirectories.configDir}
+                      {appDirectories.dataDir !== appDirectories.configDir && (
+                        <React.Fragment>
+                          <br />
+                          {appDirectories.dataDir}
+                        </React.Fragment>
+                      )}
+                    </Typography>
+                  )}
+                  <Button
+                    variant="contained"
+                    style={{ margin: "0.5em 0em" }}
+                    onClick={handleShowAppDirectories}
+                  >
+                    Show App Data
+                  </Button>
+                </FormGroup>
+                <FormGroup>
+                  <Typography>Logging</Typography>
+                  <Typography
+                    variant="caption"
+                    color={"GrayText"}
+                    maxWidth={"22em"}
+                  >
+                    A level for all messages, optionally followed by levels
+                    for single parts of the app, e.g.
+                    "warn,app::mdns=debug". Applied right away.
+                  </Typography>
+                  <TextField
+                    size="small"
+                    label="Log filter"
+                    value={logFilter}
+                    onChange={(e) => setLogFilter(e.target.value)}
+                    style={{ marginTop: "0.5em" }}
+                  />
+                  <Button
+                    variant="contained"
+                    style={{ margin: "0.5em 0em" }}
+                    onClick={handleApplyLogFilter}
+                  >
+                    Apply
+                  </Button>
+                  {logFilterStatus && (
+                    <Typography variant="caption">{logFilterStatus}</Typography>
+                  )}
+                </FormGroup>
+                <FormGroup>
+                  <Typography>Excluded From Folders</Typography>
+                  <Typography
+                    variant="caption"
+                    color={"GrayText"}
+                    maxWidth={"22em"}
+                  >
+                    Files left out whenever a folder is added, one pattern per
+                    line, e.g. "*.tmp", "node_modules/" or ".*" for hidden
+                    files.
+                  </Typography>
+                  <TextField
+                    size="small"
+                    multiline
+                    minRows={2}
+                    label="Patterns"
+                    value={excludePatterns}
+                    onChange={(e) => setExcludePatterns(e.target.value)}
+                    style={{ marginTop: "0.5em" }}
+                  />
+                  <Button
+                    variant="contained"
+                    style={{ margin: "0.5em 0em" }}
+                    onClick={handleApplyExcludePatterns}
+                  >
+                    Apply
+                  </Button>
+                  {excludeStatus && (
+                    <Typography variant="caption">{excludeStatus}</Typography>
+                  )}
+                </FormGroup>
+                <FormGroup>
+                  <Typography>Network Interface</Typography>
+                  <Typography
+                    variant="caption"
+                    color={"GrayText"}
+                    maxWidth={"22em"}
+                  >
+                    "auto" for the first network found, or the one devices on
+                    your network can reach when a VPN or virtual network is
+                    picked instead, by name ("eth0") or subnet
+                    ("192.168.1.0/24"). Applied within a few seconds.
+                  </Typography>
+                  <TextField
+                    size="small"
+                    label="Interface"
+                    value={networkInterface}
+                    onChange={(e) => setNetworkInterface(e.target.value)}
+                    style={{ marginTop: "0.5em" }}
+                  />
+                  <Button
+                    variant="contained"
+                    style={{ margin: "0.5em 0em" }}
+                    onClick={handleApplyNetworkInterface}
+                  >
+                    Apply
+                  </Button>
+                  {interfaceStatus && (
+                    <Typography variant="caption">{interfaceStatus}</Typography>
+                  )}
+                </FormGroup>
               </Stack>
             </Stack>
             <Button color="success" onClick={handleSave} variant="contained">